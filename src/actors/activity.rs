@@ -0,0 +1,71 @@
+//! Activity summaries: a periodic line per board at the INFO level (see `activity_log`), so that
+//! the health of an archive can be checked without debug logs.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{actors::LagStats, four_chan::Board};
+
+/// The activity of a board since the last summary.
+#[derive(Clone, Copy, Debug, Default)]
+struct BoardActivity {
+    /// Threads fetched, including those which weren't modified
+    threads_polled: u64,
+    posts_inserted: u64,
+    /// Media and thumbnails sent to the fetcher
+    media_queued: u64,
+    /// Failed thread fetches and database writes
+    failures: u64,
+}
+
+/// Counts the activity of each board between summaries. It can be shared with the futures which
+/// insert posts.
+#[derive(Clone, Default)]
+pub struct ActivityTracker(Arc<Mutex<HashMap<Board, BoardActivity>>>);
+
+impl ActivityTracker {
+    pub fn thread_polled(&self, board: Board) {
+        self.update(board, |activity| activity.threads_polled += 1);
+    }
+
+    pub fn posts_inserted(&self, board: Board, posts: usize) {
+        self.update(board, |activity| activity.posts_inserted += posts as u64);
+    }
+
+    pub fn media_queued(&self, board: Board, media: usize) {
+        self.update(board, |activity| activity.media_queued += media as u64);
+    }
+
+    pub fn failed(&self, board: Board) {
+        self.update(board, |activity| activity.failures += 1);
+    }
+
+    fn update<F: FnOnce(&mut BoardActivity)>(&self, board: Board, f: F) {
+        f(self.0.lock().unwrap().entry(board).or_default());
+    }
+
+    /// Log the activity of each of `boards` since the last summary (with its latest scrape lag),
+    /// and start counting again.
+    pub fn log_summary(&self, boards: &[Board], lag: &HashMap<Board, LagStats>) {
+        let activity = std::mem::replace(&mut *self.0.lock().unwrap(), HashMap::new());
+        for board in boards {
+            let BoardActivity {
+                threads_polled,
+                posts_inserted,
+                media_queued,
+                failures,
+            } = activity.get(board).cloned().unwrap_or_default();
+            let lag = match lag.get(board) {
+                Some(lag) if lag.samples > 0 => format!("{} s", lag.latest.as_secs()),
+                _ => String::from("unknown"),
+            };
+            info!(
+                "/{}/: {} threads polled, {} posts inserted, {} media queued, {} failures, \
+                 lag {}",
+                board, threads_polled, posts_inserted, media_queued, failures, lag,
+            );
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! An archive sink which saves the final state of archived threads as JSON files.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use actix::prelude::*;
+use failure::{Error, ResultExt};
+
+use super::thread_updater::ThreadArchived;
+use crate::{
+    config::ArchiveExportConfig,
+    four_chan::{Board, ThreadNo},
+};
+
+/// An actor which writes each archived thread to `<path>/<board>/<no>.json`, in the same format as
+/// the 4chan API. It receives the `ThreadArchived` events sent by the
+/// [`ThreadUpdater`](struct.ThreadUpdater.html).
+pub struct ArchiveExporter {
+    path: PathBuf,
+}
+
+impl Actor for ArchiveExporter {
+    type Context = Context<Self>;
+}
+
+impl ArchiveExporter {
+    pub fn try_new(config: &ArchiveExportConfig) -> Result<Self, Error> {
+        fs::create_dir_all(&config.path)
+            .with_context(|_| format!("Could not create {}", config.path.display()))?;
+        info!("Exporting archived threads to {}", config.path.display());
+        Ok(Self {
+            path: config.path.clone(),
+        })
+    }
+
+    /// Start the exporter in its own arbiter, so that file writes don't block other actors.
+    pub fn start_in_own_arbiter(self) -> Addr<Self> {
+        Self::start_in_arbiter(&Arbiter::new("archive_export"), move |_| self)
+    }
+
+    fn export(&self, board: Board, no: ThreadNo, json: &str) -> Result<(), Error> {
+        let dir = self.path.join(board.to_string());
+        fs::create_dir_all(&dir).with_context(|_| format!("Could not create {}", dir.display()))?;
+
+        let path = dir.join(format!("{}.json", no));
+        let temp_path = path.with_extension("json.tmp");
+        write_file(&temp_path, json)
+            .with_context(|_| format!("Could not write {}", temp_path.display()))?;
+        fs::rename(&temp_path, &path)
+            .with_context(|_| format!("Could not replace {}", path.display()))?;
+        Ok(())
+    }
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+impl Handler<ThreadArchived> for ArchiveExporter {
+    type Result = ();
+
+    fn handle(&mut self, msg: ThreadArchived, _ctx: &mut Self::Context) {
+        let ThreadArchived(board, no, json) = msg;
+        match self.export(board, no, &json) {
+            Ok(()) => debug!("/{}/ No. {}: Exported archived thread", board, no),
+            Err(err) => log_error!(err.as_fail()),
+        }
+    }
+}
@@ -1,33 +1,58 @@
-use std::{
-    cmp::Ordering,
-    collections::HashMap,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc, time::Duration};
 
 use actix::{fut, prelude::*};
 use chrono::prelude::*;
 use futures::prelude::*;
 use log::Level;
-use tokio::timer::Delay;
+use tokio::{clock, timer::Delay};
 
-use super::{fetcher::*, ThreadUpdater};
+use super::{
+    database::DatabaseLoad, fetcher::*, thread_updater::ForgetBoard, write_backlog::WriteBacklog,
+    ThreadUpdater,
+};
 use crate::{
     config::{Config, ScrapingConfig},
-    four_chan::{Board, Thread},
+    four_chan::{Board, Thread, ThreadNo},
 };
 
 #[derive(Message)]
-pub struct ArchiveUpdate(pub Board, pub Vec<u64>);
+pub struct ArchiveUpdate(pub Board, pub Vec<ThreadNo>);
 
 #[derive(Message)]
 pub struct BoardUpdate(pub Board, pub Vec<ThreadUpdate>, pub DateTime<Utc>);
 
+/// Start polling a board (see `Coordinator`).
+#[derive(Message)]
+pub struct StartPolling(pub Board);
+
+/// Stop polling a board, and forget its threads (see `Coordinator`).
+#[derive(Message)]
+pub struct StopPolling(pub Board);
+
+/// The polling state of a board.
+#[derive(Clone, Copy, Debug)]
+pub struct BoardPollStatus {
+    /// Whether the board is being polled. With `coordination`, boards whose leases are held by
+    /// other instances aren't.
+    pub polling: bool,
+    /// Threads in the latest thread list
+    pub threads: usize,
+    /// When the thread list was last fetched (or found to be unmodified)
+    pub last_poll: Option<DateTime<Utc>>,
+    /// Database writes which haven't completed yet
+    pub waiting_writes: usize,
+}
+
+pub struct GetBoardPollStatus;
+impl Message for GetBoardPollStatus {
+    type Result = Result<HashMap<Board, BoardPollStatus>, ()>;
+}
+
 pub enum ThreadUpdate {
-    New(u64),
-    Modified(u64),
-    BumpedOff(u64),
-    Deleted(u64),
+    New(ThreadNo),
+    Modified(ThreadNo),
+    BumpedOff(ThreadNo),
+    Deleted(ThreadNo),
 }
 
 /// An actor which watches a board's threads and sends updates to
@@ -37,17 +62,30 @@ pub struct BoardPoller {
     threads: HashMap<Board, Vec<Thread>>,
     thread_updater: Arc<Addr<ThreadUpdater>>,
     fetcher: Addr<Fetcher>,
+    backlog: WriteBacklog,
+    load: DatabaseLoad,
+    /// What the poll interval of each board is multiplied by while the database is overloaded
+    stretch: HashMap<Board, u32>,
+    max_stretch: u32,
+    /// The boards being polled, with the generation of their poll loop. A board's loop stops when
+    /// it is no longer the board's current generation, so that a board which is stopped and
+    /// started again isn't polled twice.
+    polling: HashMap<Board, u64>,
+    generation: u64,
+    /// Whether boards are only polled once `Coordinator` starts them
+    coordinated: bool,
+    last_poll: HashMap<Board, DateTime<Utc>>,
 }
 
 impl Actor for BoardPoller {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        for (&board, config) in self.boards.iter() {
-            if config.fetch_archive && board.is_archived() {
-                self.poll_archive(board, ctx);
+        if !self.coordinated {
+            let boards: Vec<Board> = self.boards.keys().cloned().collect();
+            for board in boards {
+                self.start_polling(board, ctx);
             }
-            self.poll(board, ctx);
         }
     }
 }
@@ -57,6 +95,8 @@ impl BoardPoller {
         config: &Config,
         thread_updater: Addr<ThreadUpdater>,
         fetcher: Addr<Fetcher>,
+        backlog: WriteBacklog,
+        load: DatabaseLoad,
     ) -> Self {
         let mut threads = HashMap::new();
         for &board in config.boards.keys() {
@@ -69,7 +109,60 @@ impl BoardPoller {
             threads,
             thread_updater: Arc::new(thread_updater),
             fetcher,
+            backlog,
+            load,
+            stretch: HashMap::new(),
+            max_stretch: config.advanced.max_poll_stretch,
+            polling: HashMap::new(),
+            generation: 0,
+            coordinated: config.coordination.is_some(),
+            last_poll: HashMap::new(),
+        }
+    }
+
+    fn start_polling(&mut self, board: Board, ctx: &mut Context<Self>) {
+        if self.polling.contains_key(&board) {
+            return;
+        }
+        self.generation += 1;
+        self.polling.insert(board, self.generation);
+        if self.boards[&board].fetch_archive && board.is_archived() {
+            self.poll_archive(board, ctx);
+        }
+        self.poll(board, self.generation, ctx);
+    }
+
+    fn stop_polling(&mut self, board: Board) {
+        if self.polling.remove(&board).is_some() {
+            self.threads.insert(board, vec![]);
+            self.stretch.remove(&board);
+            self.thread_updater.do_send(ForgetBoard(board));
+        }
+    }
+
+    /// The delay before the next poll of a board. While the database is overloaded, it is doubled
+    /// after each poll (up to `advanced.max_poll_stretch` times the poll interval).
+    fn next_poll_interval(&mut self, board: Board) -> Duration {
+        let poll_interval = self.boards[&board].poll_interval;
+        let stretch = self.stretch.entry(board).or_insert(1);
+        if self.load.is_overloaded() {
+            if *stretch < self.max_stretch {
+                *stretch = (*stretch * 2).min(self.max_stretch);
+                let (writes, latency) = self.load.current();
+                warn!(
+                    "/{}/: Database is overloaded ({} unfinished writes, {} ms per insert), \
+                     polling every {} seconds",
+                    board,
+                    writes,
+                    latency.as_secs() * 1000 + u64::from(latency.subsec_millis()),
+                    (poll_interval * *stretch).as_secs(),
+                );
+            }
+        } else if *stretch > 1 {
+            info!("/{}/: Database caught up, polling normally", board);
+            *stretch = 1;
         }
+        poll_interval * *stretch
     }
 
     fn update_threads(
@@ -280,7 +373,7 @@ impl BoardPoller {
             // It often takes 1-2 seconds for new data to go from an updated last_modified in
             // threads.json to actually showing up at the .json endpoint. We wait 3 seconds to be
             // safe and ensure that ThreadUpdater doesn't read old data.
-            Delay::new(Instant::now() + Duration::from_secs(3))
+            Delay::new(clock::now() + Duration::from_secs(3))
                 .map_err(|err| error!("{}", err))
                 .and_then(move |_| {
                     thread_updater
@@ -291,7 +384,24 @@ impl BoardPoller {
         self.threads.insert(board, curr_threads);
     }
 
-    fn poll(&self, board: Board, ctx: &mut Context<Self>) {
+    fn poll(&self, board: Board, generation: u64, ctx: &mut Context<Self>) {
+        if self.polling.get(&board) != Some(&generation) {
+            return;
+        }
+
+        // Polling creates more writes, so wait for the database to catch up first
+        if self.backlog.is_saturated(board) {
+            warn!(
+                "/{}/: {} database writes are waiting, skipping this poll",
+                board,
+                self.backlog.len(board)
+            );
+            ctx.run_later(self.boards[&board].poll_interval, move |act, ctx| {
+                act.poll(board, generation, ctx);
+            });
+            return;
+        }
+
         ctx.spawn(
             self.fetcher
                 .send(FetchThreadList(board))
@@ -299,19 +409,25 @@ impl BoardPoller {
                 .into_actor(self)
                 .timeout(self.boards[&board].poll_interval, ())
                 .then(move |res, act, ctx| {
+                    if act.polling.get(&board) != Some(&generation) {
+                        return fut::ok(());
+                    }
                     if let Ok(res) = res {
                         match res {
                             Ok((threads, last_modified)) => {
+                                act.last_poll.insert(board, Utc::now());
                                 act.update_threads(board, threads, last_modified);
                             }
                             Err(err) => match err {
-                                FetchError::NotModified => {}
+                                FetchError::NotModified => {
+                                    act.last_poll.insert(board, Utc::now());
+                                }
                                 _ => error!("/{}/: Failed to fetch threads: {}", board, err),
                             },
                         }
                     }
-                    ctx.run_later(act.boards[&board].poll_interval, move |act, ctx| {
-                        act.poll(board, ctx);
+                    ctx.run_later(act.next_poll_interval(board), move |act, ctx| {
+                        act.poll(board, generation, ctx);
                     });
                     fut::ok(())
                 }),
@@ -324,6 +440,7 @@ impl BoardPoller {
                 .send(FetchArchive(board))
                 .into_actor(self)
                 .map(move |res, act, _ctx| match res {
+                    Ok(_) if !act.polling.contains_key(&board) => {}
                     Ok(threads) => {
                         let len = threads.len();
                         debug!(
@@ -348,3 +465,39 @@ impl BoardPoller {
         );
     }
 }
+
+impl Handler<StartPolling> for BoardPoller {
+    type Result = ();
+
+    fn handle(&mut self, msg: StartPolling, ctx: &mut Self::Context) {
+        self.start_polling(msg.0, ctx);
+    }
+}
+
+impl Handler<StopPolling> for BoardPoller {
+    type Result = ();
+
+    fn handle(&mut self, msg: StopPolling, _: &mut Self::Context) {
+        self.stop_polling(msg.0);
+    }
+}
+
+impl Handler<GetBoardPollStatus> for BoardPoller {
+    type Result = Result<HashMap<Board, BoardPollStatus>, ()>;
+
+    fn handle(&mut self, _: GetBoardPollStatus, _: &mut Self::Context) -> Self::Result {
+        Ok(self
+            .boards
+            .keys()
+            .map(|&board| {
+                let status = BoardPollStatus {
+                    polling: self.polling.contains_key(&board),
+                    threads: self.threads[&board].len(),
+                    last_poll: self.last_poll.get(&board).cloned(),
+                    waiting_writes: self.backlog.len(board),
+                };
+                (board, status)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,153 @@
+//! An analytics sink which mirrors post metadata into ClickHouse.
+
+use std::sync::Arc;
+
+use actix::prelude::*;
+use failure::{Error, Fail, ResultExt};
+use futures::prelude::*;
+use hyper::{client::HttpConnector, Body, Client, Request, StatusCode};
+use hyper_tls::HttpsConnector;
+use serde::Serialize;
+use tokio::runtime::Runtime;
+
+use super::thread_updater::{PostSummary, PostsInserted};
+use crate::{
+    config::ClickHouseConfig,
+    four_chan::{Board, PostNo, ThreadNo},
+};
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+const TABLE_REPLACE: &str = "%%TABLE%%";
+
+#[derive(Debug, Fail)]
+enum ClickHouseError {
+    #[fail(display = "Bad status: {}: {}", _0, _1)]
+    BadStatus(StatusCode, String),
+}
+
+/// An actor which inserts the metadata (but not the media or text) of new posts into ClickHouse, for
+/// fast aggregate queries. It receives the same `PostsInserted` events that are sent along with
+/// posts to the [`Database`](struct.Database.html) actor.
+pub struct ClickHouse {
+    client: Arc<HttpsClient>,
+    url: String,
+    table: String,
+}
+
+impl Actor for ClickHouse {
+    type Context = Context<Self>;
+}
+
+impl ClickHouse {
+    pub fn try_new(config: &ClickHouseConfig) -> Result<Self, Error> {
+        // Connections made on this runtime can't be used on the Actix runtime after this runtime is
+        // shutdown, so this instance (and its client) is only used to create the table
+        let init = Self::with_client(config)?;
+        info!("Creating ClickHouse table");
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(init.query(
+                include_str!("../sql/clickhouse.sql").replace(TABLE_REPLACE, &init.table),
+                None,
+            ))
+            .context("Could not create ClickHouse table")?;
+        drop(init);
+        runtime.shutdown_on_idle().wait().unwrap();
+
+        Self::with_client(config)
+    }
+
+    fn with_client(config: &ClickHouseConfig) -> Result<Self, Error> {
+        let https = HttpsConnector::new(1).context("Could not create HttpsConnector")?;
+        Ok(Self {
+            client: Arc::new(Client::builder().build::<_, Body>(https)),
+            url: config.url.trim_end_matches('/').to_owned(),
+            table: config.table.clone(),
+        })
+    }
+
+    /// Send a query, with optional data, over ClickHouse's HTTP interface.
+    fn query(&self, query: String, data: Option<String>) -> impl Future<Item = (), Error = Error> {
+        let (uri, body) = match data {
+            // The query is sent in the URL so that the data can be sent as the body
+            Some(data) => (
+                format!("{}/?query={}", self.url, query.replace(' ', "%20")),
+                data,
+            ),
+            None => (format!("{}/", self.url), query),
+        };
+        let request = Request::post(uri).body(Body::from(body));
+
+        let client = self.client.clone();
+        futures::future::result(request)
+            .from_err::<Error>()
+            .and_then(move |request| client.request(request).from_err())
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().from_err().and_then(move |body| {
+                    if status.is_success() {
+                        Ok(())
+                    } else {
+                        let body = String::from_utf8_lossy(&body).trim().to_owned();
+                        Err(ClickHouseError::BadStatus(status, body).into())
+                    }
+                })
+            })
+    }
+}
+
+/// A row of the ClickHouse table.
+#[derive(Serialize)]
+struct Row<'a> {
+    board: Board,
+    num: PostNo,
+    thread_num: ThreadNo,
+    op: u8,
+    timestamp: u64,
+    capcode: char,
+    country: Option<&'a str>,
+    poster_hash: Option<&'a str>,
+    has_media: u8,
+}
+
+impl<'a> Row<'a> {
+    fn new(board: Board, post: &'a PostSummary) -> Self {
+        Self {
+            board,
+            num: post.num,
+            thread_num: post.thread_num,
+            op: post.op as u8,
+            timestamp: post.timestamp,
+            capcode: post.capcode,
+            country: post.country.as_ref().map(String::as_str),
+            poster_hash: post.poster_hash.as_ref().map(String::as_str),
+            has_media: post.has_media as u8,
+        }
+    }
+}
+
+impl Handler<PostsInserted> for ClickHouse {
+    type Result = ();
+
+    fn handle(&mut self, msg: PostsInserted, _: &mut Self::Context) {
+        let PostsInserted(board, posts) = msg;
+        let mut data = String::new();
+        for post in posts.iter() {
+            // Serializing a struct of plain data can't fail
+            data.push_str(&serde_json::to_string(&Row::new(board, post)).unwrap());
+            data.push('\n');
+        }
+
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", self.table);
+        Arbiter::spawn(self.query(query, Some(data)).map_err(move |err| {
+            error!(
+                "/{}/: Failed to insert {} post{} into ClickHouse: {}",
+                board,
+                posts.len(),
+                if posts.len() == 1 { "" } else { "s" },
+                err
+            )
+        }));
+    }
+}
@@ -0,0 +1,258 @@
+//! Board leases, for running several instances of Ena against the same database (see
+//! `coordination`). Each instance renews its row in `ena_instances`, and claims its share of the
+//! boards in `ena_board_leases`: the boards in its config divided by the number of live instances,
+//! rounded up. The boards of an instance which stops are taken over once its leases expire, and an
+//! instance holding more than its share (e.g. after another instance starts) releases the rest.
+
+use std::{collections::HashSet, time::Duration};
+
+use actix::{fut, prelude::*};
+use futures::{prelude::*, stream};
+use mysql_async::{error::Error, params, prelude::*, Conn, Pool, Value};
+use tokio::runtime::Runtime;
+
+use super::{
+    board_poller::{BoardPoller, StartPolling, StopPolling},
+    database::pool_opts,
+};
+use crate::{
+    config::{Config, CoordinationConfig},
+    four_chan::Board,
+};
+
+mod tests;
+
+/// After this many failed renewals in a row, our leases may have expired (and been taken over), so
+/// we stop scraping until we can renew them again.
+const MAX_FAILED_RENEWALS: u32 = 2;
+
+/// An actor which claims and renews board leases, and tells
+/// [`BoardPoller`](struct.BoardPoller.html) which boards to poll.
+pub struct Coordinator {
+    pool: Pool,
+    instance_id: String,
+    lease_duration: Duration,
+    /// The boards in the config, sorted
+    boards: Vec<Board>,
+    board_poller: Addr<BoardPoller>,
+    /// The boards whose leases we hold, and which are being polled
+    held: HashSet<Board>,
+    failed_renewals: u32,
+    renewing: bool,
+}
+
+impl Actor for Coordinator {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.renew(ctx);
+        ctx.run_interval(self.lease_duration / 3, |act, ctx| act.renew(ctx));
+    }
+}
+
+impl Coordinator {
+    pub fn try_new(
+        config: &Config,
+        coordination: &CoordinationConfig,
+        board_poller: Addr<BoardPoller>,
+    ) -> Result<Self, Error> {
+        let pool = Pool::new(pool_opts(&config.database_media.database_url, None)?);
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(
+            pool.get_conn()
+                .and_then(|conn| conn.drop_query(include_str!("../sql/coordination.sql")))
+                // Connections used on this runtime can't be used on the Actix runtime
+                .and_then(|conn| conn.disconnect()),
+        )?;
+        runtime.shutdown_on_idle().wait().unwrap();
+
+        let mut boards: Vec<Board> = config.boards.keys().cloned().collect();
+        boards.sort();
+        Ok(Self {
+            pool,
+            instance_id: coordination.instance_id.clone(),
+            lease_duration: coordination.lease_duration,
+            boards,
+            board_poller,
+            held: HashSet::new(),
+            failed_renewals: 0,
+            renewing: false,
+        })
+    }
+
+    /// Renew our leases and claim or release boards, unless the previous renewal is still going.
+    fn renew(&mut self, ctx: &mut Context<Self>) {
+        if self.renewing {
+            return;
+        }
+        self.renewing = true;
+
+        let instance = self.instance_id.clone();
+        let lease = self.lease_duration.as_secs();
+        let boards = self.boards.clone();
+        ctx.spawn(
+            self.pool
+                .get_conn()
+                .and_then(move |conn| claim_leases(conn, instance, lease, boards))
+                .into_actor(self)
+                .then(|res, act, _ctx| {
+                    act.renewing = false;
+                    match res {
+                        Ok(held) => {
+                            act.failed_renewals = 0;
+                            act.set_held(held);
+                        }
+                        Err(err) => {
+                            act.failed_renewals += 1;
+                            warn!("Could not renew board leases: {}", err);
+                            if act.failed_renewals >= MAX_FAILED_RENEWALS && !act.held.is_empty() {
+                                warn!("Stopping every board until board leases can be renewed");
+                                act.set_held(HashSet::new());
+                            }
+                        }
+                    }
+                    fut::ok(())
+                }),
+        );
+    }
+
+    /// Start polling newly held boards, and stop polling boards which are no longer held.
+    fn set_held(&mut self, held: HashSet<Board>) {
+        for &board in &self.boards {
+            match (self.held.contains(&board), held.contains(&board)) {
+                (false, true) => {
+                    info!("/{}/: Acquired board lease, starting", board);
+                    self.board_poller.do_send(StartPolling(board));
+                }
+                (true, false) => {
+                    info!("/{}/: No longer holding board lease, stopping", board);
+                    self.board_poller.do_send(StopPolling(board));
+                }
+                _ => {}
+            }
+        }
+        self.held = held;
+    }
+}
+
+/// Renew our leases, claim or release boards to hold our share, and return the boards whose leases
+/// we hold. Times are taken from the database server, so that instances' clocks don't matter.
+fn claim_leases(
+    conn: Conn,
+    instance: String,
+    lease: u64,
+    boards: Vec<Board>,
+) -> impl Future<Item = HashSet<Board>, Error = Error> {
+    let names: Vec<String> = boards.iter().map(Board::to_string).collect();
+    conn.drop_exec(
+        "INSERT INTO `ena_instances` (instance, expires) \
+         VALUES (:instance, UNIX_TIMESTAMP() + :lease) \
+         ON DUPLICATE KEY UPDATE expires = VALUES(expires)",
+        params! { "instance" => instance.clone(), "lease" => lease },
+    )
+    .and_then({
+        let instance = instance.clone();
+        move |conn| {
+            conn.drop_exec(
+                "UPDATE `ena_board_leases` SET expires = UNIX_TIMESTAMP() + :lease \
+                 WHERE instance = :instance",
+                params! { "instance" => instance, "lease" => lease },
+            )
+        }
+    })
+    .and_then({
+        let names = names.clone();
+        move |conn| {
+            conn.batch_exec(
+                "INSERT IGNORE INTO `ena_board_leases` (board) VALUES (:board)",
+                names
+                    .into_iter()
+                    .map(|board| params! { board })
+                    .collect::<Vec<_>>(),
+            )
+        }
+    })
+    .and_then(|conn| {
+        conn.first("SELECT COUNT(*) FROM `ena_instances` WHERE expires > UNIX_TIMESTAMP()")
+    })
+    .and_then(|(conn, live): (_, Option<(u64,)>)| {
+        conn.query("SELECT board, instance, expires > UNIX_TIMESTAMP() FROM `ena_board_leases`")
+            .and_then(|result| result.collect_and_drop::<(String, String, bool)>())
+            .map(move |(conn, leases)| (conn, live.map_or(1, |(live,)| live.max(1)), leases))
+    })
+    .and_then({
+        let instance = instance.clone();
+        move |(conn, live, leases)| {
+            let changes = lease_changes(&names, &instance, lease, live, &leases);
+            stream::iter_ok::<_, Error>(changes)
+                .fold(conn, |conn, (query, params)| conn.drop_exec(query, params))
+        }
+    })
+    .and_then(move |conn| {
+        conn.prep_exec(
+            "SELECT board FROM `ena_board_leases` \
+             WHERE instance = :instance AND expires > UNIX_TIMESTAMP()",
+            params! { instance },
+        )
+        .and_then(|result| result.collect_and_drop::<String>())
+    })
+    .map(move |(_conn, held)| {
+        boards
+            .into_iter()
+            .filter(|board| held.contains(&board.to_string()))
+            .collect()
+    })
+}
+
+/// The queries which claim free boards (or release ours) so that we hold `ceil(boards / live)`
+/// boards. `leases` are the `(board, instance, valid)` rows of `ena_board_leases`. Claims only
+/// succeed if the lease is still free when they run, in case another instance claimed it first.
+fn lease_changes(
+    boards: &[String],
+    instance: &str,
+    lease: u64,
+    live: u64,
+    leases: &[(String, String, bool)],
+) -> Vec<(&'static str, Vec<(String, Value)>)> {
+    // We're live (since we just renewed our row), even if our row expired before it was counted
+    let live = live.max(1) as usize;
+    let share = (boards.len() + live - 1) / live;
+    let ours = |board: &&String| {
+        leases
+            .iter()
+            .any(|(name, holder, valid)| name == *board && holder == instance && *valid)
+    };
+    let free = |board: &&String| {
+        leases
+            .iter()
+            .any(|(name, _, valid)| name == *board && !*valid)
+    };
+    let held: Vec<&String> = boards.iter().filter(ours).collect();
+
+    if held.len() > share {
+        held[share..]
+            .iter()
+            .map(|&board| {
+                (
+                    "UPDATE `ena_board_leases` SET instance = '', expires = 0 \
+                     WHERE board = :board AND instance = :instance",
+                    params! { "board" => board.clone(), "instance" => instance },
+                )
+            })
+            .collect()
+    } else {
+        boards
+            .iter()
+            .filter(free)
+            .take(share - held.len())
+            .map(|board| {
+                (
+                    "UPDATE `ena_board_leases` \
+                     SET instance = :instance, expires = UNIX_TIMESTAMP() + :lease \
+                     WHERE board = :board AND expires <= UNIX_TIMESTAMP()",
+                    params! { "board" => board.clone(), "instance" => instance, "lease" => lease },
+                )
+            })
+            .collect()
+    }
+}
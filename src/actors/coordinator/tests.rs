@@ -0,0 +1,110 @@
+#![cfg(test)]
+
+use mysql_async::Value;
+
+use super::lease_changes;
+
+fn boards(names: &[&str]) -> Vec<String> {
+    names.iter().map(|&name| String::from(name)).collect()
+}
+
+fn lease(board: &str, instance: &str, valid: bool) -> (String, String, bool) {
+    (String::from(board), String::from(instance), valid)
+}
+
+/// The boards that the changes claim and release, in order.
+fn claims_and_releases(
+    boards: &[String],
+    live: u64,
+    leases: &[(String, String, bool)],
+) -> (Vec<String>, Vec<String>) {
+    let (mut claims, mut releases) = (vec![], vec![]);
+    for (query, params) in lease_changes(boards, "me", 60, live, leases) {
+        let board = params
+            .iter()
+            .find(|(name, _)| name == "board")
+            .map(|(_, value)| match value {
+                Value::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                _ => panic!("Board isn't a string"),
+            })
+            .unwrap();
+        if query.contains("SET instance = ''") {
+            releases.push(board);
+        } else {
+            claims.push(board);
+        }
+    }
+    (claims, releases)
+}
+
+#[test]
+fn lease_single_instance() {
+    let boards = boards(&["a", "b", "c"]);
+    let leases = vec![
+        lease("a", "", false),
+        lease("b", "", false),
+        lease("c", "", false),
+    ];
+    let (claims, releases) = claims_and_releases(&boards, 1, &leases);
+    assert_eq!(claims, vec!["a", "b", "c"]);
+    assert!(releases.is_empty());
+
+    // Once every board is held, nothing changes
+    let leases = vec![
+        lease("a", "me", true),
+        lease("b", "me", true),
+        lease("c", "me", true),
+    ];
+    assert_eq!(claims_and_releases(&boards, 1, &leases), (vec![], vec![]));
+}
+
+#[test]
+fn lease_instance_joins() {
+    // Another instance started, so our share is ceil(3 / 2) = 2, and we release the rest
+    let boards = boards(&["a", "b", "c"]);
+    let leases = vec![
+        lease("a", "me", true),
+        lease("b", "me", true),
+        lease("c", "me", true),
+    ];
+    let (claims, releases) = claims_and_releases(&boards, 2, &leases);
+    assert!(claims.is_empty());
+    assert_eq!(releases, vec!["c"]);
+
+    // The new instance claims the released board, and then there's nothing left to change
+    let leases = vec![
+        lease("a", "me", true),
+        lease("b", "me", true),
+        lease("c", "other", true),
+    ];
+    assert_eq!(claims_and_releases(&boards, 2, &leases), (vec![], vec![]));
+}
+
+#[test]
+fn lease_expired() {
+    // The other instance stopped, and its leases expired, so we take over its boards. Boards
+    // held by live instances aren't claimed.
+    let boards = boards(&["a", "b", "c", "d"]);
+    let leases = vec![
+        lease("a", "me", true),
+        lease("b", "other", false),
+        lease("c", "third", true),
+        lease("d", "other", false),
+    ];
+    let (claims, releases) = claims_and_releases(&boards, 2, &leases);
+    assert_eq!(claims, vec!["b"]);
+    assert!(releases.is_empty());
+
+    let (claims, _) = claims_and_releases(&boards, 1, &leases);
+    assert_eq!(claims, vec!["b", "d"]);
+}
+
+#[test]
+fn lease_no_live_instances() {
+    // Our row expired before it was counted, so we count ourselves instead of dividing by zero
+    let boards = boards(&["a", "b"]);
+    let leases = vec![lease("a", "", false), lease("b", "", false)];
+    let (claims, releases) = claims_and_releases(&boards, 0, &leases);
+    assert_eq!(claims, vec!["a", "b"]);
+    assert!(releases.is_empty());
+}
@@ -0,0 +1,106 @@
+//! Rollups of board activity (see `database_media.board_stats`), so that dashboards don't need to
+//! group every post of a board. Each run recomputes the rollups from the day before the last
+//! rolled up day, so that posts inserted late (e.g. from threads fetched after a restart) are
+//! counted.
+
+use futures::future::Either;
+use mysql_async::Conn;
+
+use super::*;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The most posts (by timestamp) rolled up in one run, so that an existing archive is backfilled a
+/// month at a time instead of in one huge query.
+const MAX_SPAN: u64 = 30 * DAY;
+
+/// The rollup tables (by suffix), with the length of their periods.
+const PERIODS: &[(&str, u64)] = &[("_stats_hourly", 60 * 60), ("_stats_daily", DAY)];
+
+impl Database {
+    /// Roll up the recent posts of every board, unless the previous run is still going.
+    pub(super) fn update_board_stats(&mut self, ctx: &mut Context<Self>) {
+        if self.updating_board_stats {
+            return;
+        }
+        self.updating_board_stats = true;
+
+        let boards: Vec<Board> = self.boards.keys().cloned().collect();
+        let updates: Vec<_> = boards
+            .into_iter()
+            .map(|board| {
+                let table = self.table(board);
+                self.retry(board, "UpdateBoardStats", move |pool| {
+                    let table = table.clone();
+                    pool.get_conn().and_then(move |conn| roll_up(conn, table))
+                })
+                .then(move |res| {
+                    match res {
+                        Ok(_conn) => debug!("/{}/: Updated board statistics", board),
+                        Err(err) => {
+                            warn!("/{}/: Could not update board statistics: {}", board, err)
+                        }
+                    }
+                    Ok::<(), ()>(())
+                })
+            })
+            .collect();
+        ctx.spawn(
+            future::join_all(updates)
+                .into_actor(self)
+                .then(|_, act, _ctx| {
+                    act.updating_board_stats = false;
+                    actix::fut::ok(())
+                }),
+        );
+    }
+}
+
+/// Roll up the posts of a board since the day before its last rolled up day.
+fn roll_up(conn: Conn, table: String) -> impl Future<Item = Conn, Error = Error> {
+    conn.first(format!("SELECT MAX(`time`) FROM `{}_stats_daily`", table))
+        .and_then({
+            let table = table.clone();
+            move |(conn, last_day): (_, Option<(Option<u64>,)>)| {
+                let since = last_day
+                    .and_then(|(last_day,)| last_day)
+                    .map_or(0, |last_day| last_day.saturating_sub(DAY));
+                // Skip ahead to the first post, in case the board was empty for a while
+                conn.first(format!(
+                    "SELECT MIN(timestamp) FROM `{}` WHERE timestamp >= {} AND subnum = 0",
+                    table, since
+                ))
+            }
+        })
+        .and_then(move |(conn, first): (_, Option<(Option<u64>,)>)| {
+            match first.and_then(|(first,)| first) {
+                Some(first) => {
+                    let start = first / DAY * DAY;
+                    Either::A(conn.drop_query(roll_up_query(&table, start)))
+                }
+                None => Either::B(future::ok(conn)),
+            }
+        })
+}
+
+/// The queries rolling up at most `MAX_SPAN` of posts from `start`, which is the start of a day.
+fn roll_up_query(table: &str, start: u64) -> String {
+    PERIODS
+        .iter()
+        .map(|(suffix, length)| {
+            format!(
+                "REPLACE INTO `{table}{suffix}` (`time`, posts, images, threads, names) \
+                 SELECT timestamp DIV {length} * {length} AS period, COUNT(*), COUNT(media_hash), \
+                     SUM(op), COUNT(DISTINCT COALESCE(name, ''), COALESCE(trip, '')) \
+                 FROM `{table}` \
+                 WHERE timestamp >= {start} AND timestamp < {end} AND subnum = 0 \
+                 GROUP BY period;",
+                table = table,
+                suffix = suffix,
+                length = length,
+                start = start,
+                end = start + MAX_SPAN,
+            )
+        })
+        .collect()
+}
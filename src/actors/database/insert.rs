@@ -0,0 +1,642 @@
+//! Post insertion. Posts are written with multi-row `INSERT`s, and can be buffered so that the
+//! posts of several threads are written at once.
+
+use std::{collections::BTreeSet, mem};
+
+use futures::{future::Either, stream, sync::oneshot};
+use mysql_async::{Conn, TransactionOptions};
+
+use super::*;
+
+/// The columns which `post_row` creates values for, in order.
+const POST_COLUMNS: &str = "num, subnum, thread_num, op, timestamp, timestamp_expired, \
+                            preview_orig, preview_w, preview_h, media_filename, media_w, media_h, \
+                            media_size, media_hash, media_orig, spoiler, capcode, name, trip, \
+                            title, comment, sticky, locked, poster_hash, poster_country, exif, \
+                            comment_truncated";
+pub(super) const POST_COLUMN_COUNT: usize = 27;
+
+/// The maximum number of rows in one `INSERT`. MySQL allows at most 65,535 placeholders in a
+/// prepared statement.
+const MAX_INSERT_ROWS: usize = 65_535 / POST_COLUMN_COUNT;
+
+/// The `max_allowed_packet` to assume if the server doesn't report one (MySQL's default, 4 MiB).
+const DEFAULT_MAX_PACKET: usize = 4 * 1024 * 1024;
+
+pub struct InsertPosts(pub Board, pub ThreadNo, pub Vec<Post>);
+impl Message for InsertPosts {
+    type Result = Result<Vec<String>, Error>;
+}
+
+impl Handler<InsertPosts> for Database {
+    type Result = ResponseFuture<Vec<String>, Error>;
+
+    fn handle(&mut self, msg: InsertPosts, ctx: &mut Self::Context) -> Self::Result {
+        assert!(!msg.2.is_empty(), "Cannot insert empty thread");
+        let InsertPosts(board, no, posts) = msg;
+        let comments = || {
+            posts
+                .iter()
+                .filter_map(|post| post.comment.as_ref().map(|comment| (post.no, comment)))
+        };
+        self.store_comment_html(board, comments());
+        self.store_quotes(board, comments());
+        self.store_links(board, comments());
+
+        let write_buffer = match self.write_buffer {
+            Some(write_buffer) => write_buffer,
+            None => {
+                return Box::new(
+                    self.insert_threads(board, vec![(no, posts)])
+                        .map(|mut files| files.pop().unwrap_or_default()),
+                );
+            }
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.buffered_rows += posts.len();
+        self.insert_buffer
+            .entry(board)
+            .or_insert_with(Vec::new)
+            .push(BufferedThread { no, posts, sender });
+
+        if self.buffered_rows >= write_buffer.max_rows {
+            self.flush_insert_buffer(ctx);
+        } else if self.flush_handle.is_none() {
+            self.flush_handle = Some(ctx.run_later(write_buffer.max_delay, |act, ctx| {
+                act.flush_handle = None;
+                act.flush_insert_buffer(ctx);
+            }));
+        }
+
+        // If the insert fails, the error is logged when flushing and the sender is dropped
+        Box::new(receiver.or_else(|_canceled| Ok::<_, Error>(vec![])))
+    }
+}
+
+/// Write the insert buffer, and reply once every buffered thread has been written (or has failed to
+/// be). Writes which are still running when the `System` stops are lost, so this should be sent
+/// before stopping it.
+pub struct FlushInsertBuffer;
+impl Message for FlushInsertBuffer {
+    type Result = ();
+}
+
+impl Handler<FlushInsertBuffer> for Database {
+    type Result = ResponseFuture<(), ()>;
+
+    fn handle(&mut self, _: FlushInsertBuffer, ctx: &mut Self::Context) -> Self::Result {
+        Box::new(self.write_insert_buffer(ctx))
+    }
+}
+
+/// A thread waiting in the insert buffer.
+pub(super) struct BufferedThread {
+    no: ThreadNo,
+    posts: Vec<Post>,
+    sender: oneshot::Sender<Vec<String>>,
+}
+
+impl Database {
+    /// Insert every buffered thread in the background.
+    pub(super) fn flush_insert_buffer(&mut self, ctx: &mut Context<Self>) {
+        Arbiter::spawn(self.write_insert_buffer(ctx));
+    }
+
+    /// Insert every buffered thread. The returned future finishes once every insert has finished.
+    /// Failed inserts are logged.
+    fn write_insert_buffer(
+        &mut self,
+        ctx: &mut Context<Self>,
+    ) -> impl Future<Item = (), Error = ()> {
+        if let Some(handle) = self.flush_handle.take() {
+            ctx.cancel_future(handle);
+        }
+        self.buffered_rows = 0;
+
+        let mut writes = vec![];
+        for (board, buffered) in mem::replace(&mut self.insert_buffer, HashMap::new()) {
+            let len = buffered.len();
+            let (threads, senders): (Vec<_>, Vec<_>) = buffered
+                .into_iter()
+                .map(|thread| ((thread.no, thread.posts), thread.sender))
+                .unzip();
+
+            writes.push(self.insert_threads(board, threads).then(move |res| {
+                match res {
+                    Ok(files) => {
+                        for (sender, files) in senders.into_iter().zip(files) {
+                            // The receiver is gone if the InsertPosts sender didn't want a reply
+                            let _ = sender.send(files);
+                        }
+                    }
+                    Err(err) => error!(
+                        "/{}/: Failed to insert {} buffered thread{}: {}",
+                        board,
+                        len,
+                        if len == 1 { "" } else { "s" },
+                        err
+                    ),
+                }
+                Ok::<(), ()>(())
+            }));
+        }
+        future::join_all(writes).map(|_| ())
+    }
+
+    /// Insert the posts of threads from a board. For each thread, the media and thumbnails which
+    /// are new to the database (and should be downloaded) are returned.
+    fn insert_threads(
+        &self,
+        board: Board,
+        threads: Vec<(ThreadNo, Vec<Post>)>,
+    ) -> Box<dyn Future<Item = Vec<Vec<String>>, Error = Error>> {
+        let adjust_timestamps = self.boards[&board].adjust_timestamps;
+        let clean_options = self.clean_options.clone();
+        let download_media = self.boards[&board].download_media;
+        let download_thumbs = self.boards[&board].download_thumbs;
+        let max_comment_bytes = self.boards[&board].max_comment_bytes;
+
+        // (thread_num, num_start, num_end) of each thread
+        let ranges: Vec<(u64, u64, u64)> = threads
+            .iter()
+            .map(|(no, posts)| (no.0, posts[0].no.0, posts.last().unwrap().no.0))
+            .collect();
+        // (num, reply_to, row) of each post, where reply_to is 0 for OPs
+        let rows: Vec<(u64, u64, Vec<Value>)> = threads
+            .into_iter()
+            .flat_map(|(_no, posts)| posts)
+            .map(|post| {
+                (
+                    post.no.0,
+                    post.reply_to.map_or(0, |no| no.0),
+                    post_row(
+                        board,
+                        post,
+                        adjust_timestamps,
+                        &clean_options,
+                        max_comment_bytes,
+                    ),
+                )
+            })
+            .collect();
+        let row_count = rows.len();
+        let table = self.table(board);
+        let derived = self.derived_tables;
+
+        let entry = self.write_entry(|| {
+            JournalEntry::InsertPosts(
+                board,
+                rows.iter()
+                    .map(|(no, reply_to, row)| {
+                        (*no, *reply_to, row.iter().map(Into::into).collect())
+                    })
+                    .collect(),
+            )
+        });
+        // Writes waiting behind journaled writes are journaled too (see `journal_if_pending`). This
+        // is checked first, since a remote request is sent as soon as it's made.
+        if self.journal_if_pending(entry.as_ref()) {
+            return self.counted(WriteKind::Insert, row_count, Box::new(future::ok(vec![])));
+        }
+        let future = match (&self.remote, entry) {
+            (Some(remote), Some(entry)) if !self.dry_run => {
+                // The writer finds the new media for us, so that they're downloaded here
+                let insert =
+                    remote.insert_posts(entry.clone(), ranges, download_media, download_thumbs);
+                self.journal_on_failure(Some(entry), insert)
+            }
+            (_, entry) => self.journaled(
+                entry,
+                self.retry(board, "InsertPosts", move |pool| {
+                    insert_with_media(
+                        pool,
+                        board,
+                        table.clone(),
+                        ranges.clone(),
+                        rows.clone(),
+                        derived,
+                        download_media,
+                        download_thumbs,
+                    )
+                }),
+            ),
+        };
+        // If the write is journaled, we lose the media to download. But at least we keep the posts.
+        self.counted(WriteKind::Insert, row_count, future)
+    }
+}
+
+/// Insert rows of post values (see `insert_post_rows`), returning the media and thumbnails of each
+/// thread which are new to the database. `ranges` are the `(thread_num, num_start, num_end)` of
+/// each thread.
+pub(super) fn insert_with_media(
+    pool: Pool,
+    board: Board,
+    table: String,
+    ranges: Vec<(u64, u64, u64)>,
+    rows: Vec<(u64, u64, Vec<Value>)>,
+    derived: DerivedTables,
+    download_media: bool,
+    download_thumbs: bool,
+) -> impl Future<Item = Vec<Vec<String>>, Error = Error> {
+    let thread_count = ranges.len();
+    pool.get_conn()
+        .and_then({
+            let (table, ranges) = (table.clone(), ranges.clone());
+            move |conn| -> Box<dyn Future<Item = (Conn, Vec<u64>), Error = Error>> {
+                if download_media || download_thumbs {
+                    Box::new(next_nums(conn, &table, ranges))
+                } else {
+                    Box::new(future::ok((conn, vec![])))
+                }
+            }
+        })
+        .and_then({
+            let table = table.clone();
+            move |(conn, next_nums)| {
+                insert_post_rows(conn, board, table, rows, derived)
+                    .map(move |conn| (conn, next_nums))
+            }
+        })
+        .and_then(move |(conn, next_nums)| {
+            if download_media || download_thumbs {
+                Either::A(new_media(
+                    conn,
+                    board,
+                    &table,
+                    derived.global_media,
+                    ranges,
+                    next_nums,
+                    download_media,
+                    download_thumbs,
+                ))
+            } else {
+                Either::B(future::ok((conn, vec![vec![]; thread_count])))
+            }
+        })
+        .map(|(_conn, files)| files)
+}
+
+/// Find the number that each thread's new posts will start at. We use this to find which media
+/// were new to the database after inserting.
+fn next_nums(
+    conn: Conn,
+    table: &str,
+    ranges: Vec<(u64, u64, u64)>,
+) -> impl Future<Item = (Conn, Vec<u64>), Error = Error> {
+    let query = board_replace(
+        table,
+        "SELECT COALESCE(MAX(num) + 1, :num_start) \
+         FROM `%%BOARD%%` \
+         WHERE
+             num BETWEEN :num_start AND :num_end \
+             AND subnum = 0 \
+             AND thread_num = :thread_num;",
+    );
+    stream::iter_ok::<_, Error>(ranges).fold(
+        (conn, vec![]),
+        move |(conn, mut nums), (thread_num, num_start, num_end)| {
+            conn.first_exec(query.clone(), params! { num_start, num_end, thread_num })
+                .map(move |(conn, next_num): (_, Option<(u64,)>)| {
+                    nums.push(next_num.unwrap().0);
+                    (conn, nums)
+                })
+        },
+    )
+}
+
+/// Insert rows of post values, given with the `num` and `reply_to` of each post. We don't insert
+/// posts which have been moved to the deleted table, or posts whose thread has been moved there.
+/// Derived tables which Ena maintains itself are updated in the same transaction.
+pub(super) fn insert_post_rows(
+    conn: Conn,
+    board: Board,
+    table: String,
+    rows: Vec<(u64, u64, Vec<Value>)>,
+    derived: DerivedTables,
+) -> Box<dyn Future<Item = Conn, Error = Error>> {
+    if derived.any() {
+        Box::new(
+            conn.start_transaction(TransactionOptions::new())
+                .and_then(move |transaction| {
+                    insert_live_rows(transaction, board, table, rows, derived)
+                })
+                .and_then(|transaction| transaction.commit()),
+        )
+    } else {
+        Box::new(insert_live_rows(conn, board, table, rows, derived))
+    }
+}
+
+fn insert_live_rows<Q: Queryable + 'static>(
+    conn: Q,
+    board: Board,
+    table: String,
+    rows: Vec<(u64, u64, Vec<Value>)>,
+    derived: DerivedTables,
+) -> impl Future<Item = Q, Error = Error> {
+    let ids: BTreeSet<u64> = rows
+        .iter()
+        .flat_map(|&(no, reply_to, _)| vec![no, reply_to])
+        .filter(|&id| id != 0)
+        .collect();
+    deleted_nums(conn, &table, ids.into_iter().collect()).and_then(move |(conn, deleted)| {
+        let rows: Vec<Vec<Value>> = rows
+            .into_iter()
+            .filter(|(no, reply_to, _)| !deleted.contains(no) && !deleted.contains(reply_to))
+            .map(|(_, _, row)| row)
+            .collect();
+        if !derived.any() || rows.is_empty() {
+            return Either::A(insert_rows(conn, table, rows));
+        }
+        Either::B(triggers::classify_rows(conn, &table, &rows).and_then(
+            move |(conn, new_rows, updated_rows)| {
+                insert_rows(conn, table.clone(), rows).and_then(move |conn| {
+                    triggers::after_insert(conn, board, table, derived, new_rows, updated_rows)
+                })
+            },
+        ))
+    })
+}
+
+/// Find which of the given post numbers are in the deleted table.
+fn deleted_nums<Q: Queryable + 'static>(
+    conn: Q,
+    table: &str,
+    ids: Vec<u64>,
+) -> impl Future<Item = (Q, BTreeSet<u64>), Error = Error> {
+    let query = board_replace(
+        table,
+        &format!(
+            "SELECT num FROM `%%BOARD%%_deleted` WHERE subnum = 0 AND num IN ({});",
+            vec!["?"; ids.len()].join(", "),
+        ),
+    );
+    conn.prep_exec(query, ids)
+        .and_then(|result| result.collect_and_drop::<u64>())
+        .map(|(conn, nums)| (conn, nums.into_iter().collect()))
+}
+
+/// Insert rows of post values with as few `INSERT` statements as possible. Rows are split into
+/// chunks so that no statement has too many placeholders or exceeds the server's
+/// `max_allowed_packet`.
+fn insert_rows<Q: Queryable + 'static>(
+    conn: Q,
+    table: String,
+    rows: Vec<Vec<Value>>,
+) -> impl Future<Item = Q, Error = Error> {
+    conn.first::<_, (u64,)>("SELECT @@max_allowed_packet;")
+        .and_then(move |(conn, max_packet)| {
+            // Leave room for the statement and protocol overhead
+            let max_bytes = max_packet.map_or(DEFAULT_MAX_PACKET, |(max,)| max as usize) / 2;
+            stream::iter_ok::<_, Error>(chunk_rows(rows, max_bytes)).fold(
+                conn,
+                move |conn, chunk| {
+                    let query = insert_query(&table, chunk.len());
+                    let params: Vec<Value> = chunk.into_iter().flatten().collect();
+                    conn.drop_exec(query, params)
+                },
+            )
+        })
+}
+
+/// Split rows into chunks of at most `MAX_INSERT_ROWS` rows and (approximately) `max_bytes` bytes.
+/// A single row larger than `max_bytes` is still given its own chunk.
+fn chunk_rows(rows: Vec<Vec<Value>>, max_bytes: usize) -> Vec<Vec<Vec<Value>>> {
+    let mut chunks = vec![];
+    let mut chunk = vec![];
+    let mut chunk_bytes = 0;
+    for row in rows {
+        let row_bytes: usize = row.iter().map(value_size).sum();
+        if !chunk.is_empty()
+            && (chunk.len() == MAX_INSERT_ROWS || chunk_bytes + row_bytes > max_bytes)
+        {
+            chunks.push(mem::replace(&mut chunk, vec![]));
+            chunk_bytes = 0;
+        }
+        chunk_bytes += row_bytes;
+        chunk.push(row);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// The approximate number of bytes a value takes up in a binary protocol packet.
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::NULL => 1,
+        // Length-encoded string, with up to 9 bytes for the length
+        Value::Bytes(bytes) => bytes.len() + 9,
+        _ => 12,
+    }
+}
+
+/// Find the media and thumbnails of each thread's new posts that are new to the database. With
+/// `global_media`, files first posted on another board are already downloaded there, so they
+/// aren't new.
+fn new_media(
+    conn: Conn,
+    board: Board,
+    table: &str,
+    global_media: bool,
+    ranges: Vec<(u64, u64, u64)>,
+    next_nums: Vec<u64>,
+    download_media: bool,
+    download_thumbs: bool,
+) -> impl Future<Item = (Conn, Vec<Vec<String>>), Error = Error> {
+    let query = if global_media {
+        board_replace(
+            table,
+            "SELECT
+                 IF(media_orig = media AND media_board = :board, media_orig, NULL), \
+                 preview_orig \
+             FROM `%%BOARD%%` \
+             INNER JOIN `ena_media` ON
+                 `%%BOARD%%`.media_id = `ena_media`.media_id \
+                 AND ((preview_orig = preview_op AND preview_op_board = :board) \
+                     OR (preview_orig = preview_reply AND preview_reply_board = :board)) \
+             WHERE
+                 num BETWEEN :num_start AND :num_end \
+                 AND subnum = 0 \
+                 AND thread_num = :thread_num \
+                 AND banned = 0;",
+        )
+    } else {
+        board_replace(
+            table,
+            "SELECT
+                 IF(media_orig = media, media_orig, NULL), \
+                 preview_orig \
+             FROM `%%BOARD%%` \
+             INNER JOIN `%%BOARD%%_images` ON
+                 `%%BOARD%%`.media_id = `%%BOARD%%_images`.media_id \
+                 AND preview_orig IN (preview_reply, preview_op) \
+             WHERE
+                 num BETWEEN :num_start AND :num_end \
+                 AND subnum = 0 \
+                 AND thread_num = :thread_num \
+                 AND banned = 0;",
+        )
+    };
+    let board = board.to_string();
+    stream::iter_ok::<_, Error>(ranges.into_iter().zip(next_nums)).fold(
+        (conn, vec![]),
+        move |(conn, mut files), ((thread_num, _, num_end), num_start)| {
+            let params = params! { num_start, num_end, thread_num, "board" => board.clone() };
+            conn.prep_exec(query.clone(), params)
+                .and_then(move |result| {
+                    result.reduce_and_drop(vec![], move |mut files: Vec<String>, row| {
+                        let (media, preview) = mysql_async::from_row(row);
+                        if download_media {
+                            if let Some(media) = media {
+                                files.push(media);
+                            }
+                        }
+                        if download_thumbs {
+                            if let Some(preview) = preview {
+                                files.push(preview);
+                            }
+                        }
+                        files
+                    })
+                })
+                .map(move |(conn, thread_files)| {
+                    files.push(thread_files);
+                    (conn, files)
+                })
+        },
+    )
+}
+
+/// Create an `INSERT` statement for `rows` rows of `POST_COLUMNS`.
+fn insert_query(table: &str, rows: usize) -> String {
+    let row = format!("({})", vec!["?"; POST_COLUMN_COUNT].join(", "));
+    board_replace(
+        table,
+        &format!(
+            "INSERT INTO `%%BOARD%%` ({}) VALUES {} \
+             ON DUPLICATE KEY UPDATE \
+                 sticky = VALUES(sticky), \
+                 locked = VALUES(locked), \
+                 timestamp_expired = VALUES(timestamp_expired), \
+                 comment = VALUES(comment), \
+                 spoiler = VALUES(spoiler), \
+                 exif = COALESCE(VALUES(exif), exif), \
+                 comment_truncated = VALUES(comment_truncated);",
+            POST_COLUMNS,
+            vec![row.as_str(); rows].join(", "),
+        ),
+    )
+}
+
+/// Convert a post into values for `POST_COLUMNS`.
+// Columns missing from `POST_COLUMNS` like media_id, poster_ip, email, and delpass are either
+// always set to their defaults, set by triggers, or unused by Ena
+fn post_row(
+    board: Board,
+    post: Post,
+    adjust_timestamps: bool,
+    clean_options: &CleanOptions,
+    max_comment_bytes: Option<usize>,
+) -> Vec<Value> {
+    let no = post.no;
+    let exif = exif(&post.op_data, post.since4pass, &post.tags);
+    let (comment, comment_truncated) =
+        clean_comment(board, no, post.comment, clean_options, max_comment_bytes);
+
+    let mut row: Vec<Value> = vec![
+        post.no.into(),
+        // subnum is used for ghost posts. All scraped posts have a subnum of 0.
+        0.into(),
+        post.thread_no().into(),
+        post.is_op().into(),
+        post.time.adjust(adjust_timestamps).into(),
+        post.op_data
+            .archived_on
+            .map_or(0, |t| t.adjust(adjust_timestamps))
+            .into(),
+    ];
+
+    // We treat deleted files as if they were never posted
+    if let Some(image) = post.image.filter(|i| !i.filedeleted) {
+        let preview_orig = if image.thumbnail_width == 0 && image.thumbnail_height == 0 {
+            None
+        } else {
+            Some(format!("{}s.jpg", image.time_millis))
+        };
+        row.extend(vec![
+            preview_orig.into(),
+            image.thumbnail_width.into(),
+            image.thumbnail_height.into(),
+            (image.filename + &image.ext).into(),
+            image.image_width.into(),
+            image.image_height.into(),
+            image.filesize.into(),
+            image.md5.into(),
+            format!("{}{}", image.time_millis, image.ext).into(),
+            image.spoiler.into(),
+        ]);
+    } else {
+        row.extend(vec![
+            None::<String>.into(),
+            0.into(),
+            0.into(),
+            None::<String>.into(),
+            0.into(),
+            0.into(),
+            0.into(),
+            None::<String>.into(),
+            None::<String>.into(),
+            false.into(),
+        ]);
+    }
+
+    row.extend(vec![
+        post.capcode
+            .map_or('N', |capcode| {
+                if let Capcode::Unknown(ref unknown) = capcode {
+                    warn!("/{}/ No. {}: Unknown capcode: {}", board, no, unknown);
+                }
+                capcode.to_asagi()
+            })
+            .to_string()
+            .into(),
+        post.name
+            .map(|name| {
+                html::unescape_with(name, Some((board, no)), clean_options.decode_numeric)
+                    .into_owned()
+            })
+            .into(),
+        post.trip.into(),
+        post.subject
+            .map(|subject| {
+                html::unescape_with(subject, Some((board, no)), clean_options.decode_numeric)
+                    .into_owned()
+            })
+            .into(),
+        comment.into(),
+        post.op_data.sticky.into(),
+        // We only want to mark threads as locked if they are closed before being archived. This is
+        // because all archived threads are marked as closed.
+        (post.op_data.closed && !post.op_data.archived).into(),
+        post.id
+            .map(|id| {
+                if id == "Developer" {
+                    String::from("Dev")
+                } else {
+                    id
+                }
+            })
+            .into(),
+        // NOTE: Asagi ignores the "XX" and "A1" flags, but why? Should we? For what it's worth,
+        // they aren't in boards.json.
+        post.country.into(),
+        exif.into(),
+        comment_truncated.into(),
+    ]);
+
+    row
+}
@@ -0,0 +1,418 @@
+//! An on-disk journal of writes which failed because the database couldn't be reached. Journaled
+//! writes are replayed once the database is back, so that downtime delays posts instead of losing
+//! them.
+//!
+//! Entries are appended to `<path>` as lines of JSON. To replay, the journal is renamed to
+//! `<path>.replay` so that writes which fail during the replay go to a new journal. The replay file
+//! is only removed once every entry has been written, and since all journaled writes are
+//! idempotent, a replay that is interrupted can be safely run again.
+//!
+//! Replayed writes are older than any write made after the database came back, so they must not be
+//! run after them. While entries are waiting to be replayed (or are being replayed), new writes are
+//! appended to the journal instead of being run, and are replayed behind the older entries.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, prelude::*, BufReader, SeekFrom},
+    path::PathBuf,
+};
+
+use failure::{Error, ResultExt};
+use futures::stream;
+use mysql_async::Conn;
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+#[derive(Clone)]
+pub struct Journal {
+    path: PathBuf,
+    replay_path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf) -> Self {
+        let mut replay_path = path.clone().into_os_string();
+        replay_path.push(".replay");
+        Self {
+            path,
+            replay_path: replay_path.into(),
+        }
+    }
+
+    /// Append an entry to the journal.
+    pub fn append(&self, entry: &JournalEntry) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|_| format!("Could not open {}", self.path.display()))?;
+        let mut line = String::new();
+        // If we crashed while appending, the last line is incomplete. Start a new line, so that this
+        // entry isn't lost along with it.
+        if ends_mid_line(&mut file)
+            .with_context(|_| format!("Could not read {}", self.path.display()))?
+        {
+            line.push('\n');
+        }
+        line += &serde_json::to_string(entry).context("Could not serialize journal entry")?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+            .with_context(|_| format!("Could not write {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Append an entry to the journal if there are entries waiting to be replayed, so that it's
+    /// written after them. Returns whether the entry was appended.
+    pub fn append_if_pending(&self, entry: &JournalEntry) -> Result<bool, Error> {
+        if self.has_entries() {
+            self.append(entry)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Whether there are entries waiting to be replayed.
+    pub fn has_entries(&self) -> bool {
+        self.replay_path.exists()
+            || fs::metadata(&self.path).map_or(false, |metadata| metadata.len() > 0)
+    }
+
+    /// Start a replay, returning the entries to replay. If an earlier replay didn't finish, its
+    /// entries are returned instead of the journal's.
+    pub fn start_replay(&self) -> Result<Vec<JournalEntry>, Error> {
+        if !self.replay_path.exists() {
+            fs::rename(&self.path, &self.replay_path)
+                .with_context(|_| format!("Could not rename {}", self.path.display()))?;
+        }
+
+        let file = File::open(&self.replay_path)
+            .with_context(|_| format!("Could not open {}", self.replay_path.display()))?;
+        let mut entries = vec![];
+        for line in BufReader::new(file).lines() {
+            let line =
+                line.with_context(|_| format!("Could not read {}", self.replay_path.display()))?;
+            // The last line may be incomplete if we crashed while appending to it
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("Skipping corrupt journal entry: {}", err),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finish a replay whose entries have all been written.
+    pub fn finish_replay(&self) -> Result<(), Error> {
+        fs::remove_file(&self.replay_path)
+            .with_context(|_| format!("Could not remove {}", self.replay_path.display()))?;
+        Ok(())
+    }
+}
+
+fn ends_mid_line(file: &mut File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// The queries that journal entries can execute. Entries name a query instead of containing its
+/// text, so that replaying an entry (or executing one from a scraping instance, in `ena writer`)
+/// can only run Ena's own queries, on the tables of a configured board.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Query {
+    /// Update a thread which has been archived, whose locked status is kept
+    UpdateArchivedOp,
+    UpdateOp,
+    UpdatePost,
+    InsertRawPosts,
+    InsertHtml,
+    InsertQuotes,
+    InsertLinks,
+    InsertMediaHash,
+    MarkPostsRemoved,
+    /// See `triggers::touch_threads`
+    TouchThreads,
+}
+
+impl Query {
+    /// The text of this query on the board with base table `table`.
+    pub fn build(self, table: &str) -> String {
+        board_replace(table, self.template())
+    }
+
+    fn template(self) -> &'static str {
+        match self {
+            Query::UpdateArchivedOp => {
+                "UPDATE `%%BOARD%%` \
+                 SET sticky = :sticky, timestamp_expired = :timestamp_expired, \
+                     exif = COALESCE(:exif, exif) \
+                 WHERE num = :num AND subnum = 0"
+            }
+            Query::UpdateOp => {
+                "UPDATE `%%BOARD%%` \
+                 SET sticky = :sticky, locked = :locked, timestamp_expired = :timestamp_expired, \
+                     exif = COALESCE(:exif, exif) \
+                 WHERE num = :num AND subnum = 0"
+            }
+            // When a file is deleted, we clear the media columns like in a post without media. But,
+            // we keep media_id and media_hash so that the triggers can keep the images table
+            // consistent.
+            Query::UpdatePost => {
+                "UPDATE `%%BOARD%%` \
+                 SET comment = :comment, comment_truncated = :comment_truncated, \
+                     spoiler = :spoiler, \
+                     media_filename = IF(:file_deleted, NULL, media_filename), \
+                     media_orig = IF(:file_deleted, NULL, media_orig), \
+                     media_w = IF(:file_deleted, 0, media_w), \
+                     media_h = IF(:file_deleted, 0, media_h), \
+                     media_size = IF(:file_deleted, 0, media_size), \
+                     preview_orig = IF(:file_deleted, NULL, preview_orig), \
+                     preview_w = IF(:file_deleted, 0, preview_w), \
+                     preview_h = IF(:file_deleted, 0, preview_h) \
+                 WHERE num = :num AND subnum = 0"
+            }
+            Query::InsertRawPosts => {
+                "INSERT IGNORE INTO `%%BOARD%%_raw` (num, timestamp_fetched, json) \
+                 VALUES (:num, :timestamp_fetched, COMPRESS(:json))"
+            }
+            Query::InsertHtml => {
+                "INSERT INTO `%%BOARD%%_html` (num, comment) VALUES (:num, :comment) \
+                 ON DUPLICATE KEY UPDATE comment = VALUES(comment)"
+            }
+            Query::InsertQuotes => {
+                "INSERT IGNORE INTO `%%BOARD%%_quotes` (num, quoted_board, quoted_num) \
+                 VALUES (:num, :quoted_board, :quoted_num)"
+            }
+            Query::InsertLinks => {
+                "INSERT IGNORE INTO `%%BOARD%%_links` (num, url_hash, url, domain) \
+                 VALUES (:num, UNHEX(MD5(:url)), :url, :domain)"
+            }
+            Query::InsertMediaHash => {
+                "INSERT IGNORE INTO `%%BOARD%%_media_hashes` (media_hash, sha256) \
+                 VALUES (:media_hash, UNHEX(:sha256))"
+            }
+            Query::MarkPostsRemoved => {
+                "UPDATE `%%BOARD%%` \
+                 SET deleted = :deleted, timestamp_expired = :timestamp_expired \
+                 WHERE num = :num AND subnum = 0"
+            }
+            Query::TouchThreads => {
+                "UPDATE `%%BOARD%%_threads` INNER JOIN `%%BOARD%%` \
+                 ON `%%BOARD%%_threads`.thread_num = `%%BOARD%%`.thread_num \
+                 SET time_last_modified = GREATEST(time_last_modified, :timestamp) \
+                 WHERE num = :num AND subnum = 0;"
+            }
+        }
+    }
+}
+
+/// A write which can be replayed. Entries don't contain table names, which are found from the
+/// board when the entry is replayed.
+#[derive(Clone, Deserialize, Serialize)]
+pub enum JournalEntry {
+    /// Rows of post values (see `insert::post_row`) to insert into a board's base table, with the
+    /// `num` and `reply_to` of each post so that posts in the deleted table can be skipped.
+    InsertPosts(Board, Vec<(u64, u64, Vec<JournalValue>)>),
+    /// A query on a board's tables, executed once for each set of named parameters.
+    Exec(Board, Query, Vec<Vec<(String, JournalValue)>>),
+    /// Entries of one board which make up a single write, replayed in order on one connection.
+    Batch(Board, Vec<JournalEntry>),
+}
+
+impl JournalEntry {
+    pub fn exec(board: Board, query: Query, params: &[Vec<(String, Value)>]) -> Self {
+        JournalEntry::Exec(
+            board,
+            query,
+            params
+                .iter()
+                .map(|params| {
+                    params
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.into()))
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    /// Log the write that this entry describes, for a dry run. Long values are truncated.
+    pub fn log_dry_run(&self) {
+        match self {
+            JournalEntry::InsertPosts(board, rows) => {
+                info!("/{}/: Dry run: Insert {} posts", board, rows.len());
+                for (no, _, row) in rows {
+                    let values: Vec<_> = row.iter().map(JournalValue::summary).collect();
+                    debug!("/{}/: Dry run: No. {}: {}", board, no, values.join(", "));
+                }
+            }
+            JournalEntry::Exec(board, query, params) => {
+                info!(
+                    "/{}/: Dry run: {:?} ({} parameter sets)",
+                    board,
+                    query,
+                    params.len()
+                );
+                for params in params {
+                    let params: Vec<_> = params
+                        .iter()
+                        .map(|(name, value)| format!("{} = {}", name, value.summary()))
+                        .collect();
+                    debug!("/{}/: Dry run: {}", board, params.join(", "));
+                }
+            }
+            JournalEntry::Batch(_, entries) => {
+                for entry in entries {
+                    entry.log_dry_run();
+                }
+            }
+        }
+    }
+
+    pub fn board(&self) -> Board {
+        match self {
+            JournalEntry::InsertPosts(board, ..)
+            | JournalEntry::Exec(board, ..)
+            | JournalEntry::Batch(board, ..) => *board,
+        }
+    }
+
+    /// Write this entry to the database. `table_template` is `database_media.table_template`, which
+    /// gives the tables of the entry's board.
+    pub fn replay(
+        self,
+        conn: Conn,
+        table_template: &str,
+        derived: DerivedTables,
+    ) -> Box<dyn Future<Item = Conn, Error = mysql_async::error::Error>> {
+        let table = table_name(table_template, self.board());
+        match self {
+            JournalEntry::InsertPosts(board, rows) => {
+                insert::insert_post_rows(conn, board, table, post_rows(rows), derived)
+            }
+            JournalEntry::Exec(_, query, params) => {
+                let params = params.into_iter().map(|params| {
+                    params
+                        .into_iter()
+                        .map(|(name, value)| (name, Value::from(value)))
+                        .collect::<Vec<_>>()
+                });
+                Box::new(conn.batch_exec(query.build(&table), params))
+            }
+            JournalEntry::Batch(_, entries) => {
+                let table_template = table_template.to_owned();
+                Box::new(stream::iter_ok(entries).fold(conn, move |conn, entry| {
+                    entry.replay(conn, &table_template, derived)
+                }))
+            }
+        }
+    }
+}
+
+/// Convert journaled rows of post values back into MySQL values.
+pub fn post_rows(rows: Vec<(u64, u64, Vec<JournalValue>)>) -> Vec<(u64, u64, Vec<Value>)> {
+    rows.into_iter()
+        .map(|(no, reply_to, row)| {
+            let mut row: Vec<Value> = row.into_iter().map(Value::from).collect();
+            // Rows journaled before `comment_truncated` was added don't have it
+            if row.len() == insert::POST_COLUMN_COUNT - 1 {
+                row.push(false.into());
+            }
+            (no, reply_to, row)
+        })
+        .collect()
+}
+
+/// Write entries to the database, in order. Entries of boards which are no longer being scraped
+/// are dropped.
+pub fn replay(
+    pools: HashMap<Board, Pool>,
+    table_template: String,
+    entries: Vec<JournalEntry>,
+    derived: DerivedTables,
+) -> impl Future<Item = (), Error = mysql_async::error::Error> {
+    stream::iter_ok(entries).for_each(
+        move |entry| -> Box<dyn Future<Item = (), Error = mysql_async::error::Error>> {
+            match pools.get(&entry.board()) {
+                Some(pool) => {
+                    let table_template = table_template.clone();
+                    Box::new(
+                        pool.get_conn()
+                            .and_then(move |conn| entry.replay(conn, &table_template, derived))
+                            .map(|_conn| ()),
+                    )
+                }
+                None => {
+                    warn!(
+                        "/{}/: Dropping journaled write of unknown board",
+                        entry.board()
+                    );
+                    Box::new(future::ok(()))
+                }
+            }
+        },
+    )
+}
+
+/// A serializable version of the MySQL values that Ena uses.
+#[derive(Clone, Deserialize, Serialize)]
+pub enum JournalValue {
+    Null,
+    Bytes(Vec<u8>),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+}
+
+impl JournalValue {
+    /// A short description of this value for logging.
+    fn summary(&self) -> String {
+        const MAX_LEN: usize = 32;
+        match self {
+            JournalValue::Null => String::from("NULL"),
+            JournalValue::Bytes(bytes) => {
+                let string = String::from_utf8_lossy(bytes);
+                if string.chars().count() > MAX_LEN {
+                    let truncated: String = string.chars().take(MAX_LEN).collect();
+                    format!("{:?}... ({} bytes)", truncated, bytes.len())
+                } else {
+                    format!("{:?}", string)
+                }
+            }
+            JournalValue::Int(int) => int.to_string(),
+            JournalValue::UInt(uint) => uint.to_string(),
+            JournalValue::Float(float) => float.to_string(),
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for JournalValue {
+    fn from(value: &'a Value) -> Self {
+        match value {
+            Value::NULL => JournalValue::Null,
+            Value::Bytes(bytes) => JournalValue::Bytes(bytes.clone()),
+            Value::Int(int) => JournalValue::Int(*int),
+            Value::UInt(uint) => JournalValue::UInt(*uint),
+            Value::Float(float) => JournalValue::Float(*float),
+            Value::Date(..) | Value::Time(..) => unreachable!("Ena doesn't write dates or times"),
+        }
+    }
+}
+
+impl From<JournalValue> for Value {
+    fn from(value: JournalValue) -> Self {
+        match value {
+            JournalValue::Null => Value::NULL,
+            JournalValue::Bytes(bytes) => Value::Bytes(bytes),
+            JournalValue::Int(int) => Value::Int(int),
+            JournalValue::UInt(uint) => Value::UInt(uint),
+            JournalValue::Float(float) => Value::Float(float),
+        }
+    }
+}
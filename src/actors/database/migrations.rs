@@ -0,0 +1,155 @@
+//! Versioned schema migrations.
+//!
+//! `boards.sql` and `triggers.sql` create the original (Asagi) schema and should not be changed.
+//! Instead, schema changes are added to `MIGRATIONS`. The version of a board's schema is the number
+//! of migrations which have been applied to it, and is stored in the `ena_schema_version` table
+//! (keyed by the board's base table name). Boards without a version (new boards, or boards created
+//! before migrations existed) are at version 0.
+//!
+//! Migrations are idempotent: a change is skipped if the schema already has it (checked through
+//! `information_schema`). So, if Ena crashes partway through a migration, or another scraper
+//! sharing the tables already made the change, the migration can be safely run again.
+
+use futures::stream;
+use mysql_async::Conn;
+
+use super::*;
+
+/// A column to add to one of a board's tables.
+struct AddColumn {
+    /// The suffix of the table (e.g. `"_deleted"`), or `""` for the board's main table
+    table_suffix: &'static str,
+    column: &'static str,
+    /// The column definition, as in `ALTER TABLE ... ADD COLUMN <column> <definition>`
+    definition: &'static str,
+}
+
+/// Migrations, in order. Never edit or remove a migration which has been released. Add a new one
+/// instead.
+const MIGRATIONS: &[&[AddColumn]] = &[
+    // 1: Mark posts whose comments were truncated by `max_comment_bytes`
+    &[
+        AddColumn {
+            table_suffix: "",
+            column: "comment_truncated",
+            definition: "bool NOT NULL DEFAULT '0'",
+        },
+        AddColumn {
+            table_suffix: "_deleted",
+            column: "comment_truncated",
+            definition: "bool NOT NULL DEFAULT '0'",
+        },
+    ],
+];
+
+/// Bring the schema of each board up to date. Boards are given by their base table names.
+pub fn migrate(pool: &Pool, tables: Vec<String>) -> impl Future<Item = (), Error = Error> {
+    pool.get_conn()
+        .and_then(|conn| conn.drop_query(include_str!("../../sql/schema_version.sql")))
+        .and_then(|conn| stream::iter_ok(tables).fold(conn, migrate_board))
+        // See the comment about disconnecting in `Database::try_new`
+        .and_then(|conn| conn.disconnect())
+}
+
+/// The latest schema version, which every board is at after `migrate`.
+pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Read the schema version of each table. Tables are given by their base table names.
+pub fn schema_versions(
+    pool: &Pool,
+    tables: Vec<String>,
+) -> impl Future<Item = Vec<usize>, Error = Error> {
+    pool.get_conn()
+        .and_then(|conn| {
+            stream::iter_ok(tables).fold((conn, vec![]), |(conn, mut versions), table| {
+                conn.first_exec(
+                    "SELECT version FROM `ena_schema_version` WHERE table_name = :table",
+                    params! { "table" => table },
+                )
+                .map(|(conn, version): (_, Option<(usize,)>)| {
+                    versions.push(version.map_or(0, |(version,)| version));
+                    (conn, versions)
+                })
+            })
+        })
+        .and_then(|(conn, versions)| conn.disconnect().map(|()| versions))
+}
+
+fn migrate_board(conn: Conn, table: String) -> impl Future<Item = Conn, Error = Error> {
+    conn.first_exec(
+        "SELECT version FROM `ena_schema_version` WHERE table_name = :table",
+        params! { "table" => table.clone() },
+    )
+    .and_then(move |(conn, version): (_, Option<(usize,)>)| {
+        let version = version.map_or(0, |(version,)| version);
+        if version > MIGRATIONS.len() {
+            warn!(
+                "`{}`: Schema version {} is newer than this version of Ena (latest is {})",
+                table,
+                version,
+                MIGRATIONS.len(),
+            );
+        }
+
+        let pending: Vec<(usize, &[AddColumn])> = MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(version)
+            .map(|(i, &migration)| (i + 1, migration))
+            .collect();
+        stream::iter_ok(pending).fold(conn, move |conn, (version, migration)| {
+            info!("`{}`: Applying schema migration {}", table, version);
+            let table = table.clone();
+            stream::iter_ok(migration)
+                .fold(conn, {
+                    let table = table.clone();
+                    move |conn, change| add_column(conn, &table, change)
+                })
+                .and_then(move |conn| {
+                    conn.drop_exec(
+                        "INSERT INTO `ena_schema_version` (table_name, version) \
+                         VALUES (:table, :version) \
+                         ON DUPLICATE KEY UPDATE version = VALUES(version)",
+                        params! {
+                            "table" => table,
+                            "version" => version,
+                        },
+                    )
+                })
+        })
+    })
+}
+
+/// Add a column to a board's table, unless the table already has it. The board is given by its
+/// base table name.
+fn add_column(
+    conn: Conn,
+    base_table: &str,
+    change: &'static AddColumn,
+) -> impl Future<Item = Conn, Error = Error> {
+    let table = format!("{}{}", base_table, change.table_suffix);
+    conn.first_exec(
+        "SELECT COUNT(*) FROM information_schema.COLUMNS \
+         WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = :table AND COLUMN_NAME = :column",
+        params! {
+            "table" => table.clone(),
+            "column" => change.column,
+        },
+    )
+    .and_then(
+        move |(conn, count): (_, Option<(u64,)>)| -> Box<dyn Future<Item = Conn, Error = Error>> {
+            if count.map_or(0, |(count,)| count) > 0 {
+                debug!("`{}` already has column `{}`", table, change.column);
+                return Box::new(future::ok(conn));
+            }
+            // Identifiers can't be bound as parameters, so the table name is formatted in, as in
+            // `board_replace`
+            Box::new(conn.drop_query(format!(
+                "ALTER TABLE `{}` ADD COLUMN `{}` {}",
+                table, change.column, change.definition,
+            )))
+        },
+    )
+}
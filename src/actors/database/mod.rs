@@ -0,0 +1,1188 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    slice,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix::prelude::*;
+use chrono::prelude::*;
+use chrono_tz::America;
+use futures::{
+    future::{self, Loop},
+    prelude::*,
+};
+use mysql_async::{
+    error::{DriverError, Error},
+    params,
+    prelude::*,
+    Opts, OptsBuilder, Pool, PoolConstraints, Value,
+};
+use tokio::{clock, runtime::Runtime, timer::Delay};
+
+use crate::{
+    config::{
+        BoardStatsConfig, Config, PoolConfig, RetryBackoffConfig, ScrapingConfig, WriteBufferConfig,
+    },
+    four_chan::{Board, Capcode, OpData, Post, PostNo, ThreadNo},
+    html::{self, CleanOptions},
+};
+
+mod board_stats;
+mod insert;
+mod journal;
+mod migrations;
+mod query;
+mod remote;
+mod stats;
+mod tests;
+mod timestamps;
+mod triggers;
+
+use self::{
+    insert::BufferedThread,
+    journal::{Journal, JournalEntry, Query},
+    remote::RemoteWriter,
+    stats::WriteKind,
+    triggers::DerivedTables,
+};
+pub use self::{
+    insert::{FlushInsertBuffer, InsertPosts},
+    migrations::latest_version as latest_schema_version,
+    query::{GetMediaHashes, GetRecentPosts, GetThread, PostRow},
+    remote::start_writer,
+    stats::{DatabaseLoad, DatabaseStats, GetDatabaseStats},
+    timestamps::{check_timestamp_modes, migrate_timestamps},
+};
+
+/// How often to check for journaled writes to replay.
+const JOURNAL_REPLAY_INTERVAL: Duration = Duration::from_secs(60);
+/// How often to log a summary of the unknown HTML entities and tags in cleaned posts.
+const UNKNOWN_HTML_REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const BOARD_REPLACE: &str = "%%BOARD%%";
+const CHARSET_REPLACE: &str = "%%CHARSET%%";
+
+/// An actor which provides an interface to the MySQL database.
+pub struct Database {
+    boards: Arc<HashMap<Board, ScrapingConfig>>,
+    /// The connection pool of each board. Boards on the same server share a pool.
+    pools: HashMap<Board, Pool>,
+    pool_count: usize,
+    clean_options: Arc<CleanOptions>,
+    table_template: String,
+    retry_backoff: Option<RetryBackoffConfig>,
+    write_buffer: Option<WriteBufferConfig>,
+    insert_buffer: HashMap<Board, Vec<BufferedThread>>,
+    buffered_rows: usize,
+    flush_handle: Option<SpawnHandle>,
+    journal: Option<Journal>,
+    replaying: bool,
+    /// Log writes instead of executing them, and don't read from the database
+    dry_run: bool,
+    /// Send writes (and the reads the scraper needs) to an `ena writer` instance instead of the
+    /// database
+    remote: Option<RemoteWriter>,
+    derived_tables: DerivedTables,
+    stats: Arc<Mutex<DatabaseStats>>,
+    slow_query_threshold: Option<Duration>,
+    mailbox_capacity: usize,
+    board_stats: Option<BoardStatsConfig>,
+    updating_board_stats: bool,
+}
+
+impl Database {
+    pub fn try_new(config: &Config) -> Result<Self, Error> {
+        // Boards are grouped by server so that each server only gets one pool
+        let mut servers: HashMap<&str, (Pool, Vec<Board>)> = HashMap::new();
+        let mut pools = HashMap::new();
+        for &board in config.boards.keys() {
+            let url = config
+                .database_media
+                .board_database_urls
+                .get(&board)
+                .unwrap_or(&config.database_media.database_url);
+            let (pool, boards) = match servers.entry(url) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let opts = pool_opts(url, config.database_media.pool)?;
+                    entry.insert((Pool::new(opts), vec![]))
+                }
+            };
+            boards.push(board);
+            pools.insert(board, pool.clone());
+        }
+
+        let table_template = table_template(config);
+        let dry_run = config.database_media.dry_run;
+        let native_triggers = config.database_media.native_triggers;
+        let global_media = config.database_media.global_media;
+
+        if dry_run {
+            warn!("Dry run: writes will be logged instead of executed");
+        } else if let Some(address) = &config.database_media.remote_writer {
+            info!("Sending writes to the remote writer at {}", address);
+        } else {
+            let mut runtime = Runtime::new().unwrap();
+
+            if config.asagi_compat.create_index_counters {
+                for (pool, _) in servers.values() {
+                    runtime.block_on(
+                        pool.get_conn()
+                            .and_then(|conn| {
+                                conn.drop_query(include_str!("../../sql/index_counters.sql"))
+                            })
+                            .and_then(|conn| conn.disconnect()),
+                    )?;
+                }
+            }
+
+            if native_triggers {
+                for (pool, boards) in servers.values() {
+                    let tables = boards
+                        .iter()
+                        .map(|&board| table_name(&table_template, board))
+                        .collect();
+                    runtime.block_on(triggers::check_no_triggers(pool, tables))?;
+                }
+            }
+
+            // The config check ensures that every board is on the same server
+            if global_media {
+                for (pool, _) in servers.values() {
+                    runtime.block_on(
+                        pool.get_conn()
+                            .and_then(|conn| {
+                                conn.drop_query(include_str!("../../sql/global_media.sql"))
+                            })
+                            .and_then(|conn| conn.disconnect()),
+                    )?;
+                }
+            }
+
+            info!("Creating database tables and triggers");
+            runtime.block_on({
+                let boards: Vec<Board> = config.boards.keys().cloned().collect();
+                let boards_config = config.boards.clone();
+                let pools = pools.clone();
+                let table_template = table_template.clone();
+                let charset = config.database_media.charset.clone();
+                let update_users_table = config.asagi_compat.update_users_table;
+                let board_stats = config.database_media.board_stats.is_some();
+                future::join_all(boards.into_iter().map(move |board| {
+                    let table = table_name(&table_template, board);
+                    let board_config = &boards_config[&board];
+                    let mut charset = board_config.charset.as_ref().unwrap_or(&charset).clone();
+                    if let Some(collation) = &board_config.collation {
+                        charset.push_str(" COLLATE=");
+                        charset.push_str(collation);
+                    }
+                    let mut init_sql = String::new();
+                    init_sql.push_str(&board_replace(
+                        &table,
+                        &include_str!("../../sql/boards.sql").replace(CHARSET_REPLACE, &charset),
+                    ));
+                    if !native_triggers {
+                        init_sql.push_str(&board_replace(
+                            &table,
+                            include_str!("../../sql/triggers.sql"),
+                        ));
+                    }
+
+                    if update_users_table {
+                        init_sql.push_str(&board_replace(
+                            &table,
+                            &include_str!("../../sql/users.sql").replace(CHARSET_REPLACE, &charset),
+                        ));
+                    }
+
+                    if board_stats {
+                        init_sql.push_str(&board_replace(
+                            &table,
+                            include_str!("../../sql/board_stats.sql"),
+                        ));
+                    }
+
+                    if board_config.store_raw_json {
+                        init_sql
+                            .push_str(&board_replace(&table, include_str!("../../sql/raw.sql")));
+                    }
+
+                    if board_config.store_comment_html {
+                        init_sql
+                            .push_str(&board_replace(&table, include_str!("../../sql/html.sql")));
+                    }
+
+                    if board_config.store_quotes {
+                        init_sql
+                            .push_str(&board_replace(&table, include_str!("../../sql/quotes.sql")));
+                    }
+
+                    if board_config.store_links {
+                        init_sql
+                            .push_str(&board_replace(&table, include_str!("../../sql/links.sql")));
+                    }
+
+                    if board_config.store_media_hashes {
+                        init_sql.push_str(&board_replace(
+                            &table,
+                            include_str!("../../sql/media_hashes.sql"),
+                        ));
+                    }
+
+                    pools[&board]
+                        .get_conn()
+                        .and_then(|conn| conn.drop_query(init_sql))
+                        // If we don't disconnect these connections, and try to use them on the Actix
+                        // current_thread runtime after we shutdown this runtime, we will get a "reactor
+                        // gone" message.
+                        .and_then(|conn| conn.disconnect())
+                        .map(move |_| debug!("/{}/: Created table and triggers", board))
+                }))
+            })?;
+
+            info!("Applying database migrations");
+            for (pool, boards) in servers.values() {
+                let tables = boards
+                    .iter()
+                    .map(|&board| table_name(&table_template, board))
+                    .collect();
+                runtime.block_on(migrations::migrate(pool, tables))?;
+            }
+            runtime.shutdown_on_idle().wait().unwrap();
+        }
+
+        Ok(Self {
+            boards: config.boards.clone(),
+            pool_count: servers.len(),
+            pools,
+            clean_options: Arc::new(CleanOptions {
+                preserve_links: config.asagi_compat.preserve_links,
+                decode_numeric: config.asagi_compat.decode_numeric_references,
+                span_tags: {
+                    let mut span_tags = CleanOptions::default().span_tags;
+                    span_tags.extend(config.html.span_tags.clone());
+                    span_tags
+                },
+                ..CleanOptions::default()
+            }),
+            table_template,
+            retry_backoff: config.database_media.retry_backoff,
+            write_buffer: config.database_media.write_buffer,
+            insert_buffer: HashMap::new(),
+            buffered_rows: 0,
+            flush_handle: None,
+            journal: if dry_run {
+                None
+            } else {
+                config.database_media.journal_path.clone().map(Journal::new)
+            },
+            replaying: false,
+            dry_run,
+            remote: config.database_media.remote_writer.clone().map(|address| {
+                RemoteWriter::new(address, config.database_media.remote_writer_secret.clone())
+            }),
+            derived_tables: DerivedTables {
+                threads_images: native_triggers,
+                global_media,
+                users: config.asagi_compat.update_users_table,
+            },
+            stats: Arc::new(Mutex::new(DatabaseStats::default())),
+            slow_query_threshold: config.database_media.slow_query_threshold,
+            mailbox_capacity: config.advanced.database_mailbox_capacity,
+            board_stats: config.database_media.board_stats,
+            updating_board_stats: false,
+        })
+    }
+}
+
+impl Database {
+    /// Get the connection pool of a board.
+    fn pool(&self, board: Board) -> Pool {
+        self.pools[&board].clone()
+    }
+
+    /// Get the base table name of a board.
+    fn table(&self, board: Board) -> String {
+        table_name(&self.table_template, board)
+    }
+
+    /// Run a database operation for a `name` message. If the connection to the database fails (or
+    /// the operation fails with another error which could go away, see `is_transient_error`), the
+    /// operation is run again after a delay (with exponential backoff), so that a brief outage
+    /// doesn't lose data. New connections are taken from the pool on each attempt, and each
+    /// attempt is timed separately (see `timed`).
+    fn retry<F, R, T>(
+        &self,
+        board: Board,
+        name: &'static str,
+        op: F,
+    ) -> Box<dyn Future<Item = T, Error = Error>>
+    where
+        F: Fn(Pool) -> R + 'static,
+        R: Future<Item = T, Error = Error> + 'static,
+        T: 'static,
+    {
+        let pool = self.pool(board);
+        let (stats, threshold) = (self.stats.clone(), self.slow_query_threshold);
+        let attempt = move |pool| -> Box<dyn Future<Item = T, Error = Error>> {
+            match threshold {
+                Some(threshold) => {
+                    stats::timed(stats.clone(), threshold, board, name, Box::new(op(pool)))
+                }
+                None => Box::new(op(pool)),
+            }
+        };
+        let backoff = match self.retry_backoff {
+            Some(backoff) => backoff,
+            None => return attempt(pool),
+        };
+
+        Box::new(future::loop_fn(backoff.base, move |delay| {
+            attempt(pool.clone()).then(
+                move |res| -> Box<dyn Future<Item = Loop<T, Duration>, Error = Error>> {
+                    match res {
+                        Ok(item) => Box::new(future::ok(Loop::Break(item))),
+                        Err(ref err) if delay <= backoff.max && is_transient_error(err) => {
+                            let wait = backoff.jitter.apply(delay);
+                            warn!(
+                                "Database operation failed, retrying in {} seconds: {}",
+                                wait.as_secs(),
+                                err
+                            );
+                            Box::new(
+                                Delay::new(clock::now() + wait)
+                                    .then(move |_| Ok(Loop::Continue(delay * backoff.factor))),
+                            )
+                        }
+                        Err(err) => Box::new(future::err(err)),
+                    }
+                },
+            )
+        }))
+    }
+
+    /// Describe a write, if the description is needed for journaling, for a dry run, or for the
+    /// remote writer.
+    fn write_entry<F: FnOnce() -> JournalEntry>(&self, entry: F) -> Option<JournalEntry> {
+        if self.dry_run || self.journal.is_some() || self.remote.is_some() {
+            Some(entry())
+        } else {
+            None
+        }
+    }
+
+    /// If a write fails because the database can't be reached, append `entry` to the journal so
+    /// that the write can be replayed later. If older writes are waiting to be replayed, `entry` is
+    /// appended behind them instead of being written (`future` is never run). In a dry run, `entry`
+    /// is logged and the write is skipped, and with a remote writer, `entry` is sent to it instead.
+    /// `entry` should be created with `write_entry`.
+    fn journaled<T>(
+        &self,
+        entry: Option<JournalEntry>,
+        future: Box<dyn Future<Item = T, Error = Error>>,
+    ) -> Box<dyn Future<Item = T, Error = Error>>
+    where
+        T: Default + 'static,
+    {
+        if self.dry_run {
+            if let Some(entry) = entry {
+                entry.log_dry_run();
+            }
+            return Box::new(future::ok(T::default()));
+        }
+        if self.journal_if_pending(entry.as_ref()) {
+            return Box::new(future::ok(T::default()));
+        }
+
+        match (&self.remote, entry) {
+            (Some(remote), Some(entry)) => {
+                let write = Box::new(remote.write(entry.clone()).map(|()| T::default()));
+                self.journal_on_failure(Some(entry), write)
+            }
+            (_, entry) => self.journal_on_failure(entry, future),
+        }
+    }
+
+    /// If older writes are waiting to be replayed, append `entry` to the journal behind them, so
+    /// that the replay can't overwrite it. Returns whether `entry` was appended, in which case the
+    /// write must be skipped.
+    fn journal_if_pending(&self, entry: Option<&JournalEntry>) -> bool {
+        let (journal, entry) = match (&self.journal, entry) {
+            (Some(journal), Some(entry)) => (journal, entry),
+            _ => return false,
+        };
+        match journal.append_if_pending(entry) {
+            Ok(appended) => appended,
+            // Better to risk writing out of order than to lose the write
+            Err(err) => {
+                log_error!(err.as_fail());
+                false
+            }
+        }
+    }
+
+    /// Append `entry` to the journal (if there is one) if `future` fails because the database (or
+    /// the remote writer) can't be reached.
+    fn journal_on_failure<T>(
+        &self,
+        entry: Option<JournalEntry>,
+        future: Box<dyn Future<Item = T, Error = Error>>,
+    ) -> Box<dyn Future<Item = T, Error = Error>>
+    where
+        T: Default + 'static,
+    {
+        let (journal, entry) = match (self.journal.clone(), entry) {
+            (Some(journal), Some(entry)) => (journal, entry),
+            _ => return future,
+        };
+
+        Box::new(future.or_else(move |err| {
+            if !is_transient_error(&err) {
+                return Err(err);
+            }
+            match journal.append(&entry) {
+                Ok(()) => {
+                    warn!("Database write failed, journaled write: {}", err);
+                    Ok(T::default())
+                }
+                Err(journal_err) => {
+                    log_error!(journal_err.as_fail());
+                    Err(err)
+                }
+            }
+        }))
+    }
+
+    /// Replay journaled writes, if there are any and a replay isn't already running.
+    fn replay_journal(&mut self, ctx: &mut Context<Self>) {
+        let journal = match &self.journal {
+            Some(journal) if !self.replaying && journal.has_entries() => journal.clone(),
+            _ => return,
+        };
+        let entries = match journal.start_replay() {
+            Ok(entries) => entries,
+            Err(err) => {
+                log_error!(err.as_fail());
+                return;
+            }
+        };
+
+        let len = entries.len();
+        info!("Replaying {} journaled writes", len);
+        self.replaying = true;
+        let replay: Box<dyn Future<Item = (), Error = Error>> = match &self.remote {
+            Some(remote) => Box::new(remote.replay(entries)),
+            None => Box::new(journal::replay(
+                self.pools.clone(),
+                self.table_template.clone(),
+                entries,
+                self.derived_tables,
+            )),
+        };
+        ctx.spawn(replay.into_actor(self).then(move |res, act, ctx| {
+            act.replaying = false;
+            match res {
+                Ok(()) => match journal.finish_replay() {
+                    Ok(()) => {
+                        info!("Replayed {} journaled writes", len);
+                        // Replay the writes which were journaled during this replay, so that new
+                        // writes stop waiting behind them as soon as possible
+                        act.replay_journal(ctx);
+                    }
+                    Err(err) => log_error!(err.as_fail()),
+                },
+                Err(err) => warn!("Could not replay journal, will retry later: {}", err),
+            }
+            actix::fut::ok(())
+        }));
+    }
+}
+
+/// MySQL server errors which don't depend on the query, so that it could succeed if retried.
+const TRANSIENT_SERVER_ERRORS: &[u16] = &[
+    1040, // ER_CON_COUNT_ERROR (too many connections)
+    1053, // ER_SERVER_SHUTDOWN
+    1205, // ER_LOCK_WAIT_TIMEOUT
+    1213, // ER_LOCK_DEADLOCK
+    1927, // ER_CONNECTION_KILLED
+    3032, // ER_SERVER_OFFLINE_MODE
+];
+
+/// Whether an error was caused by a lost or refused connection, a server shutdown, or a lock
+/// conflict (as opposed to, say, a bad query), meaning that the operation could succeed if retried.
+/// Depending on how the connection is lost, the driver reports it as an I/O error or as a closed
+/// connection.
+fn is_transient_error(err: &Error) -> bool {
+    match err {
+        Error::Io(_) => true,
+        Error::Driver(DriverError::ConnectionClosed)
+        | Error::Driver(DriverError::PoolDisconnected) => true,
+        Error::Server(err) => TRANSIENT_SERVER_ERRORS.contains(&err.code),
+        _ => false,
+    }
+}
+
+impl Actor for Database {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.mailbox_capacity);
+
+        if self.journal.is_some() {
+            self.replay_journal(ctx);
+            ctx.run_interval(JOURNAL_REPLAY_INTERVAL, |act, ctx| act.replay_journal(ctx));
+        }
+
+        if !self.dry_run && self.remote.is_none() {
+            ctx.run_interval(timestamps::CHECK_INTERVAL, |act, _| {
+                act.check_timestamp_conversions()
+            });
+        }
+
+        if let (Some(board_stats), false) =
+            (self.board_stats, self.dry_run || self.remote.is_some())
+        {
+            self.update_board_stats(ctx);
+            ctx.run_interval(board_stats.interval, |act, ctx| act.update_board_stats(ctx));
+        }
+
+        // Comments are cleaned here, so we're responsible for reporting on unknown HTML
+        ctx.run_interval(UNKNOWN_HTML_REPORT_INTERVAL, |_, _| {
+            html::log_unknown_report()
+        });
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        self.flush_insert_buffer(ctx);
+        Running::Stop
+    }
+}
+
+impl Supervised for Database {
+    fn restarting(&mut self, ctx: &mut Self::Context) {
+        // Futures spawned in our context (such as the flush timer and journal replay) were
+        // dropped, so their state is reset. Buffered posts are flushed right away instead of
+        // waiting for a timer.
+        self.flush_handle = None;
+        self.replaying = false;
+        self.updating_board_stats = false;
+        self.flush_insert_buffer(ctx);
+    }
+}
+
+pub struct GetUnarchivedThreads(pub Board, pub Vec<ThreadNo>);
+impl Message for GetUnarchivedThreads {
+    type Result = Result<Vec<ThreadNo>, Error>;
+}
+
+impl Handler<GetUnarchivedThreads> for Database {
+    type Result = ResponseFuture<Vec<ThreadNo>, Error>;
+
+    fn handle(&mut self, msg: GetUnarchivedThreads, _: &mut Self::Context) -> Self::Result {
+        if self.dry_run {
+            debug!(
+                "/{}/: Dry run: assuming all archived threads are in the database",
+                msg.0
+            );
+            return Box::new(future::ok(vec![]));
+        }
+        if let Some(remote) = &self.remote {
+            return Box::new(remote.get_unarchived_threads(msg.0, msg.1));
+        }
+
+        let future = Box::new(
+            self.pool(msg.0)
+                .get_conn()
+                .and_then(|conn| {
+                    conn.drop_query("CREATE TEMPORARY TABLE archive_threads (id int unsigned);")
+                })
+                .and_then({
+                    let params = msg.1.into_iter().map(|id| params! { id });
+                    |conn| conn.batch_exec("INSERT INTO archive_threads SET id = :id;", params)
+                })
+                .and_then({
+                    let query = board_replace(
+                        &self.table(msg.0),
+                        "DELETE archive_threads FROM archive_threads \
+                         INNER JOIN `%%BOARD%%` ON id = num AND subnum = 0 \
+                         WHERE timestamp_expired != 0; \
+                         DELETE archive_threads FROM archive_threads \
+                         INNER JOIN `%%BOARD%%_deleted` ON id = num AND subnum = 0;",
+                    );
+                    |conn| conn.drop_query(query)
+                })
+                .and_then(|conn| conn.query("SELECT id FROM archive_threads;"))
+                .and_then(|result| result.collect_and_drop::<u64>())
+                .and_then(|(conn, nums)| {
+                    // It seems the table persists and causes errors if the connection is reused, so
+                    // we drop it explicitly
+                    conn.drop_query("DROP TABLE archive_threads;")
+                        .map(|_conn| nums.into_iter().map(ThreadNo).collect())
+                }),
+        );
+        self.timed(msg.0, "GetUnarchivedThreads", future)
+    }
+}
+
+/// Get the time of the newest post of each live thread (one which hasn't been archived or deleted)
+/// that has been posted in since `since`. A thread's `Last-Modified` is at least this time, so it
+/// can be used as the `If-Modified-Since` of the thread's first fetch.
+pub struct GetThreadModifiedTimes(pub Board, pub DateTime<Utc>);
+impl Message for GetThreadModifiedTimes {
+    type Result = Result<Vec<(ThreadNo, DateTime<Utc>)>, Error>;
+}
+
+impl Handler<GetThreadModifiedTimes> for Database {
+    type Result = ResponseFuture<Vec<(ThreadNo, DateTime<Utc>)>, Error>;
+
+    fn handle(&mut self, msg: GetThreadModifiedTimes, _: &mut Self::Context) -> Self::Result {
+        let GetThreadModifiedTimes(board, since) = msg;
+        if self.dry_run {
+            debug!("/{}/: Dry run: not reading thread modified times", board);
+            return Box::new(future::ok(vec![]));
+        }
+        if let Some(remote) = &self.remote {
+            return Box::new(remote.get_thread_modified_times(board, since));
+        }
+
+        let query = board_replace(
+            &self.table(board),
+            "SELECT post.thread_num, MAX(post.timestamp) FROM `%%BOARD%%` AS post \
+             INNER JOIN `%%BOARD%%` AS op ON op.num = post.thread_num AND op.subnum = 0 \
+             WHERE post.timestamp > :since AND post.subnum = 0 \
+             AND op.op = 1 AND op.timestamp_expired = 0 AND op.deleted = 0 \
+             GROUP BY post.thread_num;",
+        );
+        let adjust_timestamps = self.boards[&board].adjust_timestamps;
+        let since = since.adjust(adjust_timestamps);
+        Box::new(
+            self.retry(board, "GetThreadModifiedTimes", move |pool| {
+                let query = query.clone();
+                pool.get_conn()
+                    .and_then(move |conn| conn.prep_exec(query, params! { since }))
+                    .and_then(move |result| {
+                        result.reduce_and_drop(vec![], move |mut times, row| {
+                            let (no, timestamp): (u64, u64) = mysql_async::from_row(row);
+                            if let Some(time) = unadjust(timestamp, adjust_timestamps) {
+                                times.push((ThreadNo(no), time));
+                            }
+                            times
+                        })
+                    })
+            })
+            .map(|(_conn, times)| times),
+        )
+    }
+}
+
+/// Update the OP data of a thread. The OP's `since4pass` and tags are needed to rebuild its `exif`
+/// column.
+pub struct UpdateOp(
+    pub Board,
+    pub ThreadNo,
+    pub OpData,
+    pub Option<u16>,
+    pub BTreeMap<String, String>,
+);
+impl Message for UpdateOp {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<UpdateOp> for Database {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: UpdateOp, _: &mut Self::Context) -> Self::Result {
+        let adjust_timestamps = self.boards[&msg.0].adjust_timestamps;
+        let mut params = params! {
+            "num" => msg.1,
+            "sticky" => msg.2.sticky,
+            "timestamp_expired" => msg.2.archived_on.map_or(0, |t| t.adjust(adjust_timestamps)),
+            "exif" => exif(&msg.2, msg.3, &msg.4),
+        };
+
+        // Preserve the locked status of a thread by only updating it if it hasn't been archived yet
+        let query = if msg.2.archived {
+            Query::UpdateArchivedOp
+        } else {
+            params.push((String::from("locked"), Value::from(msg.2.closed)));
+            Query::UpdateOp
+        };
+
+        let (table, num) = (self.table(msg.0), msg.1);
+        let expired = match msg.2.archived_on {
+            Some(time) if self.derived_tables.threads_images => {
+                vec![(num.0, time.adjust(adjust_timestamps))]
+            }
+            _ => vec![],
+        };
+        let entry = self.write_entry(|| {
+            let entry = JournalEntry::exec(msg.0, query, slice::from_ref(&params));
+            triggers::journal_touch_threads(entry, &expired)
+        });
+        let query = query.build(&table);
+        let future = self.journaled(
+            entry,
+            self.retry(msg.0, "UpdateOp", move |pool| {
+                let (table, query, params) = (table.clone(), query.clone(), params.clone());
+                let expired = expired.clone();
+                pool.get_conn()
+                    .and_then(|conn| conn.drop_exec(query, params))
+                    .and_then(move |conn| triggers::touch_threads(conn, &table, &expired))
+                    .map(|_conn| ())
+            }),
+        );
+        self.counted(WriteKind::Update, 1, future)
+    }
+}
+
+/// Update the comment, spoiler flag, and file deleted flag of posts.
+pub struct UpdatePost(
+    pub Board,
+    pub Vec<(PostNo, Option<String>, Option<bool>, bool)>,
+);
+impl Message for UpdatePost {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<UpdatePost> for Database {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: UpdatePost, _: &mut Self::Context) -> Self::Result {
+        let board = msg.0;
+        let query = Query::UpdatePost.build(&self.table(board));
+        let comments = || {
+            msg.1
+                .iter()
+                .filter_map(|(no, comment, _, _)| comment.as_ref().map(|comment| (*no, comment)))
+        };
+        self.store_comment_html(board, comments());
+        self.store_quotes(board, comments());
+        self.store_links(board, comments());
+        let clean_options = self.clean_options.clone();
+        let max_comment_bytes = self.boards[&board].max_comment_bytes;
+        let params = msg
+            .1
+            .into_iter()
+            .map(move |(no, comment, spoiler, file_deleted)| {
+                let (comment, comment_truncated) =
+                    clean_comment(board, no, comment, &clean_options, max_comment_bytes);
+                params! {
+                    "num" => no,
+                    comment,
+                    comment_truncated,
+                    "spoiler" => spoiler.unwrap_or(false) && !file_deleted,
+                    file_deleted,
+                }
+            })
+            .collect::<Vec<_>>();
+        let rows = params.len();
+        let entry = self.write_entry(|| JournalEntry::exec(board, Query::UpdatePost, &params));
+        let future = self.journaled(
+            entry,
+            self.retry(board, "UpdatePost", move |pool| {
+                let (query, params) = (query.clone(), params.clone());
+                pool.get_conn()
+                    .and_then(|conn| conn.batch_exec(query, params))
+                    .map(|_conn| ())
+            }),
+        );
+        self.counted(WriteKind::Update, rows, future)
+    }
+}
+
+/// Store the unmodified JSON of posts, fetched at the given time.
+pub struct InsertRawPosts(pub Board, pub Vec<(PostNo, String)>, pub DateTime<Utc>);
+impl Message for InsertRawPosts {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<InsertRawPosts> for Database {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: InsertRawPosts, _: &mut Self::Context) -> Self::Result {
+        let query = Query::InsertRawPosts.build(&self.table(msg.0));
+        // This is a new table, so we don't need to adjust timestamps for Asagi
+        let timestamp_fetched = msg.2.timestamp() as u64;
+        let params = msg.1.into_iter().map(move |(num, json)| {
+            params! { num, timestamp_fetched, json }
+        });
+        let params: Vec<_> = params.collect();
+        let rows = params.len();
+        let entry = self.write_entry(|| JournalEntry::exec(msg.0, Query::InsertRawPosts, &params));
+        let future = self.journaled(
+            entry,
+            self.retry(msg.0, "InsertRawPosts", move |pool| {
+                let (query, params) = (query.clone(), params.clone());
+                pool.get_conn()
+                    .and_then(|conn| conn.batch_exec(query, params))
+                    .map(|_conn| ())
+            }),
+        );
+        self.counted(WriteKind::Insert, rows, future)
+    }
+}
+
+impl Database {
+    /// Store the original HTML of comments in the `%%BOARD%%_html` table if the board has
+    /// `store_comment_html` enabled. The write runs in the background.
+    fn store_comment_html<'a>(
+        &self,
+        board: Board,
+        comments: impl Iterator<Item = (PostNo, &'a String)>,
+    ) {
+        if !self.boards[&board].store_comment_html {
+            return;
+        }
+        let params = comments
+            .map(|(num, comment)| params! { num, "comment" => comment.clone() })
+            .collect();
+        self.spawn_insert(
+            board,
+            "InsertHtml",
+            "comment HTML",
+            Query::InsertHtml,
+            params,
+        );
+    }
+
+    /// Store the posts quoted by comments in the `%%BOARD%%_quotes` table if the board has
+    /// `store_quotes` enabled. The write runs in the background.
+    fn store_quotes<'a>(&self, board: Board, comments: impl Iterator<Item = (PostNo, &'a String)>) {
+        if !self.boards[&board].store_quotes {
+            return;
+        }
+        let params = comments
+            .flat_map(|(num, comment)| {
+                html::quoted_posts(comment, board).into_iter().map(
+                    move |(quoted_board, quoted_num)| {
+                        params! { num, quoted_board, quoted_num }
+                    },
+                )
+            })
+            .collect();
+        // Quotes can't be removed from a comment, so changed comments only add rows
+        self.spawn_insert(board, "InsertQuotes", "quotes", Query::InsertQuotes, params);
+    }
+
+    /// Store the URLs in comments in the `%%BOARD%%_links` table if the board has `store_links`
+    /// enabled. The write runs in the background.
+    fn store_links<'a>(&self, board: Board, comments: impl Iterator<Item = (PostNo, &'a String)>) {
+        if !self.boards[&board].store_links {
+            return;
+        }
+        let params = comments
+            .flat_map(|(num, comment)| {
+                html::urls(comment)
+                    .into_iter()
+                    .map(move |(url, domain)| params! { num, url, domain })
+            })
+            .collect();
+        // Like quotes, links aren't removed from changed comments
+        self.spawn_insert(board, "InsertLinks", "links", Query::InsertLinks, params);
+    }
+
+    /// Store the SHA-256 of a downloaded file in the `%%BOARD%%_media_hashes` table. The write
+    /// runs in the background.
+    fn store_media_hash(&self, board: Board, media_hash: String, sha256: String) {
+        // A file is only downloaded once, so a row which already exists is from a refetch of a
+        // file which was missing. Its hash shouldn't change.
+        self.spawn_insert(
+            board,
+            "InsertMediaHash",
+            "media hash",
+            Query::InsertMediaHash,
+            vec![params! { media_hash, sha256 }],
+        );
+    }
+
+    /// Run a batched insert of rows derived from posts in the background, logging failures. `name`
+    /// is the name of the write for `timed`, and `what` describes the rows in error messages.
+    fn spawn_insert(
+        &self,
+        board: Board,
+        name: &'static str,
+        what: &'static str,
+        query: Query,
+        params: Vec<Vec<(String, Value)>>,
+    ) {
+        if params.is_empty() {
+            return;
+        }
+
+        let entry = self.write_entry(|| JournalEntry::exec(board, query, &params));
+        let query = query.build(&self.table(board));
+        let rows = params.len();
+        let future = self.journaled(
+            entry,
+            self.retry(board, name, move |pool| {
+                let (query, params) = (query.clone(), params.clone());
+                pool.get_conn()
+                    .and_then(|conn| conn.batch_exec(query, params))
+                    .map(|_conn| ())
+            }),
+        );
+        Arbiter::spawn(
+            self.counted(WriteKind::Insert, rows, future)
+                .map_err(move |err| error!("/{}/: Failed to store {}: {}", board, what, err)),
+        );
+    }
+}
+
+/// The hashes of a file downloaded on a board with `store_media_hashes`. The first is the base64
+/// MD5 (like `media_hash`), and the second is the hex SHA-256.
+pub struct StoreMediaHash(pub Board, pub String, pub String);
+impl Message for StoreMediaHash {
+    type Result = ();
+}
+
+impl Handler<StoreMediaHash> for Database {
+    type Result = ();
+
+    fn handle(&mut self, msg: StoreMediaHash, _: &mut Self::Context) {
+        let StoreMediaHash(board, media_hash, sha256) = msg;
+        if self.boards[&board].store_media_hashes {
+            self.store_media_hash(board, media_hash, sha256);
+        }
+    }
+}
+
+pub enum RemovedStatus {
+    Archived,
+    Deleted,
+}
+
+pub struct MarkPostsRemoved(
+    pub Board,
+    pub Vec<(PostNo, RemovedStatus)>,
+    pub DateTime<Utc>,
+);
+impl Message for MarkPostsRemoved {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<MarkPostsRemoved> for Database {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: MarkPostsRemoved, _: &mut Self::Context) -> Self::Result {
+        let query = Query::MarkPostsRemoved.build(&self.table(msg.0));
+        let timestamp_expired = msg.2.adjust(self.boards[&msg.0].adjust_timestamps);
+        let expired: Vec<(u64, u64)> = if self.derived_tables.threads_images {
+            msg.1
+                .iter()
+                .map(|&(no, _)| (no.0, timestamp_expired))
+                .collect()
+        } else {
+            vec![]
+        };
+        let params = msg.1.into_iter().map(move |(no, status)| {
+            params! {
+                "num" => no,
+                "deleted" => match status {
+                    RemovedStatus::Archived => false,
+                    RemovedStatus::Deleted => true,
+                },
+                timestamp_expired,
+            }
+        });
+        let params: Vec<_> = params.collect();
+        let rows = params.len();
+        let table = self.table(msg.0);
+        let entry = self.write_entry(|| {
+            let entry = JournalEntry::exec(msg.0, Query::MarkPostsRemoved, &params);
+            triggers::journal_touch_threads(entry, &expired)
+        });
+        let future = self.journaled(
+            entry,
+            self.retry(msg.0, "MarkPostsRemoved", move |pool| {
+                let (table, query, params) = (table.clone(), query.clone(), params.clone());
+                let expired = expired.clone();
+                pool.get_conn()
+                    .and_then(|conn| conn.batch_exec(query, params))
+                    .and_then(move |conn| triggers::touch_threads(conn, &table, &expired))
+                    .map(|_conn| ())
+            }),
+        );
+        self.counted(WriteKind::Update, rows, future)
+    }
+}
+
+impl From<PostNo> for Value {
+    fn from(no: PostNo) -> Self {
+        Value::from(no.0)
+    }
+}
+
+impl From<ThreadNo> for Value {
+    fn from(no: ThreadNo) -> Self {
+        Value::from(no.0)
+    }
+}
+
+trait TimestampExt {
+    fn adjust(&self, adjust: bool) -> u64;
+}
+
+impl TimestampExt for u64 {
+    fn adjust(&self, adjust: bool) -> u64 {
+        if adjust {
+            America::New_York
+                .timestamp(*self as i64, 0)
+                .naive_local()
+                .timestamp() as u64
+        } else {
+            *self
+        }
+    }
+}
+
+impl TimestampExt for DateTime<Utc> {
+    fn adjust(&self, adjust: bool) -> u64 {
+        if adjust {
+            self.with_timezone(&America::New_York)
+                .naive_local()
+                .timestamp() as u64
+        } else {
+            self.timestamp() as u64
+        }
+    }
+}
+
+/// Undo `TimestampExt::adjust`. An adjusted timestamp in the hour repeated when daylight saving time
+/// ends is ambiguous, so the earlier time is returned. Adjusted timestamps which can't exist (in the
+/// hour skipped when daylight saving time starts) return `None`.
+fn unadjust(timestamp: u64, adjusted: bool) -> Option<DateTime<Utc>> {
+    if adjusted {
+        America::New_York
+            .from_local_datetime(&NaiveDateTime::from_timestamp(timestamp as i64, 0))
+            .earliest()
+            .map(|time| time.with_timezone(&Utc))
+    } else {
+        Some(Utc.timestamp(timestamp as i64, 0))
+    }
+}
+
+/// Create the JSON object stored in the `exif` column. Like Asagi, numbers are stored as strings.
+/// Tags from post processors don't replace the entries from 4chan. If there's nothing to store,
+/// `None` is returned.
+fn exif(
+    op_data: &OpData,
+    since4pass: Option<u16>,
+    tags: &BTreeMap<String, String>,
+) -> Option<String> {
+    let mut exif = serde_json::Map::new();
+    if let Some(unique_ips) = op_data.unique_ips {
+        exif.insert(String::from("uniqueIps"), unique_ips.to_string().into());
+    }
+    if let Some(since4pass) = since4pass {
+        exif.insert(String::from("since4pass"), since4pass.to_string().into());
+    }
+    if op_data.bumplimit {
+        exif.insert(String::from("bumpLimit"), "1".into());
+    }
+    if op_data.imagelimit {
+        exif.insert(String::from("imageLimit"), "1".into());
+    }
+    for (key, value) in tags {
+        exif.entry(key.clone())
+            .or_insert_with(|| value.clone().into());
+    }
+
+    if exif.is_empty() {
+        None
+    } else {
+        Some(serde_json::Value::Object(exif).to_string())
+    }
+}
+
+/// Clean a comment, and truncate it to `max_comment_bytes` (see `html::truncate`) if given.
+/// Returns the comment and whether it was truncated.
+fn clean_comment(
+    board: Board,
+    no: PostNo,
+    comment: Option<String>,
+    clean_options: &CleanOptions,
+    max_comment_bytes: Option<usize>,
+) -> (Option<String>, bool) {
+    let mut comment = match comment {
+        Some(comment) => html::clean_with(comment, Some((board, no)), clean_options).into_owned(),
+        None => return (None, false),
+    };
+    let truncated = match max_comment_bytes {
+        Some(max_bytes) => html::truncate(&mut comment, max_bytes),
+        None => false,
+    };
+    (Some(comment), truncated)
+}
+
+/// Create connection options from a database URL, with the pool settings from the config (if any).
+pub(crate) fn pool_opts(url: &str, pool_config: Option<PoolConfig>) -> Result<Opts, Error> {
+    let mut builder = OptsBuilder::from_opts(Opts::from_url(url)?);
+    if let Some(pool_config) = pool_config {
+        builder
+            .pool_constraints(PoolConstraints::new(pool_config.min, pool_config.max))
+            .conn_ttl(pool_config.conn_ttl.map(|ttl| ttl.as_secs() as u32));
+    }
+    Ok(builder.into())
+}
+
+/// Connect to each database server and read the schema version of each of its boards, for
+/// `ena doctor`. Servers are named by their host and port.
+pub fn check_database_servers(
+    config: &Config,
+) -> Vec<(String, Result<Vec<(Board, usize)>, Error>)> {
+    let table_template = table_template(config);
+    let mut runtime = Runtime::new().unwrap();
+    let results = boards_by_server(config)
+        .into_iter()
+        .map(|(url, boards)| {
+            let opts = match pool_opts(url, None) {
+                Ok(opts) => opts,
+                Err(err) => return (String::from("(invalid URL)"), Err(err)),
+            };
+            let server = format!("{}:{}", opts.get_ip_or_hostname(), opts.get_tcp_port());
+            let pool = Pool::new(opts);
+            let tables = boards
+                .iter()
+                .map(|&board| table_name(&table_template, board))
+                .collect();
+            let versions = runtime
+                .block_on(migrations::schema_versions(&pool, tables))
+                .map(|versions| boards.into_iter().zip(versions).collect());
+            (server, versions)
+        })
+        .collect();
+    runtime.shutdown_on_idle().wait().unwrap();
+    results
+}
+
+/// Group boards by the URL of the database server that they're stored on.
+fn boards_by_server(config: &Config) -> HashMap<&str, Vec<Board>> {
+    let mut servers: HashMap<&str, Vec<Board>> = HashMap::new();
+    for &board in config.boards.keys() {
+        let url = config
+            .database_media
+            .board_database_urls
+            .get(&board)
+            .unwrap_or(&config.database_media.database_url);
+        servers.entry(url).or_default().push(board);
+    }
+    servers
+}
+
+fn table_template(config: &Config) -> String {
+    config
+        .database_media
+        .table_template
+        .clone()
+        .unwrap_or_else(|| String::from(BOARD_REPLACE))
+}
+
+/// Get the base table name of a board from a template (see `database_media.table_template`).
+fn table_name(template: &str, board: Board) -> String {
+    template.replace(BOARD_REPLACE, &board.to_string())
+}
+
+fn board_replace(table: &str, query: &str) -> String {
+    query.replace(BOARD_REPLACE, table)
+}
@@ -0,0 +1,222 @@
+//! Read queries, for users of Ena who want to look up archived posts without writing their own SQL.
+
+use mysql_async::{Params, Row};
+
+use super::*;
+
+/// The columns which `PostRow::from_row` reads, in order.
+const POST_ROW_COLUMNS: &str = "num, subnum, thread_num, op, timestamp, timestamp_expired, \
+                                preview_orig, preview_w, preview_h, media_filename, media_w, \
+                                media_h, media_size, media_hash, media_orig, spoiler, deleted, \
+                                capcode, name, trip, title, comment, sticky, locked, poster_hash, \
+                                poster_country, exif";
+
+/// A post, as it is stored in the database. Timestamps are adjusted if the board's
+/// `adjust_timestamps` is set.
+#[derive(Clone, Debug)]
+pub struct PostRow {
+    pub num: u64,
+    pub subnum: u64,
+    pub thread_num: u64,
+    pub op: bool,
+    pub timestamp: u64,
+    pub timestamp_expired: u64,
+    pub preview_orig: Option<String>,
+    pub preview_w: u16,
+    pub preview_h: u16,
+    pub media_filename: Option<String>,
+    pub media_w: u16,
+    pub media_h: u16,
+    pub media_size: u32,
+    pub media_hash: Option<String>,
+    pub media_orig: Option<String>,
+    pub spoiler: bool,
+    pub deleted: bool,
+    pub capcode: String,
+    pub name: Option<String>,
+    pub trip: Option<String>,
+    pub title: Option<String>,
+    pub comment: Option<String>,
+    pub sticky: bool,
+    pub locked: bool,
+    pub poster_hash: Option<String>,
+    pub poster_country: Option<String>,
+    pub exif: Option<String>,
+}
+
+impl PostRow {
+    /// Convert a row of `POST_ROW_COLUMNS`. An unexpected value (such as a `NULL` in a column
+    /// which Ena never leaves `NULL`, in an archive written by another scraper) is an error.
+    fn from_row(mut row: Row) -> Result<Self, Error> {
+        Ok(Self {
+            num: take(&mut row, 0)?,
+            subnum: take(&mut row, 1)?,
+            thread_num: take(&mut row, 2)?,
+            op: take(&mut row, 3)?,
+            timestamp: take(&mut row, 4)?,
+            timestamp_expired: take(&mut row, 5)?,
+            preview_orig: take(&mut row, 6)?,
+            preview_w: take(&mut row, 7)?,
+            preview_h: take(&mut row, 8)?,
+            media_filename: take(&mut row, 9)?,
+            media_w: take(&mut row, 10)?,
+            media_h: take(&mut row, 11)?,
+            media_size: take(&mut row, 12)?,
+            media_hash: take(&mut row, 13)?,
+            media_orig: take(&mut row, 14)?,
+            spoiler: take(&mut row, 15)?,
+            deleted: take(&mut row, 16)?,
+            capcode: take(&mut row, 17)?,
+            name: take(&mut row, 18)?,
+            trip: take(&mut row, 19)?,
+            title: take(&mut row, 20)?,
+            comment: take(&mut row, 21)?,
+            sticky: take(&mut row, 22)?,
+            locked: take(&mut row, 23)?,
+            poster_hash: take(&mut row, 24)?,
+            poster_country: take(&mut row, 25)?,
+            exif: take(&mut row, 26)?,
+        })
+    }
+}
+
+/// Take the value of column `index` of `POST_ROW_COLUMNS` from a row.
+fn take<T: FromValue>(row: &mut Row, index: usize) -> Result<T, Error> {
+    let column = || POST_ROW_COLUMNS.split(", ").nth(index).unwrap_or("?");
+    match row.take_opt(index) {
+        Some(Ok(value)) => Ok(value),
+        Some(Err(err)) => Err(Error::Other(
+            format!("Unexpected value in column `{}`: {:?}", column(), err.0).into(),
+        )),
+        None => Err(Error::Other(
+            format!("Missing column `{}`", column()).into(),
+        )),
+    }
+}
+
+/// Get the posts of a thread (including ghost posts), in order. If the thread isn't in the
+/// database, no posts are returned.
+pub struct GetThread(pub Board, pub ThreadNo);
+impl Message for GetThread {
+    type Result = Result<Vec<PostRow>, Error>;
+}
+
+impl Handler<GetThread> for Database {
+    type Result = ResponseFuture<Vec<PostRow>, Error>;
+
+    fn handle(&mut self, msg: GetThread, _: &mut Self::Context) -> Self::Result {
+        let query = board_replace(
+            &self.table(msg.0),
+            &format!(
+                "SELECT {} FROM `%%BOARD%%` \
+                 WHERE thread_num = :thread_num \
+                 ORDER BY num, subnum;",
+                POST_ROW_COLUMNS,
+            ),
+        );
+        self.select(
+            msg.0,
+            "GetThread",
+            query,
+            params! { "thread_num" => msg.1 }.into(),
+            PostRow::from_row,
+        )
+    }
+}
+
+/// Get the newest posts of a board (including ghost posts), newest first.
+pub struct GetRecentPosts(pub Board, pub usize);
+impl Message for GetRecentPosts {
+    type Result = Result<Vec<PostRow>, Error>;
+}
+
+impl Handler<GetRecentPosts> for Database {
+    type Result = ResponseFuture<Vec<PostRow>, Error>;
+
+    fn handle(&mut self, msg: GetRecentPosts, _: &mut Self::Context) -> Self::Result {
+        let query = board_replace(
+            &self.table(msg.0),
+            &format!(
+                "SELECT {} FROM `%%BOARD%%` \
+                 ORDER BY num DESC, subnum DESC \
+                 LIMIT :limit;",
+                POST_ROW_COLUMNS,
+            ),
+        );
+        self.select(
+            msg.0,
+            "GetRecentPosts",
+            query,
+            params! { "limit" => msg.1 as u64 }.into(),
+            PostRow::from_row,
+        )
+    }
+}
+
+/// Get the SHA-256 hashes (in lowercase hex) of downloaded files by their `media_hash`, on a board
+/// with `store_media_hashes`. Files without a stored hash are left out.
+pub struct GetMediaHashes(pub Board, pub Vec<String>);
+impl Message for GetMediaHashes {
+    type Result = Result<HashMap<String, String>, Error>;
+}
+
+impl Handler<GetMediaHashes> for Database {
+    type Result = ResponseFuture<HashMap<String, String>, Error>;
+
+    fn handle(&mut self, msg: GetMediaHashes, _: &mut Self::Context) -> Self::Result {
+        let GetMediaHashes(board, media_hashes) = msg;
+        if media_hashes.is_empty() || !self.boards[&board].store_media_hashes {
+            return Box::new(future::ok(HashMap::new()));
+        }
+        let query = board_replace(
+            &self.table(board),
+            &format!(
+                "SELECT media_hash, LOWER(HEX(sha256)) FROM `%%BOARD%%_media_hashes` \
+                 WHERE media_hash IN ({});",
+                vec!["?"; media_hashes.len()].join(", "),
+            ),
+        );
+        let params: Vec<Value> = media_hashes.into_iter().map(Value::from).collect();
+        Box::new(
+            self.select(board, "GetMediaHashes", query, params.into(), |row| {
+                mysql_async::from_row_opt::<(String, String)>(row).map_err(|_| {
+                    Error::Other("Unexpected value in `%%BOARD%%_media_hashes`".into())
+                })
+            })
+            .map(|hashes| hashes.into_iter().collect()),
+        )
+    }
+}
+
+impl Database {
+    /// Run a read query, converting each row with `convert`. If a row can't be converted, the
+    /// query fails with its error.
+    fn select<T: 'static>(
+        &self,
+        board: Board,
+        name: &'static str,
+        query: String,
+        params: Params,
+        convert: fn(Row) -> Result<T, Error>,
+    ) -> Box<dyn Future<Item = Vec<T>, Error = Error>> {
+        if self.dry_run {
+            debug!("/{}/: Dry run: not reading from the database", board);
+            return Box::new(future::ok(vec![]));
+        }
+        if self.remote.is_some() {
+            return Box::new(future::err(Error::Other(
+                "The database can't be read through a remote writer".into(),
+            )));
+        }
+
+        Box::new(
+            self.retry(board, name, move |pool| {
+                let (query, params) = (query.clone(), params.clone());
+                pool.get_conn()
+                    .and_then(|conn| conn.prep_exec(query, params))
+                    .and_then(move |result| result.map_and_drop(convert))
+            })
+            .and_then(|(_conn, rows)| rows.into_iter().collect::<Result<Vec<T>, Error>>()),
+        )
+    }
+}
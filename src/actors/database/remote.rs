@@ -0,0 +1,581 @@
+//! Scraping and writing on different machines (see `database_media.remote_writer`). The database
+//! actor of a scraping instance doesn't connect to MySQL. Instead, it sends its writes (as journal
+//! entries, so posts are cleaned before they're sent) and the few reads that scraping needs to an
+//! instance running `ena writer` next to the database, which executes them with its own database
+//! actor. Journal entries name one of Ena's queries instead of containing SQL (see
+//! `journal::Query`), and the writer finds the tables from its own configuration, so a scraping
+//! instance can only make the writes that Ena makes.
+//!
+//! Messages are JSON, each prefixed by its length as a 4-byte big-endian integer. The first message
+//! of a connection is a `Hello` with the secret of `writer.secret`, and the writer closes the
+//! connection if it's wrong. The secret isn't encrypted, so connections should stay on a private
+//! network (or go through a tunnel). Requests are numbered, and each reply has the number of its
+//! request, since replies can arrive out of order. If the connection is lost, requests fail with
+//! an I/O error, so that writes are journaled (if `database_media.journal_path` is set) like when
+//! the database can't be reached.
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+use bytes::{Bytes, BytesMut};
+use failure::ResultExt;
+use futures::{
+    future::Either,
+    stream,
+    sync::{mpsc, oneshot},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    codec::{Framed, LengthDelimitedCodec},
+    net::{TcpListener, TcpStream},
+};
+
+use super::{journal::post_rows, *};
+use crate::{
+    actors::{PanicSupervisor, RestartPolicy},
+    config::WriterConfig,
+};
+
+/// The longest message, in bytes. A buffered insert of several threads can be a few megabytes.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// The first message of a connection.
+#[derive(Deserialize, Serialize)]
+struct Hello {
+    secret: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Frame<T> {
+    id: u64,
+    body: T,
+}
+
+#[derive(Deserialize, Serialize)]
+enum Request {
+    Write(JournalEntry),
+    /// Insert posts (which must be a `JournalEntry::InsertPosts`), and reply with the media and
+    /// thumbnails of each thread which are new to the database. `ranges` are the
+    /// `(thread_num, num_start, num_end)` of each thread.
+    InsertPosts {
+        entry: JournalEntry,
+        ranges: Vec<(u64, u64, u64)>,
+        download_media: bool,
+        download_thumbs: bool,
+    },
+    GetUnarchivedThreads(Board, Vec<ThreadNo>),
+    /// Times are Unix timestamps
+    GetThreadModifiedTimes(Board, i64),
+}
+
+#[derive(Deserialize, Serialize)]
+enum Reply {
+    Done,
+    Files(Vec<Vec<String>>),
+    Threads(Vec<ThreadNo>),
+    ModifiedTimes(Vec<(ThreadNo, i64)>),
+    Error(String),
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LENGTH)
+        .new_codec()
+}
+
+fn encode<T: Serialize>(id: u64, body: T) -> serde_json::Result<Bytes> {
+    serde_json::to_vec(&Frame { id, body }).map(Bytes::from)
+}
+
+fn connection_lost() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Lost the connection to the remote writer",
+    ))
+}
+
+fn unexpected_reply() -> Error {
+    Error::Other("Unexpected reply from the remote writer".into())
+}
+
+/// A connection to an `ena writer` instance. It's opened when a request is made, and opened again
+/// by the next request after it fails.
+#[derive(Clone)]
+pub(super) struct RemoteWriter(Arc<Mutex<ClientState>>);
+
+struct ClientState {
+    address: String,
+    secret: Option<String>,
+    next_id: u64,
+    connection: Option<Connection>,
+}
+
+#[derive(Clone)]
+struct Connection {
+    sender: mpsc::UnboundedSender<Bytes>,
+    /// The requests waiting for a reply, by ID
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<Reply>>>>,
+}
+
+impl RemoteWriter {
+    pub fn new(address: String, secret: Option<String>) -> Self {
+        RemoteWriter(Arc::new(Mutex::new(ClientState {
+            address,
+            secret,
+            next_id: 0,
+            connection: None,
+        })))
+    }
+
+    pub fn write(&self, entry: JournalEntry) -> impl Future<Item = (), Error = Error> {
+        self.request(Request::Write(entry))
+            .and_then(|reply| match reply {
+                Reply::Done => Ok(()),
+                _ => Err(unexpected_reply()),
+            })
+    }
+
+    /// Insert posts, returning the media and thumbnails of each thread which should be downloaded.
+    pub fn insert_posts(
+        &self,
+        entry: JournalEntry,
+        ranges: Vec<(u64, u64, u64)>,
+        download_media: bool,
+        download_thumbs: bool,
+    ) -> Box<dyn Future<Item = Vec<Vec<String>>, Error = Error>> {
+        Box::new(
+            self.request(Request::InsertPosts {
+                entry,
+                ranges,
+                download_media,
+                download_thumbs,
+            })
+            .and_then(|reply| match reply {
+                Reply::Files(files) => Ok(files),
+                _ => Err(unexpected_reply()),
+            }),
+        )
+    }
+
+    pub fn get_unarchived_threads(
+        &self,
+        board: Board,
+        threads: Vec<ThreadNo>,
+    ) -> impl Future<Item = Vec<ThreadNo>, Error = Error> {
+        self.request(Request::GetUnarchivedThreads(board, threads))
+            .and_then(|reply| match reply {
+                Reply::Threads(threads) => Ok(threads),
+                _ => Err(unexpected_reply()),
+            })
+    }
+
+    pub fn get_thread_modified_times(
+        &self,
+        board: Board,
+        since: DateTime<Utc>,
+    ) -> impl Future<Item = Vec<(ThreadNo, DateTime<Utc>)>, Error = Error> {
+        self.request(Request::GetThreadModifiedTimes(board, since.timestamp()))
+            .and_then(|reply| match reply {
+                Reply::ModifiedTimes(times) => Ok(times
+                    .into_iter()
+                    .map(|(no, time)| (no, Utc.timestamp(time, 0)))
+                    .collect()),
+                _ => Err(unexpected_reply()),
+            })
+    }
+
+    /// Send journaled writes, in order.
+    pub fn replay(&self, entries: Vec<JournalEntry>) -> impl Future<Item = (), Error = Error> {
+        let writer = self.clone();
+        stream::iter_ok::<_, Error>(entries).for_each(move |entry| writer.write(entry))
+    }
+
+    fn request(&self, request: Request) -> Box<dyn Future<Item = Reply, Error = Error>> {
+        let mut state = self.0.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let frame = match encode(id, request) {
+            Ok(frame) => frame,
+            Err(err) => return Box::new(future::err(Error::Other(err.to_string().into()))),
+        };
+        let connection = match &state.connection {
+            Some(connection) => connection.clone(),
+            None => {
+                let connection = self.connect(&state.address, state.secret.clone());
+                state.connection = Some(connection.clone());
+                connection
+            }
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        connection.pending.lock().unwrap().insert(id, sender);
+        if connection.sender.unbounded_send(frame).is_err() {
+            // The connection has closed, so the receiver is cancelled
+            connection.pending.lock().unwrap().remove(&id);
+        }
+        Box::new(
+            receiver
+                .map_err(|_| connection_lost())
+                .and_then(|reply| match reply {
+                    Reply::Error(err) => Err(Error::Other(err.into())),
+                    reply => Ok(reply),
+                }),
+        )
+    }
+
+    /// Open a connection in the background. When it closes, the requests waiting for a reply fail,
+    /// and it's removed so that the next request opens a new one.
+    fn connect(&self, address: &str, secret: Option<String>) -> Connection {
+        let (sender, receiver) = mpsc::unbounded();
+        // The hello is queued first, so it's sent before any request
+        let hello = serde_json::to_vec(&Hello { secret }).expect("Could not serialize hello");
+        let _ = sender.unbounded_send(Bytes::from(hello));
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let connection = Connection {
+            sender,
+            pending: pending.clone(),
+        };
+
+        let state = self.0.clone();
+        let address = address.to_owned();
+        let resolved = address.to_socket_addrs().and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address found"))
+        });
+        Arbiter::spawn(
+            future::result(resolved)
+                .and_then(|addr| TcpStream::connect(&addr))
+                .and_then({
+                    let pending = pending.clone();
+                    move |stream| {
+                        let (sink, stream) = Framed::new(stream, codec()).split();
+                        let send = receiver
+                            .map_err(|()| io::Error::from(io::ErrorKind::BrokenPipe))
+                            .forward(sink)
+                            .map(|_| ());
+                        let receive = stream.for_each(move |frame| {
+                            let Frame { id, body } = serde_json::from_slice(&frame)
+                                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                            if let Some(sender) = pending.lock().unwrap().remove(&id) {
+                                let _ = sender.send(body);
+                            }
+                            Ok(())
+                        });
+                        send.select(receive).map(|_| ()).map_err(|(err, _)| err)
+                    }
+                })
+                .then(move |res| {
+                    match res {
+                        Ok(()) => warn!("Remote writer {} closed the connection", address),
+                        Err(err) => {
+                            warn!("Connection to remote writer {} failed: {}", address, err)
+                        }
+                    }
+                    let mut state = state.lock().unwrap();
+                    if state.connection.as_ref().map_or(false, |connection| {
+                        Arc::ptr_eq(&connection.pending, &pending)
+                    }) {
+                        state.connection = None;
+                    }
+                    pending.lock().unwrap().clear();
+                    Ok::<(), ()>(())
+                }),
+        );
+        connection
+    }
+}
+
+/// Start a database actor, and execute the requests of scraping instances which connect to
+/// `writer.listen` with it. This must be called from within a running `System`.
+pub fn start_writer(config: &Config, writer: &WriterConfig) -> Result<(), failure::Error> {
+    if config.database_media.remote_writer.is_some() {
+        return Err(failure::err_msg(
+            "`database_media.remote_writer` must not be set for `ena writer`",
+        ));
+    }
+    let address = writer.listen;
+    if writer.secret.is_none() {
+        if !address.ip().is_loopback() {
+            return Err(failure::err_msg(
+                "`writer.secret` must be set to listen on an address other than localhost",
+            ));
+        }
+        warn!(
+            "`writer.secret` isn't set, so any program which can connect to {} can write to the \
+             database",
+            address
+        );
+    }
+
+    check_timestamp_modes(config).context("Database initialization error")?;
+    let database = Database::try_new(config).context("Database initialization error")?;
+    let arbiter = Arbiter::builder()
+        .name("database")
+        .stop_system_on_panic(true)
+        .build();
+    let database = PanicSupervisor::start_in_arbiter(
+        "database",
+        RestartPolicy::new(&config.advanced),
+        &arbiter,
+        config.advanced.database_mailbox_capacity,
+        database,
+    );
+
+    let listener =
+        TcpListener::bind(&address).with_context(|_| format!("Could not listen on {}", address))?;
+    info!("Listening for scraping instances on {}", address);
+    let secret = Arc::new(writer.secret.clone());
+    Arbiter::spawn(
+        listener
+            .incoming()
+            .map_err(|err| error!("Could not accept connection: {}", err))
+            .for_each(move |stream| {
+                serve(stream, database.clone(), secret.clone());
+                Ok(())
+            }),
+    );
+    Ok(())
+}
+
+/// Check the `Hello` of a scraping instance, and then execute its requests and send back the
+/// replies.
+fn serve(stream: TcpStream, database: Addr<Database>, secret: Arc<Option<String>>) {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| String::from("unknown address"));
+
+    let (sink, stream) = Framed::new(stream, codec()).split();
+    let serve = stream
+        .into_future()
+        .map_err(|(err, _stream)| err)
+        .and_then({
+            let peer = peer.clone();
+            move |(hello, stream)| {
+                let hello = hello.and_then(|frame| serde_json::from_slice::<Hello>(&frame).ok());
+                let authenticated = match (&*secret, hello) {
+                    (None, Some(_)) => true,
+                    (Some(secret), Some(hello)) => hello
+                        .secret
+                        .map_or(false, |given| secrets_match(secret, &given)),
+                    (_, None) => false,
+                };
+                if !authenticated {
+                    return Either::A(future::err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Wrong or missing secret",
+                    )));
+                }
+                info!("Scraping instance connected from {}", peer);
+                Either::B(exchange(sink, stream, database))
+            }
+        });
+    Arbiter::spawn(serve.then(move |res| {
+        match res {
+            Ok(()) => info!("Scraping instance {} disconnected", peer),
+            Err(err) => warn!("Connection from scraping instance {} failed: {}", peer, err),
+        }
+        Ok::<(), ()>(())
+    }));
+}
+
+/// Compare secrets in constant time, so that the secret can't be guessed from how long it takes to
+/// reject a wrong one.
+fn secrets_match(secret: &str, given: &str) -> bool {
+    secret.len() == given.len()
+        && secret
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Execute the requests of an authenticated scraping instance, and send back the replies.
+fn exchange(
+    sink: impl Sink<SinkItem = Bytes, SinkError = io::Error>,
+    stream: impl Stream<Item = BytesMut, Error = io::Error>,
+    database: Addr<Database>,
+) -> impl Future<Item = (), Error = io::Error> {
+    let (sender, receiver) = mpsc::unbounded();
+    let send = receiver
+        .map_err(|()| io::Error::from(io::ErrorKind::BrokenPipe))
+        .forward(sink)
+        .map(|_| ());
+    let receive = stream.for_each(move |frame| {
+        let Frame { id, body } = serde_json::from_slice(&frame)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let sender = sender.clone();
+        Arbiter::spawn(handle(&database, body).then(move |reply| {
+            let reply = reply.unwrap_or_else(|err| Reply::Error(err.to_string()));
+            match encode(id, reply) {
+                Ok(frame) => {
+                    let _ = sender.unbounded_send(frame);
+                }
+                Err(err) => error!("Could not serialize reply: {}", err),
+            }
+            Ok::<(), ()>(())
+        }));
+        Ok(())
+    });
+    send.select(receive).map(|_| ()).map_err(|(err, _)| err)
+}
+
+fn handle(
+    database: &Addr<Database>,
+    request: Request,
+) -> Box<dyn Future<Item = Reply, Error = Error>> {
+    let flatten = |res: Result<Result<Reply, Error>, MailboxError>| match res {
+        Ok(res) => res,
+        Err(err) => Err(Error::Other(err.to_string().into())),
+    };
+    match request {
+        Request::Write(entry) => Box::new(
+            database
+                .send(RemoteWrite(entry))
+                .then(move |res| flatten(res.map(|res| res.map(|()| Reply::Done)))),
+        ),
+        Request::InsertPosts {
+            entry,
+            ranges,
+            download_media,
+            download_thumbs,
+        } => Box::new(
+            database
+                .send(RemoteInsertPosts {
+                    entry,
+                    ranges,
+                    download_media,
+                    download_thumbs,
+                })
+                .then(move |res| flatten(res.map(|res| res.map(Reply::Files)))),
+        ),
+        Request::GetUnarchivedThreads(board, threads) => Box::new(
+            database
+                .send(GetUnarchivedThreads(board, threads))
+                .then(move |res| flatten(res.map(|res| res.map(Reply::Threads)))),
+        ),
+        Request::GetThreadModifiedTimes(board, since) => Box::new(
+            database
+                .send(GetThreadModifiedTimes(board, Utc.timestamp(since, 0)))
+                .then(move |res| {
+                    flatten(res.map(|res| {
+                        res.map(|times| {
+                            Reply::ModifiedTimes(
+                                times
+                                    .into_iter()
+                                    .map(|(no, time)| (no, time.timestamp()))
+                                    .collect(),
+                            )
+                        })
+                    }))
+                }),
+        ),
+    }
+}
+
+fn unknown_board(board: Board) -> Error {
+    Error::Other(format!("Board /{}/ isn't in the writer's configuration", board).into())
+}
+
+/// Execute a write from a scraping instance.
+struct RemoteWrite(JournalEntry);
+impl Message for RemoteWrite {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RemoteWrite> for Database {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: RemoteWrite, _: &mut Self::Context) -> Self::Result {
+        let RemoteWrite(entry) = msg;
+        let board = entry.board();
+        if !self.boards.contains_key(&board) {
+            return Box::new(future::err(unknown_board(board)));
+        }
+
+        let (kind, rows) = write_count(&entry);
+        let (table_template, derived) = (self.table_template.clone(), self.derived_tables);
+        let future = self.journaled(
+            Some(entry.clone()),
+            self.retry(board, "RemoteWrite", move |pool| {
+                let (entry, table_template) = (entry.clone(), table_template.clone());
+                pool.get_conn()
+                    .and_then(move |conn| entry.replay(conn, &table_template, derived))
+                    .map(|_conn| ())
+            }),
+        );
+        self.counted(kind, rows, future)
+    }
+}
+
+/// The kind of write and the number of rows written, for the write counts.
+fn write_count(entry: &JournalEntry) -> (WriteKind, usize) {
+    match entry {
+        JournalEntry::InsertPosts(_, rows) => (WriteKind::Insert, rows.len()),
+        JournalEntry::Exec(_, _, params) => (WriteKind::Update, params.len()),
+        // The rest of a batch only keeps the derived tables up to date with the first entry
+        JournalEntry::Batch(_, entries) => {
+            entries.first().map_or((WriteKind::Update, 0), write_count)
+        }
+    }
+}
+
+/// Insert posts from a scraping instance, returning the media and thumbnails to download.
+struct RemoteInsertPosts {
+    entry: JournalEntry,
+    ranges: Vec<(u64, u64, u64)>,
+    download_media: bool,
+    download_thumbs: bool,
+}
+impl Message for RemoteInsertPosts {
+    type Result = Result<Vec<Vec<String>>, Error>;
+}
+
+impl Handler<RemoteInsertPosts> for Database {
+    type Result = ResponseFuture<Vec<Vec<String>>, Error>;
+
+    fn handle(&mut self, msg: RemoteInsertPosts, _: &mut Self::Context) -> Self::Result {
+        let RemoteInsertPosts {
+            entry,
+            ranges,
+            download_media,
+            download_thumbs,
+        } = msg;
+        let board = entry.board();
+        if !self.boards.contains_key(&board) {
+            return Box::new(future::err(unknown_board(board)));
+        }
+        let rows = match entry.clone() {
+            JournalEntry::InsertPosts(_, rows) => post_rows(rows),
+            JournalEntry::Exec(..) | JournalEntry::Batch(..) => {
+                return Box::new(future::err(unexpected_request()));
+            }
+        };
+
+        let row_count = rows.len();
+        let (table, derived) = (self.table(board), self.derived_tables);
+        let future = self.journaled(
+            Some(entry),
+            self.retry(board, "RemoteInsertPosts", move |pool| {
+                insert::insert_with_media(
+                    pool,
+                    board,
+                    table.clone(),
+                    ranges.clone(),
+                    rows.clone(),
+                    derived,
+                    download_media,
+                    download_thumbs,
+                )
+            }),
+        );
+        self.counted(WriteKind::Insert, row_count, future)
+    }
+}
+
+fn unexpected_request() -> Error {
+    Error::Other("Unexpected request from the scraping instance".into())
+}
@@ -0,0 +1,186 @@
+//! Write statistics, for monitoring the health of the database connection.
+
+use std::sync::Mutex;
+
+use super::*;
+use crate::config::AdvancedConfig;
+
+/// Counts of the writes made since Ena started. A write is one message (e.g. `InsertPosts` or
+/// `MarkPostsRemoved`), which may write many rows. Retried and buffered writes are counted once.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DatabaseStats {
+    /// Completed writes which inserted rows
+    pub inserts: u64,
+    pub inserted_rows: u64,
+    /// Completed writes which updated rows
+    pub updates: u64,
+    pub updated_rows: u64,
+    /// Writes which failed. Journaled writes are counted as completed
+    pub errors: u64,
+    /// Writes which are currently in progress, and so are holding (or waiting for) a connection
+    pub active_writes: u64,
+    /// A moving average of how long inserts take to complete (including waiting for a connection)
+    pub insert_latency: Duration,
+    /// The number of separate database servers (and so, pools)
+    pub pools: usize,
+    /// Unknown HTML entities and tags seen while cleaning posts (see `html::log_unknown_report`)
+    pub unknown_html_tokens: u64,
+    /// Queries which took longer than `database_media.slow_query_threshold`. Each attempt of a
+    /// retried query is counted separately
+    pub slow_queries: u64,
+}
+
+impl DatabaseStats {
+    /// The average number of rows per completed write.
+    pub fn average_batch_size(&self) -> f64 {
+        let writes = self.inserts + self.updates;
+        if writes == 0 {
+            0.0
+        } else {
+            (self.inserted_rows + self.updated_rows) as f64 / writes as f64
+        }
+    }
+}
+
+/// Each insert's latency makes up 1/`LATENCY_WEIGHT` of `DatabaseStats::insert_latency`.
+const LATENCY_WEIGHT: u32 = 10;
+
+#[derive(Clone, Copy)]
+pub(super) enum WriteKind {
+    Insert,
+    Update,
+}
+
+impl Database {
+    /// Count a write of `rows` rows in the statistics once it completes.
+    pub(super) fn counted<T: 'static>(
+        &self,
+        kind: WriteKind,
+        rows: usize,
+        future: Box<dyn Future<Item = T, Error = Error>>,
+    ) -> Box<dyn Future<Item = T, Error = Error>> {
+        let stats = self.stats.clone();
+        stats.lock().unwrap().active_writes += 1;
+        let start = clock::now();
+        Box::new(future.then(move |res| {
+            let mut stats = stats.lock().unwrap();
+            stats.active_writes -= 1;
+            match (&res, kind) {
+                (Ok(_), WriteKind::Insert) => {
+                    stats.inserts += 1;
+                    stats.inserted_rows += rows as u64;
+                    let latency = clock::now() - start;
+                    stats.insert_latency = if stats.inserts == 1 {
+                        latency
+                    } else {
+                        stats.insert_latency * (LATENCY_WEIGHT - 1) / LATENCY_WEIGHT
+                            + latency / LATENCY_WEIGHT
+                    };
+                }
+                (Ok(_), WriteKind::Update) => {
+                    stats.updates += 1;
+                    stats.updated_rows += rows as u64;
+                }
+                (Err(_), _) => stats.errors += 1,
+            }
+            res
+        }))
+    }
+}
+
+impl Database {
+    /// Log the query `future` (for a `name` message of `board`) if it takes longer than
+    /// `database_media.slow_query_threshold`.
+    pub(super) fn timed<T: 'static>(
+        &self,
+        board: Board,
+        name: &'static str,
+        future: Box<dyn Future<Item = T, Error = Error>>,
+    ) -> Box<dyn Future<Item = T, Error = Error>> {
+        let threshold = match self.slow_query_threshold {
+            Some(threshold) => threshold,
+            None => return future,
+        };
+        timed(self.stats.clone(), threshold, board, name, future)
+    }
+}
+
+pub(super) fn timed<T: 'static>(
+    stats: Arc<Mutex<DatabaseStats>>,
+    threshold: Duration,
+    board: Board,
+    name: &'static str,
+    future: Box<dyn Future<Item = T, Error = Error>>,
+) -> Box<dyn Future<Item = T, Error = Error>> {
+    let start = clock::now();
+    Box::new(future.then(move |res| {
+        let elapsed = clock::now() - start;
+        if elapsed > threshold {
+            stats.lock().unwrap().slow_queries += 1;
+            warn!(
+                "/{}/: Slow query: {} took {} ms{}",
+                board,
+                name,
+                elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+                if res.is_err() { " and failed" } else { "" },
+            );
+        }
+        res
+    }))
+}
+
+/// A handle for checking whether the database is falling behind, so that other actors can slow
+/// down. It can be shared across threads.
+#[derive(Clone)]
+pub struct DatabaseLoad {
+    stats: Arc<Mutex<DatabaseStats>>,
+    max_writes: Option<usize>,
+    max_insert_latency: Option<Duration>,
+}
+
+impl DatabaseLoad {
+    /// Whether there are more unfinished writes than `advanced.max_database_writes`, or inserts take
+    /// longer than `advanced.max_insert_latency` on average.
+    pub fn is_overloaded(&self) -> bool {
+        let stats = self.stats.lock().unwrap();
+        self.max_writes
+            .map_or(false, |max| stats.active_writes > max as u64)
+            || self
+                .max_insert_latency
+                .map_or(false, |max| stats.insert_latency > max)
+    }
+
+    /// The number of unfinished writes, and the average insert latency.
+    pub fn current(&self) -> (u64, Duration) {
+        let stats = self.stats.lock().unwrap();
+        (stats.active_writes, stats.insert_latency)
+    }
+}
+
+impl Database {
+    /// Get a handle for checking the load of this database actor.
+    pub fn load(&self, config: &AdvancedConfig) -> DatabaseLoad {
+        DatabaseLoad {
+            stats: self.stats.clone(),
+            max_writes: config.max_database_writes,
+            max_insert_latency: config.max_insert_latency,
+        }
+    }
+}
+
+/// Get the write statistics of the database.
+pub struct GetDatabaseStats;
+impl Message for GetDatabaseStats {
+    type Result = Result<DatabaseStats, Error>;
+}
+
+impl Handler<GetDatabaseStats> for Database {
+    type Result = Result<DatabaseStats, Error>;
+
+    fn handle(&mut self, _: GetDatabaseStats, _: &mut Self::Context) -> Self::Result {
+        let mut stats = *self.stats.lock().unwrap();
+        stats.pools = self.pool_count;
+        stats.unknown_html_tokens = html::unknown_token_count();
+        Ok(stats)
+    }
+}
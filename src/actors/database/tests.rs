@@ -0,0 +1,307 @@
+#![cfg(test)]
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use mysql_async::{
+    error::{DriverError, Error, ServerError},
+    Value,
+};
+
+use super::{
+    exif,
+    insert::POST_COLUMN_COUNT,
+    is_transient_error,
+    journal::{post_rows, Journal, JournalEntry, JournalValue, Query},
+    triggers,
+};
+use crate::four_chan::{Board, OpData};
+
+/// A journal in a new temporary directory, which is removed when the test ends.
+struct TempJournal {
+    dir: PathBuf,
+    path: PathBuf,
+    journal: Journal,
+}
+
+impl TempJournal {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("ena-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.jsonl");
+        Self {
+            journal: Journal::new(path.clone()),
+            dir,
+            path,
+        }
+    }
+
+    fn replay_path(&self) -> PathBuf {
+        self.dir.join("journal.jsonl.replay")
+    }
+}
+
+impl Drop for TempJournal {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// An entry which is told apart from others by `num`.
+fn entry(num: u64) -> JournalEntry {
+    JournalEntry::exec(
+        Board::a,
+        Query::UpdatePost,
+        &[vec![(String::from("num"), Value::from(num))]],
+    )
+}
+
+/// The `num` of each entry made by `entry` (for a batch, its first entry's).
+fn nums(entries: &[JournalEntry]) -> Vec<u64> {
+    entries
+        .iter()
+        .map(|entry| match entry {
+            JournalEntry::Exec(_, _, params) => match &params[0][0].1 {
+                JournalValue::UInt(num) => *num,
+                _ => panic!("Wrong journal value"),
+            },
+            JournalEntry::Batch(_, entries) => nums(&entries[..1])[0],
+            JournalEntry::InsertPosts(..) => panic!("Wrong journal entry"),
+        })
+        .collect()
+}
+
+fn append_raw(path: &Path, contents: &str) {
+    fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .unwrap()
+        .write_all(contents.as_bytes())
+        .unwrap();
+}
+
+#[test]
+fn journal_round_trip() {
+    let temp = TempJournal::new("journal-round-trip");
+    let journal = &temp.journal;
+    assert!(!journal.has_entries());
+
+    journal.append(&entry(1)).unwrap();
+    journal.append(&entry(2)).unwrap();
+    assert!(journal.has_entries());
+
+    let entries = journal.start_replay().unwrap();
+    assert_eq!(nums(&entries), vec![1, 2]);
+    assert!(!temp.path.exists());
+
+    // Writes which fail during the replay go to a new journal
+    journal.append(&entry(3)).unwrap();
+    journal.finish_replay().unwrap();
+    assert!(!temp.replay_path().exists());
+    assert!(journal.has_entries());
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![3]);
+    journal.finish_replay().unwrap();
+    assert!(!journal.has_entries());
+}
+
+#[test]
+fn journal_append_if_pending() {
+    let temp = TempJournal::new("journal-append-if-pending");
+    let journal = &temp.journal;
+
+    // With nothing to replay, writes are run instead of journaled
+    assert!(!journal.append_if_pending(&entry(1)).unwrap());
+    assert!(!journal.has_entries());
+
+    journal.append(&entry(1)).unwrap();
+    assert!(journal.append_if_pending(&entry(2)).unwrap());
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![1, 2]);
+
+    // Writes made during a replay wait behind it
+    assert!(journal.append_if_pending(&entry(3)).unwrap());
+    journal.finish_replay().unwrap();
+    assert!(journal.append_if_pending(&entry(4)).unwrap());
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![3, 4]);
+    journal.finish_replay().unwrap();
+    assert!(!journal.append_if_pending(&entry(5)).unwrap());
+}
+
+#[test]
+fn journal_interrupted_replay() {
+    let temp = TempJournal::new("journal-interrupted-replay");
+    let journal = &temp.journal;
+    journal.append(&entry(1)).unwrap();
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![1]);
+    journal.append(&entry(2)).unwrap();
+
+    // The replay didn't finish, so it's run again before the new journal
+    assert!(journal.has_entries());
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![1]);
+    journal.finish_replay().unwrap();
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![2]);
+}
+
+#[test]
+fn journal_partial_line() {
+    let temp = TempJournal::new("journal-partial-line");
+    let journal = &temp.journal;
+    journal.append(&entry(1)).unwrap();
+    journal.append(&entry(2)).unwrap();
+
+    // Ena crashed while appending the third entry
+    let line = serde_json::to_string(&entry(3)).unwrap();
+    append_raw(&temp.path, &line[..line.len() / 2]);
+
+    // The next entry starts on a new line
+    journal.append(&entry(4)).unwrap();
+    assert_eq!(nums(&journal.start_replay().unwrap()), vec![1, 2, 4]);
+}
+
+#[test]
+fn journal_touch_threads() {
+    // Without expired posts, there's nothing to add
+    let update = triggers::journal_touch_threads(entry(1), &[]);
+    match update {
+        JournalEntry::Exec(Board::a, Query::UpdatePost, _) => {}
+        _ => panic!("Wrong journal entry"),
+    }
+
+    // The thread update is replayed along with the update which expired the posts
+    let temp = TempJournal::new("journal-touch-threads");
+    let journal = &temp.journal;
+    let update = triggers::journal_touch_threads(entry(1), &[(1, 2)]);
+    journal.append(&update).unwrap();
+    let entries = journal.start_replay().unwrap();
+    assert_eq!(nums(&entries), vec![1]);
+    match &entries[0] {
+        JournalEntry::Batch(Board::a, batch) => {
+            assert_eq!(batch.len(), 2);
+            match &batch[1] {
+                JournalEntry::Exec(Board::a, Query::TouchThreads, params) => {
+                    assert_eq!(params.len(), 1)
+                }
+                _ => panic!("Wrong journal entry"),
+            }
+        }
+        _ => panic!("Wrong journal entry"),
+    }
+}
+
+#[test]
+fn journal_queries() {
+    // Tables are found from the board when an entry is replayed
+    assert_eq!(
+        Query::MarkPostsRemoved.build("b"),
+        "UPDATE `b` SET deleted = :deleted, timestamp_expired = :timestamp_expired \
+         WHERE num = :num AND subnum = 0"
+    );
+    assert!(Query::TouchThreads
+        .build("b")
+        .starts_with("UPDATE `b_threads` INNER JOIN `b`"));
+}
+
+#[test]
+fn journal_values() {
+    let values = vec![
+        Value::NULL,
+        Value::from(&b"\xff\x00bytes"[..]),
+        Value::Int(-1),
+        Value::UInt(u64::max_value()),
+        Value::Float(0.5),
+    ];
+    let params: Vec<_> = values
+        .iter()
+        .map(|value| vec![(String::from("value"), value.clone())])
+        .collect();
+    let line =
+        serde_json::to_string(&JournalEntry::exec(Board::a, Query::InsertHtml, &params)).unwrap();
+
+    match serde_json::from_str::<JournalEntry>(&line).unwrap() {
+        JournalEntry::Exec(Board::a, query, params) => {
+            assert_eq!(query, Query::InsertHtml);
+            let replayed: Vec<_> = params
+                .into_iter()
+                .flatten()
+                .map(|(_, value)| Value::from(value))
+                .collect();
+            assert_eq!(replayed, values);
+        }
+        _ => panic!("Wrong journal entry"),
+    }
+}
+
+#[test]
+fn journal_post_rows() {
+    let row = vec![JournalValue::Null; POST_COLUMN_COUNT];
+    let old_row = vec![JournalValue::Null; POST_COLUMN_COUNT - 1];
+    let rows = post_rows(vec![(1, 0, row), (2, 1, old_row)]);
+    assert_eq!(rows[0].2.len(), POST_COLUMN_COUNT);
+    assert_eq!(rows[0].2[POST_COLUMN_COUNT - 1], Value::NULL);
+
+    // Rows journaled before `comment_truncated` was added are padded
+    assert_eq!((rows[1].0, rows[1].1), (2, 1));
+    assert_eq!(rows[1].2.len(), POST_COLUMN_COUNT);
+    assert_eq!(rows[1].2[POST_COLUMN_COUNT - 1], Value::from(false));
+}
+
+#[test]
+fn transient_errors() {
+    let server_error = |code| {
+        Error::Server(ServerError {
+            code,
+            message: String::new(),
+            state: String::new(),
+        })
+    };
+    let io_error = io::Error::from(io::ErrorKind::ConnectionReset);
+    assert!(is_transient_error(&Error::Io(io_error)));
+    // A pooled connection which was dropped by the server
+    assert!(is_transient_error(&Error::Driver(
+        DriverError::ConnectionClosed
+    )));
+    assert!(is_transient_error(&Error::Driver(
+        DriverError::PoolDisconnected
+    )));
+    // Server shutdown, lock wait timeout, and deadlock
+    assert!(is_transient_error(&server_error(1053)));
+    assert!(is_transient_error(&server_error(1205)));
+    assert!(is_transient_error(&server_error(1213)));
+
+    // Errors in the query itself won't go away
+    assert!(!is_transient_error(&server_error(1064)));
+    assert!(!is_transient_error(&Error::Driver(
+        DriverError::MixedParams
+    )));
+    assert!(!is_transient_error(&Error::Other("other".into())));
+}
+
+#[test]
+fn exif_json() {
+    let op_data: OpData =
+        serde_json::from_str(r#"{"unique_ips": 12, "bumplimit": 1, "imagelimit": 0}"#).unwrap();
+    let mut tags = BTreeMap::new();
+    tags.insert(String::from("uniqueIps"), String::from("0"));
+    tags.insert(String::from("flag"), String::from("AC"));
+
+    let exif = exif(&op_data, Some(2014), &tags).unwrap();
+    let exif: serde_json::Value = serde_json::from_str(&exif).unwrap();
+    // Tags can't replace the fields that Ena sets
+    assert_eq!(
+        exif,
+        serde_json::json!({
+            "uniqueIps": "12",
+            "since4pass": "2014",
+            "bumpLimit": "1",
+            "flag": "AC",
+        })
+    );
+
+    let op_data: OpData = serde_json::from_str("{}").unwrap();
+    assert_eq!(exif(&op_data, None, &BTreeMap::new()), None);
+}
@@ -0,0 +1,385 @@
+//! Converting adjusted timestamps to UTC.
+//!
+//! Asagi stores timestamps "adjusted" to New York time (the UTC timestamp of the New York local
+//! time), which repeats an hour of timestamps every fall. `ena migrate-timestamps` converts the
+//! timestamps of each board with `adjust_timestamps` disabled to UTC in place, in batches. Its
+//! progress is stored in the `ena_utc_timestamps` table (keyed by base table name), so it can be
+//! stopped and resumed.
+//!
+//! Every board in that table has UTC timestamps (or is being converted), and every other board has
+//! adjusted timestamps. So, boards in the table can't be scraped with `adjust_timestamps` enabled,
+//! boards whose conversion is unfinished can't be scraped at all, and boards with posts can't be
+//! scraped with `adjust_timestamps` disabled until they've been converted. New (or empty) boards
+//! scraped with it disabled are added to the table as already converted. Running instances check
+//! the table every `CHECK_INTERVAL`, and stop if a conversion of one of their boards starts.
+
+use std::thread;
+
+use chrono::Duration as ChronoDuration;
+use failure::{err_msg, ResultExt};
+use futures::future::Either;
+use mysql_async::Conn;
+
+use super::*;
+
+/// The range of primary keys converted in each transaction.
+const BATCH_SIZE: u64 = 10_000;
+/// How often running instances check that their boards' timestamps aren't being converted.
+pub(super) const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The tables (by suffix) to convert, with their primary keys and timestamp columns, in order. The
+/// threads table must come before the base table, because updating `timestamp_expired` in the base
+/// table runs a trigger which raises `time_last_modified` to it, and that time shouldn't be
+/// converted twice.
+const TABLES: &[(&str, &str, &[&str])] = &[
+    (
+        "_threads",
+        "thread_num",
+        &[
+            "time_op",
+            "time_last",
+            "time_bump",
+            "time_ghost",
+            "time_ghost_bump",
+            "time_last_modified",
+        ],
+    ),
+    ("_users", "user_id", &["firstseen"]),
+    ("", "doc_id", &["timestamp", "timestamp_expired"]),
+    ("_deleted", "doc_id", &["timestamp", "timestamp_expired"]),
+];
+
+/// Convert the timestamps of every board with `adjust_timestamps` disabled to UTC, so that Ena
+/// keeps writing UTC timestamps afterwards. Boards with it enabled are left as they are.
+pub fn migrate_timestamps(config: &Config) -> Result<(), failure::Error> {
+    if config
+        .boards
+        .values()
+        .all(|board_config| board_config.adjust_timestamps)
+    {
+        return Err(err_msg(
+            "Set `adjust_timestamps = false` for the boards to convert (in `asagi_compat` or \
+             `[boards]`) before converting timestamps",
+        ));
+    }
+
+    let offsets = offsets();
+    let table_template = table_template(config);
+    let mut runtime = Runtime::new().unwrap();
+    for (url, mut boards) in boards_by_server(config) {
+        boards.retain(|board| !config.boards[board].adjust_timestamps);
+        if boards.is_empty() {
+            continue;
+        }
+        let pool = Pool::new(pool_opts(url, None)?);
+        let (mut conn, statuses) = runtime.block_on(
+            pool.get_conn()
+                .and_then(|conn| conn.drop_query(include_str!("../../sql/utc_timestamps.sql")))
+                .and_then(conversion_statuses),
+        )?;
+
+        // Register the boards first, so that running instances which still write adjusted
+        // timestamps to them notice and stop before their timestamps are converted
+        let tables: Vec<_> = boards
+            .iter()
+            .map(|&board| table_name(&table_template, board))
+            .collect();
+        let new_tables: Vec<_> = tables
+            .iter()
+            .filter(|table| !statuses.iter().any(|(name, _)| name == *table))
+            .collect();
+        if !new_tables.is_empty() {
+            conn = runtime.block_on(
+                conn.batch_exec(
+                    "INSERT IGNORE INTO `ena_utc_timestamps` (table_name) VALUES (:table)",
+                    new_tables
+                        .iter()
+                        .map(|table| params! { "table" => table.as_str() }),
+                ),
+            )?;
+            let wait = CHECK_INTERVAL * 2;
+            info!(
+                "Waiting {} seconds for running instances of Ena to stop scraping the boards",
+                wait.as_secs()
+            );
+            thread::sleep(wait);
+        }
+
+        for table in tables {
+            conn = migrate_board(&mut runtime, conn, &table, &offsets)
+                .with_context(|_| format!("`{}`: Could not convert timestamps", table))?;
+        }
+        runtime.block_on(conn.disconnect())?;
+    }
+    runtime.shutdown_on_idle().wait().unwrap();
+    Ok(())
+}
+
+fn migrate_board(
+    runtime: &mut Runtime,
+    conn: Conn,
+    table: &str,
+    offsets: &[(i64, i64)],
+) -> Result<Conn, Error> {
+    let (mut conn, progress): (_, Option<(usize, u64, bool)>) =
+        runtime.block_on(conn.first_exec(
+            "SELECT step, last_key, done FROM `ena_utc_timestamps` WHERE table_name = :table",
+            params! { table },
+        ))?;
+    let (step, mut last_key, done) = progress.unwrap();
+    if done {
+        info!("`{}`: Timestamps are already UTC", table);
+        return Ok(conn);
+    }
+
+    for (i, &(suffix, key, columns)) in TABLES.iter().enumerate().skip(step) {
+        let name = format!("{}{}", table, suffix);
+        let (next_conn, exists): (_, Option<(u64,)>) = runtime.block_on(conn.first_exec(
+            "SELECT COUNT(*) FROM information_schema.tables \
+             WHERE table_schema = DATABASE() AND table_name = :name",
+            params! { "name" => name.clone() },
+        ))?;
+        conn = next_conn;
+
+        // The users table only exists if `update_users_table` was ever enabled
+        if exists.map_or(false, |(count,)| count > 0) {
+            let (next_conn, max_key): (_, Option<(u64,)>) = runtime.block_on(conn.first(
+                format!("SELECT COALESCE(MAX(`{}`), 0) FROM `{}`", key, name),
+            ))?;
+            conn = next_conn;
+            let max_key = max_key.map_or(0, |(max_key,)| max_key);
+
+            info!("`{}`: Converting timestamps", name);
+            let set = columns
+                .iter()
+                .map(|column| format!("`{0}` = {1}", column, unadjust_sql(column, offsets)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            while last_key < max_key {
+                let next_key = last_key + BATCH_SIZE;
+                conn = runtime.block_on(conn.drop_query(format!(
+                    "START TRANSACTION; \
+                     UPDATE `{name}` SET {set} WHERE `{key}` > {last} AND `{key}` <= {next}; \
+                     UPDATE `ena_utc_timestamps` SET last_key = {next} \
+                     WHERE table_name = '{table}'; \
+                     COMMIT;",
+                    name = name,
+                    set = set,
+                    key = key,
+                    last = last_key,
+                    next = next_key,
+                    table = table,
+                )))?;
+                debug!(
+                    "`{}`: Converted up to {} {} of {}",
+                    name, key, next_key, max_key
+                );
+                last_key = next_key;
+            }
+        }
+
+        conn = runtime.block_on(conn.drop_query(format!(
+            "UPDATE `ena_utc_timestamps` SET step = {}, last_key = 0 WHERE table_name = '{}';",
+            i + 1,
+            table
+        )))?;
+        last_key = 0;
+    }
+
+    let conn = runtime.block_on(conn.drop_query(format!(
+        "UPDATE `ena_utc_timestamps` SET done = 1 WHERE table_name = '{}';",
+        table
+    )))?;
+    info!("`{}`: Timestamps converted to UTC", table);
+    Ok(conn)
+}
+
+/// The offsets from adjusted timestamps to UTC, as `(start, offset)` pairs: adjusted timestamps from
+/// `start` until the next pair's `start` are `offset` seconds behind UTC. Like `unadjust`, times in
+/// the hour repeated when daylight saving time ends are taken to be the earlier of the two.
+fn offsets() -> Vec<(i64, i64)> {
+    // 4chan didn't exist before 2003
+    let mut local = NaiveDate::from_ymd(2003, 1, 1).and_hms(0, 0, 0);
+    let end = (Utc::now() + ChronoDuration::days(366)).naive_utc();
+    let mut offsets: Vec<(i64, i64)> = vec![];
+    while local < end {
+        // Adjusted timestamps can't be in the hour skipped when daylight saving time starts, so
+        // those times are left with the previous offset
+        if let Some(utc) = America::New_York.from_local_datetime(&local).earliest() {
+            let offset = utc.timestamp() - local.timestamp();
+            if offsets.last().map_or(true, |&(_, last)| last != offset) {
+                offsets.push((local.timestamp(), offset));
+            }
+        }
+        local += ChronoDuration::hours(1);
+    }
+    offsets
+}
+
+/// An SQL expression converting an adjusted timestamp column to UTC. Zero (e.g. an unset
+/// `timestamp_expired`) and `NULL` are left as they are.
+fn unadjust_sql(column: &str, offsets: &[(i64, i64)]) -> String {
+    let mut case = String::from("CASE");
+    for window in offsets.windows(2) {
+        case.push_str(&format!(
+            " WHEN `{}` < {} THEN {}",
+            column, window[1].0, window[0].1
+        ));
+    }
+    format!(
+        "IF(`{0}` IS NULL OR `{0}` = 0, `{0}`, `{0}` + {1} ELSE {2} END)",
+        column,
+        case,
+        offsets.last().map_or(0, |&(_, offset)| offset),
+    )
+}
+
+/// Read the `(table_name, done)` of every board in `ena_utc_timestamps`.
+fn conversion_statuses(
+    conn: Conn,
+) -> impl Future<Item = (Conn, Vec<(String, bool)>), Error = Error> {
+    conn.query("SELECT table_name, done FROM `ena_utc_timestamps`")
+        .and_then(|result| result.collect_and_drop::<(String, bool)>())
+}
+
+/// Why a board with base table `table` can't be scraped, if it can't. `converted` is whether the
+/// board's timestamps have been converted, if it's in `ena_utc_timestamps`.
+pub(super) fn timestamp_mode_error(
+    table: &str,
+    adjust_timestamps: bool,
+    converted: Option<bool>,
+) -> Option<String> {
+    match converted {
+        Some(false) => Some(format!(
+            "`{}`: Timestamps are being converted to UTC. Run `ena migrate-timestamps` to finish",
+            table
+        )),
+        Some(true) if adjust_timestamps => Some(format!(
+            "`{}`: Timestamps were converted to UTC, so the board's `adjust_timestamps` must be \
+             `false`",
+            table
+        )),
+        _ => None,
+    }
+}
+
+/// Check that no board's timestamps are being converted, that boards with converted timestamps are
+/// scraped with `adjust_timestamps` disabled, and that boards with adjusted timestamps are scraped
+/// with it enabled. New and empty boards scraped with it disabled are registered as converted.
+pub fn check_timestamp_modes(config: &Config) -> Result<(), failure::Error> {
+    let table_template = table_template(config);
+    let mut runtime = Runtime::new().unwrap();
+    for (url, boards) in boards_by_server(config) {
+        let pool = Pool::new(pool_opts(url, None)?);
+        let (mut conn, statuses) = runtime.block_on(
+            pool.get_conn()
+                .and_then(|conn| conn.drop_query(include_str!("../../sql/utc_timestamps.sql")))
+                .and_then(conversion_statuses),
+        )?;
+
+        for board in boards {
+            let table = table_name(&table_template, board);
+            let adjust_timestamps = config.boards[&board].adjust_timestamps;
+            let converted = statuses
+                .iter()
+                .find(|(name, _)| *name == table)
+                .map(|&(_, done)| done);
+            let mut error = timestamp_mode_error(&table, adjust_timestamps, converted);
+
+            if error.is_none() && converted.is_none() && !adjust_timestamps {
+                let (next_conn, has_posts) = runtime.block_on(has_posts(conn, &table))?;
+                conn = next_conn;
+                if has_posts {
+                    error = Some(format!(
+                        "`{}`: Timestamps are adjusted to New York time. Run \
+                         `ena migrate-timestamps` to convert them to UTC before scraping the \
+                         board with `adjust_timestamps = false`",
+                        table
+                    ));
+                } else {
+                    // Every timestamp that will be written is UTC, so there's nothing to convert
+                    conn = runtime.block_on(conn.drop_exec(
+                        "INSERT IGNORE INTO `ena_utc_timestamps` (table_name, step, done) \
+                         VALUES (:table, :step, 1)",
+                        params! { "table" => table.as_str(), "step" => TABLES.len() },
+                    ))?;
+                }
+            }
+
+            if let Some(error) = error {
+                runtime.block_on(conn.disconnect())?;
+                return Err(err_msg(error));
+            }
+        }
+        runtime.block_on(conn.disconnect())?;
+    }
+    runtime.shutdown_on_idle().wait().unwrap();
+    Ok(())
+}
+
+/// Whether the board with base table `table` has any posts. Its tables may not exist yet.
+fn has_posts(conn: Conn, table: &str) -> impl Future<Item = (Conn, bool), Error = Error> {
+    let table = table.to_owned();
+    conn.first_exec(
+        "SELECT COUNT(*) FROM information_schema.tables \
+         WHERE table_schema = DATABASE() AND table_name = :table",
+        params! { "table" => table.clone() },
+    )
+    .and_then(move |(conn, exists): (_, Option<(u64,)>)| {
+        if exists.map_or(true, |(count,)| count == 0) {
+            return Either::A(future::ok((conn, false)));
+        }
+        Either::B(
+            conn.first(format!("SELECT EXISTS(SELECT 1 FROM `{}`)", table))
+                .map(|(conn, exists): (_, Option<(bool,)>)| {
+                    (conn, exists.map_or(false, |(exists,)| exists))
+                }),
+        )
+    })
+}
+
+impl Database {
+    /// Stop Ena if the timestamps of one of our boards started being converted (or were converted)
+    /// after we started, so that we don't write timestamps of the wrong kind to it.
+    pub(super) fn check_timestamp_conversions(&self) {
+        let checks: Vec<_> = self
+            .boards
+            .iter()
+            .map(|(&board, board_config)| {
+                let table = self.table(board);
+                let adjust_timestamps = board_config.adjust_timestamps;
+                self.pool(board)
+                    .get_conn()
+                    .and_then({
+                        let table = table.clone();
+                        move |conn| {
+                            conn.first_exec(
+                                "SELECT done FROM `ena_utc_timestamps` WHERE table_name = :table",
+                                params! { table },
+                            )
+                        }
+                    })
+                    .map(move |(_conn, done): (_, Option<(bool,)>)| {
+                        timestamp_mode_error(&table, adjust_timestamps, done.map(|(done,)| done))
+                    })
+            })
+            .collect();
+        Arbiter::spawn(future::join_all(checks).then(|res| {
+            match res {
+                Ok(errors) => {
+                    let mut stop = false;
+                    for error in errors.into_iter().flatten() {
+                        error!("{}", error);
+                        stop = true;
+                    }
+                    if stop {
+                        error!("Stopping, since timestamps of the wrong kind would be written");
+                        System::current().stop();
+                    }
+                }
+                Err(err) => warn!("Could not check for timestamp conversions: {}", err),
+            }
+            Ok::<(), ()>(())
+        }));
+    }
+}
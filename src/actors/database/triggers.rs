@@ -0,0 +1,491 @@
+//! Native replacements for the Asagi triggers in `triggers.sql`. When `native_triggers` is enabled,
+//! the triggers aren't created, and the `%%BOARD%%_threads` and `%%BOARD%%_images` tables are
+//! maintained here instead, in the same transaction as the insert. Ena never deletes posts, so the
+//! delete trigger has no replacement. If the Asagi triggers already exist (from an earlier run of
+//! Ena or Asagi), Ena refuses to start, since the derived tables would be updated twice.
+//!
+//! With `global_media`, media is added to the `ena_media` table shared by all boards instead of
+//! `%%BOARD%%_images`.
+//!
+//! The `%%BOARD%%_users` table (which Asagi's triggers don't maintain in its current versions) is
+//! also updated here, if `asagi_compat.update_users_table` is enabled.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures::{future::Either, stream};
+use mysql_async::from_value;
+
+use super::*;
+
+/// The maximum number of rows in one `INSERT` into a derived table.
+const MAX_ROWS: usize = 10_000;
+
+/// Which derived tables Ena maintains itself when inserting posts.
+#[derive(Clone, Copy)]
+pub(super) struct DerivedTables {
+    /// `%%BOARD%%_threads` and `%%BOARD%%_images` (see `database_media.native_triggers`)
+    pub threads_images: bool,
+    /// Use `ena_media` instead of `%%BOARD%%_images` (see `database_media.global_media`)
+    pub global_media: bool,
+    pub users: bool,
+}
+
+impl DerivedTables {
+    /// Whether inserted rows need to be split into new and existing posts.
+    pub fn any(self) -> bool {
+        self.threads_images || self.users
+    }
+}
+
+/// The values of a post row that the triggers need. The indices are positions in
+/// `insert::POST_COLUMNS`.
+pub(super) struct TriggerRow {
+    num: u64,
+    thread_num: u64,
+    op: bool,
+    timestamp: u64,
+    timestamp_expired: u64,
+    preview_orig: Option<String>,
+    media_w: u64,
+    media_h: u64,
+    media_size: u64,
+    media_hash: Option<String>,
+    media_orig: Option<String>,
+    name: Option<String>,
+    trip: Option<String>,
+}
+
+impl TriggerRow {
+    fn new(row: &[Value]) -> Self {
+        Self {
+            num: from_value(row[0].clone()),
+            thread_num: from_value(row[2].clone()),
+            op: from_value(row[3].clone()),
+            timestamp: from_value(row[4].clone()),
+            timestamp_expired: from_value(row[5].clone()),
+            preview_orig: from_value(row[6].clone()),
+            media_w: from_value(row[10].clone()),
+            media_h: from_value(row[11].clone()),
+            media_size: from_value(row[12].clone()),
+            media_hash: from_value(row[13].clone()),
+            media_orig: from_value(row[14].clone()),
+            name: from_value(row[17].clone()),
+            trip: from_value(row[18].clone()),
+        }
+    }
+}
+
+/// Check that none of the given tables have the Asagi triggers. Tables are given by their base
+/// table names.
+pub(super) fn check_no_triggers(
+    pool: &Pool,
+    tables: Vec<String>,
+) -> impl Future<Item = (), Error = Error> {
+    pool.get_conn()
+        .and_then(|conn| {
+            stream::iter_ok(tables).fold(conn, |conn, table| {
+                let names: Vec<String> = ["before_ins", "after_ins", "after_del", "after_upd"]
+                    .iter()
+                    .map(|name| format!("{}_{}", name, table))
+                    .collect();
+                let query = format!(
+                    "SELECT TRIGGER_NAME FROM information_schema.TRIGGERS \
+                     WHERE TRIGGER_SCHEMA = DATABASE() AND EVENT_OBJECT_TABLE = ? \
+                     AND TRIGGER_NAME IN ({});",
+                    vec!["?"; names.len()].join(", "),
+                );
+                let params: Vec<Value> = Some(table.clone())
+                    .into_iter()
+                    .chain(names)
+                    .map(Value::from)
+                    .collect();
+                conn.prep_exec(query, params)
+                    .and_then(|result| result.collect_and_drop::<String>())
+                    .and_then(move |(conn, triggers)| {
+                        if triggers.is_empty() {
+                            Ok(conn)
+                        } else {
+                            Err(Error::Other(
+                                format!(
+                                    "`{}` has Asagi triggers ({}), which would update the \
+                                     derived tables twice with `native_triggers`. Drop them, \
+                                     or disable `native_triggers`",
+                                    table,
+                                    triggers.join(", "),
+                                )
+                                .into(),
+                            ))
+                        }
+                    })
+            })
+        })
+        // See the comment about disconnecting in `Database::try_new`
+        .and_then(|conn| conn.disconnect())
+}
+
+/// Split post rows into new posts and posts which already exist. Only new posts are counted in
+/// the derived tables.
+pub(super) fn classify_rows<Q: Queryable + 'static>(
+    conn: Q,
+    table: &str,
+    rows: &[Vec<Value>],
+) -> impl Future<Item = (Q, Vec<TriggerRow>, Vec<TriggerRow>), Error = Error> {
+    let rows: Vec<TriggerRow> = rows.iter().map(|row| TriggerRow::new(row)).collect();
+    // `FOR UPDATE` locks the rows (and the gaps where missing rows would go) until the transaction
+    // ends. Otherwise, a concurrent insert of the same posts (e.g. by a journal replay) could see
+    // them as new too, and count them twice.
+    let query = board_replace(
+        table,
+        &format!(
+            "SELECT num FROM `%%BOARD%%` WHERE subnum = 0 AND num IN ({}) FOR UPDATE;",
+            vec!["?"; rows.len()].join(", "),
+        ),
+    );
+    let nums: Vec<u64> = rows.iter().map(|row| row.num).collect();
+    conn.prep_exec(query, nums)
+        .and_then(|result| result.collect_and_drop::<u64>())
+        .map(move |(conn, existing)| {
+            let existing: BTreeSet<u64> = existing.into_iter().collect();
+            let (updated_rows, new_rows) = rows
+                .into_iter()
+                .partition(|row| existing.contains(&row.num));
+            (conn, new_rows, updated_rows)
+        })
+}
+
+/// Update the derived tables after inserting posts, like the `before_ins` and `after_ins` triggers
+/// (for `new_rows`) and the `after_upd` trigger (for `updated_rows`).
+pub(super) fn after_insert<Q: Queryable + 'static>(
+    conn: Q,
+    board: Board,
+    table: String,
+    derived: DerivedTables,
+    new_rows: Vec<TriggerRow>,
+    updated_rows: Vec<TriggerRow>,
+) -> Box<dyn Future<Item = Q, Error = Error>> {
+    let users = if derived.users {
+        Either::A(update_users(conn, &table, &new_rows))
+    } else {
+        Either::B(future::ok(conn))
+    };
+    if !derived.threads_images {
+        return Box::new(users);
+    }
+
+    Box::new(
+        users
+            .and_then({
+                let table = table.clone();
+                move |conn| -> Box<dyn Future<Item = (Q, Vec<TriggerRow>), Error = Error>> {
+                    let images = if derived.global_media {
+                        insert_global_media(conn, board, table, &new_rows)
+                    } else {
+                        insert_images(conn, table, &new_rows)
+                    };
+                    Box::new(images.map(move |conn| (conn, new_rows)))
+                }
+            })
+            .and_then({
+                let table = table.clone();
+                move |(conn, new_rows)| update_threads(conn, table, new_rows)
+            })
+            .and_then(move |conn| {
+                let expired: Vec<_> = updated_rows
+                    .into_iter()
+                    .filter(|row| row.timestamp_expired != 0)
+                    .map(|row| (row.num, row.timestamp_expired))
+                    .collect();
+                touch_threads(conn, &table, &expired)
+            }),
+    )
+}
+
+/// Count new posts in `%%BOARD%%_users`, by name and tripcode.
+fn update_users<Q: Queryable + 'static>(
+    conn: Q,
+    table: &str,
+    rows: &[TriggerRow],
+) -> Box<dyn Future<Item = Q, Error = Error>> {
+    // (name, trip) => (firstseen, postcount)
+    let mut users: BTreeMap<(String, String), (u64, u64)> = BTreeMap::new();
+    for row in rows {
+        let name = row.name.clone().unwrap_or_default();
+        let trip = row.trip.clone().unwrap_or_default();
+        let user = users.entry((name, trip)).or_insert((row.timestamp, 0));
+        user.0 = user.0.min(row.timestamp);
+        user.1 += 1;
+    }
+    if users.is_empty() {
+        return Box::new(future::ok(conn));
+    }
+
+    let users: Vec<Vec<Value>> = users
+        .into_iter()
+        .map(|((name, trip), (firstseen, postcount))| {
+            vec![name.into(), trip.into(), firstseen.into(), postcount.into()]
+        })
+        .collect();
+    let chunks: Vec<Vec<Vec<Value>>> = users.chunks(MAX_ROWS).map(<[_]>::to_vec).collect();
+    let table = table.to_owned();
+    Box::new(
+        stream::iter_ok::<_, Error>(chunks).fold(conn, move |conn, chunk| {
+            let query = board_replace(
+                &table,
+                &format!(
+                    "INSERT INTO `%%BOARD%%_users` (name, trip, firstseen, postcount) VALUES {} \
+                     ON DUPLICATE KEY UPDATE \
+                         firstseen = LEAST(firstseen, VALUES(firstseen)), \
+                         postcount = postcount + VALUES(postcount);",
+                    vec!["(?, ?, ?, ?)"; chunk.len()].join(", "),
+                ),
+            );
+            let params: Vec<Value> = chunk.into_iter().flatten().collect();
+            conn.drop_exec(query, params)
+        }),
+    )
+}
+
+/// Add the media of new posts to `%%BOARD%%_images` and set the `media_id` of the posts.
+fn insert_images<Q: Queryable + 'static>(
+    conn: Q,
+    table: String,
+    rows: &[TriggerRow],
+) -> Box<dyn Future<Item = Q, Error = Error>> {
+    let mut nums = vec![];
+    let mut params = vec![];
+    for row in rows {
+        if let Some(media_hash) = &row.media_hash {
+            nums.push(Value::from(row.num));
+            let (preview_op, preview_reply) = if row.op {
+                (row.preview_orig.clone(), None)
+            } else {
+                (None, row.preview_orig.clone())
+            };
+            params.push(vec![
+                media_hash.clone().into(),
+                row.media_orig.clone().into(),
+                preview_op.into(),
+                preview_reply.into(),
+            ]);
+        }
+    }
+    if nums.is_empty() {
+        return Box::new(future::ok(conn));
+    }
+
+    let chunks: Vec<Vec<Vec<Value>>> = params.chunks(MAX_ROWS).map(<[_]>::to_vec).collect();
+    let media_id_query = board_replace(
+        &table,
+        &format!(
+            "UPDATE `%%BOARD%%` INNER JOIN `%%BOARD%%_images` \
+             ON `%%BOARD%%`.media_hash = `%%BOARD%%_images`.media_hash \
+             SET `%%BOARD%%`.media_id = `%%BOARD%%_images`.media_id \
+             WHERE subnum = 0 AND num IN ({});",
+            vec!["?"; nums.len()].join(", "),
+        ),
+    );
+    Box::new(
+        stream::iter_ok::<_, Error>(chunks)
+            .fold(conn, move |conn, chunk| {
+                let query = board_replace(
+                    &table,
+                    &format!(
+                        "INSERT INTO `%%BOARD%%_images` \
+                         (media_hash, media, preview_op, preview_reply, total) VALUES {} \
+                         ON DUPLICATE KEY UPDATE \
+                             total = total + 1, \
+                             media = COALESCE(media, VALUES(media)), \
+                             preview_op = COALESCE(preview_op, VALUES(preview_op)), \
+                             preview_reply = COALESCE(preview_reply, VALUES(preview_reply));",
+                        vec!["(?, ?, ?, ?, 1)"; chunk.len()].join(", "),
+                    ),
+                );
+                let params: Vec<Value> = chunk.into_iter().flatten().collect();
+                conn.drop_exec(query, params)
+            })
+            .and_then(move |conn| conn.drop_exec(media_id_query, nums)),
+    )
+}
+
+/// Add the media of new posts to `ena_media` and set the `media_id` of the posts. Each filename is
+/// set by the first post with it, along with the board whose directory the file is downloaded to.
+fn insert_global_media<Q: Queryable + 'static>(
+    conn: Q,
+    board: Board,
+    table: String,
+    rows: &[TriggerRow],
+) -> Box<dyn Future<Item = Q, Error = Error>> {
+    let board = board.to_string();
+    let board_of = |name: &Option<String>| name.as_ref().map(|_| board.clone());
+    let mut nums = vec![];
+    let mut params = vec![];
+    for row in rows {
+        if let Some(media_hash) = &row.media_hash {
+            nums.push(Value::from(row.num));
+            let (preview_op, preview_reply) = if row.op {
+                (row.preview_orig.clone(), None)
+            } else {
+                (None, row.preview_orig.clone())
+            };
+            params.push(vec![
+                media_hash.clone().into(),
+                row.media_size.into(),
+                row.media_w.into(),
+                row.media_h.into(),
+                board_of(&row.media_orig).into(),
+                row.media_orig.clone().into(),
+                board_of(&preview_op).into(),
+                preview_op.into(),
+                board_of(&preview_reply).into(),
+                preview_reply.into(),
+            ]);
+        }
+    }
+    if nums.is_empty() {
+        return Box::new(future::ok(conn));
+    }
+
+    // Each row has 10 placeholders, and a prepared statement can have at most 65,535
+    let chunks: Vec<Vec<Vec<Value>>> = params.chunks(MAX_ROWS / 2).map(<[_]>::to_vec).collect();
+    let media_id_query = board_replace(
+        &table,
+        &format!(
+            "UPDATE `%%BOARD%%` INNER JOIN `ena_media` \
+             ON `%%BOARD%%`.media_hash = `ena_media`.media_hash \
+             SET `%%BOARD%%`.media_id = `ena_media`.media_id \
+             WHERE subnum = 0 AND num IN ({});",
+            vec!["?"; nums.len()].join(", "),
+        ),
+    );
+    Box::new(
+        stream::iter_ok::<_, Error>(chunks)
+            .fold(conn, move |conn, chunk| {
+                // Assignments are made from left to right, so each board is set before its
+                // filename is
+                let query = format!(
+                    "INSERT INTO `ena_media` \
+                     (media_hash, media_size, media_w, media_h, media_board, media, \
+                     preview_op_board, preview_op, preview_reply_board, preview_reply, total) \
+                     VALUES {} \
+                     ON DUPLICATE KEY UPDATE \
+                         total = total + 1, \
+                         media_board = IF(media IS NULL, VALUES(media_board), media_board), \
+                         media = COALESCE(media, VALUES(media)), \
+                         preview_op_board = \
+                             IF(preview_op IS NULL, VALUES(preview_op_board), preview_op_board), \
+                         preview_op = COALESCE(preview_op, VALUES(preview_op)), \
+                         preview_reply_board = IF(preview_reply IS NULL, \
+                             VALUES(preview_reply_board), preview_reply_board), \
+                         preview_reply = COALESCE(preview_reply, VALUES(preview_reply));",
+                    vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1)"; chunk.len()].join(", "),
+                );
+                let params: Vec<Value> = chunk.into_iter().flatten().collect();
+                conn.drop_exec(query, params)
+            })
+            .and_then(move |conn| conn.drop_exec(media_id_query, nums)),
+    )
+}
+
+/// Create the `%%BOARD%%_threads` rows of new threads, and update the reply counts and times of
+/// threads with new posts.
+fn update_threads<Q: Queryable + 'static>(
+    conn: Q,
+    table: String,
+    rows: Vec<TriggerRow>,
+) -> Box<dyn Future<Item = Q, Error = Error>> {
+    if rows.is_empty() {
+        return Box::new(future::ok(conn));
+    }
+
+    let new_threads: Vec<Value> = rows
+        .iter()
+        .filter(|row| row.op)
+        .flat_map(|row| {
+            let timestamp = Value::from(row.timestamp);
+            vec![
+                row.num.into(),
+                timestamp.clone(),
+                timestamp.clone(),
+                timestamp.clone(),
+                timestamp,
+            ]
+        })
+        .collect();
+
+    // thread_num => (replies, images, time_last)
+    let mut threads: BTreeMap<u64, (u64, u64, u64)> = BTreeMap::new();
+    for row in &rows {
+        let thread = threads.entry(row.thread_num).or_insert((0, 0, 0));
+        thread.0 += 1;
+        thread.1 += row.media_hash.is_some() as u64;
+        thread.2 = thread.2.max(row.timestamp);
+    }
+    let params: Vec<_> = threads
+        .into_iter()
+        .map(|(thread_num, (replies, images, time_last))| {
+            params! { thread_num, replies, images, time_last }
+        })
+        .collect();
+    // Ena doesn't store emails, so every post bumps its thread
+    let update_query = board_replace(
+        &table,
+        "UPDATE `%%BOARD%%_threads` \
+         SET time_last = GREATEST(time_last, :time_last), \
+             time_bump = GREATEST(time_bump, :time_last), \
+             time_last_modified = GREATEST(time_last_modified, :time_last), \
+             nreplies = nreplies + :replies, \
+             nimages = nimages + :images \
+         WHERE thread_num = :thread_num;",
+    );
+
+    let future = if new_threads.is_empty() {
+        Either::A(future::ok(conn))
+    } else {
+        let query = board_replace(
+            &table,
+            &format!(
+                "INSERT IGNORE INTO `%%BOARD%%_threads` (thread_num, time_op, time_last, \
+                 time_bump, time_ghost, time_ghost_bump, time_last_modified, nreplies, nimages, \
+                 sticky, locked) VALUES {};",
+                vec!["(?, ?, ?, ?, NULL, NULL, ?, 0, 0, 0, 0)"; new_threads.len() / 5].join(", "),
+            ),
+        );
+        Either::B(conn.drop_exec(query, new_threads))
+    };
+    Box::new(future.and_then(move |conn| conn.batch_exec(update_query, params)))
+}
+
+/// Update the last modified time of the threads of posts which expired, like the `after_upd`
+/// trigger. Posts are given as `(num, timestamp_expired)`.
+pub(super) fn touch_threads<Q: Queryable + 'static>(
+    conn: Q,
+    table: &str,
+    posts: &[(u64, u64)],
+) -> Box<dyn Future<Item = Q, Error = Error>> {
+    if posts.is_empty() {
+        return Box::new(future::ok(conn));
+    }
+    Box::new(conn.batch_exec(
+        Query::TouchThreads.build(table),
+        touch_threads_params(posts),
+    ))
+}
+
+/// The parameters of the `touch_threads` query.
+fn touch_threads_params(posts: &[(u64, u64)]) -> Vec<Vec<(String, Value)>> {
+    posts
+        .iter()
+        .map(|&(num, timestamp)| params! { num, timestamp })
+        .collect()
+}
+
+/// Add the `touch_threads` write to the journal entry of an update which expired posts, so that
+/// the whole write is replayed.
+pub(super) fn journal_touch_threads(entry: JournalEntry, posts: &[(u64, u64)]) -> JournalEntry {
+    if posts.is_empty() {
+        return entry;
+    }
+    let board = entry.board();
+    let touch = JournalEntry::exec(board, Query::TouchThreads, &touch_threads_params(posts));
+    JournalEntry::Batch(board, vec![entry, touch])
+}
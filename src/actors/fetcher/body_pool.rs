@@ -0,0 +1,80 @@
+use std::{
+    mem,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use lazy_static::lazy_static;
+
+/// The most buffers which are kept for reuse.
+const MAX_POOLED_BUFFERS: usize = 64;
+/// Buffers larger than this are freed instead of kept, so that a few huge threads don't stay
+/// allocated for the rest of the run.
+const MAX_POOLED_CAPACITY: usize = 8 * 1024 * 1024;
+
+lazy_static! {
+    /// The buffers that response bodies are read into.
+    pub static ref BODY_BUFFERS: BufferPool = BufferPool::default();
+}
+
+/// A pool of buffers for response bodies. A body is read into a buffer taken from the pool, and
+/// the buffer goes back to the pool (keeping its capacity) once the body has been deserialized, so
+/// that each fetch doesn't allocate and grow a new multi-megabyte buffer.
+#[derive(Clone, Default)]
+pub struct BufferPool(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl BufferPool {
+    /// Take an empty buffer with room for at least `capacity` bytes.
+    pub fn take(&self, capacity: usize) -> PooledBuffer {
+        let mut buf = self.0.lock().unwrap().pop().unwrap_or_default();
+        buf.clear();
+        buf.reserve(capacity);
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    /// The number of buffers waiting to be reused.
+    #[cfg(test)]
+    pub fn pooled(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// A buffer which goes back to its pool when dropped.
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl PooledBuffer {
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if self.buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        let mut buffers = self.pool.0.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(mem::replace(&mut self.buf, vec![]));
+        }
+    }
+}
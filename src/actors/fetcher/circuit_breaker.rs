@@ -0,0 +1,138 @@
+//! A circuit breaker which stops fetching from the API while it is failing every request (e.g.
+//! during an outage), instead of retrying each request separately.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::clock;
+
+use crate::config::CircuitBreakerConfig;
+
+/// How often a paused `RateLimiter` checks whether a probe has finished.
+const PROBE_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A circuit breaker shared by the API `Throttle`s.
+///
+/// After `failures` consecutive failed requests, the breaker trips and fetching pauses for
+/// `cooldown`. Then, a single probe request is made. If it succeeds, fetching resumes, and if it
+/// fails, fetching pauses for another cooldown.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker(Arc<Mutex<BreakerState>>);
+
+#[derive(Debug)]
+struct BreakerState {
+    max_failures: usize,
+    cooldown: Duration,
+    status: Status,
+    /// The number of times that the breaker has tripped
+    trips: u64,
+}
+
+#[derive(Debug)]
+enum Status {
+    Closed {
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The cooldown has ended. `probe` is when the probe request was started (if it has been).
+    HalfOpen {
+        probe: Option<Instant>,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker(Arc::new(Mutex::new(BreakerState {
+            max_failures: config.failures,
+            cooldown: config.cooldown,
+            status: Status::Closed { failures: 0 },
+            trips: 0,
+        })))
+    }
+
+    /// When fetching can resume, or `None` if new requests can be started now.
+    pub fn paused_until(&self) -> Option<Instant> {
+        let mut guard = self.0.lock().unwrap();
+        let state = &mut *guard;
+        let now = clock::now();
+        match state.status {
+            Status::Closed { .. } | Status::HalfOpen { probe: None } => None,
+            Status::Open { until } if until > now => Some(until),
+            Status::Open { .. } => {
+                info!("Circuit breaker cooldown over, probing the API");
+                state.status = Status::HalfOpen { probe: None };
+                None
+            }
+            // If the probe never finished (e.g. it was dropped), allow another one
+            Status::HalfOpen { probe: Some(start) } if now - start >= state.cooldown => {
+                state.status = Status::HalfOpen { probe: None };
+                None
+            }
+            Status::HalfOpen { probe: Some(_) } => Some(now + PROBE_RECHECK_INTERVAL),
+        }
+    }
+
+    /// Called when a request is started. Returns `true` if it is the probe request, in which case
+    /// no other requests should be started until it finishes.
+    pub fn start_request(&self) -> bool {
+        let mut guard = self.0.lock().unwrap();
+        let state = &mut *guard;
+        if let Status::HalfOpen { probe: None } = state.status {
+            state.status = Status::HalfOpen {
+                probe: Some(clock::now()),
+            };
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record whether a request succeeded. A request fails if the connection fails or if the API
+    /// responds with a server error.
+    pub fn record(&self, success: bool) {
+        let mut guard = self.0.lock().unwrap();
+        let state = &mut *guard;
+        let cooldown = state.cooldown;
+        match (&mut state.status, success) {
+            (Status::Closed { failures }, true) => *failures = 0,
+            (Status::Closed { failures }, false) => {
+                *failures += 1;
+                if *failures >= state.max_failures {
+                    error!(
+                        "{} API requests failed in a row, pausing fetching for {} seconds",
+                        failures,
+                        cooldown.as_secs(),
+                    );
+                    state.status = Status::Open {
+                        until: clock::now() + cooldown,
+                    };
+                    state.trips += 1;
+                }
+            }
+            // Requests which were started before the breaker tripped
+            (Status::Open { .. }, _) => {}
+            (Status::HalfOpen { .. }, true) => {
+                info!("The API is responding again, resuming fetching");
+                state.status = Status::Closed { failures: 0 };
+            }
+            (Status::HalfOpen { .. }, false) => {
+                warn!(
+                    "Circuit breaker probe failed, pausing fetching for {} seconds",
+                    cooldown.as_secs(),
+                );
+                state.status = Status::Open {
+                    until: clock::now() + cooldown,
+                };
+                state.trips += 1;
+            }
+        }
+    }
+
+    pub fn trips(&self) -> u64 {
+        self.0.lock().unwrap().trips
+    }
+}
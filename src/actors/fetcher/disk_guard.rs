@@ -0,0 +1,105 @@
+//! Pausing downloads while a disk is nearly full. Writing to a full disk corrupts the files being
+//! written (and MySQL's tables, if its datadir fills up), so it is better to stop and wait for the
+//! operator to free some space.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::clock;
+
+use crate::config::DiskSpaceConfig;
+
+/// How often a paused `RateLimiter` checks whether space has been freed.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Once paused, downloads resume when free space is this much above `min_free`, so that we don't
+/// repeatedly pause and resume while hovering around the threshold.
+const RESUME_MARGIN: f64 = 1.1;
+
+/// Whether any watched volume is low on space. It is shared by the `Throttle`s which it pauses.
+#[derive(Clone, Debug)]
+pub struct DiskGuard(Arc<Mutex<GuardState>>);
+
+#[derive(Debug)]
+struct GuardState {
+    /// In bytes
+    min_free: u64,
+    low: bool,
+    /// The number of times that downloads were paused
+    pauses: u64,
+}
+
+impl DiskGuard {
+    pub fn new(config: &DiskSpaceConfig) -> Self {
+        DiskGuard(Arc::new(Mutex::new(GuardState {
+            min_free: config.min_free * 1024 * 1024,
+            low: false,
+            pauses: 0,
+        })))
+    }
+
+    /// When to check again whether fetching can resume, or `None` if it can.
+    pub fn paused_until(&self) -> Option<Instant> {
+        if self.0.lock().unwrap().low {
+            Some(clock::now() + RECHECK_INTERVAL)
+        } else {
+            None
+        }
+    }
+
+    /// Update the guard with the free space (in bytes) of each watched path.
+    pub fn update(&self, free: &[(PathBuf, u64)]) {
+        let mut state = self.0.lock().unwrap();
+        let resume_free = (state.min_free as f64 * RESUME_MARGIN) as u64;
+        if !state.low {
+            let full: Vec<_> = free
+                .iter()
+                .filter(|(_, free)| *free < state.min_free)
+                .collect();
+            if !full.is_empty() {
+                for (path, free) in full {
+                    error!(
+                        "Only {} MiB free on the volume of {}, pausing downloads",
+                        free / (1024 * 1024),
+                        path.display(),
+                    );
+                }
+                state.low = true;
+                state.pauses += 1;
+            }
+        } else if free.iter().all(|(_, free)| *free >= resume_free) {
+            info!("Disk space freed, resuming downloads");
+            state.low = false;
+        }
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.0.lock().unwrap().low
+    }
+
+    pub fn pauses(&self) -> u64 {
+        self.0.lock().unwrap().pauses
+    }
+}
+
+/// Get the free space (in bytes) of each path's volume. Paths which can't be checked are logged and
+/// left out, so that a transient error doesn't pause downloads.
+pub fn free_space(paths: &[PathBuf]) -> Vec<(PathBuf, u64)> {
+    paths
+        .iter()
+        .filter_map(|path| match fs2::available_space(path) {
+            Ok(free) => Some((path.clone(), free)),
+            Err(err) => {
+                warn!(
+                    "Could not get the free space of {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        })
+        .collect()
+}
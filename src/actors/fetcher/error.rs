@@ -5,6 +5,9 @@ pub enum FetchError {
     #[fail(display = "Bad status: {}", _0)]
     BadStatus(hyper::StatusCode),
 
+    #[fail(display = "Blocked by a CDN challenge on {}", _0)]
+    Blocked(String),
+
     #[fail(display = "Thread has no posts")]
     EmptyThread,
 
@@ -35,6 +38,9 @@ pub enum FetchError {
     #[fail(display = "Resource not modified")]
     NotModified,
 
+    #[fail(display = "Rate limited ({}), retrying after {} seconds", _0, _1)]
+    RateLimited(hyper::StatusCode, u64),
+
     #[fail(display = "Timer error: {}", _0)]
     TimerError(tokio::timer::Error),
 }
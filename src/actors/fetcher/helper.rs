@@ -1,33 +1,223 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex},
+};
+
 use actix::{dev::MessageResponse, prelude::*};
 use futures::sync::mpsc::Sender;
 
 use super::*;
-use crate::four_chan::Board;
+use crate::four_chan::{Board, ThreadNo};
 
 pub trait ToUri {
-    fn to_uri(&self) -> Uri;
+    /// The URI of the resource on the API host `host`.
+    fn to_uri(&self, host: &str) -> Uri;
 }
 
-/// A key for `Fetcher`'s last modified hashmap. `LastModifiedKey(board, Some(no))` represents a
-/// thread and `LastModifiedKey(board, None)` represents the `threads.json` of that board.
+/// A key for `Fetcher`'s fetch cache. `FetchCacheKey(board, Some(no))` represents a thread and
+/// `FetchCacheKey(board, None)` represents the `threads.json` of that board.
 #[derive(Debug, Eq, Hash, PartialEq)]
-pub struct LastModifiedKey(Board, Option<u64>);
+pub struct FetchCacheKey(Board, Option<ThreadNo>);
 
-impl From<&(Board, u64)> for LastModifiedKey {
-    fn from(msg: &(Board, u64)) -> Self {
-        LastModifiedKey(msg.0, Some(msg.1))
+impl From<&(Board, ThreadNo)> for FetchCacheKey {
+    fn from(msg: &(Board, ThreadNo)) -> Self {
+        FetchCacheKey(msg.0, Some(msg.1))
     }
 }
 
-impl From<&FetchThread> for LastModifiedKey {
+impl From<&FetchThread> for FetchCacheKey {
     fn from(msg: &FetchThread) -> Self {
-        LastModifiedKey(msg.0, Some(msg.1))
+        FetchCacheKey(msg.0, Some(msg.1))
     }
 }
 
-impl From<&FetchThreadList> for LastModifiedKey {
+impl From<&FetchThreadList> for FetchCacheKey {
     fn from(msg: &FetchThreadList) -> Self {
-        LastModifiedKey(msg.0, None)
+        FetchCacheKey(msg.0, None)
+    }
+}
+
+/// The validators of the last fetched version of a resource, which are sent with the next request
+/// so that the API can reply with `304 Not Modified` if nothing changed.
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub last_modified: DateTime<Utc>,
+    pub etag: Option<String>,
+}
+
+/// The threads which are queued or being fetched (including while waiting to be retried). It is
+/// shared between `Fetcher` and its thread pipeline, which starts a thread's fetch when it's
+/// dequeued and removes the thread once its result has been sent to `ThreadUpdater`.
+///
+/// A thread can be requested again while it's still in flight (e.g. by the board poller and then
+/// by the archive poller). Requests for a queued thread are merged into the queued request, so
+/// that the thread is only fetched once. Requests for a thread which is being fetched are merged
+/// and kept until the fetch finishes, unless the fetch already answers them.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<Mutex<HashMap<(Board, ThreadNo), InFlightThread>>>);
+
+struct InFlightThread {
+    request: ThreadRequest,
+    started: bool,
+    /// Requests which arrived after the fetch was started
+    pending: Option<ThreadRequest>,
+}
+
+#[derive(Clone, Copy)]
+struct ThreadRequest {
+    from_archive_json: bool,
+    json: ThreadJson,
+    priority: FetchPriority,
+}
+
+impl ThreadRequest {
+    /// Whether the result of a fetch for this request is also a result for `other`. A request from
+    /// archive.json never answers a live one, since a 404 of an archived thread is handled as the
+    /// thread expiring, and not as it being deleted.
+    fn covers(&self, other: &Self) -> bool {
+        (!self.from_archive_json || other.from_archive_json)
+            && json_rank(self.json) >= json_rank(other.json)
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            // Like in `covers`, a live request isn't absorbed by one from archive.json
+            from_archive_json: self.from_archive_json && other.from_archive_json,
+            json: if json_rank(other.json) > json_rank(self.json) {
+                other.json
+            } else {
+                self.json
+            },
+            priority: if priority_rank(other.priority) > priority_rank(self.priority) {
+                other.priority
+            } else {
+                self.priority
+            },
+        }
+    }
+}
+
+/// How much a fetch from a JSON endpoint returns. A fallback fetch returns everything that a full
+/// fetch would, but ignores the fetch cache.
+fn json_rank(json: ThreadJson) -> u8 {
+    match json {
+        ThreadJson::Tail => 0,
+        ThreadJson::Full => 1,
+        ThreadJson::Fallback => 2,
+    }
+}
+
+fn priority_rank(priority: FetchPriority) -> u8 {
+    match priority {
+        FetchPriority::Normal => 0,
+        FetchPriority::High => 1,
+        FetchPriority::Sticky => 2,
+    }
+}
+
+impl InFlight {
+    /// Queue a thread. Returns `true` if the request must be sent to the thread pipeline, which is
+    /// the case if the thread wasn't in flight, or if it was queued at a lower priority. (The copy
+    /// sent at the lower priority is skipped by `start`.) Otherwise, the request is merged.
+    pub fn insert(
+        &self,
+        board: Board,
+        no: ThreadNo,
+        from_archive_json: bool,
+        json: ThreadJson,
+        priority: FetchPriority,
+    ) -> bool {
+        let request = ThreadRequest {
+            from_archive_json,
+            json,
+            priority,
+        };
+        let mut threads = self.0.lock().unwrap();
+        let thread = match threads.entry((board, no)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(InFlightThread {
+                    request,
+                    started: false,
+                    pending: None,
+                });
+                return true;
+            }
+        };
+
+        if thread.started {
+            if !thread.request.covers(&request) {
+                thread.pending = Some(match thread.pending {
+                    Some(pending) => pending.merge(request),
+                    None => request,
+                });
+            }
+            false
+        } else {
+            let upgrade = priority_rank(priority) > priority_rank(thread.request.priority);
+            thread.request = thread.request.merge(request);
+            upgrade
+        }
+    }
+
+    /// Start fetching a queued thread. Returns the `from_archive_json` flag and the JSON endpoint
+    /// of its merged requests, or `None` if the thread isn't queued (because another copy of its
+    /// request already started it, or because it was removed).
+    pub fn start(&self, board: Board, no: ThreadNo) -> Option<(bool, ThreadJson)> {
+        let mut threads = self.0.lock().unwrap();
+        let thread = threads
+            .get_mut(&(board, no))
+            .filter(|thread| !thread.started)?;
+        thread.started = true;
+        Some((thread.request.from_archive_json, thread.request.json))
+    }
+
+    /// Remove a thread once it's finished (or if it couldn't be queued). If requests which the
+    /// fetch didn't answer arrived while it was running, they are returned to be sent again.
+    pub fn remove(&self, board: Board, no: ThreadNo) -> Option<FetchThreads> {
+        let thread = self.0.lock().unwrap().remove(&(board, no))?;
+        thread.pending.map(|pending| {
+            FetchThreads(
+                board,
+                vec![no],
+                pending.from_archive_json,
+                pending.json,
+                pending.priority,
+            )
+        })
+    }
+}
+
+/// A fetch cache entry as it is saved to disk: `(board, thread, Last-Modified timestamp, ETag)`.
+pub type SavedCacheEntry = (Board, Option<ThreadNo>, i64, Option<String>);
+
+pub fn save_cache_entry((key, entry): (&FetchCacheKey, &CacheEntry)) -> SavedCacheEntry {
+    (
+        key.0,
+        key.1,
+        entry.last_modified.timestamp(),
+        entry.etag.clone(),
+    )
+}
+
+pub fn restore_cache_entry(saved: SavedCacheEntry) -> (FetchCacheKey, CacheEntry) {
+    let (board, no, last_modified, etag) = saved;
+    (
+        FetchCacheKey(board, no),
+        CacheEntry {
+            last_modified: Utc.timestamp(last_modified, 0),
+            etag,
+        },
+    )
+}
+
+impl Default for CacheEntry {
+    /// The validators used for resources that we haven't fetched before.
+    fn default() -> Self {
+        Self {
+            last_modified: Utc.timestamp(1_065_062_160, 0),
+            etag: None,
+        }
     }
 }
 
@@ -57,6 +247,30 @@ where
     }
 }
 
+/// An Actix `MessageResponse` which either replies immediately with a cached item or queues a
+/// future in our `RateLimiter` to fetch it.
+pub enum CachedResponse<I, E> {
+    Cached(I),
+    Fetch(RateLimitedResponse<I, E>),
+}
+
+impl<A, M, I: 'static, E: 'static> MessageResponse<A, M> for CachedResponse<I, E>
+where
+    A: Actor,
+    M: Message<Result = Result<I, E>>,
+{
+    fn handle<R: ResponseChannel<M>>(self, ctx: &mut A::Context, tx: Option<R>) {
+        match self {
+            CachedResponse::Cached(item) => {
+                if let Some(tx) = tx {
+                    tx.send(Ok(item));
+                }
+            }
+            CachedResponse::Fetch(response) => MessageResponse::<A, M>::handle(response, ctx, tx),
+        }
+    }
+}
+
 /// A stream-to-future adapter which polls a "task" stream to completion.
 #[must_use = "futures do nothing unless polled"]
 pub struct Consume<S>
@@ -1,54 +1,135 @@
 use super::*;
 
-// The only way to update `last_modified` would be to use an ActorFuture. But, Fetcher sends its
+// The only way to update `fetch_cache` would be to use an ActorFuture. But, Fetcher sends its
 // futures to RateLimiters, which prevent ActorFutures from being used. So, Fetcher must send a
-// message to itself to update `last_modified`.
-pub struct UpdateLastModified(pub LastModifiedKey, pub DateTime<Utc>);
-impl Message for UpdateLastModified {
+// message to itself to update `fetch_cache`.
+pub struct UpdateFetchCache(pub FetchCacheKey, pub CacheEntry);
+impl Message for UpdateFetchCache {
     type Result = Result<(), FetchError>;
 }
 
-impl Handler<UpdateLastModified> for Fetcher {
+impl Handler<UpdateFetchCache> for Fetcher {
     type Result = Result<(), FetchError>;
 
-    fn handle(&mut self, msg: UpdateLastModified, _: &mut Self::Context) -> Self::Result {
-        if self
-            .last_modified
+    fn handle(&mut self, msg: UpdateFetchCache, _: &mut Self::Context) -> Self::Result {
+        let prev = self
+            .fetch_cache
             .get(&msg.0)
-            .map_or(true, |&dt| dt <= msg.1)
-        {
-            self.last_modified.insert(msg.0, msg.1);
+            .map(|entry| entry.last_modified);
+        if prev.map_or(true, |dt| dt <= msg.1.last_modified) {
+            self.fetch_cache.insert(msg.0, msg.1);
             Ok(())
         } else {
             error!(
                 "Ignoring older Last-Modified for {:?}: {} > {}",
-                msg.0, self.last_modified[&msg.0], msg.1
+                msg.0,
+                prev.unwrap(),
+                msg.1.last_modified
             );
             Err(FetchError::NotModified)
         }
     }
 }
 
+/// Seeds the fetch cache with the `Last-Modified` times of threads, so that threads which haven't
+/// changed since they were last inserted aren't downloaded again when Ena starts. Threads which
+/// already have a cache entry (e.g. one restored from saved state) are skipped.
 #[derive(Message)]
-pub struct FetchThreads(pub Board, pub Vec<u64>, pub bool);
+pub struct SeedFetchCache(pub Board, pub Vec<(ThreadNo, DateTime<Utc>)>);
+
+impl Handler<SeedFetchCache> for Fetcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: SeedFetchCache, _: &mut Self::Context) {
+        let SeedFetchCache(board, times) = msg;
+        let mut seeded = 0;
+        for (no, last_modified) in times {
+            self.fetch_cache
+                .entry(FetchCacheKey::from(&(board, no)))
+                .or_insert_with(|| {
+                    seeded += 1;
+                    CacheEntry {
+                        last_modified,
+                        etag: None,
+                    }
+                });
+        }
+        info!(
+            "/{}/: Seeded the fetch cache with {} thread{} from the database",
+            board,
+            seeded,
+            if seeded == 1 { "" } else { "s" },
+        );
+    }
+}
+
+#[derive(Message)]
+pub struct FetchThreads(
+    pub Board,
+    pub Vec<ThreadNo>,
+    pub bool,
+    pub ThreadJson,
+    pub FetchPriority,
+);
 
 impl Handler<FetchThreads> for Fetcher {
     type Result = ();
 
-    fn handle(&mut self, msg: FetchThreads, _: &mut Self::Context) {
+    fn handle(&mut self, mut msg: FetchThreads, _: &mut Self::Context) {
         let board = msg.0;
-        let last_modified = msg
+
+        // Requests for threads which are already in flight are merged into the queued requests
+        // (see `InFlight`), unless they have a higher priority
+        let in_flight = &self.in_flight;
+        let (from_archive_json, json, priority) = (msg.2, msg.3, msg.4);
+        let requested = msg.1.len();
+        msg.1
+            .retain(|&no| in_flight.insert(board, no, from_archive_json, json, priority));
+        let skipped = requested - msg.1.len();
+        if skipped > 0 {
+            debug!(
+                "/{}/: Skipping {} thread{} which {} already queued",
+                board,
+                skipped,
+                if skipped == 1 { "" } else { "s" },
+                if skipped == 1 { "is" } else { "are" },
+            );
+        }
+        if msg.1.is_empty() {
+            return;
+        }
+        self.thread_throttle.counters().queue(msg.1.len());
+
+        let cache_entries = msg
             .1
             .iter()
-            .map(|&no| self.get_last_modified(&(board, no)))
+            .map(|&no| {
+                if json == ThreadJson::Fallback {
+                    CacheEntry::default()
+                } else {
+                    self.get_cache_entry(&(board, no))
+                }
+            })
             .collect();
 
+        let sender = match priority {
+            FetchPriority::Sticky => &self.sticky_thread_sender,
+            FetchPriority::High => &self.high_priority_thread_sender,
+            FetchPriority::Normal => &self.thread_sender,
+        };
+        let nums = msg.1.clone();
+        let in_flight = self.in_flight.clone();
         Arbiter::spawn(
-            self.thread_sender
+            sender
                 .clone()
-                .send((msg, last_modified))
+                .send((msg, cache_entries))
                 .map(|_| ())
-                .map_err(|err| error!("{}", err)),
+                .map_err(move |err| {
+                    error!("{}", err);
+                    for no in nums {
+                        in_flight.remove(board, no);
+                    }
+                }),
         );
     }
 }
@@ -59,22 +140,22 @@ impl Message for FetchThreadList {
 }
 
 impl ToUri for &FetchThreadList {
-    fn to_uri(&self) -> Uri {
-        format!("{}/{}/threads.json", API_URI_PREFIX, self.0)
-            .parse()
-            .unwrap()
+    fn to_uri(&self, host: &str) -> Uri {
+        format!("{}/{}/threads.json", host, self.0).parse().unwrap()
     }
 }
 
 impl Handler<FetchThreadList> for Fetcher {
     type Result = RateLimitedResponse<(Vec<Thread>, DateTime<Utc>), FetchError>;
     fn handle(&mut self, msg: FetchThreadList, ctx: &mut Self::Context) -> Self::Result {
+        self.thread_list_throttle.counters().queue(1);
         RateLimitedResponse {
             sender: self.thread_list_sender.clone(),
             future: fetch_thread_list(
                 &msg,
-                self.get_last_modified(&msg),
+                self.get_cache_entry(&msg),
                 &self.client,
+                &self.thread_list_throttle,
                 ctx.address(),
             ),
         }
@@ -83,46 +164,154 @@ impl Handler<FetchThreadList> for Fetcher {
 
 pub struct FetchArchive(pub Board);
 impl Message for FetchArchive {
-    type Result = Result<Vec<u64>, FetchError>;
+    type Result = Result<Vec<ThreadNo>, FetchError>;
 }
 
 impl ToUri for FetchArchive {
-    fn to_uri(&self) -> Uri {
-        format!("{}/{}/archive.json", API_URI_PREFIX, self.0)
-            .parse()
-            .unwrap()
+    fn to_uri(&self, host: &str) -> Uri {
+        format!("{}/{}/archive.json", host, self.0).parse().unwrap()
     }
 }
 
 impl Handler<FetchArchive> for Fetcher {
-    type Result = RateLimitedResponse<Vec<u64>, FetchError>;
+    type Result = RateLimitedResponse<Vec<ThreadNo>, FetchError>;
     fn handle(&mut self, msg: FetchArchive, _: &mut Self::Context) -> Self::Result {
+        self.thread_list_throttle.counters().queue(1);
         RateLimitedResponse {
             sender: self.thread_list_sender.clone(),
-            future: fetch_archive(&msg, &self.client),
+            future: fetch_archive(&msg, &self.client, &self.thread_list_throttle),
         }
     }
 }
 
+pub struct FetchCatalog(pub Board);
+impl Message for FetchCatalog {
+    type Result = Result<Vec<CatalogThread>, FetchError>;
+}
+
+impl ToUri for FetchCatalog {
+    fn to_uri(&self, host: &str) -> Uri {
+        format!("{}/{}/catalog.json", host, self.0).parse().unwrap()
+    }
+}
+
+impl Handler<FetchCatalog> for Fetcher {
+    type Result = RateLimitedResponse<Vec<CatalogThread>, FetchError>;
+    fn handle(&mut self, msg: FetchCatalog, _: &mut Self::Context) -> Self::Result {
+        self.thread_list_throttle.counters().queue(1);
+        RateLimitedResponse {
+            sender: self.thread_list_sender.clone(),
+            future: fetch_catalog(&msg, &self.client, &self.thread_list_throttle),
+        }
+    }
+}
+
+/// Gets the settings of every board from `boards.json`. The result is cached for a day.
+pub struct GetBoardInfo;
+impl Message for GetBoardInfo {
+    type Result = Result<Arc<HashMap<Board, BoardInfo>>, FetchError>;
+}
+
+impl ToUri for GetBoardInfo {
+    fn to_uri(&self, host: &str) -> Uri {
+        format!("{}/boards.json", host).parse().unwrap()
+    }
+}
+
+impl Handler<GetBoardInfo> for Fetcher {
+    type Result = CachedResponse<Arc<HashMap<Board, BoardInfo>>, FetchError>;
+    fn handle(&mut self, msg: GetBoardInfo, ctx: &mut Self::Context) -> Self::Result {
+        if let Some((fetched, boards)) = &self.board_info {
+            if clock::now() - *fetched < BOARD_INFO_TTL {
+                return CachedResponse::Cached(boards.clone());
+            }
+        }
+        self.thread_list_throttle.counters().queue(1);
+        CachedResponse::Fetch(RateLimitedResponse {
+            sender: self.thread_list_sender.clone(),
+            future: fetch_board_info(
+                &msg,
+                &self.client,
+                &self.thread_list_throttle,
+                ctx.address(),
+            ),
+        })
+    }
+}
+
+/// Fetches the custom spoiler images of a board (`spoiler-{board}{n}.png`), as listed in
+/// `boards.json`. Spoilers which were already downloaded are skipped like any other media.
+#[derive(Message)]
+pub struct FetchSpoilers(pub Board);
+
+impl Handler<FetchSpoilers> for Fetcher {
+    type Result = ();
+    fn handle(&mut self, msg: FetchSpoilers, ctx: &mut Self::Context) {
+        let board = msg.0;
+        let fetcher = ctx.address();
+        Arbiter::spawn(
+            fetcher
+                .send(GetBoardInfo)
+                .from_err()
+                .and_then(|res| res)
+                .map(move |boards| {
+                    let count = boards
+                        .get(&board)
+                        .and_then(|info| info.custom_spoilers)
+                        .unwrap_or(0);
+                    let filenames: Vec<_> = (1..=count)
+                        .map(|n| format!("spoiler-{}{}.png", board, n))
+                        .collect();
+                    if !filenames.is_empty() {
+                        fetcher.do_send(FetchMedia(board, filenames));
+                    }
+                })
+                .map_err(move |err: FetchError| {
+                    error!("/{}/: Failed to fetch custom spoilers: {}", board, err)
+                }),
+        );
+    }
+}
+
+/// Stores a freshly fetched `boards.json` in `board_info`. See the note on `UpdateFetchCache`.
+#[derive(Message)]
+pub struct UpdateBoardInfo(pub Arc<HashMap<Board, BoardInfo>>);
+
+impl Handler<UpdateBoardInfo> for Fetcher {
+    type Result = ();
+    fn handle(&mut self, msg: UpdateBoardInfo, _: &mut Self::Context) {
+        self.board_info = Some((clock::now(), msg.0));
+    }
+}
+
 #[derive(Message)]
 pub struct FetchMedia(pub Board, pub Vec<String>);
 
 impl Handler<FetchMedia> for Fetcher {
     type Result = ();
     fn handle(&mut self, msg: FetchMedia, _: &mut Self::Context) {
-        // If a media future panics, the media runtime will crash and the sender will close. The
-        // Actix system has its own runtime, so it won't crash. But, we can't recover from a media
-        // runtime panic, so if the media runtime crashes we crash the Actix system as well.
-        if self.media_sender.is_closed() {
-            panic!("Media sender is closed");
-        }
+        let FetchMedia(board, filenames) = msg;
+        let (thumbs, media): (Vec<_>, Vec<_>) = filenames
+            .into_iter()
+            .partition(|filename| filename.ends_with("s.jpg"));
 
-        self.runtime.spawn(
-            self.media_sender
-                .clone()
-                .send(msg)
-                .map(|_| ())
-                .map_err(|err| error!("{}", err)),
+        let send = |sender: &Sender<FetchMedia>, throttle: &Throttle, filenames: Vec<String>| {
+            if !filenames.is_empty() {
+                throttle.counters().queue(filenames.len());
+                Arbiter::spawn(
+                    sender
+                        .clone()
+                        .send(FetchMedia(board, filenames))
+                        .map(|_| ())
+                        .map_err(|err| error!("{}", err)),
+                );
+            }
+        };
+        send(
+            &self.thumb_sender,
+            self.thumb_throttle.as_ref().unwrap_or(&self.media_throttle),
+            thumbs,
         );
+        send(&self.media_sender, &self.media_throttle, media);
     }
 }
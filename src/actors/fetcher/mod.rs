@@ -1,6 +1,17 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use actix::{dev::ResponseChannel, prelude::*};
+use actix::{
+    actors::signal::{ProcessSignals, Signal, SignalType, Subscribe},
+    dev::ResponseChannel,
+    prelude::*,
+};
 use chrono::prelude::*;
 use failure::{Error, ResultExt};
 use futures::{
@@ -9,59 +20,174 @@ use futures::{
     stream,
     sync::mpsc::{self, Sender},
 };
+use futures_cpupool::CpuPool;
 use hyper::{
     client::HttpConnector,
     header::{self, HeaderValue},
-    Body, Client, Request, StatusCode, Uri,
+    Body, Client, Method, Request, Response, StatusCode, Uri,
 };
 use hyper_tls::HttpsConnector;
-use tokio::runtime::Runtime;
+use md5::Md5;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tokio::clock;
 
-use super::thread_updater::{FetchedThread, ThreadUpdater};
-use crate::{config::Config, four_chan::*};
+use super::{
+    database::StoreMediaHash,
+    state,
+    supervisor::{PanicSupervisor, RestartPolicy},
+    thread_updater::{FetchedThread, ThreadUpdater},
+};
+use crate::{
+    config::{
+        Config, DiskSpaceConfig, HttpClientConfig, MediaFsync, RateLimitingSettings,
+        TempCleanupConfig,
+    },
+    four_chan::*,
+};
 
+mod body_pool;
+mod circuit_breaker;
+mod disk_guard;
 mod error;
 mod helper;
 mod messages;
+mod priority;
+mod proxy;
 mod rate_limiter;
+mod recorder;
+mod request_log;
 mod retry;
+mod round_robin;
+mod stats;
+mod temp_cleanup;
+mod tests;
+
+use {
+    body_pool::{PooledBuffer, BODY_BUFFERS},
+    circuit_breaker::CircuitBreaker,
+    disk_guard::DiskGuard,
+    helper::*,
+    proxy::{ProxyConnector, ProxySource},
+    rate_limiter::{GlobalLimiter, HostBlock, StreamExt, Throttle},
+    recorder::ApiClient,
+    request_log::RequestLog,
+    retry::Retry,
+    stats::ChannelCounters,
+};
+pub use {
+    error::FetchError,
+    messages::*,
+    stats::{ChannelStats, FetcherStats, GetFetcherStats},
+};
 
-pub use {error::FetchError, messages::*};
-use {helper::*, rate_limiter::StreamExt, retry::Retry};
+type HttpsClient = Client<HttpsConnector<ProxyConnector<HttpConnector>>>;
 
-type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+const STATE_NAME: &str = "fetch_cache";
+/// The version of the format of the saved state (see `state`)
+const STATE_VERSION: u32 = 1;
 
 const RFC_1123_FORMAT: &str = "%a, %d %b %Y %T GMT";
 
-const FETCHER_MAILBOX_CAPACITY: usize = 500;
-
 const MEDIA_CHANNEL_CAPACITY: usize = 1000;
+/// Media is written in chunks of this size, rather than one write per received chunk
+const MEDIA_WRITE_BUFFER: usize = 256 * 1024;
 const THREAD_CHANNEL_CAPACITY: usize = 500;
 const THREAD_LIST_CHANNEL_CAPACITY: usize = 200;
 
-/// An actor which fetches threads, thread lists, archives, and media from the 4chan API.
+/// The largest buffer that we allocate up front for a response body, so that a bad Content-Length
+/// can't make us allocate a huge buffer.
+const MAX_PREALLOCATED_BODY: usize = 32 * 1024 * 1024;
+
+/// How long we stop fetching from a host after the CDN serves us a challenge page, if
+/// `network.challenge_cooldown` isn't set.
+const DEFAULT_CHALLENGE_COOLDOWN: Duration = Duration::from_secs(900);
+
+/// The longest `Retry-After` delay that we honor, so that a bad header can't stall us indefinitely.
+const MAX_RETRY_AFTER: u64 = 600;
+
+/// How long we keep `boards.json` before fetching it again. Board settings rarely change.
+const BOARD_INFO_TTL: Duration = Duration::from_secs(86400);
+
+/// An actor which fetches threads, thread lists, archives, catalogs, and media from the 4chan API.
 ///
-/// Fetching the catalog or pages of a board or `boards.json` is not used and thus unsupported.
+/// Fetching the pages of a board is not used and thus unsupported.
 pub struct Fetcher {
-    client: Arc<HttpsClient>,
-    last_modified: HashMap<LastModifiedKey, DateTime<Utc>>,
+    client: Arc<ApiClient>,
+    fetch_cache: HashMap<FetchCacheKey, CacheEntry>,
+    board_info: Option<(Instant, Arc<HashMap<Board, BoardInfo>>)>,
+    in_flight: InFlight,
     media_sender: Sender<FetchMedia>,
-    thread_sender: Sender<(FetchThreads, Vec<DateTime<Utc>>)>,
+    thumb_sender: Sender<FetchMedia>,
+    thread_sender: Sender<(FetchThreads, Vec<CacheEntry>)>,
+    high_priority_thread_sender: Sender<(FetchThreads, Vec<CacheEntry>)>,
+    sticky_thread_sender: Sender<(FetchThreads, Vec<CacheEntry>)>,
     thread_list_sender: Sender<Box<dyn Future<Item = (), Error = ()>>>,
-    // Fetcher must use its own runtime for fetching media because tokio::fs functions can't use the
-    // current_thread runtime that Actix provides
-    runtime: Runtime,
+    media_throttle: Throttle,
+    /// Set if thumbnails have their own rate limits. Otherwise, they share `media_throttle`
+    thumb_throttle: Option<Throttle>,
+    thread_throttle: Throttle,
+    thread_list_throttle: Throttle,
+    breaker: Option<CircuitBreaker>,
+    api_host: HostBlock,
+    media_host: HostBlock,
+    state_path: Option<PathBuf>,
+    save_interval: Duration,
+    io_pool: CpuPool,
+    media_path: PathBuf,
+    boards: Vec<Board>,
+    temp_cleanup: Option<TempCleanupConfig>,
+    disk_guard: Option<DiskGuard>,
+    disk_check_interval: Duration,
+    /// The paths whose volumes are checked by `disk_guard`
+    disk_paths: Vec<PathBuf>,
 }
 
 impl Actor for Fetcher {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // Clean up old Last-Modified values so that we don't leak memory
+        // Clean up old cache entries so that we don't leak memory
         ctx.run_interval(Duration::from_secs(86400), |act, _ctx| {
             let yesterday = Utc::now() - chrono::Duration::days(1);
-            act.last_modified.retain(|_key, &mut dt| dt > yesterday);
+            act.fetch_cache
+                .retain(|_key, entry| entry.last_modified > yesterday);
         });
+
+        if self.state_path.is_some() {
+            ProcessSignals::from_registry().do_send(Subscribe(ctx.address().recipient()));
+            ctx.run_interval(self.save_interval, |act, _ctx| act.save_state());
+        }
+
+        if let Some(cleanup) = self.temp_cleanup {
+            self.clean_temp_files(ctx);
+            ctx.run_interval(cleanup.interval, |act, ctx| act.clean_temp_files(ctx));
+        }
+
+        if self.disk_guard.is_some() {
+            self.check_disk_space();
+            ctx.run_interval(self.disk_check_interval, |act, _ctx| act.check_disk_space());
+        }
+    }
+}
+
+impl Supervised for Fetcher {
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        // The request streams run outside of the actor's context, so they keep running. Only
+        // `boards.json` is dropped, in case it was what we panicked on.
+        self.board_info = None;
+    }
+}
+
+impl Handler<Signal> for Fetcher {
+    type Result = ();
+
+    // ThreadUpdater stops the system, so we only save here
+    fn handle(&mut self, msg: Signal, _: &mut Self::Context) {
+        match msg.0 {
+            SignalType::Int | SignalType::Term | SignalType::Quit => self.save_state(),
+            SignalType::Hup | SignalType::Child => {}
+        }
     }
 }
 
@@ -74,200 +200,675 @@ impl Fetcher {
     pub fn create(
         config: &Config,
         thread_updater: Addr<ThreadUpdater>,
+        database: Recipient<StoreMediaHash>,
     ) -> Result<Addr<Self>, Error> {
         let ctx = {
-            let (_, receiver) = actix::dev::channel::channel(FETCHER_MAILBOX_CAPACITY);
+            let (_, receiver) =
+                actix::dev::channel::channel(config.advanced.fetcher_mailbox_capacity);
             Context::with_receiver(receiver)
         };
-        let fetcher = Fetcher::try_new(config, thread_updater, ctx.address())?;
-        Ok(ctx.run(fetcher))
+        let addr = ctx.address();
+        let fetcher = Fetcher::try_new(config, thread_updater, database, addr.clone())?;
+        PanicSupervisor::run(
+            "fetcher",
+            RestartPolicy::new(&config.advanced),
+            ctx,
+            fetcher,
+        );
+        Ok(addr)
     }
 
     fn try_new(
         config: &Config,
         thread_updater: Addr<ThreadUpdater>,
+        database: Recipient<StoreMediaHash>,
         fetcher: Addr<Self>,
     ) -> Result<Self, Error> {
-        let mut runtime = Runtime::new().unwrap();
-        let https = HttpsConnector::new(1).context("Could not create HttpsConnector")?;
-        let client = Arc::new(Client::builder().build::<_, Body>(https));
-
-        let media_sender = {
-            let (sender, receiver) = mpsc::channel(MEDIA_CHANNEL_CAPACITY);
-            let client = client.clone();
-            let media_path = config.database_media.media_path.to_owned();
-
-            let (retry_sender, retry_receiver) = retry::retry_channel(MEDIA_CHANNEL_CAPACITY);
-            let retry_backoff = config.network.retry_backoff;
+        let (api_proxy, media_proxy) = proxy::proxy_sources(config.network.proxy.as_ref())?;
+        let request_log = RequestLog::new(config.network.request_log.as_ref())?;
+        let client = Arc::new(ApiClient::new(
+            https_client(config, api_proxy)?,
+            &config.network,
+            request_log.clone(),
+        )?);
+        let media_client = Arc::new(MediaClient {
+            client: https_client(config, media_proxy)?,
+            log: request_log,
+            image_host: config.network.hosts.image.clone(),
+            static_host: config.network.hosts.static_files.clone(),
+        });
+        // The circuit breaker only watches the API, so media fetching isn't paused by it
+        let breaker = config
+            .network
+            .circuit_breaker
+            .as_ref()
+            .map(CircuitBreaker::new);
+        // API and media requests go to different hosts, which the CDN can block separately
+        let challenge_cooldown = config
+            .network
+            .challenge_cooldown
+            .unwrap_or(DEFAULT_CHALLENGE_COOLDOWN);
+        let api_host = HostBlock::new(challenge_cooldown);
+        let media_host = HostBlock::new(challenge_cooldown);
+        let global = config
+            .network
+            .rate_limiting
+            .total_per_second
+            .map(GlobalLimiter::new);
+        let disk_guard = config
+            .database_media
+            .disk_space
+            .as_ref()
+            .map(DiskGuard::new);
+        let api_disk_guard = match &config.database_media.disk_space {
+            Some(disk_space) if disk_space.pause_scraping => disk_guard.clone(),
+            _ => None,
+        };
+        let media_throttle = Throttle::new(&media_host, None, global.clone(), disk_guard.clone());
+        let thread_throttle = Throttle::new(
+            &api_host,
+            breaker.clone(),
+            global.clone(),
+            api_disk_guard.clone(),
+        );
+        let thread_list_throttle =
+            Throttle::new(&api_host, breaker.clone(), global.clone(), api_disk_guard);
 
-            let future = receiver
-                .map(|FetchMedia(board, filenames)| {
-                    stream::iter_ok(filenames.into_iter().map(move |filename| (board, filename)))
-                })
-                .flatten()
-                .map(move |request| Retry::new(request, &retry_backoff))
-                .select(retry_receiver)
-                .map(move |retry| {
-                    fetch_media_retry(retry, &client, media_path.clone(), retry_sender.clone())
-                })
-                .rate_limit(&config.network.rate_limiting.media)
-                .consume();
-            runtime.spawn(future);
-            sender
+        // Actix's current_thread runtime can't run blocking file IO (which is why tokio::fs
+        // doesn't work on it), so media files are written on a separate thread pool
+        let io_pool = futures_cpupool::Builder::new()
+            .name_prefix("ena-media-io-")
+            .create();
+        let hash_sink = MediaHashSink::new(config, database);
+        let (thumb_sender, thumb_receiver) = mpsc::channel(MEDIA_CHANNEL_CAPACITY);
+        let (media_sender, media_receiver) = mpsc::channel(MEDIA_CHANNEL_CAPACITY);
+        let thumb_throttle = match &config.network.rate_limiting.thumbs {
+            Some(settings) => {
+                let throttle = Throttle::new(&media_host, None, global.clone(), disk_guard.clone());
+                spawn_media_pipeline(
+                    config,
+                    settings,
+                    &media_client,
+                    &throttle,
+                    &io_pool,
+                    hash_sink.as_ref(),
+                    thumb_receiver,
+                );
+                spawn_media_pipeline(
+                    config,
+                    &config.network.rate_limiting.media,
+                    &media_client,
+                    &media_throttle,
+                    &io_pool,
+                    hash_sink.as_ref(),
+                    media_receiver,
+                );
+                Some(throttle)
+            }
+            // Without their own rate limits, thumbnails share the media pipeline. Queued thumbnails
+            // are always fetched before full media, so that previews show up quickly.
+            None => {
+                spawn_media_pipeline(
+                    config,
+                    &config.network.rate_limiting.media,
+                    &media_client,
+                    &media_throttle,
+                    &io_pool,
+                    hash_sink.as_ref(),
+                    priority::priority_select(thumb_receiver, media_receiver),
+                );
+                None
+            }
         };
 
-        let thread_sender = {
+        let in_flight = InFlight::default();
+        let (thread_sender, high_priority_thread_sender, sticky_thread_sender) = {
             let (sender, receiver) = mpsc::channel(THREAD_CHANNEL_CAPACITY);
+            let (high_sender, high_receiver) = mpsc::channel(THREAD_CHANNEL_CAPACITY);
+            let (sticky_sender, sticky_receiver) = mpsc::channel(THREAD_CHANNEL_CAPACITY);
             let client = client.clone();
 
             let (retry_sender, retry_receiver) = retry::retry_channel(THREAD_CHANNEL_CAPACITY);
-            let retry_backoff = config.network.retry_backoff;
-
-            let future = receiver
-                .map(|(msg, last_modified): (FetchThreads, Vec<DateTime<Utc>>)| {
-                    let FetchThreads(board, nums, from_archive_json) = msg;
-                    stream::iter_ok(nums.into_iter().zip(last_modified.into_iter())).map(
-                        move |(no, last_modified)| {
-                            (FetchThread(board, no, from_archive_json), last_modified)
-                        },
-                    )
-                })
-                .flatten()
-                .map(move |request| Retry::new(request, &retry_backoff))
-                .select(retry_receiver)
-                .map(move |retry| {
-                    fetch_thread_retry(
-                        retry,
-                        &client,
-                        fetcher.clone(),
-                        thread_updater.clone(),
-                        retry_sender.clone(),
-                    )
-                })
-                .rate_limit(&config.network.rate_limiting.thread)
-                .consume();
+            let retry_backoff = config.network.thread_retry_backoff();
+            let boards = config.boards.clone();
+            let throttle = thread_throttle.clone();
+            let counters = thread_throttle.counters().clone();
+            let retry_counters = counters.clone();
+            let in_flight = in_flight.clone();
+            let dequeued_in_flight = in_flight.clone();
+
+            let to_requests = |(msg, cache_entries): (FetchThreads, Vec<CacheEntry>)| {
+                let FetchThreads(board, nums, from_archive_json, json, _) = msg;
+                stream::iter_ok(nums.into_iter().zip(cache_entries.into_iter())).map(
+                    move |(no, cache_entry)| {
+                        (FetchThread(board, no, from_archive_json, json), cache_entry)
+                    },
+                )
+            };
+
+            // Boards take turns, so that a busy board can't starve the others. Stickies are few,
+            // so they don't need to.
+            let by_board = |(FetchThread(board, ..), _): &(FetchThread, CacheEntry)| *board;
+            let future = priority::priority_select(
+                sticky_receiver.map(to_requests).flatten(),
+                priority::priority_select(
+                    round_robin::round_robin(
+                        high_receiver.map(to_requests).flatten(),
+                        THREAD_CHANNEL_CAPACITY,
+                        by_board,
+                    ),
+                    round_robin::round_robin(
+                        receiver.map(to_requests).flatten(),
+                        THREAD_CHANNEL_CAPACITY,
+                        by_board,
+                    ),
+                ),
+            )
+            .filter_map(move |(FetchThread(board, no, ..), cache_entry)| {
+                counters.dequeue();
+                // Use the merged request, and skip copies which were sent again at a higher
+                // priority and have already been started
+                let (from_archive_json, json) = dequeued_in_flight.start(board, no)?;
+                let cache_entry = if json == ThreadJson::Fallback {
+                    CacheEntry::default()
+                } else {
+                    cache_entry
+                };
+                let request = (FetchThread(board, no, from_archive_json, json), cache_entry);
+                Some(Retry::new(request, &retry_backoff))
+            })
+            .select(retry_receiver.inspect(move |_| retry_counters.dequeue_retry()))
+            .map(move |retry| {
+                let raw_json = boards[&(retry.as_data().0).0].store_raw_json;
+                fetch_thread_retry(
+                    retry,
+                    &client,
+                    &throttle,
+                    fetcher.clone(),
+                    thread_updater.clone(),
+                    retry_sender.clone(),
+                    in_flight.clone(),
+                    raw_json,
+                )
+            })
+            .rate_limit(&config.network.rate_limiting.thread, &thread_throttle)
+            .consume();
             Arbiter::spawn(future);
-            sender
+            (sender, high_sender, sticky_sender)
         };
 
         let thread_list_sender = {
             let (sender, receiver) = mpsc::channel(THREAD_LIST_CHANNEL_CAPACITY);
+            let counters = thread_list_throttle.counters().clone();
             Arbiter::spawn(
                 receiver
-                    .rate_limit(&config.network.rate_limiting.thread_list)
+                    .inspect(move |_| counters.dequeue())
+                    .rate_limit(
+                        &config.network.rate_limiting.thread_list,
+                        &thread_list_throttle,
+                    )
                     .consume(),
             );
             sender
         };
 
+        let mut fetch_cache = HashMap::new();
+        if let Some(state_path) = &config.state.path {
+            match state::load::<Vec<SavedCacheEntry>>(state_path, STATE_NAME, STATE_VERSION) {
+                Ok(Some(saved)) => {
+                    // Like the daily cleanup, drop entries which are more than a day old
+                    let yesterday = (Utc::now() - chrono::Duration::days(1)).timestamp();
+                    fetch_cache.extend(
+                        saved
+                            .into_iter()
+                            .filter(|(board, _, last_modified, _)| {
+                                config.boards.contains_key(board) && *last_modified > yesterday
+                            })
+                            .map(restore_cache_entry),
+                    );
+                    info!("Restored {} fetch cache entries", fetch_cache.len());
+                }
+                Ok(None) => {}
+                Err(err) => log_error!(err.as_fail()),
+            }
+        }
+
         Ok(Self {
             client,
-            last_modified: HashMap::new(),
+            fetch_cache,
+            board_info: None,
+            in_flight,
             media_sender,
+            thumb_sender,
             thread_sender,
+            high_priority_thread_sender,
+            sticky_thread_sender,
             thread_list_sender,
-            runtime,
+            media_throttle,
+            thumb_throttle,
+            thread_throttle,
+            thread_list_throttle,
+            breaker,
+            api_host,
+            media_host,
+            state_path: config.state.path.clone(),
+            save_interval: config.state.save_interval,
+            io_pool,
+            media_path: config.database_media.media_path.clone(),
+            boards: config.boards.keys().cloned().collect(),
+            temp_cleanup: config.database_media.temp_cleanup,
+            disk_guard,
+            disk_check_interval: config
+                .database_media
+                .disk_space
+                .as_ref()
+                .map_or_else(Duration::default, |disk_space| disk_space.interval),
+            disk_paths: config
+                .database_media
+                .disk_space
+                .as_ref()
+                .map_or_else(Vec::new, |disk_space| disk_paths(config, disk_space)),
         })
     }
 
-    fn get_last_modified<'a, K: 'a>(&self, key: &'a K) -> DateTime<Utc>
+    /// Check the free space of `disk_paths`, and pause or resume downloads.
+    fn check_disk_space(&self) {
+        let guard = match &self.disk_guard {
+            Some(guard) => guard.clone(),
+            None => return,
+        };
+        let paths = self.disk_paths.clone();
+        Arbiter::spawn(
+            self.io_pool
+                .spawn_fn(move || -> Result<_, ()> { Ok(disk_guard::free_space(&paths)) })
+                .map(move |free| guard.update(&free)),
+        );
+    }
+
+    /// Remove stale temporary media files, and fetch them again if `temp_cleanup.requeue` is set.
+    fn clean_temp_files(&self, ctx: &mut Context<Self>) {
+        let cleanup = match self.temp_cleanup {
+            Some(cleanup) => cleanup,
+            None => return,
+        };
+        let media_path = self.media_path.clone();
+        let boards = self.boards.clone();
+        let fetcher = ctx.address();
+        Arbiter::spawn(
+            self.io_pool
+                .spawn_fn(move || -> Result<_, ()> {
+                    Ok(temp_cleanup::remove_stale_files(
+                        &media_path,
+                        &boards,
+                        cleanup.max_age,
+                    ))
+                })
+                .map(move |removed| {
+                    if removed.is_empty() {
+                        return;
+                    }
+                    info!("Removed {} stale temporary media files", removed.len());
+                    if !cleanup.requeue {
+                        return;
+                    }
+                    let mut by_board: HashMap<Board, Vec<String>> = HashMap::new();
+                    for (board, filename) in removed {
+                        by_board.entry(board).or_default().push(filename);
+                    }
+                    for (board, filenames) in by_board {
+                        fetcher.do_send(FetchMedia(board, filenames));
+                    }
+                }),
+        );
+    }
+
+    fn save_state(&self) {
+        if let Some(state_path) = &self.state_path {
+            let saved: Vec<_> = self.fetch_cache.iter().map(save_cache_entry).collect();
+            match state::save(state_path, STATE_NAME, STATE_VERSION, &saved) {
+                Ok(()) => debug!("Saved {} fetch cache entries", saved.len()),
+                Err(err) => log_error!(err.as_fail()),
+            }
+        }
+    }
+
+    fn get_cache_entry<'a, K: 'a>(&self, key: &'a K) -> CacheEntry
     where
-        &'a K: Into<LastModifiedKey>,
+        &'a K: Into<FetchCacheKey>,
     {
-        self.last_modified
+        self.fetch_cache
             .get(&key.into())
             .cloned()
-            .unwrap_or_else(|| Utc.timestamp(1_065_062_160, 0))
+            .unwrap_or_default()
+    }
+}
+
+/// The client used for media requests.
+struct MediaClient {
+    client: HttpsClient,
+    log: RequestLog,
+    image_host: String,
+    static_host: String,
+}
+
+impl MediaClient {
+    /// Fetch a file which has been retried `retries` times before.
+    fn get(
+        &self,
+        uri: Uri,
+        retries: u32,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error>> {
+        self.log
+            .track(&Method::GET, &uri, retries, self.client.get(uri.clone()))
     }
 }
 
-fn fetch_with_last_modified<'a, R: 'a>(
+/// The paths whose free space is watched: the media directory, and MySQL's datadir if it is set.
+fn disk_paths(config: &Config, disk_space: &DiskSpaceConfig) -> Vec<PathBuf> {
+    let mut paths = vec![config.database_media.media_path.clone()];
+    paths.extend(disk_space.mysql_datadir.clone());
+    paths
+}
+
+/// Create an HTTPS client which connects through `proxy`.
+fn https_client(config: &Config, proxy: ProxySource) -> Result<HttpsClient, Error> {
+    let mut builder = client_builder(config.network.client.as_ref());
+    // Idle connections would keep using the same proxy, so make a new connection for every request
+    if proxy.is_pool() {
+        builder.keep_alive(false);
+    }
+    let mut http = HttpConnector::new(1);
+    http.enforce_http(false);
+    if let Some(client) = &config.network.client {
+        http.set_local_address(client.local_address);
+    }
+    let tls = native_tls::TlsConnector::new().context("Could not create TlsConnector")?;
+    let https = HttpsConnector::from((ProxyConnector::new(http, proxy), tls));
+    Ok(builder.build::<_, Body>(https))
+}
+
+/// Create a hyper client builder with the settings from `network.client` (if any).
+fn client_builder(config: Option<&HttpClientConfig>) -> hyper::client::Builder {
+    let mut builder = Client::builder();
+    if let Some(config) = config {
+        builder.http2_only(config.http2_only);
+        if config.keep_alive_timeout.is_some() {
+            builder.keep_alive_timeout(config.keep_alive_timeout);
+        }
+        if let Some(max_idle) = config.max_idle_per_host {
+            builder.max_idle_per_host(max_idle);
+        }
+    }
+    builder
+}
+
+/// Fetch a resource with a conditional request. The ETag of the last fetch is sent in
+/// If-None-Match (which the API prefers), and its Last-Modified time in If-Modified-Since.
+fn fetch_with_cache<'a, R: 'a>(
     request: &'a R,
-    last_modified: DateTime<Utc>,
-    client: &Arc<HttpsClient>,
+    cache_entry: CacheEntry,
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
     fetcher: Addr<Fetcher>,
-) -> impl Future<Item = (hyper::Chunk, DateTime<Utc>), Error = FetchError>
+    retries: u32,
+) -> impl Future<Item = (PooledBuffer, DateTime<Utc>), Error = FetchError>
 where
-    &'a R: ToUri + Into<LastModifiedKey>,
+    &'a R: ToUri + Into<FetchCacheKey>,
 {
-    let uri = request.to_uri();
+    let uri = request.to_uri(client.host());
     let key = request.into();
+    let throttle = throttle.clone();
+    let CacheEntry {
+        last_modified,
+        etag,
+    } = cache_entry;
 
     let mut request = Request::get(uri.clone()).body(Body::default()).unwrap();
     let headers = request.headers_mut();
-    headers.reserve(1);
+    headers.reserve(2);
     headers.insert(
         header::IF_MODIFIED_SINCE,
         HeaderValue::from_str(last_modified.format(RFC_1123_FORMAT).to_string().as_str()).unwrap(),
     );
+    if let Some(etag) = etag
+        .as_ref()
+        .and_then(|etag| HeaderValue::from_str(etag).ok())
+    {
+        headers.insert(header::IF_NONE_MATCH, etag);
+    }
 
+    let counters = throttle.counters().clone();
     client
-        .request(request)
+        .request(request, retries)
+        .then({
+            let throttle = throttle.clone();
+            move |res| {
+                throttle.record_response(&res);
+                res
+            }
+        })
         .from_err()
-        .and_then(move |res| match res.status() {
-            StatusCode::NOT_FOUND => Err(FetchError::NotFound(uri.to_string())),
-            StatusCode::NOT_MODIFIED => Err(FetchError::NotModified),
-            StatusCode::OK => {
-                let new_modified =
-                    res.headers()
-                        .get(header::LAST_MODIFIED)
-                        .map_or_else(Utc::now, |h| {
-                            h.to_str()
-                                .map(|h| Utc.datetime_from_str(h, RFC_1123_FORMAT))
-                                .unwrap_or_else(|err| {
-                                    error!("Could not parse Last-Modified header: {}", err);
-                                    Ok(Utc::now())
-                                })
-                                .unwrap_or_else(|err| {
-                                    error!("Could not parse Last-Modified header: {}", err);
-                                    Utc::now()
-                                })
-                        });
-
-                if last_modified > new_modified {
-                    warn!(
-                        "API sent old data: If-Modified-Since: {}, but Last-Modified: {}",
-                        last_modified.format(RFC_1123_FORMAT),
-                        new_modified.format(RFC_1123_FORMAT),
-                    );
-                    Err(FetchError::NotModified)
-                } else {
-                    Ok((res, new_modified))
+        .and_then(move |res| -> Result<_, FetchError> {
+            check_challenge(&res, &uri, &throttle)?;
+            check_retry_after(&res, &throttle)?;
+            match res.status() {
+                StatusCode::NOT_FOUND => Err(FetchError::NotFound(uri.to_string())),
+                StatusCode::NOT_MODIFIED => Err(FetchError::NotModified),
+                StatusCode::OK => {
+                    let new_modified =
+                        res.headers()
+                            .get(header::LAST_MODIFIED)
+                            .map_or_else(Utc::now, |h| {
+                                h.to_str()
+                                    .map(|h| Utc.datetime_from_str(h, RFC_1123_FORMAT))
+                                    .unwrap_or_else(|err| {
+                                        error!("Could not parse Last-Modified header: {}", err);
+                                        Ok(Utc::now())
+                                    })
+                                    .unwrap_or_else(|err| {
+                                        error!("Could not parse Last-Modified header: {}", err);
+                                        Utc::now()
+                                    })
+                            });
+
+                    let new_etag = res
+                        .headers()
+                        .get(header::ETAG)
+                        .and_then(|h| h.to_str().ok())
+                        .map(String::from);
+
+                    if last_modified > new_modified {
+                        warn!(
+                            "API sent old data: If-Modified-Since: {}, but Last-Modified: {}",
+                            last_modified.format(RFC_1123_FORMAT),
+                            new_modified.format(RFC_1123_FORMAT),
+                        );
+                        Err(FetchError::NotModified)
+                    } else {
+                        let cache_entry = CacheEntry {
+                            last_modified: new_modified,
+                            etag: new_etag,
+                        };
+                        Ok((res, cache_entry))
+                    }
                 }
+                _ => Err(res.status().into()),
             }
-            _ => Err(res.status().into()),
         })
-        .and_then(move |(res, last_modified)| {
+        .and_then(move |(res, cache_entry)| {
+            let last_modified = cache_entry.last_modified;
             fetcher
-                .send(UpdateLastModified(key, last_modified))
+                .send(UpdateFetchCache(key, cache_entry))
                 .from_err()
-                .and_then(|_| res.into_body().concat2().from_err())
+                .and_then(move |_| read_body(res, &counters).from_err())
                 .map(move |body| (body, last_modified))
         })
 }
 
+/// Read a response body into a buffer from `BODY_BUFFERS`, which is reused once the body has been
+/// deserialized and dropped. The buffer is reserved up front from the Content-Length header (if
+/// there is one), so that it doesn't have to be regrown and copied as chunks arrive (as `concat2`
+/// does).
+fn read_body(
+    res: Response<Body>,
+    counters: &ChannelCounters,
+) -> impl Future<Item = PooledBuffer, Error = hyper::Error> {
+    let counters = counters.clone();
+    let capacity = res
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<usize>().ok())
+        .map_or(0, |len| len.min(MAX_PREALLOCATED_BODY));
+    res.into_body()
+        .fold(BODY_BUFFERS.take(capacity), move |mut buf, chunk| {
+            counters.downloaded(chunk.len());
+            buf.extend_from_slice(&chunk);
+            Ok::<_, hyper::Error>(buf)
+        })
+}
+
+/// If the CDN (Cloudflare) served us a challenge page instead of a response, block the host of
+/// `throttle` for its cooldown and return an error.
+fn check_challenge(res: &Response<Body>, uri: &Uri, throttle: &Throttle) -> Result<(), FetchError> {
+    let status = res.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(());
+    }
+    let headers = res.headers();
+    let header_is = |name, value: &str| {
+        headers
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .map_or(false, |h| h.trim().eq_ignore_ascii_case(value))
+    };
+    let is_html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map_or(false, |h| h.starts_with("text/html"));
+    // `cf-mitigated: challenge` is only sent on challenges. Older challenges can only be told apart
+    // from API errors by being HTML pages served by Cloudflare.
+    if !header_is("cf-mitigated", "challenge") && !(header_is("server", "cloudflare") && is_html) {
+        return Ok(());
+    }
+
+    let host = uri.host().unwrap_or_default().to_owned();
+    let cooldown = throttle.block_host();
+    error!(
+        "{} responded with a {} challenge page, pausing its requests for {} seconds. \
+         Check that this IP isn't blocked",
+        host,
+        status,
+        cooldown.as_secs(),
+    );
+    Err(FetchError::Blocked(host))
+}
+
+/// If the API asked us to slow down (a 429 or 503 response with a `Retry-After` header), pause
+/// `throttle` for the given time and return an error.
+fn check_retry_after(res: &Response<Body>, throttle: &Throttle) -> Result<(), FetchError> {
+    let status = res.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(());
+    }
+    let retry_after = match res
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|h| h.to_str().ok())
+    {
+        Some(retry_after) => retry_after.trim(),
+        None => return Ok(()),
+    };
+
+    // Retry-After is either a number of seconds or an HTTP date
+    let secs = match retry_after.parse::<u64>() {
+        Ok(secs) => secs,
+        Err(_) => match Utc.datetime_from_str(retry_after, RFC_1123_FORMAT) {
+            Ok(date) => (date - Utc::now()).num_seconds().max(0) as u64,
+            Err(err) => {
+                error!("Could not parse Retry-After header: {}", err);
+                return Ok(());
+            }
+        },
+    }
+    .min(MAX_RETRY_AFTER);
+
+    warn!(
+        "API responded with {}, pausing for {} seconds",
+        status, secs
+    );
+    throttle.pause(Duration::from_secs(secs));
+    Err(FetchError::RateLimited(status, secs))
+}
+
 #[derive(Clone, Copy)]
-pub struct FetchThread(pub Board, pub u64, pub bool);
+pub struct FetchThread(pub Board, pub ThreadNo, pub bool, pub ThreadJson);
+
+/// The JSON endpoint to fetch a thread from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ThreadJson {
+    /// `thread/{no}.json`
+    Full,
+    /// `thread/{no}-tail.json`, which only has the OP and the last few replies of a thread
+    Tail,
+    /// `thread/{no}.json`, fetched regardless of the fetch cache because a tail fetch couldn't be
+    /// used (e.g. it didn't cover every new post)
+    Fallback,
+}
+
+/// The queue that a thread fetch waits in. Sticky fetches are always started before high priority
+/// ones, which are always started before normal ones (but retries are not prioritized).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FetchPriority {
+    /// Refetches of modified sticky threads (often announcements which are edited), which are
+    /// started before all other fetches
+    Sticky,
+    /// New threads, bumped-off threads, and threads from `archive.json`, which may 404 if they
+    /// wait too long
+    High,
+    /// Refetches of modified threads
+    Normal,
+}
 
 impl ToUri for &FetchThread {
-    fn to_uri(&self) -> Uri {
-        format!("{}/{}/thread/{}.json", API_URI_PREFIX, self.0, self.1)
-            .parse()
-            .unwrap()
+    fn to_uri(&self, host: &str) -> Uri {
+        format!(
+            "{}/{}/thread/{}{}.json",
+            host,
+            self.0,
+            self.1,
+            if self.3 == ThreadJson::Tail {
+                "-tail"
+            } else {
+                ""
+            },
+        )
+        .parse()
+        .unwrap()
     }
 }
 
 fn fetch_thread(
-    request: (FetchThread, DateTime<Utc>),
-    client: &Arc<HttpsClient>,
+    request: (FetchThread, CacheEntry),
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
     fetcher: Addr<Fetcher>,
+    raw_json: bool,
+    retries: u32,
 ) -> impl Future<Item = (Vec<Post>, DateTime<Utc>), Error = FetchError> {
-    fetch_with_last_modified(&request.0, request.1, client, fetcher).and_then(
+    fetch_with_cache(&request.0, request.1, client, throttle, fetcher, retries).and_then(
         move |(body, last_modified)| {
-            let PostsWrapper { posts } = serde_json::from_slice(&body)?;
+            let PostsWrapper { mut posts } = serde_json::from_slice(&body)?;
+            if raw_json {
+                let RawPostsWrapper { posts: raw_posts } = serde_json::from_slice(&body)?;
+                for (post, raw) in posts.iter_mut().zip(raw_posts) {
+                    post.raw_json = Some(raw.to_string());
+                }
+            }
+
             if posts.is_empty() {
                 Err(FetchError::EmptyThread)
-            } else if posts[0].reply_to != 0 || posts.iter().skip(1).any(|p| p.reply_to == 0) {
+            } else if !posts[0].is_op() || posts.iter().skip(1).any(Post::is_op) {
                 Err(FetchError::InvalidReplyTo)
             } else {
                 Ok((posts, last_modified))
@@ -276,14 +877,27 @@ fn fetch_thread(
     )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn fetch_thread_retry(
-    retry: Retry<(FetchThread, DateTime<Utc>)>,
-    client: &Arc<HttpsClient>,
+    retry: Retry<(FetchThread, CacheEntry)>,
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
     fetcher: Addr<Fetcher>,
     thread_updater: Addr<ThreadUpdater>,
-    retry_sender: Sender<Retry<(FetchThread, DateTime<Utc>)>>,
+    retry_sender: Sender<Retry<(FetchThread, CacheEntry)>>,
+    in_flight: InFlight,
+    raw_json: bool,
 ) -> impl Future<Item = (), Error = ()> {
-    fetch_thread(retry.to_data(), client, fetcher).then(move |result| {
+    let counters = throttle.counters().clone();
+    fetch_thread(
+        retry.to_data(),
+        client,
+        throttle,
+        fetcher.clone(),
+        raw_json,
+        retry.retries(),
+    )
+    .then(move |result| {
         use FetchError::*;
         if let Err(ref err) = result {
             let will_retry = retry.can_retry()
@@ -294,32 +908,41 @@ fn fetch_thread_retry(
                 };
 
             if will_retry {
-                let &(FetchThread(board, no, _), _) = retry.as_data();
+                let &(FetchThread(board, no, _, _), _) = retry.as_data();
                 error!("/{}/ No. {}: Failed to fetch, retrying: {}", board, no, err);
-                return Either::A(
-                    retry_sender
-                        .send(retry)
-                        .map(|_| ())
-                        .map_err(|err| error!("{}", err)),
-                );
+                counters.queue_retry();
+                return Either::A(retry_sender.send(retry).map(|_| ()).map_err(move |err| {
+                    error!("{}", err);
+                    // The thread won't be fetched, so it can be requested again
+                    in_flight.remove(board, no);
+                }));
             }
         }
-        let reply = FetchedThread {
-            request: retry.into_data().0,
-            result,
-        };
-        Either::B(thread_updater.send(reply).map_err(|err| log_error!(&err)))
+        let request = retry.into_data().0;
+        let requeued = in_flight.remove(request.0, request.1);
+        let reply = FetchedThread { request, result };
+        Either::B(
+            thread_updater
+                .send(reply)
+                .map(move |()| {
+                    if let Some(msg) = requeued {
+                        fetcher.do_send(msg);
+                    }
+                })
+                .map_err(|err| log_error!(&err)),
+        )
     })
 }
 
 fn fetch_thread_list(
     msg: &FetchThreadList,
-    last_modified: DateTime<Utc>,
-    client: &Arc<HttpsClient>,
+    cache_entry: CacheEntry,
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
     fetcher: Addr<Fetcher>,
 ) -> Box<dyn Future<Item = (Vec<Thread>, DateTime<Utc>), Error = FetchError>> {
     Box::new(
-        fetch_with_last_modified(msg, last_modified, client, fetcher)
+        fetch_with_cache(msg, cache_entry, client, throttle, fetcher, 0)
             .from_err()
             .and_then(move |(body, last_modified)| {
                 let threads: Vec<ThreadPage> = serde_json::from_slice(&body)?;
@@ -335,99 +958,324 @@ fn fetch_thread_list(
     )
 }
 
+/// Fetch and deserialize a JSON resource which isn't in the fetch cache.
+fn fetch_json<T: DeserializeOwned + 'static>(
+    uri: Uri,
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
+) -> impl Future<Item = T, Error = FetchError> {
+    let throttle = throttle.clone();
+    let counters = throttle.counters().clone();
+    client
+        .get(uri.clone())
+        .then({
+            let throttle = throttle.clone();
+            move |res| {
+                throttle.record_response(&res);
+                res
+            }
+        })
+        .from_err()
+        .and_then(move |res| -> Result<_, FetchError> {
+            check_challenge(&res, &uri, &throttle)?;
+            check_retry_after(&res, &throttle)?;
+            match res.status() {
+                StatusCode::OK => Ok(res),
+                _ => Err(res.status().into()),
+            }
+        })
+        .and_then(move |res| read_body(res, &counters).from_err())
+        .and_then(|body| Ok(serde_json::from_slice(&body)?))
+}
+
 fn fetch_archive(
     msg: &FetchArchive,
-    client: &Arc<HttpsClient>,
-) -> Box<dyn Future<Item = Vec<u64>, Error = FetchError>> {
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
+) -> Box<dyn Future<Item = Vec<ThreadNo>, Error = FetchError>> {
     assert!(msg.0.is_archived());
+    Box::new(fetch_json(msg.to_uri(client.host()), client, throttle))
+}
+
+fn fetch_catalog(
+    msg: &FetchCatalog,
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
+) -> Box<dyn Future<Item = Vec<CatalogThread>, Error = FetchError>> {
+    let uri = msg.to_uri(client.host());
     Box::new(
-        client
-            .get(msg.to_uri())
-            .from_err()
-            .and_then(move |res| match res.status() {
-                StatusCode::OK => Ok(res),
-                _ => Err(res.status().into()),
+        fetch_json(uri, client, throttle).map(|pages: Vec<CatalogPage>| {
+            pages.into_iter().fold(vec![], |mut acc, mut page| {
+                acc.append(&mut page.threads);
+                acc
             })
-            .and_then(|res| res.into_body().concat2().from_err())
-            .and_then(move |body| {
-                let archive: Vec<u64> = serde_json::from_slice(&body)?;
-                Ok(archive)
-            }),
+        }),
+    )
+}
+
+fn fetch_board_info(
+    msg: &GetBoardInfo,
+    client: &Arc<ApiClient>,
+    throttle: &Throttle,
+    fetcher: Addr<Fetcher>,
+) -> Box<dyn Future<Item = Arc<HashMap<Board, BoardInfo>>, Error = FetchError>> {
+    let uri = msg.to_uri(client.host());
+    Box::new(
+        fetch_json(uri, client, throttle).map(move |BoardsWrapper { boards }| {
+            let boards: Arc<HashMap<_, _>> =
+                Arc::new(boards.into_iter().map(|info| (info.board, info)).collect());
+            fetcher.do_send(UpdateBoardInfo(boards.clone()));
+            boards
+        }),
     )
 }
 
+/// Where the hashes of media downloaded on boards with `store_media_hashes` are sent.
+#[derive(Clone)]
+struct MediaHashSink {
+    boards: Arc<HashSet<Board>>,
+    database: Recipient<StoreMediaHash>,
+}
+
+impl MediaHashSink {
+    fn new(config: &Config, database: Recipient<StoreMediaHash>) -> Option<Self> {
+        let boards: HashSet<Board> = config
+            .boards
+            .iter()
+            .filter(|(_, board_config)| board_config.store_media_hashes)
+            .map(|(&board, _)| board)
+            .collect();
+        if boards.is_empty() {
+            None
+        } else {
+            Some(Self {
+                boards: Arc::new(boards),
+                database,
+            })
+        }
+    }
+}
+
+/// The hashes of a file, computed as it's downloaded.
+#[derive(Default)]
+struct FileHashes {
+    md5: Md5,
+    sha256: Sha256,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn fetch_media(
     (board, filename): (Board, String),
-    client: &Arc<HttpsClient>,
+    client: &Arc<MediaClient>,
+    throttle: &Throttle,
+    io_pool: &CpuPool,
     media_path: PathBuf,
+    fsync: MediaFsync,
+    hash_sink: Option<MediaHashSink>,
+    retries: u32,
 ) -> impl Future<Item = (), Error = FetchError> {
     let is_thumb = filename.ends_with("s.jpg");
+    // Custom spoiler images are shared by a board's posts, so they aren't sorted by time
+    let is_spoiler = filename.starts_with("spoiler-");
+    // Only full media has an MD5 in the API, so only its hashes are stored
+    let hash_sink =
+        hash_sink.filter(|sink| !is_thumb && !is_spoiler && sink.boards.contains(&board));
 
-    let mut temp_path = media_path.clone();
-    temp_path.push(board.to_string());
-    temp_path.push("tmp");
-    let temp_dir_future = tokio::fs::create_dir_all(temp_path.clone());
+    let mut temp_dir = media_path.clone();
+    temp_dir.push(board.to_string());
+    temp_dir.push("tmp");
+    let mut temp_path = temp_dir.clone();
     temp_path.push(&filename);
-    let temp_file_future = tokio::fs::File::create(temp_path.clone());
-
-    let mut real_path = media_path;
-    real_path.push(board.to_string());
-    real_path.push(if is_thumb { "thumb" } else { "image" });
-    real_path.push(&filename[0..4]);
-    real_path.push(&filename[4..6]);
-    let real_dir_future = tokio::fs::create_dir_all(real_path.clone());
+
+    let mut real_dir = media_path;
+    real_dir.push(board.to_string());
+    if is_spoiler {
+        real_dir.push("spoiler");
+    } else {
+        real_dir.push(if is_thumb { "thumb" } else { "image" });
+        real_dir.push(&filename[0..4]);
+        real_dir.push(&filename[4..6]);
+    }
+    let mut real_path = real_dir.clone();
     real_path.push(&filename);
 
     if real_path.exists() {
         return Either::A(future::err(FetchError::ExistingMedia));
     }
 
-    let uri: Uri = match format!("{}/{}/{}", IMG_URI_PREFIX, board, filename).parse() {
+    let uri = if is_spoiler {
+        format!("{}/image/{}", client.static_host, filename)
+    } else {
+        format!("{}/{}/{}", client.image_host, board, filename)
+    };
+    let uri: Uri = match uri.parse() {
         Ok(uri) => uri,
         Err(err) => return Either::A(future::err(err.into())),
     };
 
+    let file_future = io_pool.spawn_fn({
+        let temp_path = temp_path.clone();
+        let real_dir = real_dir.clone();
+        move || {
+            fs::create_dir_all(&temp_dir)?;
+            fs::create_dir_all(&real_dir)?;
+            File::create(&temp_path).map(|file| BufWriter::with_capacity(MEDIA_WRITE_BUFFER, file))
+        }
+    });
+
+    let throttle = throttle.clone();
+    let counters = throttle.counters().clone();
+    let io_pool = io_pool.clone();
     let future = client
-        .get(uri.clone())
+        .get(uri.clone(), retries)
         .from_err()
-        .join3(
-            temp_dir_future.and_then(|_| temp_file_future).from_err(),
-            real_dir_future.from_err(),
-        )
-        .and_then(move |(res, file, _)| match res.status() {
-            StatusCode::OK => Ok((res, file)),
-            StatusCode::NOT_FOUND => Err(FetchError::NotFound(uri.to_string())),
-            _ => Err(res.status().into()),
+        .join(file_future.from_err())
+        .and_then(move |(res, file)| -> Result<_, FetchError> {
+            check_challenge(&res, &uri, &throttle)?;
+            check_retry_after(&res, &throttle)?;
+            match res.status() {
+                StatusCode::OK => Ok((res, file)),
+                StatusCode::NOT_FOUND => Err(FetchError::NotFound(uri.to_string())),
+                _ => Err(res.status().into()),
+            }
         })
-        .and_then(|(res, file)| {
-            res.into_body().from_err().fold(file, |file, chunk| {
-                tokio::io::write_all(file, chunk)
-                    .from_err::<FetchError>()
-                    .map(|(file, _)| file)
-            })
+        .and_then({
+            let io_pool = io_pool.clone();
+            let hashes = hash_sink.as_ref().map(|_| FileHashes::default());
+            move |(res, file)| {
+                res.into_body().from_err().fold(
+                    (file, hashes),
+                    move |(mut file, mut hashes), chunk| {
+                        counters.downloaded(chunk.len());
+                        io_pool
+                            .spawn_fn(move || {
+                                if let Some(hashes) = &mut hashes {
+                                    hashes.md5.input(&chunk);
+                                    hashes.sha256.input(&chunk);
+                                }
+                                file.write_all(&chunk).map(|_| (file, hashes))
+                            })
+                            .from_err::<FetchError>()
+                    },
+                )
+            }
         })
         .and_then({
             let filename = filename.clone();
-            move |_| {
+            move |(file, hashes)| {
                 debug!(
                     "/{}/: Fetched {}{}",
                     board,
                     if is_thumb { "" } else { " " },
                     filename
                 );
-                tokio::fs::rename(temp_path, real_path).from_err()
+                io_pool
+                    .spawn_fn(move || -> io::Result<()> {
+                        let file = file.into_inner()?;
+                        if fsync != MediaFsync::None {
+                            file.sync_all()?;
+                        }
+                        fs::rename(temp_path, real_path)?;
+                        if fsync == MediaFsync::FileAndDir {
+                            File::open(real_dir)?.sync_all()?;
+                        }
+                        Ok(())
+                    })
+                    .from_err()
+                    .map(move |()| {
+                        if let (Some(sink), Some(hashes)) = (hash_sink, hashes) {
+                            let md5 = base64::encode(&hashes.md5.result());
+                            let sha256 = format!("{:x}", hashes.sha256.result());
+                            if let Err(err) =
+                                sink.database.do_send(StoreMediaHash(board, md5, sha256))
+                            {
+                                error!(
+                                    "/{}/: Failed to store hash of {}: {}",
+                                    board, filename, err
+                                );
+                            }
+                        }
+                    })
             }
         });
     Either::B(future)
 }
 
+/// Fetch the media requested by `requests` with the given rate limits.
+fn spawn_media_pipeline<S>(
+    config: &Config,
+    settings: &RateLimitingSettings,
+    client: &Arc<MediaClient>,
+    throttle: &Throttle,
+    io_pool: &CpuPool,
+    hash_sink: Option<&MediaHashSink>,
+    requests: S,
+) where
+    S: Stream<Item = FetchMedia, Error = ()> + 'static,
+{
+    let client = client.clone();
+    let media_path = config.database_media.media_path.to_owned();
+    let fsync = config.database_media.media_fsync;
+    let io_pool = io_pool.clone();
+    let hash_sink = hash_sink.cloned();
+    let counters = throttle.counters().clone();
+    let retry_counters = counters.clone();
+
+    let (retry_sender, retry_receiver) = retry::retry_channel(MEDIA_CHANNEL_CAPACITY);
+    let retry_backoff = config.network.media_retry_backoff();
+
+    let request_throttle = throttle.clone();
+    let to_requests = |FetchMedia(board, filenames): FetchMedia| {
+        stream::iter_ok(filenames.into_iter().map(move |filename| (board, filename)))
+    };
+    let future = requests
+        .map(to_requests)
+        .flatten()
+        .map(move |request| {
+            counters.dequeue();
+            Retry::new(request, &retry_backoff)
+        })
+        .select(retry_receiver.inspect(move |_| retry_counters.dequeue_retry()))
+        .map(move |retry| {
+            fetch_media_retry(
+                retry,
+                &client,
+                &request_throttle,
+                &io_pool,
+                media_path.clone(),
+                fsync,
+                hash_sink.clone(),
+                retry_sender.clone(),
+            )
+        })
+        .rate_limit(settings, throttle)
+        .consume();
+    Arbiter::spawn(future);
+}
+
+#[allow(clippy::too_many_arguments)]
 fn fetch_media_retry(
     retry: Retry<(Board, String)>,
-    client: &Arc<HttpsClient>,
+    client: &Arc<MediaClient>,
+    throttle: &Throttle,
+    io_pool: &CpuPool,
     media_path: PathBuf,
+    fsync: MediaFsync,
+    hash_sink: Option<MediaHashSink>,
     retry_sender: Sender<Retry<(Board, String)>>,
 ) -> impl Future<Item = (), Error = ()> {
-    fetch_media(retry.to_data(), client, media_path).or_else(move |err| {
+    let counters = throttle.counters().clone();
+    fetch_media(
+        retry.to_data(),
+        client,
+        throttle,
+        io_pool,
+        media_path,
+        fsync,
+        hash_sink,
+        retry.retries(),
+    )
+    .or_else(move |err| {
         use FetchError::*;
         let will_retry = retry.can_retry()
             && match err {
@@ -446,6 +1294,7 @@ fn fetch_media_retry(
         );
 
         if will_retry {
+            counters.queue_retry();
             Either::A(
                 retry_sender
                     .send(retry)
@@ -0,0 +1,58 @@
+use futures::prelude::*;
+
+/// A stream which merges two streams, always taking an item from `high` if one is ready before
+/// taking one from `low`. Unlike `Stream::select`, this doesn't alternate between the streams, so a
+/// backlog in `high` will starve `low`. The stream ends when both streams have ended.
+#[must_use = "streams do nothing unless polled"]
+pub struct PrioritySelect<H, L> {
+    high: H,
+    low: L,
+    high_done: bool,
+    low_done: bool,
+}
+
+impl<H, L> Stream for PrioritySelect<H, L>
+where
+    H: Stream,
+    L: Stream<Item = H::Item, Error = H::Error>,
+{
+    type Item = H::Item;
+    type Error = H::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.high_done {
+            match self.high.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.high_done = true,
+                Async::NotReady => {}
+            }
+        }
+
+        if !self.low_done {
+            match self.low.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.low_done = true,
+                Async::NotReady => {}
+            }
+        }
+
+        if self.high_done && self.low_done {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+pub fn priority_select<H, L>(high: H, low: L) -> PrioritySelect<H, L>
+where
+    H: Stream,
+    L: Stream<Item = H::Item, Error = H::Error>,
+{
+    PrioritySelect {
+        high,
+        low,
+        high_done: false,
+        low_done: false,
+    }
+}
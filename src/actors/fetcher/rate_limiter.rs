@@ -1,5 +1,6 @@
 use std::{
     fmt,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
@@ -8,13 +9,218 @@ use futures::{
     stream::{Fuse, FuturesUnordered},
     try_ready,
 };
-use tokio::timer::Delay;
+use hyper::{Body, Response};
+use tokio::{clock, timer::Delay};
 
+use super::{circuit_breaker::CircuitBreaker, disk_guard::DiskGuard, stats::ChannelCounters};
 use crate::config::RateLimitingSettings;
 
+/// A handle for pausing a `RateLimiter`, for when the API asks us to slow down (with a
+/// `Retry-After` header), when the CDN blocks its host, when its circuit breaker (if any) trips, or
+/// when its disk guard (if any) finds a disk nearly full. It can be shared across threads.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    state: Arc<Mutex<ThrottleState>>,
+    host: HostBlock,
+    breaker: Option<CircuitBreaker>,
+    global: Option<GlobalLimiter>,
+    disk: Option<DiskGuard>,
+    counters: ChannelCounters,
+}
+
+#[derive(Debug, Default)]
+struct ThrottleState {
+    until: Option<Instant>,
+    /// The number of times we've been asked to pause
+    events: u64,
+}
+
+/// A handle for pausing every `RateLimiter` which fetches from a host, for when the CDN serves us
+/// challenge pages instead of responses. Solving the challenge needs a human, so we back off for a
+/// long time (`cooldown`) instead of retrying.
+#[derive(Clone, Debug)]
+pub struct HostBlock(Arc<Mutex<HostBlockState>>);
+
+#[derive(Debug)]
+struct HostBlockState {
+    until: Option<Instant>,
+    cooldown: Duration,
+    /// The number of times the host has been blocked
+    events: u64,
+}
+
+impl HostBlock {
+    pub fn new(cooldown: Duration) -> Self {
+        HostBlock(Arc::new(Mutex::new(HostBlockState {
+            until: None,
+            cooldown,
+            events: 0,
+        })))
+    }
+
+    pub fn events(&self) -> u64 {
+        self.0.lock().unwrap().events
+    }
+}
+
+/// A limit on the total request rate of every `RateLimiter` which shares it, on top of their own
+/// limits. It can be shared across threads.
+#[derive(Clone, Debug)]
+pub struct GlobalLimiter(Arc<Mutex<TokenBucket>>);
+
+impl GlobalLimiter {
+    /// Allow `per_sec` requests per second, with bursts of up to one second's worth of requests.
+    pub fn new(per_sec: f64) -> Self {
+        GlobalLimiter(Arc::new(Mutex::new(TokenBucket::new(
+            per_sec.max(1.0),
+            per_sec,
+        ))))
+    }
+
+    /// When the next request can be made, or `None` if one can be made now.
+    pub(super) fn paused_until(&self) -> Option<Instant> {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill();
+        if bucket.tokens >= 1.0 {
+            None
+        } else {
+            Some(clock::now() + bucket.next_token())
+        }
+    }
+
+    pub(super) fn take(&self) {
+        self.0.lock().unwrap().tokens -= 1.0;
+    }
+}
+
+impl Throttle {
+    pub fn new(
+        host: &HostBlock,
+        breaker: Option<CircuitBreaker>,
+        global: Option<GlobalLimiter>,
+        disk: Option<DiskGuard>,
+    ) -> Self {
+        Self {
+            state: Default::default(),
+            host: host.clone(),
+            breaker,
+            global,
+            disk,
+            counters: Default::default(),
+        }
+    }
+
+    pub fn counters(&self) -> &ChannelCounters {
+        &self.counters
+    }
+
+    /// Stop starting new futures for `duration`. If already paused, the later end time is kept.
+    pub fn pause(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let until = clock::now() + duration;
+        state.until = Some(state.until.map_or(until, |prev| prev.max(until)));
+        state.events += 1;
+    }
+
+    /// Stop starting new futures on every channel of this throttle's host for the host's cooldown.
+    /// Returns the cooldown.
+    pub fn block_host(&self) -> Duration {
+        let mut state = self.host.0.lock().unwrap();
+        let until = clock::now() + state.cooldown;
+        state.until = Some(state.until.map_or(until, |prev| prev.max(until)));
+        state.events += 1;
+        state.cooldown
+    }
+
+    fn paused_until(&self) -> Option<Instant> {
+        let until = self.state.lock().unwrap().until;
+        let host_until = self.host.0.lock().unwrap().until;
+        let breaker_until = self.breaker.as_ref().and_then(CircuitBreaker::paused_until);
+        let global_until = self.global.as_ref().and_then(GlobalLimiter::paused_until);
+        let disk_until = self.disk.as_ref().and_then(DiskGuard::paused_until);
+        until
+            .into_iter()
+            .chain(host_until)
+            .chain(breaker_until)
+            .chain(global_until)
+            .chain(disk_until)
+            .max()
+    }
+
+    /// Returns `true` if no more futures should be started (because this one is a circuit breaker
+    /// probe).
+    fn start_future(&self) -> bool {
+        if let Some(global) = &self.global {
+            global.take();
+        }
+        self.breaker
+            .as_ref()
+            .map_or(false, CircuitBreaker::start_request)
+    }
+
+    /// Record the result of an API request in the circuit breaker (if any).
+    pub fn record_response(&self, res: &Result<Response<Body>, hyper::Error>) {
+        if let Some(breaker) = &self.breaker {
+            breaker.record(match res {
+                Ok(res) => !res.status().is_server_error(),
+                Err(_) => false,
+            });
+        }
+    }
+
+    pub fn events(&self) -> u64 {
+        self.state.lock().unwrap().events
+    }
+}
+
+/// A token bucket which holds up to `capacity` tokens and refills continuously at `per_sec` tokens
+/// per second. Starting a future takes one token.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, per_sec: f64) -> Self {
+        Self {
+            tokens: capacity,
+            capacity,
+            per_sec,
+            last_refill: clock::now(),
+        }
+    }
+
+    /// A bucket which refills at `max_interval` tokens per `interval`.
+    fn from_settings(settings: &RateLimitingSettings, capacity: usize) -> Self {
+        let interval = settings.interval;
+        let secs = interval.as_secs() as f64 + f64::from(interval.subsec_nanos()) / 1e9;
+        Self::new(capacity as f64, settings.max_interval as f64 / secs)
+    }
+
+    fn refill(&mut self) {
+        let now = clock::now();
+        let elapsed = now - self.last_refill;
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + secs * self.per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// How long until the next token is available.
+    fn next_token(&self) -> Duration {
+        let secs = (1.0 - self.tokens).max(0.0) / self.per_sec;
+        Duration::from_nanos((secs * 1e9).ceil() as u64)
+    }
+}
+
 /// An adapter for a stream of futures which limits the number of concurrently running futures and
 /// the number of futures that run in a given time interval. Results are returned in the order that
 /// the futures complete.
+///
+/// If `burst` is set in the settings, a token bucket is used instead of fixed intervals, so that
+/// requests are spread out evenly after an initial burst.
 #[must_use = "streams do nothing unless polled"]
 pub struct RateLimiter<S>
 where
@@ -25,6 +231,9 @@ where
     queue: FuturesUnordered<<S::Item as IntoFuture>::Future>,
     delay: Option<Delay>,
     interval: Duration,
+    throttle: Throttle,
+    /// Wakes us up when the throttle's pause ends
+    pause: Option<Delay>,
 
     /// The number of futures which have run in the current interval
     curr_interval: usize,
@@ -32,6 +241,9 @@ where
     /// The maximum number of futures which can run in a given interval
     max_interval: usize,
 
+    /// Used instead of `curr_interval` and `max_interval` in token bucket mode
+    bucket: Option<TokenBucket>,
+
     /// The maximum number of futures which can run at the same time
     max_concurrent: usize,
 }
@@ -41,17 +253,55 @@ where
     S: Stream,
     S::Item: IntoFuture<Error = <S as Stream>::Error>,
 {
-    pub fn new(s: S, settings: &RateLimitingSettings) -> Self {
+    pub fn new(s: S, settings: &RateLimitingSettings, throttle: &Throttle) -> Self {
         Self {
             stream: s.fuse(),
             queue: FuturesUnordered::new(),
             delay: None,
             interval: settings.interval,
+            throttle: throttle.clone(),
+            pause: None,
             curr_interval: 0,
             max_interval: settings.max_interval,
+            bucket: settings
+                .burst
+                .map(|capacity| TokenBucket::from_settings(settings, capacity)),
             max_concurrent: settings.max_concurrent,
         }
     }
+
+    /// Whether the throttle is paused (e.g. because the API has asked us to back off). If so, we are
+    /// woken up when the pause ends.
+    fn poll_paused(&mut self) -> bool {
+        match self.throttle.paused_until() {
+            Some(until) if until > clock::now() => {
+                if self
+                    .pause
+                    .as_ref()
+                    .map_or(true, |pause| pause.deadline() != until)
+                {
+                    self.pause = Some(Delay::new(until));
+                }
+                match self.pause.as_mut().unwrap().poll() {
+                    Ok(Async::Ready(())) => false,
+                    Ok(Async::NotReady) => true,
+                    Err(err) => panic!("Timer error: {}", err),
+                }
+            }
+            _ => {
+                self.pause = None;
+                false
+            }
+        }
+    }
+
+    /// Whether another future can be started in the current interval (or with the current tokens).
+    fn has_budget(&self) -> bool {
+        match &self.bucket {
+            Some(bucket) => bucket.tokens >= 1.0,
+            None => self.curr_interval < self.max_interval,
+        }
+    }
 }
 
 impl<S> fmt::Debug for RateLimiter<S>
@@ -66,8 +316,11 @@ where
             .field("queue", &self.queue)
             .field("delay", &self.delay)
             .field("interval", &self.interval)
+            .field("throttle", &self.throttle)
+            .field("pause", &self.pause)
             .field("curr_interval", &self.curr_interval)
             .field("max_interval", &self.max_interval)
+            .field("bucket", &self.bucket)
             .field("max_concurrent", &self.max_concurrent)
             .finish()
     }
@@ -82,7 +335,8 @@ where
     type Error = <S as Stream>::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        // Reset our interval count if the Delay has elapsed
+        // Reset our interval count if the Delay has elapsed. In token bucket mode, the Delay only
+        // wakes us up once a token is available.
         if let Some(res) = self.delay.as_mut().map(|delay| delay.poll()) {
             match res {
                 Ok(Async::Ready(())) => {
@@ -99,20 +353,46 @@ where
             }
         }
 
-        // Queue up as many futures as we can
-        while self.queue.len() < self.max_concurrent && self.curr_interval < self.max_interval {
+        if let Some(bucket) = &mut self.bucket {
+            bucket.refill();
+        }
+
+        // Queue up as many futures as we can. The throttle is checked on every iteration because
+        // starting a future may use up the global limiter's budget.
+        while self.queue.len() < self.max_concurrent && self.has_budget() && !self.poll_paused() {
             let future = match self.stream.poll()? {
                 Async::Ready(Some(s)) => s.into_future(),
                 Async::Ready(None) | Async::NotReady => break,
             };
 
-            self.curr_interval += 1;
+            match &mut self.bucket {
+                Some(bucket) => bucket.tokens -= 1.0,
+                None => self.curr_interval += 1,
+            }
             self.queue.push(future);
+            self.throttle.counters().start();
+            if self.throttle.start_future() {
+                break;
+            }
         }
 
         // Set up the next Delay if one currently isn't running
-        if self.delay.is_none() && self.curr_interval > 0 {
-            self.delay = Some(Delay::new(Instant::now() + self.interval));
+        match &self.bucket {
+            Some(bucket) => {
+                if self.delay.is_none() && bucket.tokens < 1.0 {
+                    let mut delay = Delay::new(clock::now() + bucket.next_token());
+                    // Poll it once so that we're woken up when the token is available
+                    if let Err(err) = delay.poll() {
+                        panic!("Timer error: {}", err);
+                    }
+                    self.delay = Some(delay);
+                }
+            }
+            None => {
+                if self.delay.is_none() && self.curr_interval > 0 {
+                    self.delay = Some(Delay::new(clock::now() + self.interval));
+                }
+            }
         }
 
         // Try polling a new future
@@ -131,18 +411,18 @@ where
 }
 
 pub trait StreamExt: Sized {
-    fn rate_limit(self, settings: &RateLimitingSettings) -> RateLimiter<Self>
+    fn rate_limit(self, settings: &RateLimitingSettings, throttle: &Throttle) -> RateLimiter<Self>
     where
         Self: Stream,
         <Self as Stream>::Item: IntoFuture<Error = <Self as Stream>::Error>;
 }
 
 impl<T: Sized> StreamExt for T {
-    fn rate_limit(self, settings: &RateLimitingSettings) -> RateLimiter<Self>
+    fn rate_limit(self, settings: &RateLimitingSettings, throttle: &Throttle) -> RateLimiter<Self>
     where
         Self: Stream,
         <Self as Stream>::Item: IntoFuture<Error = <Self as Stream>::Error>,
     {
-        RateLimiter::new(self, settings)
+        RateLimiter::new(self, settings, throttle)
     }
 }
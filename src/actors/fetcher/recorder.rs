@@ -0,0 +1,240 @@
+//! Recording and replaying API responses. Recorded board activity can be replayed
+//! deterministically, e.g. to reproduce a bug or to test changes to the thread update logic.
+//!
+//! Only API requests (threads, thread lists, archives, catalogs, and `boards.json`) are recorded.
+//! Media is always fetched from the network.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{request_log::RequestLog, *};
+use crate::config::NetworkConfig;
+
+/// The client used for API requests.
+pub struct ApiClient {
+    source: ApiSource,
+    log: RequestLog,
+    /// The URI prefix of API requests
+    host: String,
+}
+
+enum ApiSource {
+    Live(HttpsClient),
+    /// Make requests to the API and save every response in a directory
+    Record(HttpsClient, PathBuf),
+    /// Answer requests with the responses recorded for each URI, in the order they were recorded.
+    /// The last recording of a URI is kept and replayed for any further requests.
+    Replay(Mutex<HashMap<String, VecDeque<Recording>>>),
+}
+
+impl ApiClient {
+    pub fn new(
+        client: HttpsClient,
+        config: &NetworkConfig,
+        log: RequestLog,
+    ) -> Result<Self, Error> {
+        let source = match (&config.record_path, &config.replay_path) {
+            (Some(path), _) => {
+                fs::create_dir_all(path)
+                    .with_context(|_| format!("Could not create {}", path.display()))?;
+                info!("Recording API responses to {}", path.display());
+                ApiSource::Record(client, path.clone())
+            }
+            (None, Some(path)) => {
+                let recordings = Recording::load_all(path).with_context(|_| {
+                    format!("Could not load recordings from {}", path.display())
+                })?;
+                info!(
+                    "Replaying {} recorded API responses from {}",
+                    recordings.values().map(VecDeque::len).sum::<usize>(),
+                    path.display()
+                );
+                ApiSource::Replay(Mutex::new(recordings))
+            }
+            (None, None) => ApiSource::Live(client),
+        };
+        Ok(Self {
+            source,
+            log,
+            host: config.hosts.api.clone(),
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn get(&self, uri: Uri) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error>> {
+        self.request(Request::get(uri).body(Body::default()).unwrap(), 0)
+    }
+
+    /// Make a request which has been retried `retries` times before.
+    pub fn request(
+        &self,
+        request: Request<Body>,
+        retries: u32,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error>> {
+        let method = request.method().clone();
+        let uri = request.uri().clone();
+        self.log
+            .track(&method, &uri, retries, self.source_request(request))
+    }
+
+    fn source_request(
+        &self,
+        request: Request<Body>,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error>> {
+        match &self.source {
+            ApiSource::Live(client) => Box::new(client.request(request)),
+            ApiSource::Record(client, path) => {
+                let path = path.clone();
+                let uri = request.uri().to_string();
+                Box::new(client.request(request).and_then(move |res| {
+                    let (parts, body) = res.into_parts();
+                    body.concat2().map(move |body| {
+                        let recording = Recording::new(uri, &parts, &body);
+                        if let Err(err) = recording.save(&path) {
+                            error!("Failed to record response of {}: {}", recording.uri, err);
+                        }
+                        Response::from_parts(parts, Body::from(body))
+                    })
+                }))
+            }
+            ApiSource::Replay(recordings) => {
+                let uri = request.uri().to_string();
+                let recording = match recordings.lock().unwrap().get_mut(&uri) {
+                    Some(queue) if queue.len() > 1 => queue.pop_front(),
+                    Some(queue) => queue.front().cloned(),
+                    None => None,
+                };
+                let response = match recording {
+                    Some(recording) => recording.to_response(&request),
+                    None => {
+                        warn!("No recorded response for {}", uri);
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                };
+                Box::new(future::ok(response))
+            }
+        }
+    }
+}
+
+/// A recorded response.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Recording {
+    uri: String,
+    /// When the response was received, in milliseconds since the Unix epoch
+    time: i64,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// The body, in base64
+    body: String,
+}
+
+impl Recording {
+    fn new(uri: String, parts: &hyper::http::response::Parts, body: &[u8]) -> Self {
+        Self {
+            uri,
+            time: Utc::now().timestamp_millis(),
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    let value = value.to_str().ok()?;
+                    Some((name.as_str().to_owned(), value.to_owned()))
+                })
+                .collect(),
+            body: base64::encode(body),
+        }
+    }
+
+    /// Save the recording as `{time}-{uri}.json`, where the URI has non-alphanumeric characters
+    /// replaced with underscores.
+    fn save(&self, dir: &Path) -> Result<(), Error> {
+        let uri: String = self
+            .uri
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let file = File::create(dir.join(format!("{}-{}.json", self.time, uri)))?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Load every recording in a directory, grouped by URI and sorted by time.
+    fn load_all(dir: &Path) -> Result<HashMap<String, VecDeque<Recording>>, Error> {
+        let mut recordings = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                let file = File::open(&path)?;
+                let recording: Recording = serde_json::from_reader(BufReader::new(file))
+                    .with_context(|_| format!("Could not parse {}", path.display()))?;
+                recordings.push(recording);
+            }
+        }
+        recordings.sort_by_key(|recording| recording.time);
+
+        let mut by_uri: HashMap<_, VecDeque<_>> = HashMap::new();
+        for recording in recordings {
+            by_uri
+                .entry(recording.uri.clone())
+                .or_default()
+                .push_back(recording);
+        }
+        Ok(by_uri)
+    }
+
+    /// Rebuild the response. Like the API, a `200 OK` is turned into a `304 Not Modified` if the
+    /// request's `If-Modified-Since` is at or after the recorded `Last-Modified`.
+    fn to_response(&self, request: &Request<Body>) -> Response<Body> {
+        let header_time = |value: Option<&str>| {
+            value.and_then(|value| Utc.datetime_from_str(value, RFC_1123_FORMAT).ok())
+        };
+        let last_modified = header_time(
+            self.headers
+                .iter()
+                .find(|(name, _)| name.as_str() == header::LAST_MODIFIED.as_str())
+                .map(|(_, value)| value.as_str()),
+        );
+        let if_modified_since = header_time(
+            request
+                .headers()
+                .get(header::IF_MODIFIED_SINCE)
+                .and_then(|value| value.to_str().ok()),
+        );
+        let not_modified = match (last_modified, if_modified_since) {
+            (Some(last_modified), Some(since)) => {
+                self.status == StatusCode::OK.as_u16() && last_modified <= since
+            }
+            _ => false,
+        };
+
+        let mut builder = Response::builder();
+        for (name, value) in &self.headers {
+            builder.header(name.as_str(), value.as_str());
+        }
+        let response = if not_modified {
+            builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+        } else {
+            let body = base64::decode(&self.body).unwrap_or_else(|err| {
+                error!("Recorded body of {} is invalid: {}", self.uri, err);
+                vec![]
+            });
+            builder.status(self.status).body(Body::from(body))
+        };
+        response.unwrap()
+    }
+}
@@ -0,0 +1,175 @@
+//! An access log of every request the fetcher makes, for finding out after the fact why a thread or
+//! file was missed. Each request is logged once its response body has been read (or dropped), as:
+//!
+//! ```text
+//! <time> <method> <uri> <status> <bytes> <milliseconds>ms retries=<retries> [<note>]
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{LineWriter, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use chrono::prelude::*;
+use failure::{Error, ResultExt};
+use futures::prelude::*;
+use hyper::{Body, Chunk, Method, Response, StatusCode, Uri};
+use tokio::clock;
+
+use crate::config::RequestLogConfig;
+
+/// The log target of request log lines when `network.request_log.path` isn't set.
+const LOG_TARGET: &str = "ena::request_log";
+
+/// Where request log lines are written, if anywhere. It can be shared across threads.
+#[derive(Clone)]
+pub struct RequestLog(Option<Arc<Output>>);
+
+enum Output {
+    File(Mutex<LineWriter<File>>),
+    Log,
+}
+
+impl RequestLog {
+    pub fn new(config: Option<&RequestLogConfig>) -> Result<Self, Error> {
+        let output = match config {
+            Some(RequestLogConfig { path: Some(path) }) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|_| format!("Could not open {}", path.display()))?;
+                info!("Logging requests to {}", path.display());
+                Some(Output::File(Mutex::new(LineWriter::new(file))))
+            }
+            Some(RequestLogConfig { path: None }) => Some(Output::Log),
+            None => None,
+        };
+        Ok(RequestLog(output.map(Arc::new)))
+    }
+
+    /// Log a request, given the future of its response.
+    pub fn track<F>(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        retries: u32,
+        response: F,
+    ) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error>>
+    where
+        F: Future<Item = Response<Body>, Error = hyper::Error> + 'static,
+    {
+        let output = match &self.0 {
+            Some(output) => output.clone(),
+            None => return Box::new(response),
+        };
+        let mut entry = Entry {
+            output,
+            method: method.clone(),
+            uri: uri.clone(),
+            retries,
+            start: clock::now(),
+            status: None,
+            bytes: 0,
+        };
+        Box::new(response.then(move |res| match res {
+            Ok(res) => {
+                entry.status = Some(res.status());
+                let (parts, body) = res.into_parts();
+                let body = LoggedBody {
+                    body,
+                    entry: Some(entry),
+                };
+                Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+            }
+            Err(err) => {
+                entry.write(&err.to_string());
+                Err(err)
+            }
+        }))
+    }
+}
+
+struct Entry {
+    output: Arc<Output>,
+    method: Method,
+    uri: Uri,
+    retries: u32,
+    start: Instant,
+    status: Option<StatusCode>,
+    bytes: usize,
+}
+
+impl Entry {
+    fn write(&self, note: &str) {
+        let elapsed = clock::now() - self.start;
+        let line = format!(
+            "{} {} {} {} {} {}ms retries={}{}{}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.method,
+            self.uri,
+            self.status
+                .map_or_else(|| "-".to_owned(), |status| status.as_u16().to_string()),
+            self.bytes,
+            elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+            self.retries,
+            if note.is_empty() { "" } else { " " },
+            note,
+        );
+        match &*self.output {
+            Output::File(file) => {
+                if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+                    error!("Failed to write to request log: {}", err);
+                }
+            }
+            Output::Log => info!(target: LOG_TARGET, "{}", line),
+        }
+    }
+}
+
+/// A response body which logs its request once it has been read.
+struct LoggedBody {
+    body: Body,
+    /// Taken once the request is logged
+    entry: Option<Entry>,
+}
+
+impl Stream for LoggedBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let result = self.body.poll();
+        match &result {
+            Ok(Async::Ready(Some(chunk))) => {
+                if let Some(entry) = &mut self.entry {
+                    entry.bytes += chunk.len();
+                }
+            }
+            Ok(Async::Ready(None)) => {
+                if let Some(entry) = self.entry.take() {
+                    entry.write("");
+                }
+            }
+            Ok(Async::NotReady) => {}
+            Err(err) => {
+                if let Some(entry) = self.entry.take() {
+                    entry.write(&err.to_string());
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        // Bodies of errors (and 304s) aren't read, so only a successful response is incomplete
+        if let Some(entry) = self.entry.take() {
+            let success = entry.status.map_or(false, |status| status.is_success());
+            entry.write(if success { "(body not read)" } else { "" });
+        }
+    }
+}
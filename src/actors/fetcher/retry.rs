@@ -8,7 +8,7 @@ use futures::{
 
 use tokio::timer::DelayQueue;
 
-use crate::config::RetryBackoffConfig;
+use crate::config::{Jitter, RetryBackoffConfig};
 
 /// A struct which represents a request that can be retried
 pub struct Retry<T> {
@@ -16,6 +16,8 @@ pub struct Retry<T> {
     delay: Duration,
     factor: u32,
     max: Duration,
+    jitter: Jitter,
+    retries: u32,
 }
 
 impl<T> Retry<T> {
@@ -25,6 +27,8 @@ impl<T> Retry<T> {
             delay: config.base,
             factor: config.factor,
             max: config.max,
+            jitter: config.jitter,
+            retries: 0,
         }
     }
 
@@ -32,6 +36,20 @@ impl<T> Retry<T> {
         self.delay <= self.max
     }
 
+    /// The delay before the next attempt, with jitter. Each call backs off the following delay by
+    /// `factor`.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay *= self.factor;
+        self.retries += 1;
+        self.jitter.apply(delay)
+    }
+
+    /// The number of times the request has been retried.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
     pub fn as_data(&self) -> &T {
         &self.data
     }
@@ -86,8 +104,7 @@ where
             match self.stream.poll()? {
                 Async::Ready(Some(mut retry)) => {
                     assert!(retry.can_retry());
-                    let delay = retry.delay;
-                    retry.delay *= retry.factor;
+                    let delay = retry.next_delay();
                     self.queue.insert(retry, delay);
                 }
                 Async::NotReady => break,
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+
+use futures::prelude::*;
+
+/// A stream which reorders the items of another stream so that each key (e.g. each board) takes
+/// turns. Up to `max_buffered` items are read ahead and queued by key, and the queues are then
+/// serviced round-robin. This keeps a key with many items from starving the others.
+#[must_use = "streams do nothing unless polled"]
+pub struct RoundRobin<S: Stream, K, F> {
+    stream: S,
+    key: F,
+    /// The queue of each key with items, in the order they will be serviced
+    queues: VecDeque<(K, VecDeque<S::Item>)>,
+    buffered: usize,
+    max_buffered: usize,
+    done: bool,
+}
+
+impl<S, K, F> Stream for RoundRobin<S, K, F>
+where
+    S: Stream,
+    K: PartialEq,
+    F: Fn(&S::Item) -> K,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done && self.buffered < self.max_buffered {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => self.push(item),
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        match self.queues.pop_front() {
+            Some((key, mut queue)) => {
+                let item = queue.pop_front().unwrap();
+                self.buffered -= 1;
+                if !queue.is_empty() {
+                    self.queues.push_back((key, queue));
+                }
+                Ok(Async::Ready(Some(item)))
+            }
+            None if self.done => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<S, K, F> RoundRobin<S, K, F>
+where
+    S: Stream,
+    K: PartialEq,
+    F: Fn(&S::Item) -> K,
+{
+    fn push(&mut self, item: S::Item) {
+        let key = (self.key)(&item);
+        self.buffered += 1;
+        // There are only as many keys as boards, so a linear search is fine
+        match self.queues.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, queue)) => queue.push_back(item),
+            None => {
+                let mut queue = VecDeque::new();
+                queue.push_back(item);
+                self.queues.push_back((key, queue));
+            }
+        }
+    }
+}
+
+pub fn round_robin<S, K, F>(stream: S, max_buffered: usize, key: F) -> RoundRobin<S, K, F>
+where
+    S: Stream,
+    K: PartialEq,
+    F: Fn(&S::Item) -> K,
+{
+    RoundRobin {
+        stream,
+        key,
+        queues: VecDeque::new(),
+        buffered: 0,
+        max_buffered,
+        done: false,
+    }
+}
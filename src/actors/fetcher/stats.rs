@@ -0,0 +1,120 @@
+//! Request statistics, for telling whether the fetcher is keeping up with the boards it scrapes.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::*;
+
+/// The live counters of a request channel. They are kept in the channel's `Throttle`, which is
+/// passed to every request of the channel.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelCounters(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    queued: AtomicUsize,
+    retrying: AtomicUsize,
+    started: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl ChannelCounters {
+    /// Count `n` requests sent to the channel.
+    pub fn queue(&self, n: usize) {
+        self.0.queued.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a request taken from the channel by its `RateLimiter`.
+    pub fn dequeue(&self) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a failed request sent to the retry queue.
+    pub fn queue_retry(&self) {
+        self.0.retrying.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a retry taken from the retry queue.
+    pub fn dequeue_retry(&self) {
+        self.0.retrying.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a request started by the channel's `RateLimiter`.
+    pub fn start(&self) {
+        self.0.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, bytes: usize) {
+        self.0.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The statistics of a request channel.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelStats {
+    /// Requests waiting to be started, not counting retries
+    pub queued: usize,
+    /// Failed requests waiting to be retried
+    pub retrying: usize,
+    /// Requests started since Ena started (including retries)
+    pub started: usize,
+    /// Response body bytes downloaded since Ena started
+    pub bytes: usize,
+    /// The number of times that the channel was paused because the API asked us to slow down
+    pub rate_limited: u64,
+}
+
+impl ChannelStats {
+    fn new(throttle: &Throttle) -> Self {
+        let counters = &(throttle.counters().0);
+        Self {
+            queued: counters.queued.load(Ordering::Relaxed),
+            retrying: counters.retrying.load(Ordering::Relaxed),
+            started: counters.started.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            rate_limited: throttle.events(),
+        }
+    }
+}
+
+/// The statistics of each request channel, the number of times that the circuit breaker tripped,
+/// the number of times that each host was blocked by a CDN challenge, and whether a disk is nearly
+/// full. A nonzero blocked count or low disk space needs the operator's attention.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetcherStats {
+    pub media: ChannelStats,
+    /// Thumbnails, if they have their own channel. Otherwise, they are counted in `media`
+    pub thumbs: Option<ChannelStats>,
+    pub thread: ChannelStats,
+    /// Thread lists and archives
+    pub thread_list: ChannelStats,
+    pub circuit_breaker_trips: u64,
+    pub api_blocked: u64,
+    pub media_blocked: u64,
+    /// Whether downloads are paused because a disk is nearly full
+    pub disk_space_low: bool,
+    /// The number of times that downloads were paused because a disk was nearly full
+    pub disk_space_pauses: u64,
+}
+
+pub struct GetFetcherStats;
+impl Message for GetFetcherStats {
+    type Result = Result<FetcherStats, ()>;
+}
+
+impl Handler<GetFetcherStats> for Fetcher {
+    type Result = Result<FetcherStats, ()>;
+
+    fn handle(&mut self, _: GetFetcherStats, _: &mut Self::Context) -> Self::Result {
+        Ok(FetcherStats {
+            media: ChannelStats::new(&self.media_throttle),
+            thumbs: self.thumb_throttle.as_ref().map(ChannelStats::new),
+            thread: ChannelStats::new(&self.thread_throttle),
+            thread_list: ChannelStats::new(&self.thread_list_throttle),
+            circuit_breaker_trips: self.breaker.as_ref().map_or(0, CircuitBreaker::trips),
+            api_blocked: self.api_host.events(),
+            media_blocked: self.media_host.events(),
+            disk_space_low: self.disk_guard.as_ref().map_or(false, DiskGuard::is_low),
+            disk_space_pauses: self.disk_guard.as_ref().map_or(0, DiskGuard::pauses),
+        })
+    }
+}
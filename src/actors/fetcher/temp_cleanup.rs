@@ -0,0 +1,84 @@
+//! Removing the temporary files of media downloads which never finished (e.g. because Ena crashed
+//! mid-write). Downloads are written to `<board>/tmp/` and only moved to their real path once
+//! complete, so anything left there long enough is garbage.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use failure::{Error, Fail, ResultExt};
+
+use crate::four_chan::Board;
+
+/// Delete the files in the `tmp` directory of each board which haven't been modified for
+/// `max_age`. Returns the filenames of the deleted files.
+pub(super) fn remove_stale_files(
+    media_path: &Path,
+    boards: &[Board],
+    max_age: Duration,
+) -> Vec<(Board, String)> {
+    let now = SystemTime::now();
+    let mut removed = vec![];
+    for &board in boards {
+        let mut temp_dir = media_path.to_owned();
+        temp_dir.push(board.to_string());
+        temp_dir.push("tmp");
+        if let Err(err) = remove_in_dir(&temp_dir, board, now, max_age, &mut removed) {
+            log_error!(err.as_fail());
+        }
+    }
+    removed
+}
+
+fn remove_in_dir(
+    temp_dir: &Path,
+    board: Board,
+    now: SystemTime,
+    max_age: Duration,
+    removed: &mut Vec<(Board, String)>,
+) -> Result<(), Error> {
+    let entries = match fs::read_dir(temp_dir) {
+        Ok(entries) => entries,
+        // Nothing has been downloaded for this board yet
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err
+                .context(format!("Could not read {}", temp_dir.display()))
+                .into())
+        }
+    };
+
+    for entry in entries {
+        let entry = entry.with_context(|_| format!("Could not read {}", temp_dir.display()))?;
+        let path = entry.path();
+        let modified = match entry.metadata().and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(err) => {
+                warn!("Could not get the age of {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        // A modified time in the future means the file is still fresh
+        if now.duration_since(modified).unwrap_or_default() < max_age {
+            continue;
+        }
+
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                debug!(
+                    "/{}/: Removed stale temporary file {}",
+                    board,
+                    path.display()
+                );
+                if let Some(filename) = entry.file_name().to_str() {
+                    removed.push((board, filename.to_owned()));
+                }
+            }
+            Err(err) => warn!("Could not remove {}: {}", path.display(), err),
+        }
+    }
+    Ok(())
+}
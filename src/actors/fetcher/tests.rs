@@ -0,0 +1,573 @@
+#![cfg(test)]
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future, prelude::*, stream, sync::mpsc, task};
+use tokio::{
+    clock::{self, Clock, Now},
+    runtime::{current_thread, Builder},
+    timer::Delay,
+};
+
+use super::{
+    body_pool::BufferPool,
+    circuit_breaker::CircuitBreaker,
+    disk_guard::DiskGuard,
+    priority::priority_select,
+    rate_limiter::{GlobalLimiter, HostBlock, StreamExt, Throttle},
+    retry::{Retry, RetryQueue},
+    round_robin::round_robin,
+    FetchPriority, FetchThreads, InFlight, ThreadJson,
+};
+use crate::{
+    config::{
+        CircuitBreakerConfig, DiskSpaceConfig, Jitter, RateLimitingSettings, RetryBackoffConfig,
+    },
+    four_chan::{Board, ThreadNo},
+};
+
+/// A clock which only moves when it is advanced.
+#[derive(Clone)]
+struct VirtualClock(Arc<Mutex<Instant>>);
+
+impl VirtualClock {
+    fn new() -> Self {
+        VirtualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Now for VirtualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Run `f` on a runtime whose timers and `clock::now()` follow a `VirtualClock`.
+fn with_virtual_clock<F>(f: F)
+where
+    F: FnOnce(&VirtualClock) + Send + 'static,
+{
+    let virtual_clock = VirtualClock::new();
+    let mut runtime = Builder::new()
+        .clock(Clock::new_with_now(virtual_clock.clone()))
+        .build()
+        .unwrap();
+    runtime
+        .block_on(future::lazy(move || {
+            f(&virtual_clock);
+            Ok::<_, ()>(())
+        }))
+        .expect("Test panicked");
+}
+
+/// Poll the stream made by `make_stream` on a runtime whose timers follow a `VirtualClock`.
+/// Whenever the stream isn't ready, the clock is advanced by `step`, until the stream ends or
+/// `limit` has passed. Returns each item with the virtual time (in milliseconds) at which it was
+/// yielded.
+fn poll_with_virtual_clock<F, S>(
+    make_stream: F,
+    step: Duration,
+    limit: Duration,
+) -> Vec<(u64, S::Item)>
+where
+    F: FnOnce() -> S,
+    S: Stream<Error = ()>,
+{
+    let virtual_clock = VirtualClock::new();
+    let start = virtual_clock.now();
+    let mut runtime = current_thread::Builder::new()
+        .clock(Clock::new_with_now(virtual_clock.clone()))
+        .build()
+        .unwrap();
+
+    // The stream is made on the runtime, so that it reads the virtual clock
+    let mut make_stream = Some(make_stream);
+    let mut stream = None;
+    let mut items = vec![];
+    runtime
+        .block_on(future::poll_fn(|| -> Poll<(), ()> {
+            let stream = stream.get_or_insert_with(|| make_stream.take().unwrap()());
+            loop {
+                let elapsed = virtual_clock.now() - start;
+                let millis = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+                match stream.poll()? {
+                    Async::Ready(Some(item)) => items.push((millis, item)),
+                    Async::Ready(None) => return Ok(Async::Ready(())),
+                    Async::NotReady if elapsed >= limit => return Ok(Async::Ready(())),
+                    Async::NotReady => {
+                        // Let the runtime fire the timers which are due before polling again
+                        virtual_clock.advance(step);
+                        task::current().notify();
+                        return Ok(Async::NotReady);
+                    }
+                }
+            }
+        }))
+        .unwrap();
+    items
+}
+
+fn rate_limiting(interval: u64, max_interval: usize, burst: Option<usize>) -> RateLimitingSettings {
+    RateLimitingSettings {
+        interval: Duration::from_secs(interval),
+        max_interval,
+        max_concurrent: 100,
+        burst,
+    }
+}
+
+fn throttle() -> Throttle {
+    Throttle::new(&HostBlock::new(Duration::from_secs(60)), None, None, None)
+}
+
+#[test]
+fn retry_backoff() {
+    let mut retry = Retry::new(
+        (),
+        &RetryBackoffConfig {
+            base: Duration::from_secs(1),
+            factor: 2,
+            max: Duration::from_secs(8),
+            jitter: Jitter::None,
+        },
+    );
+    let mut delays = vec![];
+    while retry.can_retry() {
+        delays.push(retry.next_delay().as_secs());
+    }
+    assert_eq!(delays, vec![1, 2, 4, 8]);
+}
+
+#[test]
+fn retry_queue() {
+    let config = RetryBackoffConfig {
+        base: Duration::from_secs(1),
+        factor: 2,
+        max: Duration::from_secs(8),
+        jitter: Jitter::None,
+    };
+    let items = poll_with_virtual_clock(
+        || {
+            let mut retried = Retry::new(2, &config);
+            retried.next_delay();
+            let retries = vec![Retry::new(1, &config), retried];
+            RetryQueue::new(stream::iter_ok(retries)).map(|retry| retry.into_data())
+        },
+        Duration::from_millis(100),
+        Duration::from_secs(60),
+    );
+    assert_eq!(items, vec![(1000, 1), (2000, 2)]);
+}
+
+#[test]
+fn rate_limiter_interval() {
+    let items = poll_with_virtual_clock(
+        || {
+            stream::iter_ok((0..5).map(future::ok::<_, ()>))
+                .rate_limit(&rate_limiting(10, 2, None), &throttle())
+        },
+        Duration::from_millis(100),
+        Duration::from_secs(60),
+    );
+    assert_eq!(
+        items,
+        vec![(0, 0), (0, 1), (10_000, 2), (10_000, 3), (20_000, 4)]
+    );
+}
+
+#[test]
+fn rate_limiter_pause() {
+    let items = poll_with_virtual_clock(
+        || {
+            let throttle = throttle();
+            throttle.pause(Duration::from_secs(5));
+            stream::iter_ok((0..2).map(future::ok::<_, ()>))
+                .rate_limit(&rate_limiting(10, 10, None), &throttle)
+        },
+        Duration::from_millis(100),
+        Duration::from_secs(60),
+    );
+    assert_eq!(items, vec![(5000, 0), (5000, 1)]);
+}
+
+#[test]
+fn rate_limiter_burst() {
+    // One token every 2 seconds, and bursts of up to 2 requests
+    let items = poll_with_virtual_clock(
+        || {
+            let later = Delay::new(clock::now() + Duration::from_secs(20))
+                .map(|()| stream::iter_ok(3..6))
+                .map_err(|err| panic!("Timer error: {}", err))
+                .flatten_stream();
+            stream::iter_ok(0..3)
+                .chain(later)
+                .map(future::ok::<_, ()>)
+                .rate_limit(&rate_limiting(10, 5, Some(2)), &throttle())
+        },
+        Duration::from_millis(100),
+        Duration::from_secs(60),
+    );
+    // After being idle, the bucket has refilled, but only up to the burst size
+    assert_eq!(
+        items,
+        vec![
+            (0, 0),
+            (0, 1),
+            (2000, 2),
+            (20_000, 3),
+            (20_000, 4),
+            (22_000, 5)
+        ]
+    );
+}
+
+#[test]
+fn retry_jitter() {
+    for &jitter in &[Jitter::Full, Jitter::Equal] {
+        let mut retry = Retry::new(
+            (),
+            &RetryBackoffConfig {
+                base: Duration::from_secs(1),
+                factor: 2,
+                max: Duration::from_secs(64),
+                jitter,
+            },
+        );
+        let mut backoff = Duration::from_secs(1);
+        while retry.can_retry() {
+            let delay = retry.next_delay();
+            assert!(delay <= backoff);
+            if jitter == Jitter::Equal {
+                assert!(delay >= backoff / 2);
+            }
+            backoff *= 2;
+        }
+        // Jitter doesn't change how many times a request is retried
+        assert_eq!(backoff, Duration::from_secs(128));
+    }
+}
+
+#[test]
+fn circuit_breaker() {
+    with_virtual_clock(|virtual_clock| {
+        let cooldown = Duration::from_secs(60);
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failures: 2,
+            cooldown,
+        });
+        assert_eq!(breaker.paused_until(), None);
+
+        breaker.record(false);
+        assert_eq!(breaker.paused_until(), None);
+        breaker.record(false);
+        assert_eq!(breaker.paused_until(), Some(clock::now() + cooldown));
+        assert_eq!(breaker.trips(), 1);
+
+        virtual_clock.advance(cooldown - Duration::from_secs(1));
+        assert!(breaker.paused_until().is_some());
+        virtual_clock.advance(Duration::from_secs(1));
+        assert_eq!(breaker.paused_until(), None);
+
+        // Only one probe at a time, and a failed probe starts another cooldown
+        assert!(breaker.start_request());
+        assert!(!breaker.start_request());
+        assert!(breaker.paused_until().is_some());
+        breaker.record(false);
+        assert_eq!(breaker.paused_until(), Some(clock::now() + cooldown));
+        assert_eq!(breaker.trips(), 2);
+
+        virtual_clock.advance(cooldown);
+        assert_eq!(breaker.paused_until(), None);
+        assert!(breaker.start_request());
+        breaker.record(true);
+        assert_eq!(breaker.paused_until(), None);
+        assert!(!breaker.start_request());
+    });
+}
+
+#[test]
+fn circuit_breaker_dropped_probe() {
+    with_virtual_clock(|virtual_clock| {
+        let cooldown = Duration::from_secs(10);
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failures: 1,
+            cooldown,
+        });
+        breaker.record(false);
+        virtual_clock.advance(cooldown);
+        assert_eq!(breaker.paused_until(), None);
+        assert!(breaker.start_request());
+
+        // The probe never finishes, so another is allowed after a cooldown
+        virtual_clock.advance(cooldown - Duration::from_secs(1));
+        assert!(breaker.paused_until().is_some());
+        virtual_clock.advance(Duration::from_secs(1));
+        assert_eq!(breaker.paused_until(), None);
+        assert!(breaker.start_request());
+    });
+}
+
+#[test]
+fn global_limiter() {
+    with_virtual_clock(|virtual_clock| {
+        let limiter = GlobalLimiter::new(2.0);
+        for _ in 0..2 {
+            assert_eq!(limiter.paused_until(), None);
+            limiter.take();
+        }
+        assert_eq!(
+            limiter.paused_until(),
+            Some(clock::now() + Duration::from_millis(500))
+        );
+
+        virtual_clock.advance(Duration::from_millis(250));
+        assert_eq!(
+            limiter.paused_until(),
+            Some(clock::now() + Duration::from_millis(250))
+        );
+        virtual_clock.advance(Duration::from_millis(250));
+        assert_eq!(limiter.paused_until(), None);
+    });
+}
+
+#[test]
+fn priority_select_order() {
+    let order: Vec<_> = priority_select(
+        stream::iter_ok::<_, ()>(vec![("sticky", 1), ("sticky", 2)]),
+        priority_select(
+            stream::iter_ok(vec![("high", 1)]),
+            stream::iter_ok(vec![("normal", 1), ("normal", 2)]),
+        ),
+    )
+    .collect()
+    .wait()
+    .unwrap();
+    assert_eq!(
+        order,
+        vec![
+            ("sticky", 1),
+            ("sticky", 2),
+            ("high", 1),
+            ("normal", 1),
+            ("normal", 2)
+        ]
+    );
+}
+
+#[test]
+fn priority_select_preemption() {
+    let (high_sender, high_receiver) = mpsc::unbounded();
+    let (low_sender, low_receiver) = mpsc::unbounded();
+    let mut requests = priority_select(high_receiver, low_receiver).wait();
+    for no in 1..=3 {
+        low_sender.unbounded_send(("low", no)).unwrap();
+    }
+    assert_eq!(requests.next(), Some(Ok(("low", 1))));
+
+    // High priority requests which arrive later are taken before the backlog
+    high_sender.unbounded_send(("high", 1)).unwrap();
+    high_sender.unbounded_send(("high", 2)).unwrap();
+    assert_eq!(requests.next(), Some(Ok(("high", 1))));
+    assert_eq!(requests.next(), Some(Ok(("high", 2))));
+
+    // Once `high` is drained (or has ended), `low` isn't starved
+    assert_eq!(requests.next(), Some(Ok(("low", 2))));
+    drop(high_sender);
+    assert_eq!(requests.next(), Some(Ok(("low", 3))));
+    low_sender.unbounded_send(("low", 4)).unwrap();
+    assert_eq!(requests.next(), Some(Ok(("low", 4))));
+    drop(low_sender);
+    assert_eq!(requests.next(), None);
+}
+
+#[test]
+fn round_robin_boards() {
+    let requests = vec![
+        ("a", 1),
+        ("a", 2),
+        ("a", 3),
+        ("a", 4),
+        ("b", 1),
+        ("c", 1),
+        ("b", 2),
+    ];
+    let order: Vec<_> = round_robin(stream::iter_ok::<_, ()>(requests), 100, |&(board, _)| board)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(
+        order,
+        vec![
+            ("a", 1),
+            ("b", 1),
+            ("c", 1),
+            ("a", 2),
+            ("b", 2),
+            ("a", 3),
+            ("a", 4)
+        ]
+    );
+}
+
+#[test]
+fn round_robin_max_buffered() {
+    // Only the first two requests are read ahead, so "b" has to wait its turn
+    let requests = vec![("a", 1), ("a", 2), ("b", 1), ("a", 3)];
+    let order: Vec<_> = round_robin(stream::iter_ok::<_, ()>(requests), 2, |&(board, _)| board)
+        .collect()
+        .wait()
+        .unwrap();
+    assert_eq!(order, vec![("a", 1), ("a", 2), ("b", 1), ("a", 3)]);
+}
+
+#[test]
+fn disk_guard() {
+    const MIB: u64 = 1024 * 1024;
+    with_virtual_clock(|_| {
+        let guard = DiskGuard::new(&DiskSpaceConfig {
+            min_free: 100,
+            interval: Duration::from_secs(60),
+            mysql_datadir: None,
+            pause_scraping: false,
+        });
+        let media = PathBuf::from("media");
+        let datadir = PathBuf::from("mysql");
+
+        guard.update(&[(media.clone(), 200 * MIB), (datadir.clone(), 100 * MIB)]);
+        assert_eq!(guard.paused_until(), None);
+
+        // Any low volume pauses downloads
+        guard.update(&[(media.clone(), 200 * MIB), (datadir.clone(), 99 * MIB)]);
+        assert!(guard.paused_until().is_some());
+        assert_eq!(guard.pauses(), 1);
+
+        // Downloads only resume with some margin above `min_free`
+        guard.update(&[(media.clone(), 200 * MIB), (datadir.clone(), 105 * MIB)]);
+        assert!(guard.is_low());
+        guard.update(&[(media.clone(), 200 * MIB), (datadir.clone(), 110 * MIB)]);
+        assert_eq!(guard.paused_until(), None);
+        assert_eq!(guard.pauses(), 1);
+    });
+}
+
+#[test]
+fn in_flight_merge() {
+    use FetchPriority::*;
+    use ThreadJson::*;
+
+    let in_flight = InFlight::default();
+    let no = ThreadNo(1);
+    assert!(in_flight.insert(Board::a, no, false, Tail, Normal));
+    assert!(!in_flight.insert(Board::a, no, false, Tail, Normal));
+    // Duplicates upgrade the queued request, but a live request stays live
+    assert!(!in_flight.insert(Board::a, no, true, Full, Normal));
+    assert!(!in_flight.insert(Board::a, no, false, Tail, Normal));
+    assert_eq!(in_flight.start(Board::a, no), Some((false, Full)));
+    assert!(in_flight.remove(Board::a, no).is_none());
+
+    // Requests from archive.json stay archived only if they all are
+    assert!(in_flight.insert(Board::a, no, true, Tail, Normal));
+    assert!(!in_flight.insert(Board::a, no, true, Full, Normal));
+    assert_eq!(in_flight.start(Board::a, no), Some((true, Full)));
+    assert!(in_flight.remove(Board::a, no).is_none());
+
+    // Fallback requests are queued like the others, and aren't answered by a cached fetch
+    assert!(in_flight.insert(Board::a, no, false, Full, Normal));
+    assert!(!in_flight.insert(Board::a, no, false, Fallback, Normal));
+    assert_eq!(in_flight.start(Board::a, no), Some((false, Fallback)));
+    assert!(in_flight.remove(Board::a, no).is_none());
+}
+
+#[test]
+fn in_flight_priority() {
+    use FetchPriority::*;
+
+    let in_flight = InFlight::default();
+    let no = ThreadNo(1);
+    assert!(in_flight.insert(Board::a, no, false, ThreadJson::Full, Normal));
+    assert!(in_flight.insert(Board::a, no, false, ThreadJson::Full, High));
+    assert!(!in_flight.insert(Board::a, no, false, ThreadJson::Full, Normal));
+    assert!(in_flight.insert(Board::a, no, false, ThreadJson::Full, Sticky));
+    assert!(!in_flight.insert(Board::a, no, false, ThreadJson::Full, High));
+
+    // Only the first copy to be dequeued is fetched
+    assert!(in_flight.start(Board::a, no).is_some());
+    assert!(in_flight.start(Board::a, no).is_none());
+    assert!(in_flight.start(Board::a, no).is_none());
+    assert!(in_flight.remove(Board::a, no).is_none());
+    assert!(in_flight.start(Board::a, no).is_none());
+}
+
+#[test]
+fn in_flight_started() {
+    use FetchPriority::*;
+    use ThreadJson::*;
+
+    let in_flight = InFlight::default();
+    let (a, b) = (ThreadNo(1), ThreadNo(2));
+    assert!(in_flight.insert(Board::a, a, false, Full, Normal));
+    assert!(in_flight.insert(Board::a, b, false, Tail, Normal));
+    assert!(in_flight.start(Board::a, a).is_some());
+    assert!(in_flight.start(Board::a, b).is_some());
+
+    // Requests answered by the running fetch are dropped
+    assert!(!in_flight.insert(Board::a, a, false, Tail, Sticky));
+    assert!(in_flight.remove(Board::a, a).is_none());
+
+    // Others are sent again once it finishes
+    assert!(!in_flight.insert(Board::a, b, true, Tail, Normal));
+    assert!(!in_flight.insert(Board::a, b, false, Full, High));
+    match in_flight.remove(Board::a, b) {
+        Some(FetchThreads(Board::a, nums, false, Full, High)) => assert_eq!(nums, vec![b]),
+        _ => panic!("Merged requests weren't returned"),
+    }
+    assert!(in_flight.insert(Board::a, b, true, Full, High));
+
+    // A fetch for archive.json doesn't answer a live request
+    assert!(in_flight.start(Board::a, b).is_some());
+    assert!(!in_flight.insert(Board::a, b, false, Tail, Normal));
+    match in_flight.remove(Board::a, b) {
+        Some(FetchThreads(Board::a, _, false, Tail, Normal)) => {}
+        _ => panic!("Live request wasn't returned"),
+    }
+}
+
+#[test]
+fn in_flight_failed_send() {
+    let in_flight = InFlight::default();
+    let no = ThreadNo(1);
+    assert!(in_flight.insert(Board::a, no, false, ThreadJson::Full, FetchPriority::Normal));
+    // The request couldn't be queued, so the thread can be requested again
+    assert!(in_flight.remove(Board::a, no).is_none());
+    assert!(in_flight.insert(Board::a, no, false, ThreadJson::Full, FetchPriority::Normal));
+}
+
+#[test]
+fn body_buffers_reused() {
+    let pool = BufferPool::default();
+    let mut buf = pool.take(1024);
+    buf.extend_from_slice(b"{}");
+    assert_eq!(&*buf, b"{}");
+    assert_eq!(pool.pooled(), 0);
+    drop(buf);
+    assert_eq!(pool.pooled(), 1);
+
+    // The buffer comes back empty, with its capacity
+    let buf = pool.take(16);
+    assert!(buf.is_empty());
+    assert!(buf.capacity() >= 1024);
+    assert_eq!(pool.pooled(), 0);
+
+    // Huge buffers are freed instead
+    drop(buf);
+    drop(pool.take(64 * 1024 * 1024));
+    assert_eq!(pool.pooled(), 0);
+}
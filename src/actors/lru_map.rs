@@ -0,0 +1,94 @@
+//! A map which can be capped at a number of entries, evicting the least recently inserted entry
+//! when it is full.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+pub struct LruMap<K, V> {
+    /// Each value, with the tick of its last insert
+    entries: HashMap<K, (u64, V)>,
+    /// The key of each entry, by the tick of its last insert
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    capacity: Option<usize>,
+}
+
+impl<K: Clone + Eq + Hash, V> LruMap<K, V> {
+    /// Create a map which holds at most `capacity` entries (which must not be 0), or any number if
+    /// `capacity` is `None`.
+    pub fn new(capacity: Option<usize>) -> Self {
+        debug_assert_ne!(capacity, Some(0));
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            capacity,
+        }
+    }
+
+    /// Insert an entry and mark it as the most recently used. If the map was full, the least
+    /// recently used entry is evicted and returned.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.insert_pinned(key, value, |_| false)
+    }
+
+    /// Like `insert`, but entries for which `pinned` returns true are never evicted. If every
+    /// other entry is pinned, nothing is evicted and the map grows past its capacity.
+    pub fn insert_pinned<F: Fn(&V) -> bool>(
+        &mut self,
+        key: K,
+        value: V,
+        pinned: F,
+    ) -> Option<(K, V)> {
+        self.tick += 1;
+        if let Some((tick, _)) = self.entries.insert(key.clone(), (self.tick, value)) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key);
+
+        match self.capacity {
+            Some(capacity) if self.entries.len() > capacity => {
+                let (entries, newest) = (&self.entries, self.tick);
+                let oldest = *self
+                    .order
+                    .iter()
+                    .find(|&(&tick, key)| tick != newest && !pinned(&entries[key].1))?
+                    .0;
+                let key = self.order.remove(&oldest).unwrap();
+                self.entries.remove(&key).map(|(_, value)| (key, value))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (tick, value) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.keys()
+    }
+
+    /// Iterate over the entries, from least to most recently used.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.order
+            .values()
+            .map(move |key| (key, &self.entries[key].1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
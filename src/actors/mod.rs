@@ -1,10 +1,42 @@
 //! Actors which fetch API data, poll threads, update threads, and write to the database.
 
+mod activity;
+mod archive_export;
 mod board_poller;
+mod clickhouse;
+mod coordinator;
 mod database;
 mod fetcher;
+mod lru_map;
+mod post_processor;
+mod scrape_lag;
+mod state;
+mod supervisor;
 mod thread_updater;
+mod write_backlog;
 
 pub use {
-    board_poller::BoardPoller, database::Database, fetcher::Fetcher, thread_updater::ThreadUpdater,
+    archive_export::ArchiveExporter,
+    board_poller::{BoardPollStatus, BoardPoller, GetBoardPollStatus},
+    clickhouse::ClickHouse,
+    coordinator::Coordinator,
+    database::{
+        check_database_servers, latest_schema_version, migrate_timestamps, start_writer, Database,
+        DatabaseLoad, DatabaseStats, GetDatabaseStats, GetMediaHashes, GetRecentPosts, GetThread,
+        PostRow, StoreMediaHash,
+    },
+    fetcher::{ChannelStats, Fetcher, FetcherStats, GetFetcherStats},
+    post_processor::{PostProcessor, ThreadContext, Verdict},
+    scrape_lag::{LagStats, LagTracker},
+    supervisor::{PanicSupervisor, RestartPolicy},
+    thread_updater::{
+        GetScrapeLag, GetThreadUpdaterStats, GetTrackedThreads, PostSummary, PostsInserted,
+        ThreadArchived, ThreadUpdater, ThreadUpdaterStats, TrackedThreads,
+    },
+    write_backlog::WriteBacklog,
+};
+pub(crate) use {
+    database::{check_timestamp_modes, GetThreadModifiedTimes},
+    fetcher::SeedFetchCache,
+    post_processor::processors_from_config,
 };
@@ -0,0 +1,176 @@
+//! Post processors, which can change new and modified posts before they're inserted (e.g. to
+//! redact text). Processors are run in order: first those in `post_processors` in the config, and
+//! then those added with `ScraperBuilder::post_processor`.
+
+#[cfg(feature = "lua")]
+use std::{fs, path::Path};
+
+use failure::Error;
+#[cfg(feature = "lua")]
+use failure::ResultExt;
+use regex::Regex;
+
+use crate::{
+    config::PostProcessorConfig,
+    four_chan::{Board, Post, ThreadNo},
+};
+
+/// The thread of a post being processed.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadContext {
+    pub board: Board,
+    pub thread: ThreadNo,
+}
+
+/// What to do with a processed post. If processors disagree, the strictest verdict is used.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verdict {
+    Keep,
+    /// Insert the post, but don't download its media or thumbnail
+    SkipMedia,
+    /// Don't insert the post (or, if it was modified, its changes). OPs can't be skipped, since
+    /// their replies need them, so they are kept instead.
+    Skip,
+}
+
+/// A step run on each new or modified post before it is inserted. Posts have already been
+/// compared with their previous versions, so changes made here don't cause posts to be updated
+/// again. Comments are still HTML at this point.
+///
+/// Processors can add `tags` to a post, which are stored in its `exif` column. Only the tags of
+/// new posts and of OPs (when their OP data changes) are stored.
+pub trait PostProcessor {
+    fn process(&mut self, context: ThreadContext, post: &mut Post) -> Verdict;
+}
+
+/// Create the processors of `post_processors` in the config.
+pub fn processors_from_config(
+    configs: &[PostProcessorConfig],
+) -> Result<Vec<Box<dyn PostProcessor>>, Error> {
+    configs
+        .iter()
+        .map(|config| -> Result<Box<dyn PostProcessor>, Error> {
+            match config {
+                PostProcessorConfig::Replace {
+                    pattern,
+                    replacement,
+                } => Ok(Box::new(Replace {
+                    pattern: pattern.clone(),
+                    replacement: replacement.clone(),
+                })),
+                #[cfg(feature = "lua")]
+                PostProcessorConfig::Lua { path } => Ok(Box::new(Lua::try_new(path)?)),
+            }
+        })
+        .collect()
+}
+
+/// Replaces the matches of a regex in comments and subjects.
+struct Replace {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl PostProcessor for Replace {
+    fn process(&mut self, _: ThreadContext, post: &mut Post) -> Verdict {
+        for field in &mut [&mut post.comment, &mut post.subject] {
+            if let Some(text) = field {
+                if self.pattern.is_match(text) {
+                    *text = self
+                        .pattern
+                        .replace_all(text, self.replacement.as_str())
+                        .into_owned();
+                }
+            }
+        }
+        Verdict::Keep
+    }
+}
+
+/// Calls the global `process` function of a Lua script with a table of each post's fields. The
+/// function can return `nil` to keep the post, `"skip"` or `"skip_media"`, or a table of tags. If
+/// the script fails, the error is logged and the post is kept.
+#[cfg(feature = "lua")]
+struct Lua {
+    lua: rlua::Lua,
+}
+
+#[cfg(feature = "lua")]
+impl Lua {
+    fn try_new(path: &Path) -> Result<Self, Error> {
+        let source = fs::read_to_string(path)
+            .with_context(|_| format!("Could not read Lua script {}", path.display()))?;
+        let name = path.to_string_lossy();
+        let lua = rlua::Lua::new();
+        lua.context(|ctx| -> rlua::Result<()> {
+            ctx.exec::<()>(&source, Some(&name))?;
+            ctx.globals().get::<_, rlua::Function>("process")?;
+            Ok(())
+        })
+        .with_context(|_| format!("Could not load Lua script {}", path.display()))?;
+        Ok(Self { lua })
+    }
+
+    fn call(ctx: rlua::Context, context: ThreadContext, post: &mut Post) -> rlua::Result<Verdict> {
+        let table = ctx.create_table()?;
+        table.set("board", context.board.to_string())?;
+        table.set("thread", context.thread.0)?;
+        table.set("no", post.no.0)?;
+        table.set("time", post.time)?;
+        for (key, value) in &[
+            ("name", &post.name),
+            ("trip", &post.trip),
+            ("id", &post.id),
+            ("country", &post.country),
+            ("subject", &post.subject),
+            ("comment", &post.comment),
+        ] {
+            table.set(*key, value.as_ref().map(String::as_str))?;
+        }
+        if let Some(image) = &post.image {
+            table.set("filename", image.filename.as_str())?;
+            table.set("ext", image.ext.as_str())?;
+            table.set("md5", image.md5.as_str())?;
+            table.set("filesize", image.filesize)?;
+            table.set("spoiler", image.spoiler)?;
+        }
+
+        let process: rlua::Function = ctx.globals().get("process")?;
+        match process.call::<_, rlua::Value>(table)? {
+            rlua::Value::Nil => Ok(Verdict::Keep),
+            rlua::Value::String(verdict) => match verdict.to_str()? {
+                "skip" => Ok(Verdict::Skip),
+                "skip_media" => Ok(Verdict::SkipMedia),
+                verdict => Err(rlua::Error::RuntimeError(format!(
+                    "Unknown verdict: {}",
+                    verdict
+                ))),
+            },
+            rlua::Value::Table(tags) => {
+                for pair in tags.pairs::<String, String>() {
+                    let (key, value) = pair?;
+                    post.tags.insert(key, value);
+                }
+                Ok(Verdict::Keep)
+            }
+            _ => Err(rlua::Error::RuntimeError(String::from(
+                "process must return nil, a string, or a table",
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+impl PostProcessor for Lua {
+    fn process(&mut self, context: ThreadContext, post: &mut Post) -> Verdict {
+        self.lua
+            .context(|ctx| Self::call(ctx, context, post))
+            .unwrap_or_else(|err| {
+                error!(
+                    "/{}/ No. {}: Lua post processor failed: {}",
+                    context.board, post.no, err
+                );
+                Verdict::Keep
+            })
+    }
+}
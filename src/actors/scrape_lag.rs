@@ -0,0 +1,149 @@
+//! Scrape lag: the time between a thread being modified and its new posts reaching the database.
+//! This is the best single sign of whether the archive is keeping up with its boards.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix::prelude::*;
+use chrono::prelude::*;
+use failure::{Error, ResultExt};
+use futures::prelude::*;
+use hyper::{client::HttpConnector, header, Body, Client, Request, Uri};
+use hyper_tls::HttpsConnector;
+use serde_json::json;
+
+use crate::{config::LagAlertConfig, four_chan::Board};
+
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+/// The scrape lag of a board since Ena started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LagStats {
+    /// The lag of the latest insert
+    pub latest: Duration,
+    pub max: Duration,
+    /// The number of inserts measured
+    pub samples: u64,
+    total: Duration,
+}
+
+impl LagStats {
+    pub fn average(&self) -> Duration {
+        if self.samples == 0 {
+            Duration::default()
+        } else {
+            self.total / self.samples as u32
+        }
+    }
+
+    fn record(&mut self, lag: Duration) {
+        self.latest = lag;
+        self.max = self.max.max(lag);
+        self.samples += 1;
+        self.total += lag;
+    }
+}
+
+/// Measures the scrape lag of each board and alerts when it goes above the threshold of
+/// `lag_alert`. It can be shared with the futures which insert posts.
+#[derive(Clone)]
+pub struct LagTracker(Arc<TrackerInner>);
+
+struct TrackerInner {
+    state: Mutex<TrackerState>,
+    threshold: Option<Duration>,
+    webhook: Option<(HttpsClient, Uri)>,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    boards: HashMap<Board, LagStats>,
+    /// Boards whose latest lag is above the threshold
+    lagging: HashSet<Board>,
+}
+
+impl LagTracker {
+    pub fn new(config: Option<&LagAlertConfig>) -> Result<Self, Error> {
+        let webhook = match config.and_then(|config| config.webhook_url.as_ref()) {
+            Some(url) => {
+                let uri = url
+                    .parse::<Uri>()
+                    .with_context(|_| format!("Invalid lag_alert.webhook_url: {}", url))?;
+                let https = HttpsConnector::new(1).context("Could not create HttpsConnector")?;
+                Some((Client::builder().build::<_, Body>(https), uri))
+            }
+            None => None,
+        };
+        Ok(LagTracker(Arc::new(TrackerInner {
+            state: Mutex::default(),
+            threshold: config.map(|config| config.threshold),
+            webhook,
+        })))
+    }
+
+    /// Record that posts of a thread last modified at `last_modified` reached the database.
+    pub fn record(&self, board: Board, last_modified: DateTime<Utc>) {
+        // The API's clock may be slightly ahead of ours
+        let lag = (Utc::now() - last_modified).to_std().unwrap_or_default();
+
+        let mut state = self.0.state.lock().unwrap();
+        state.boards.entry(board).or_default().record(lag);
+
+        let threshold = match self.0.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        if lag > threshold {
+            if state.lagging.insert(board) {
+                warn!(
+                    "/{}/: Scrape lag is {} seconds, above the threshold of {} seconds",
+                    board,
+                    lag.as_secs(),
+                    threshold.as_secs(),
+                );
+                self.alert(board, lag, threshold, true);
+            }
+        } else if state.lagging.remove(&board) {
+            info!(
+                "/{}/: Scrape lag is back to {} seconds",
+                board,
+                lag.as_secs()
+            );
+            self.alert(board, lag, threshold, false);
+        }
+    }
+
+    pub fn stats(&self) -> HashMap<Board, LagStats> {
+        self.0.state.lock().unwrap().boards.clone()
+    }
+
+    fn alert(&self, board: Board, lag: Duration, threshold: Duration, lagging: bool) {
+        let (client, uri) = match &self.0.webhook {
+            Some(webhook) => webhook,
+            None => return,
+        };
+        let body = json!({
+            "board": board,
+            "lag": lag.as_secs(),
+            "threshold": threshold.as_secs(),
+            "lagging": lagging,
+        });
+        let request = Request::post(uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        Arbiter::spawn(
+            client
+                .request(request)
+                .map(|res| {
+                    if !res.status().is_success() {
+                        warn!("Lag alert webhook responded with {}", res.status());
+                    }
+                })
+                .map_err(|err| warn!("Failed to send lag alert webhook: {}", err)),
+        );
+    }
+}
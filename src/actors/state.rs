@@ -0,0 +1,87 @@
+//! Saving and loading actor state so that it survives restarts.
+//!
+//! State is saved with the version of its format. Each actor has its own version, which must be
+//! increased whenever the format of its state changes (e.g. a field is added to a saved struct).
+//! State saved in another format can't be read, so it is discarded with a warning.
+
+use std::{
+    fs::{self, File},
+    io::{prelude::*, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use failure::{Error, ResultExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize)]
+struct Saved<T> {
+    version: u32,
+    state: T,
+}
+
+/// Only the version of saved state, so that it can be checked without parsing the state.
+#[derive(Deserialize)]
+struct SavedVersion {
+    version: u32,
+}
+
+/// Load the state saved in `<dir>/<name>.json`. If the file doesn't exist, or if the state was saved
+/// in another format than `version`, `None` is returned.
+pub fn load<T: DeserializeOwned>(dir: &Path, name: &str, version: u32) -> Result<Option<T>, Error> {
+    let path = state_file(dir, name);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents =
+        fs::read_to_string(&path).with_context(|_| format!("Could not read {}", path.display()))?;
+    match serde_json::from_str::<SavedVersion>(&contents) {
+        Ok(saved) if saved.version == version => {}
+        Ok(saved) => {
+            warn!(
+                "Discarding {}, which was saved in format version {} (expected {})",
+                path.display(),
+                saved.version,
+                version
+            );
+            return Ok(None);
+        }
+        Err(_) => {
+            warn!(
+                "Discarding {}, which was saved without a format version",
+                path.display()
+            );
+            return Ok(None);
+        }
+    }
+
+    let saved: Saved<T> = serde_json::from_str(&contents)
+        .with_context(|_| format!("Could not parse {}", path.display()))?;
+    Ok(Some(saved.state))
+}
+
+/// Save state to `<dir>/<name>.json`. The state is written to a temporary file which then replaces
+/// the old file, so a crash while saving won't corrupt previously saved state.
+pub fn save<T: Serialize>(dir: &Path, name: &str, version: u32, state: &T) -> Result<(), Error> {
+    let path = state_file(dir, name);
+    let temp_path = path.with_extension("json.tmp");
+
+    let file = File::create(&temp_path)
+        .with_context(|_| format!("Could not create {}", temp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &Saved { version, state })
+        .with_context(|_| format!("Could not write {}", temp_path.display()))?;
+    writer
+        .flush()
+        .with_context(|_| format!("Could not write {}", temp_path.display()))?;
+
+    fs::rename(&temp_path, &path)
+        .with_context(|_| format!("Could not replace {}", path.display()))?;
+    Ok(())
+}
+
+fn state_file(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.to_owned();
+    path.push(format!("{}.json", name));
+    path
+}
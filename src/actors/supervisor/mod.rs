@@ -0,0 +1,150 @@
+//! Restarting actors which panic, so that one bad message doesn't stop the whole scraper.
+
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use actix::{
+    dev::{channel, ContextFut},
+    msgs::Execute,
+    prelude::*,
+};
+use futures::prelude::*;
+use tokio::clock;
+
+use crate::config::AdvancedConfig;
+
+mod tests;
+
+/// How often an actor may be restarted: at most `max_restarts` times in any `window`.
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,
+    pub window: Duration,
+}
+
+impl RestartPolicy {
+    pub fn new(config: &AdvancedConfig) -> Self {
+        Self {
+            max_restarts: config.max_restarts,
+            window: config.restart_window,
+        }
+    }
+}
+
+/// Like Actix's `Supervisor`, except that the actor is also restarted if it panics while handling
+/// a message (or running a future spawned in its context). On a restart, the actor keeps its
+/// fields, `Supervised::restarting` is called to reset whatever the panic may have left
+/// inconsistent, and then the actor is started again. The actor isn't recreated, because Actix
+/// doesn't let a context's actor be replaced. So, fields which the handler changed before it
+/// panicked keep their changes, and actors must reset anything that could be left half-updated in
+/// `restarting`. The message which caused the panic is dropped, and its sender gets a
+/// `MailboxError`.
+///
+/// If the actor panics more often than its `RestartPolicy` allows, the `System` is stopped.
+///
+/// Panics in futures which are returned from handlers (e.g. `ResponseFuture`) run on the arbiter
+/// rather than in the actor's context, so they aren't caught, and they stop the arbiter.
+pub struct PanicSupervisor<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+{
+    name: &'static str,
+    fut: ContextFut<A, Context<A>>,
+    policy: RestartPolicy,
+    /// When the actor was restarted after panicking, within the last `policy.window`
+    restarts: VecDeque<Instant>,
+}
+
+impl<A> PanicSupervisor<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+{
+    /// Run an actor in the current arbiter with a previously created `Context`.
+    pub fn run(name: &'static str, policy: RestartPolicy, ctx: Context<A>, act: A) {
+        Arbiter::spawn(Self {
+            name,
+            fut: ctx.into_future(act),
+            policy,
+            restarts: VecDeque::new(),
+        });
+    }
+
+    /// Run an actor in another arbiter, with a mailbox of `capacity` messages.
+    pub fn start_in_arbiter(
+        name: &'static str,
+        policy: RestartPolicy,
+        arbiter: &Addr<Arbiter>,
+        capacity: usize,
+        act: A,
+    ) -> Addr<A>
+    where
+        A: Send,
+    {
+        let (sender, receiver) = channel::channel(capacity);
+        arbiter.do_send(Execute::new(move || -> Result<(), ()> {
+            Self::run(name, policy, Context::with_receiver(receiver), act);
+            Ok(())
+        }));
+        Addr::new(sender)
+    }
+
+    /// Record a restart, returning `false` if the actor has restarted too often.
+    fn allow_restart(&mut self) -> bool {
+        let now = clock::now();
+        while let Some(&restart) = self.restarts.front() {
+            if now - restart >= self.policy.window {
+                self.restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.restarts.len() as u32 >= self.policy.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+impl<A> Future for PanicSupervisor<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let fut = &mut self.fut;
+            match panic::catch_unwind(AssertUnwindSafe(|| fut.poll())) {
+                Ok(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+                // Like Actix's `Supervisor`, an actor which stops is restarted as long as it
+                // still has addresses
+                Ok(_) => {
+                    if !self.fut.restart() {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                Err(_) => {
+                    if !self.allow_restart() {
+                        error!(
+                            "The {} actor panicked {} times in {} seconds, stopping",
+                            self.name,
+                            self.restarts.len() + 1,
+                            self.policy.window.as_secs(),
+                        );
+                        System::current().stop();
+                        return Ok(Async::Ready(()));
+                    }
+                    error!("The {} actor panicked, restarting it", self.name);
+                    if !self.fut.restart() {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+        }
+    }
+}
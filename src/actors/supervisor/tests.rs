@@ -0,0 +1,149 @@
+#![cfg(test)]
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use futures::{future, prelude::*};
+use tokio::timer::Delay;
+
+use super::{PanicSupervisor, RestartPolicy};
+
+#[derive(Default)]
+struct Counter {
+    count: u32,
+    restarts: u32,
+}
+
+impl Actor for Counter {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Counter {
+    fn restarting(&mut self, _ctx: &mut Self::Context) {
+        self.restarts += 1;
+    }
+}
+
+/// Add to the count, and then panic if the flag is set. Replies with the count and the number of
+/// restarts.
+struct Add(u32, bool);
+impl Message for Add {
+    type Result = (u32, u32);
+}
+
+impl Handler<Add> for Counter {
+    type Result = MessageResult<Add>;
+
+    fn handle(&mut self, msg: Add, _ctx: &mut Self::Context) -> Self::Result {
+        self.count += msg.0;
+        if msg.1 {
+            panic!("Add panicked");
+        }
+        MessageResult((self.count, self.restarts))
+    }
+}
+
+/// Reply with a future which panics.
+struct PanicLater;
+impl Message for PanicLater {
+    type Result = Result<(), ()>;
+}
+
+impl Handler<PanicLater> for Counter {
+    type Result = ResponseFuture<(), ()>;
+
+    fn handle(&mut self, _: PanicLater, _ctx: &mut Self::Context) -> Self::Result {
+        Box::new(future::lazy(|| -> Result<(), ()> {
+            panic!("PanicLater panicked")
+        }))
+    }
+}
+
+fn start(max_restarts: u32) -> Addr<Counter> {
+    let ctx = Context::new();
+    let addr = ctx.address();
+    let policy = RestartPolicy {
+        max_restarts,
+        window: Duration::from_secs(60),
+    };
+    PanicSupervisor::run("counter", policy, ctx, Counter::default());
+    addr
+}
+
+/// Stop the system if the test hasn't stopped it in time, and return whether that happened.
+fn timeout() -> Arc<AtomicBool> {
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let flag = timed_out.clone();
+    Arbiter::spawn(
+        Delay::new(Instant::now() + Duration::from_secs(10))
+            .map(move |()| {
+                flag.store(true, Ordering::SeqCst);
+                System::current().stop();
+            })
+            .map_err(|err| panic!("{}", err)),
+    );
+    timed_out
+}
+
+#[test]
+fn handler_panic() {
+    let sys = System::new("test");
+    let addr = start(1);
+    let finished = Arc::new(AtomicBool::new(false));
+    let flag = finished.clone();
+    Arbiter::spawn(
+        addr.send(Add(1, false))
+            .and_then(move |res| {
+                assert_eq!(res, (1, 0));
+                addr.send(Add(2, true)).then(move |res| {
+                    // The message which panicked is dropped
+                    assert!(res.is_err());
+                    addr.send(Add(4, false))
+                })
+            })
+            .map(move |res| {
+                // The actor is restarted in place, so the change made before the panic is kept
+                assert_eq!(res, (7, 1));
+                flag.store(true, Ordering::SeqCst);
+                System::current().stop();
+            })
+            .map_err(|err| panic!("{}", err)),
+    );
+    let timed_out = timeout();
+    sys.run();
+    assert!(finished.load(Ordering::SeqCst));
+    assert!(!timed_out.load(Ordering::SeqCst));
+}
+
+#[test]
+fn too_many_restarts() {
+    let sys = System::new("test");
+    let addr = start(1);
+    addr.do_send(Add(1, true));
+    addr.do_send(Add(1, true));
+    addr.do_send(Add(1, false));
+    let timed_out = timeout();
+    sys.run();
+    // The second panic stopped the system
+    assert!(!timed_out.load(Ordering::SeqCst));
+}
+
+#[test]
+fn returned_future_panic() {
+    // Panics in returned futures aren't caught by the supervisor
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let sys = System::new("test");
+        let addr = start(1);
+        addr.do_send(PanicLater);
+        timeout();
+        sys.run();
+    }));
+    assert!(result.is_err());
+}
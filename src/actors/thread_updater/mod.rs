@@ -0,0 +1,1126 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    mem,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix::{
+    actors::signal::{ProcessSignals, Signal, SignalType, Subscribe},
+    dev::ToEnvelope,
+    prelude::*,
+};
+use chrono::prelude::*;
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use log::Level;
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash;
+
+use super::{
+    activity::ActivityTracker,
+    board_poller::*,
+    database::*,
+    fetcher::*,
+    lru_map::LruMap,
+    post_processor::{PostProcessor, ThreadContext, Verdict},
+    scrape_lag::{LagStats, LagTracker},
+    state,
+    write_backlog::WriteBacklog,
+};
+use crate::{
+    config::{Config, ScrapingConfig, ThreadFilter},
+    four_chan::{Board, Capcode, OpData, Post, PostNo, ThreadNo},
+};
+
+mod tests;
+
+const STATE_NAME: &str = "thread_updater";
+/// The version of the format of the saved state (see `state`)
+const STATE_VERSION: u32 = 2;
+
+/// The minimum number of posts a thread must have before we fetch its tail JSON. Tails have around
+/// 50 replies, so fetching the tail of a shorter thread wouldn't save much.
+const TAIL_MIN_POSTS: usize = 100;
+
+/// An actor which updates threads when it receives change notifications from
+/// [`BoardPoller`](struct.BoardPoller.html).
+pub struct ThreadUpdater {
+    thread_meta: LruMap<(Board, ThreadNo), ThreadMetadata>,
+    /// The number of threads evicted from `thread_meta` because it was full
+    evicted_threads: u64,
+    /// Boards with restored metadata that hasn't been checked against a thread list yet
+    restored_boards: HashSet<Board>,
+    /// Threads whose OP doesn't match their board's `thread_filter`. They are ignored until they
+    /// leave the thread list.
+    ignored_threads: HashSet<(Board, ThreadNo)>,
+    /// Like `ignored_threads`, but for threads in the archive, which are ignored until they leave
+    /// archive.json
+    ignored_archived_threads: HashMap<Board, HashSet<ThreadNo>>,
+    /// Boards whose custom spoiler images have been requested
+    spoiler_boards: HashSet<Board>,
+    boards: Arc<HashMap<Board, ScrapingConfig>>,
+    fetcher: Arc<Addr<Fetcher>>,
+    database: Addr<Database>,
+    /// Actors which mirror the posts sent to `database`
+    post_sinks: Vec<Recipient<PostsInserted>>,
+    /// Actors which receive the final state of threads once they're archived
+    archive_sinks: Vec<Recipient<ThreadArchived>>,
+    /// Run on new and modified posts before they're sent to `database`
+    post_processors: Vec<Box<dyn PostProcessor>>,
+    lag: LagTracker,
+    backlog: WriteBacklog,
+    activity: ActivityTracker,
+    /// How often to log a summary of `activity`
+    activity_log_interval: Option<Duration>,
+    state_path: Option<PathBuf>,
+    save_interval: Duration,
+    /// Whether the database buffers inserts, which must be written before stopping
+    write_buffer: bool,
+}
+
+impl Actor for ThreadUpdater {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.state_path.is_some() || self.write_buffer {
+            ProcessSignals::from_registry().do_send(Subscribe(ctx.address().recipient()));
+        }
+        if self.state_path.is_some() {
+            ctx.run_interval(self.save_interval, |act, _ctx| act.save_state());
+        }
+
+        if let Some(interval) = self.activity_log_interval {
+            ctx.run_interval(interval, |act, _ctx| {
+                let mut boards: Vec<Board> = act.boards.keys().cloned().collect();
+                boards.sort();
+                act.activity.log_summary(&boards, &act.lag.stats());
+            });
+        }
+    }
+}
+
+impl ThreadUpdater {
+    pub fn new(
+        config: &Config,
+        database: Addr<Database>,
+        fetcher: Addr<Fetcher>,
+        post_sinks: Vec<Recipient<PostsInserted>>,
+        archive_sinks: Vec<Recipient<ThreadArchived>>,
+        post_processors: Vec<Box<dyn PostProcessor>>,
+        lag: LagTracker,
+        backlog: WriteBacklog,
+    ) -> Self {
+        let mut thread_meta = LruMap::new(config.advanced.max_tracked_threads);
+        if let Some(state_path) = &config.state.path {
+            match state::load::<Vec<((Board, ThreadNo), ThreadMetadata)>>(
+                state_path,
+                STATE_NAME,
+                STATE_VERSION,
+            ) {
+                Ok(Some(saved)) => {
+                    // Saved metadata is ordered from least to most recently used, so if the cap
+                    // was lowered, the oldest threads are the ones dropped
+                    for (key, meta) in saved {
+                        if config.boards.contains_key(&key.0) {
+                            thread_meta.insert(key, meta);
+                        }
+                    }
+                    info!("Restored metadata of {} threads", thread_meta.len());
+                }
+                Ok(None) => {}
+                Err(err) => log_error!(err.as_fail()),
+            }
+        }
+        let restored_boards = thread_meta.keys().map(|&(board, _)| board).collect();
+
+        Self {
+            thread_meta,
+            evicted_threads: 0,
+            restored_boards,
+            ignored_threads: HashSet::new(),
+            ignored_archived_threads: HashMap::new(),
+            spoiler_boards: HashSet::new(),
+            boards: config.boards.clone(),
+            fetcher: Arc::new(fetcher),
+            database,
+            post_sinks,
+            archive_sinks,
+            post_processors,
+            lag,
+            backlog,
+            activity: ActivityTracker::default(),
+            activity_log_interval: config
+                .activity_log
+                .as_ref()
+                .map(|activity_log| activity_log.interval),
+            state_path: config.state.path.clone(),
+            save_interval: config.state.save_interval,
+            write_buffer: config.database_media.write_buffer.is_some(),
+        }
+    }
+
+    /// Start tracking a thread, evicting the least recently updated thread if `thread_meta` is full.
+    /// Stickies are never evicted.
+    fn track_thread(&mut self, board: Board, no: ThreadNo, meta: ThreadMetadata) {
+        let evicted = self
+            .thread_meta
+            .insert_pinned((board, no), meta, |meta| meta.op_data.sticky);
+        if let Some(((board, no), _)) = evicted {
+            self.evicted_threads += 1;
+            debug!(
+                "/{}/ No. {}: Too many tracked threads, forgetting",
+                board, no
+            );
+        }
+    }
+
+    fn save_state(&self) {
+        if let Some(state_path) = &self.state_path {
+            let thread_meta: Vec<_> = self.thread_meta.iter().collect();
+            match state::save(state_path, STATE_NAME, STATE_VERSION, &thread_meta) {
+                Ok(()) => debug!("Saved metadata of {} threads", thread_meta.len()),
+                Err(err) => log_error!(err.as_fail()),
+            }
+        }
+    }
+
+    /// Run the post processors on a post, returning the strictest verdict.
+    fn process_post(&mut self, board: Board, thread: ThreadNo, post: &mut Post) -> Verdict {
+        let context = ThreadContext { board, thread };
+        let mut verdict = Verdict::Keep;
+        for processor in &mut self.post_processors {
+            verdict = verdict.max(processor.process(context, post));
+            if verdict == Verdict::Skip {
+                break;
+            }
+        }
+        if verdict == Verdict::Skip && post.is_op() {
+            warn!("/{}/ No. {}: Post processors can't skip OPs", board, thread);
+            Verdict::Keep
+        } else {
+            verdict
+        }
+    }
+
+    /// Insert posts, and if `last_modified` is given, measure the scrape lag of the insert.
+    fn insert_posts(
+        &mut self,
+        board: Board,
+        no: ThreadNo,
+        mut posts: Vec<Post>,
+        last_modified: Option<DateTime<Utc>>,
+    ) {
+        let mut skipped_media = HashSet::new();
+        if !self.post_processors.is_empty() {
+            posts = posts
+                .into_iter()
+                .filter_map(|mut post| match self.process_post(board, no, &mut post) {
+                    Verdict::Keep => Some(post),
+                    Verdict::SkipMedia => {
+                        if let Some(image) = &post.image {
+                            skipped_media.insert(format!("{}{}", image.time_millis, image.ext));
+                            skipped_media.insert(format!("{}s.jpg", image.time_millis));
+                        }
+                        Some(post)
+                    }
+                    Verdict::Skip => None,
+                })
+                .collect();
+        }
+
+        if !posts.is_empty() {
+            self.fetch_spoilers(board, &posts);
+
+            if !self.post_sinks.is_empty() {
+                let summaries = Arc::new(posts.iter().map(PostSummary::new).collect::<Vec<_>>());
+                for sink in &self.post_sinks {
+                    if let Err(err) = sink.do_send(PostsInserted(board, summaries.clone())) {
+                        error!("/{}/: Failed to send posts to sink: {}", board, err);
+                    }
+                }
+            }
+
+            let database = self.database.clone();
+            let fetcher = self.fetcher.clone();
+            let lag = self.lag.clone();
+            let (activity, failures) = (self.activity.clone(), self.activity.clone());
+            let post_count = posts.len();
+            self.backlog.spawn(
+                board,
+                future::lazy(move || database.send(InsertPosts(board, no, posts)))
+                    .map_err(|err| log_error!(&err))
+                    .and_then(|res| res.map_err(|err| error!("{}", err)))
+                    .map_err(move |()| failures.failed(board))
+                    .and_then(move |mut filenames| {
+                        if let Some(last_modified) = last_modified {
+                            lag.record(board, last_modified);
+                        }
+                        activity.posts_inserted(board, post_count);
+                        filenames.retain(|filename| !skipped_media.contains(filename));
+                        activity.media_queued(board, filenames.len());
+                        if filenames.is_empty() {
+                            Either::A(future::ok(()))
+                        } else {
+                            Either::B(
+                                fetcher
+                                    .send(FetchMedia(board, filenames))
+                                    .map_err(|err| error!("{}", err)),
+                            )
+                        }
+                    }),
+            );
+        }
+    }
+
+    /// Spoilered images on boards with custom spoilers are shown with one of the board's spoiler
+    /// images (chosen by `custom_spoiler`). The first time we see one, we download all of them.
+    fn fetch_spoilers(&mut self, board: Board, posts: &[Post]) {
+        if !self.boards[&board].download_thumbs || self.spoiler_boards.contains(&board) {
+            return;
+        }
+        let has_custom_spoiler = posts.iter().any(|post| {
+            post.custom_spoiler.is_some() && post.image.as_ref().map_or(false, |i| i.spoiler)
+        });
+        if has_custom_spoiler {
+            debug!("/{}/: Fetching custom spoilers", board);
+            self.spoiler_boards.insert(board);
+            self.fetcher.do_send(FetchSpoilers(board));
+        }
+    }
+
+    /// The priority of a refetch of a tracked thread. Stickies (as of their last fetch, since
+    /// `threads.json` doesn't say) are fetched first.
+    fn refetch_priority(&self, board: Board, no: ThreadNo) -> FetchPriority {
+        match self.thread_meta.get(&(board, no)) {
+            Some(meta) if meta.op_data.sticky => FetchPriority::Sticky,
+            _ => FetchPriority::Normal,
+        }
+    }
+
+    fn fetch_threads(
+        &self,
+        board: Board,
+        nums: Vec<ThreadNo>,
+        from_archive_json: bool,
+        json: ThreadJson,
+        priority: FetchPriority,
+    ) {
+        if !nums.is_empty() {
+            Arbiter::spawn(
+                self.fetcher
+                    .send(FetchThreads(board, nums, from_archive_json, json, priority))
+                    .map_err(|err| log_error!(&err)),
+            );
+        }
+    }
+
+    fn modify_posts(
+        &self,
+        board: Board,
+        modified_posts: Vec<(PostNo, Option<String>, Option<bool>, bool)>,
+    ) {
+        if !modified_posts.is_empty() {
+            self.write(board, UpdatePost(board, modified_posts));
+        }
+    }
+
+    fn update_op_data(&self, board: Board, no: ThreadNo, op_data: OpData, op: &Post) {
+        self.write(
+            board,
+            UpdateOp(board, no, op_data, op.since4pass, op.tags.clone()),
+        );
+    }
+
+    fn insert_raw_posts(&self, board: Board, raw_posts: Vec<(PostNo, String)>) {
+        if !raw_posts.is_empty() {
+            self.write(board, InsertRawPosts(board, raw_posts, Utc::now()));
+        }
+    }
+
+    fn remove_posts(
+        &self,
+        board: Board,
+        removed_posts: Vec<(PostNo, RemovedStatus)>,
+        time: DateTime<Utc>,
+    ) {
+        if !removed_posts.is_empty() {
+            self.write(board, MarkPostsRemoved(board, removed_posts, time));
+        }
+    }
+
+    /// Send a write to the database through the write backlog, so that writes are sent in order
+    /// and only a few at a time.
+    fn write<M, E>(&self, board: Board, msg: M)
+    where
+        M: Message<Result = Result<(), E>> + Send + 'static,
+        E: Display + Send + 'static,
+        Database: Handler<M>,
+        <Database as Actor>::Context: ToEnvelope<Database, M>,
+    {
+        let database = self.database.clone();
+        let activity = self.activity.clone();
+        self.backlog.spawn(
+            board,
+            future::lazy(move || database.send(msg))
+                .map_err(|err| error!("{}", err))
+                .and_then(|res| res.map_err(|err| error!("{}", err)))
+                .map_err(move |()| activity.failed(board)),
+        );
+    }
+
+    fn process_modified(
+        &mut self,
+        board: Board,
+        no: ThreadNo,
+        mut thread: Vec<Post>,
+        last_modified: DateTime<Utc>,
+        curr_meta: &ThreadMetadata,
+        prev_meta: &ThreadMetadata,
+    ) {
+        let mut new_posts = vec![];
+        let mut modified_posts = vec![];
+        let mut deleted_posts = vec![];
+        let mut raw_posts = vec![];
+
+        // The OP's tags are stored with its OP data, so it is processed here if that changed
+        let mut op_processed = false;
+        if op_data_changed(&prev_meta.op_data, &curr_meta.op_data) {
+            debug!("/{}/ No. {}: Updating OP data", board, no);
+            self.process_post(board, no, &mut thread[0]);
+            op_processed = true;
+            self.update_op_data(board, no, curr_meta.op_data.clone(), &thread[0]);
+            raw_posts.extend(take_raw_json(&mut thread[..1]));
+        }
+
+        let mut prev_iter = prev_meta.posts.iter();
+        let mut curr_iter = curr_meta.posts.iter().enumerate();
+
+        let mut curr_meta = curr_iter.next();
+
+        loop {
+            match (prev_iter.next(), curr_meta) {
+                (Some(prev), Some((i, curr))) => {
+                    if prev.no == curr.no {
+                        if prev.metadata != curr.metadata {
+                            let image = thread[i].image.as_ref();
+                            let spoiler = image.map(|i| i.spoiler);
+                            // The image fields might be removed entirely when a file is deleted
+                            let file_deleted =
+                                image.map_or(prev.metadata.1.is_some(), |i| i.filedeleted);
+                            let skip = if i == 0 && op_processed {
+                                false
+                            } else {
+                                self.process_post(board, no, &mut thread[i]) == Verdict::Skip
+                            };
+                            if !skip {
+                                modified_posts.push((
+                                    thread[i].no,
+                                    thread[i].comment.take(),
+                                    spoiler,
+                                    file_deleted,
+                                ));
+                            }
+                            raw_posts.extend(take_raw_json(&mut thread[i..=i]));
+                        }
+                        curr_meta = curr_iter.next();
+                    } else {
+                        deleted_posts.push((prev.no, RemovedStatus::Deleted));
+                    }
+                }
+                (Some(prev), None) => {
+                    deleted_posts.push((prev.no, RemovedStatus::Deleted));
+                }
+                (None, Some((i, _))) => {
+                    new_posts = thread.split_off(i);
+                    raw_posts.extend(take_raw_json(&mut new_posts));
+                    break;
+                }
+                (None, None) => break,
+            }
+        }
+
+        if log_enabled!(Level::Debug) {
+            let new = new_posts.len();
+            let modified = modified_posts.len();
+            let deleted = deleted_posts.len();
+
+            // There might not always be post updates (e.g. only OP data was updated)
+            if (new + modified + deleted) > 0 {
+                debug!(
+                    "/{}/ No. {}: {}",
+                    board,
+                    no,
+                    nonzero_list_format!(
+                        "{} new",
+                        new,
+                        "{} modified",
+                        modified,
+                        "{} deleted",
+                        deleted,
+                    ),
+                );
+            }
+        }
+
+        self.insert_posts(board, no, new_posts, Some(last_modified));
+        self.modify_posts(board, modified_posts);
+        self.remove_posts(board, deleted_posts, last_modified);
+        self.insert_raw_posts(board, raw_posts);
+    }
+
+    fn process_thread(&mut self, msg: FetchedThread) {
+        let FetchedThread { request, result } = msg;
+        let FetchThread(board, no, from_archive_json, json) = request;
+
+        match &result {
+            Err(FetchError::NotModified) | Err(FetchError::NotFound(_)) | Ok(_) => {
+                self.activity.thread_polled(board)
+            }
+            Err(_) => self.activity.failed(board),
+        }
+        match result {
+            Ok((mut thread, last_modified)) => {
+                // Sort ascending by no. The posts should already be sorted, but I have seen one
+                // case where they weren't. So it's better to be safe.
+                thread.sort_by(|a, b| a.no.cmp(&b.no));
+
+                let mut curr_meta = ThreadMetadata::from_thread(&thread);
+                let prev_meta = self.thread_meta.remove(&(board, no));
+                if let Some(prev_meta) = &prev_meta {
+                    // `unique_ips` is removed when a thread is archived, so we keep the last value
+                    if curr_meta.op_data.unique_ips.is_none() {
+                        curr_meta.op_data.unique_ips = prev_meta.op_data.unique_ips;
+                    }
+                }
+                // A tail is only part of a thread, so it can't be exported. This is rare, since
+                // archived threads leave threads.json and are then fetched in full.
+                let archived_json = if curr_meta.op_data.archived
+                    && json != ThreadJson::Tail
+                    && !self.archive_sinks.is_empty()
+                {
+                    match serde_json::to_string(&ThreadJsonOut { posts: &thread }) {
+                        Ok(archived_json) => Some(Arc::new(archived_json)),
+                        Err(err) => {
+                            error!(
+                                "/{}/ No. {}: Failed to serialize thread: {}",
+                                board, no, err
+                            );
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let curr_meta = match (prev_meta, json) {
+                    (Some(prev_meta), ThreadJson::Tail) => match prev_meta.split_tail(&curr_meta) {
+                        Ok((head, prev_meta)) => {
+                            self.process_modified(
+                                board,
+                                no,
+                                thread,
+                                last_modified,
+                                &curr_meta,
+                                &prev_meta,
+                            );
+                            curr_meta.join_head(head)
+                        }
+                        Err(prev_meta) => {
+                            debug!(
+                                "/{}/ No. {}: Tail doesn't cover all new posts, fetching full thread",
+                                board, no,
+                            );
+                            self.track_thread(board, no, prev_meta);
+                            self.fetch_threads(
+                                board,
+                                vec![no],
+                                from_archive_json,
+                                ThreadJson::Fallback,
+                                self.refetch_priority(board, no),
+                            );
+                            return;
+                        }
+                    },
+                    (Some(prev_meta), _) => {
+                        self.process_modified(
+                            board,
+                            no,
+                            thread,
+                            last_modified,
+                            &curr_meta,
+                            &prev_meta,
+                        );
+                        curr_meta
+                    }
+                    (None, ThreadJson::Tail) => {
+                        // We stopped tracking this thread while its tail was being fetched. A tail
+                        // is only part of a thread, so we need the full thread to insert it.
+                        self.fetch_threads(
+                            board,
+                            vec![no],
+                            from_archive_json,
+                            ThreadJson::Fallback,
+                            FetchPriority::Normal,
+                        );
+                        return;
+                    }
+                    (None, _) => {
+                        let filtered = self.boards[&board]
+                            .thread_filter
+                            .as_ref()
+                            .map_or(false, |filter| !matches_filter(filter, &thread[0]));
+                        if filtered {
+                            debug!("/{}/ No. {}: Filtered out, ignoring", board, no);
+                            if from_archive_json {
+                                self.ignored_archived_threads
+                                    .entry(board)
+                                    .or_default()
+                                    .insert(no);
+                            } else {
+                                self.ignored_threads.insert((board, no));
+                            }
+                            return;
+                        }
+                        debug!("/{}/ No. {}: Inserting thread", board, no);
+                        self.insert_raw_posts(board, take_raw_json(&mut thread));
+                        // A thread we haven't seen before may have been modified long ago (e.g. when
+                        // Ena starts), so its lag isn't measured
+                        self.insert_posts(board, no, thread, None);
+                        curr_meta
+                    }
+                };
+
+                if let Some(archived_json) = archived_json {
+                    for sink in &self.archive_sinks {
+                        if let Err(err) =
+                            sink.do_send(ThreadArchived(board, no, archived_json.clone()))
+                        {
+                            error!(
+                                "/{}/ No. {}: Failed to send archived thread to sink: {}",
+                                board, no, err,
+                            );
+                        }
+                    }
+                }
+
+                if !curr_meta.op_data.archived {
+                    self.track_thread(board, no, curr_meta);
+                }
+            }
+            Err(err) => match err {
+                FetchError::NotModified => {}
+                // A missing tail doesn't mean that the thread is gone, so we check the full thread
+                FetchError::NotFound(_) if json == ThreadJson::Tail => {
+                    self.fetch_threads(
+                        board,
+                        vec![no],
+                        from_archive_json,
+                        ThreadJson::Fallback,
+                        self.refetch_priority(board, no),
+                    );
+                }
+                FetchError::NotFound(_) => {
+                    if from_archive_json {
+                        // If a thread loaded from archive.json 404's, then it expired before we
+                        // could process it, and was not deleted. So, we don't mark it as such.
+                        warn!(
+                            "/{}/ No. {}: Archived thread expired before it could be processed",
+                            board, no,
+                        );
+                    } else {
+                        warn!(
+                            "/{}/ No. {}: Thread deleted before it could be processed",
+                            board, no,
+                        );
+                        self.thread_meta.remove(&(board, no));
+                        self.remove_posts(
+                            board,
+                            vec![(no.into(), RemovedStatus::Deleted)],
+                            Utc::now(),
+                        );
+                    }
+                }
+                _ => error!("/{}/ No. {} fetch failed: {}", board, no, err),
+            },
+        }
+    }
+}
+
+/// Whether a thread's OP matches at least one of the regexes of a `thread_filter`.
+fn matches_filter(filter: &ThreadFilter, op: &Post) -> bool {
+    let filename = op
+        .image
+        .as_ref()
+        .map(|image| format!("{}{}", image.filename, image.ext));
+    [
+        (&filter.subject, op.subject.as_ref()),
+        (&filter.comment, op.comment.as_ref()),
+        (&filter.filename, filename.as_ref()),
+    ]
+    .iter()
+    .any(|(regex, text)| match (regex, text) {
+        (Some(regex), Some(text)) => regex.is_match(text),
+        _ => false,
+    })
+}
+
+/// Take the raw JSON (if any) out of posts.
+fn take_raw_json(posts: &mut [Post]) -> Vec<(PostNo, String)> {
+    posts
+        .iter_mut()
+        .filter_map(|post| post.raw_json.take().map(|raw| (post.no, raw)))
+        .collect()
+}
+
+/// An event sent to the post sinks of `ThreadUpdater` whenever posts are sent to the database.
+#[derive(Message)]
+pub struct PostsInserted(pub Board, pub Arc<Vec<PostSummary>>);
+
+/// An event sent to the archive sinks of `ThreadUpdater` when a thread is archived. The last field
+/// is the thread in the same format as the 4chan API (`{"posts":[...]}`).
+///
+/// Archived threads are no longer tracked, so this is sent once per thread with its final state.
+#[derive(Message)]
+pub struct ThreadArchived(pub Board, pub ThreadNo, pub Arc<String>);
+
+#[derive(Serialize)]
+struct ThreadJsonOut<'a> {
+    posts: &'a [Post],
+}
+
+/// The metadata of a post.
+pub struct PostSummary {
+    pub num: PostNo,
+    pub thread_num: ThreadNo,
+    pub op: bool,
+    pub timestamp: u64,
+    pub capcode: char,
+    pub country: Option<String>,
+    pub poster_hash: Option<String>,
+    pub has_media: bool,
+}
+
+impl PostSummary {
+    fn new(post: &Post) -> Self {
+        Self {
+            num: post.no,
+            thread_num: post.thread_no(),
+            op: post.is_op(),
+            timestamp: post.time,
+            capcode: post.capcode.as_ref().map_or('N', Capcode::to_asagi),
+            country: post.country.clone(),
+            poster_hash: post.id.clone(),
+            has_media: post
+                .image
+                .as_ref()
+                .map_or(false, |image| !image.filedeleted),
+        }
+    }
+}
+
+#[derive(Message)]
+pub struct FetchedThread {
+    pub request: FetchThread,
+    pub result: Result<(Vec<Post>, DateTime<Utc>), FetchError>,
+}
+
+impl Handler<FetchedThread> for ThreadUpdater {
+    type Result = ();
+
+    fn handle(&mut self, msg: FetchedThread, _: &mut Self::Context) {
+        self.process_thread(msg);
+    }
+}
+
+impl Handler<BoardUpdate> for ThreadUpdater {
+    type Result = ();
+
+    fn handle(&mut self, msg: BoardUpdate, _: &mut Self::Context) {
+        let mut urgent_threads_to_fetch = vec![];
+        let mut threads_to_fetch = vec![];
+        let mut tails_to_fetch = vec![];
+        let mut sticky_threads_to_fetch = vec![];
+        let mut sticky_tails_to_fetch = vec![];
+        let mut removed_threads = vec![];
+        let BoardUpdate(board, mut updates, last_modified) = msg;
+        let use_tail_json = self.boards[&board].use_tail_json;
+
+        // BoardPoller doesn't know about threads that were removed while we were stopped. So, on
+        // the first update of a board with restored metadata, we assume that every tracked thread
+        // missing from the thread list was bumped off.
+        if self.restored_boards.remove(&board) {
+            let listed: HashSet<ThreadNo> = updates
+                .iter()
+                .map(|update| match *update {
+                    ThreadUpdate::New(no)
+                    | ThreadUpdate::Modified(no)
+                    | ThreadUpdate::BumpedOff(no)
+                    | ThreadUpdate::Deleted(no) => no,
+                })
+                .collect();
+            updates.extend(
+                self.thread_meta
+                    .keys()
+                    .filter(|&&(b, no)| b == board && !listed.contains(&no))
+                    .map(|&(_, no)| ThreadUpdate::BumpedOff(no)),
+            );
+        }
+
+        for thread in updates {
+            use ThreadUpdate::*;
+            match thread {
+                New(no) | Modified(no) if self.ignored_threads.contains(&(board, no)) => {}
+                BumpedOff(no) | Deleted(no) if self.ignored_threads.remove(&(board, no)) => {
+                    // Keep ignoring the thread once it's in the archive, so that it isn't fetched
+                    // again when archive.json is
+                    if let BumpedOff(_) = thread {
+                        if board.is_archived() {
+                            self.ignored_archived_threads
+                                .entry(board)
+                                .or_default()
+                                .insert(no);
+                        }
+                    }
+                }
+                New(no) => urgent_threads_to_fetch.push(no),
+                Modified(no) => {
+                    let long_thread = self
+                        .thread_meta
+                        .get(&(board, no))
+                        .map_or(false, ThreadMetadata::is_long);
+                    let sticky = self.refetch_priority(board, no) == FetchPriority::Sticky;
+                    match (use_tail_json && long_thread, sticky) {
+                        (true, true) => sticky_tails_to_fetch.push(no),
+                        (true, false) => tails_to_fetch.push(no),
+                        (false, true) => sticky_threads_to_fetch.push(no),
+                        (false, false) => threads_to_fetch.push(no),
+                    }
+                }
+                BumpedOff(no) => {
+                    // If this thread isn't in the map, it's already been archived or deleted
+                    if self.thread_meta.contains_key(&(board, no)) {
+                        if board.is_archived() && self.boards[&board].refetch_archived_threads {
+                            debug!("/{}/ No. {}: Bumped off, refetching", board, no);
+                            urgent_threads_to_fetch.push(no);
+                        } else {
+                            debug!("/{}/ No. {}: Bumped off", board, no);
+                            if board.is_archived() || self.boards[&board].always_add_archive_times {
+                                removed_threads.push((no.into(), RemovedStatus::Archived));
+                            }
+                            self.thread_meta.remove(&(board, no));
+                        }
+                    }
+                }
+                Deleted(no) => {
+                    // If this thread isn't in the map, then we've already handled its deletion
+                    if self.thread_meta.remove(&(board, no)).is_some() {
+                        debug!("/{}/ No. {} was deleted", board, no);
+                        removed_threads.push((no.into(), RemovedStatus::Deleted));
+                    }
+                }
+            }
+        }
+        self.remove_posts(board, removed_threads, last_modified);
+        self.fetch_threads(
+            board,
+            sticky_threads_to_fetch,
+            false,
+            ThreadJson::Full,
+            FetchPriority::Sticky,
+        );
+        self.fetch_threads(
+            board,
+            sticky_tails_to_fetch,
+            false,
+            ThreadJson::Tail,
+            FetchPriority::Sticky,
+        );
+        self.fetch_threads(
+            board,
+            urgent_threads_to_fetch,
+            false,
+            ThreadJson::Full,
+            FetchPriority::High,
+        );
+        self.fetch_threads(
+            board,
+            threads_to_fetch,
+            false,
+            ThreadJson::Full,
+            FetchPriority::Normal,
+        );
+        self.fetch_threads(
+            board,
+            tails_to_fetch,
+            false,
+            ThreadJson::Tail,
+            FetchPriority::Normal,
+        );
+    }
+}
+
+impl Handler<ArchiveUpdate> for ThreadUpdater {
+    type Result = ();
+
+    fn handle(&mut self, msg: ArchiveUpdate, ctx: &mut Self::Context) {
+        let ArchiveUpdate(board, mut nums) = msg;
+        if let Some(ignored) = self.ignored_archived_threads.get_mut(&board) {
+            let listed: HashSet<ThreadNo> = nums.iter().cloned().collect();
+            ignored.retain(|no| listed.contains(no));
+            nums.retain(|no| !ignored.contains(no));
+        }
+        ctx.spawn(
+            self.database
+                .send(GetUnarchivedThreads(board, nums))
+                .into_actor(self)
+                .map(move |res, act, _| match res {
+                    Ok(threads) => {
+                        let len = threads.len();
+                        debug!(
+                            "/{}/: Found {} new archived thread{}",
+                            board,
+                            len,
+                            if len == 1 { "" } else { "s" },
+                        );
+                        act.fetch_threads(
+                            board,
+                            threads,
+                            true,
+                            ThreadJson::Full,
+                            FetchPriority::High,
+                        );
+                    }
+                    Err(err) => error!("/{}/: Failed to process archived threads: {}", board, err),
+                })
+                .map_err(move |err, _act, _ctx| {
+                    error!("/{}/: Failed to process archived threads: {}", board, err)
+                }),
+        );
+    }
+}
+
+/// Forget the threads of a board which is no longer polled (see `Coordinator`). If it's polled
+/// again, its threads are fetched in full.
+#[derive(Message)]
+pub struct ForgetBoard(pub Board);
+
+impl Handler<ForgetBoard> for ThreadUpdater {
+    type Result = ();
+
+    fn handle(&mut self, msg: ForgetBoard, _: &mut Self::Context) {
+        let board = msg.0;
+        let threads: Vec<(Board, ThreadNo)> = self
+            .thread_meta
+            .keys()
+            .filter(|&&(b, _)| b == board)
+            .cloned()
+            .collect();
+        for key in &threads {
+            self.thread_meta.remove(key);
+        }
+        self.restored_boards.remove(&board);
+        self.ignored_threads.retain(|&(b, _)| b != board);
+        self.ignored_archived_threads.remove(&board);
+    }
+}
+
+/// Get the scrape lag of each board.
+pub struct GetScrapeLag;
+impl Message for GetScrapeLag {
+    type Result = Result<HashMap<Board, LagStats>, ()>;
+}
+
+impl Handler<GetScrapeLag> for ThreadUpdater {
+    type Result = Result<HashMap<Board, LagStats>, ()>;
+
+    fn handle(&mut self, _: GetScrapeLag, _: &mut Self::Context) -> Self::Result {
+        Ok(self.lag.stats())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadUpdaterStats {
+    /// Threads whose metadata is in memory
+    pub tracked_threads: usize,
+    /// Threads forgotten because `advanced.max_tracked_threads` was reached
+    pub evicted_threads: u64,
+    /// Threads ignored because their OP didn't match `thread_filter`
+    pub ignored_threads: usize,
+}
+
+pub struct GetThreadUpdaterStats;
+impl Message for GetThreadUpdaterStats {
+    type Result = Result<ThreadUpdaterStats, ()>;
+}
+
+impl Handler<GetThreadUpdaterStats> for ThreadUpdater {
+    type Result = Result<ThreadUpdaterStats, ()>;
+
+    fn handle(&mut self, _: GetThreadUpdaterStats, _: &mut Self::Context) -> Self::Result {
+        Ok(ThreadUpdaterStats {
+            tracked_threads: self.thread_meta.len(),
+            evicted_threads: self.evicted_threads,
+            ignored_threads: self.ignored_threads.len()
+                + self
+                    .ignored_archived_threads
+                    .values()
+                    .map(HashSet::len)
+                    .sum::<usize>(),
+        })
+    }
+}
+
+/// The threads of a board whose metadata is in memory.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrackedThreads {
+    pub threads: usize,
+    /// An estimate of the memory used by their metadata
+    pub metadata_bytes: usize,
+}
+
+pub struct GetTrackedThreads;
+impl Message for GetTrackedThreads {
+    type Result = Result<HashMap<Board, TrackedThreads>, ()>;
+}
+
+impl Handler<GetTrackedThreads> for ThreadUpdater {
+    type Result = Result<HashMap<Board, TrackedThreads>, ()>;
+
+    fn handle(&mut self, _: GetTrackedThreads, _: &mut Self::Context) -> Self::Result {
+        let mut tracked: HashMap<Board, TrackedThreads> = HashMap::new();
+        for ((board, _), meta) in self.thread_meta.iter() {
+            let tracked = tracked.entry(*board).or_default();
+            tracked.threads += 1;
+            tracked.metadata_bytes += mem::size_of::<((Board, ThreadNo), ThreadMetadata)>()
+                + meta.posts.capacity() * mem::size_of::<PostMetadata>();
+        }
+        Ok(tracked)
+    }
+}
+
+impl Handler<Signal> for ThreadUpdater {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Self::Context) {
+        match msg.0 {
+            SignalType::Int | SignalType::Term | SignalType::Quit => {
+                if self.state_path.is_some() {
+                    info!("Saving state before stopping");
+                    self.save_state();
+                }
+                if self.write_buffer {
+                    // Buffered inserts which are still running when the system stops are lost
+                    info!("Writing buffered posts before stopping");
+                    Arbiter::spawn(self.database.send(FlushInsertBuffer).then(|_| {
+                        System::current().stop();
+                        Ok(())
+                    }));
+                } else {
+                    System::current().stop();
+                }
+            }
+            SignalType::Hup | SignalType::Child => {}
+        }
+    }
+}
+
+/// Whether the OP data of a thread changed. `unique_ips` changes with nearly every new poster, so
+/// a change to it alone isn't worth a write. Its latest value is written along with the next other
+/// change (at the latest, when the thread is archived).
+fn op_data_changed(prev: &OpData, curr: &OpData) -> bool {
+    let ignore_unique_ips = |op_data: &OpData| OpData {
+        unique_ips: None,
+        ..op_data.clone()
+    };
+    ignore_unique_ips(prev) != ignore_unique_ips(curr)
+}
+
+#[derive(Deserialize, Serialize)]
+struct ThreadMetadata {
+    op_data: OpData,
+    posts: Vec<PostMetadata>,
+}
+
+impl ThreadMetadata {
+    fn from_thread(thread: &[Post]) -> Self {
+        Self {
+            op_data: thread[0].op_data.clone(),
+            posts: thread.iter().map(PostMetadata::from).collect(),
+        }
+    }
+
+    /// Whether the thread is long enough for fetching its tail JSON to be worthwhile.
+    fn is_long(&self) -> bool {
+        self.posts.len() >= TAIL_MIN_POSTS
+    }
+
+    /// Split off the posts which come before the first reply of a thread's `tail`. On success, the
+    /// split off posts and the remaining metadata (the OP and the posts the tail covers) are
+    /// returned. If there may be posts that neither we nor the tail have seen, `self` is returned
+    /// unchanged as an error.
+    fn split_tail(
+        mut self,
+        tail: &ThreadMetadata,
+    ) -> Result<(Vec<PostMetadata>, ThreadMetadata), ThreadMetadata> {
+        let tail_start = match tail.posts.get(1) {
+            Some(first_reply) => first_reply.no,
+            // The tail has no replies, so it only covers us if we don't have any replies either
+            None if self.posts.len() == 1 => return Ok((vec![], self)),
+            None => return Err(self),
+        };
+        if tail_start > self.posts.last().unwrap().no {
+            return Err(self);
+        }
+
+        let split = self
+            .posts
+            .iter()
+            .skip(1)
+            .position(|post| post.no >= tail_start)
+            .map_or(self.posts.len(), |i| i + 1);
+        let mut covered = self.posts.split_off(split);
+        let head = self.posts.split_off(1);
+        self.posts.append(&mut covered);
+        Ok((head, self))
+    }
+
+    /// Insert the posts split off by `split_tail` back between the OP and the tail.
+    fn join_head(mut self, mut head: Vec<PostMetadata>) -> Self {
+        let mut tail = self.posts.split_off(1);
+        self.posts.append(&mut head);
+        self.posts.append(&mut tail);
+        self
+    }
+}
+
+/// Used to determine if a post was modified or not
+#[derive(Deserialize, Serialize)]
+struct PostMetadata {
+    no: PostNo,
+    /// Hash of a comment before HTML cleaning, the image spoiler flag, and the image file deleted
+    /// flag
+    metadata: (Option<u64>, Option<bool>, Option<bool>),
+}
+
+impl From<&Post> for PostMetadata {
+    fn from(post: &Post) -> Self {
+        let comment_hash = post.comment.as_ref().map(|c| {
+            let mut hasher = XxHash::default();
+            c.hash(&mut hasher);
+            hasher.finish()
+        });
+        let spoiler = post.image.as_ref().map(|i| i.spoiler);
+        let file_deleted = post.image.as_ref().map(|i| i.filedeleted);
+
+        Self {
+            no: post.no,
+            metadata: (comment_hash, spoiler, file_deleted),
+        }
+    }
+}
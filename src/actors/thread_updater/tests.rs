@@ -0,0 +1,110 @@
+#![cfg(test)]
+
+use super::{op_data_changed, PostMetadata, ThreadMetadata, TAIL_MIN_POSTS};
+use crate::four_chan::{OpData, PostNo};
+
+fn metadata(nos: &[u64]) -> ThreadMetadata {
+    ThreadMetadata {
+        op_data: serde_json::from_str("{}").unwrap(),
+        posts: nos
+            .iter()
+            .map(|&no| PostMetadata {
+                no: PostNo(no),
+                metadata: (None, None, None),
+            })
+            .collect(),
+    }
+}
+
+fn post_nos(posts: &[PostMetadata]) -> Vec<u64> {
+    posts.iter().map(|post| post.no.0).collect()
+}
+
+fn thread(replies: std::ops::RangeInclusive<u64>) -> Vec<u64> {
+    let mut nos = vec![1];
+    nos.extend(replies);
+    nos
+}
+
+#[test]
+fn long_threads() {
+    assert!(!metadata(&thread(2..=TAIL_MIN_POSTS as u64 - 1)).is_long());
+    assert!(metadata(&thread(2..=TAIL_MIN_POSTS as u64)).is_long());
+}
+
+#[test]
+fn split_and_join() {
+    let prev = metadata(&thread(2..=150));
+    let tail = metadata(&thread(100..=160));
+
+    let (head, covered) = prev.split_tail(&tail).ok().unwrap();
+    assert_eq!(post_nos(&head), (2..=99).collect::<Vec<_>>());
+    assert_eq!(post_nos(&covered.posts), thread(100..=150));
+
+    // The posts the tail covers are replaced by the tail, and the head is put back in front of it
+    let joined = tail.join_head(head);
+    assert_eq!(post_nos(&joined.posts), thread(2..=160));
+}
+
+#[test]
+fn split_at_last_post() {
+    let prev = metadata(&thread(2..=150));
+    let tail = metadata(&thread(150..=151));
+
+    let (head, covered) = prev.split_tail(&tail).ok().unwrap();
+    assert_eq!(post_nos(&head), (2..=149).collect::<Vec<_>>());
+    assert_eq!(post_nos(&covered.posts), vec![1, 150]);
+    assert_eq!(post_nos(&tail.join_head(head).posts), thread(2..=151));
+}
+
+#[test]
+fn split_with_deleted_tail_start() {
+    // Post 100 was deleted after the last fetch, so the tail starts in a gap of our metadata
+    let mut nos = thread(2..=99);
+    nos.extend(101..=150);
+    let prev = metadata(&nos);
+    let tail = metadata(&thread(100..=150));
+
+    let (head, covered) = prev.split_tail(&tail).ok().unwrap();
+    assert_eq!(post_nos(&head), (2..=99).collect::<Vec<_>>());
+    assert_eq!(post_nos(&covered.posts), thread(101..=150));
+}
+
+#[test]
+fn tail_without_replies() {
+    let prev = metadata(&[1]);
+    let (head, covered) = prev.split_tail(&metadata(&[1])).ok().unwrap();
+    assert!(head.is_empty());
+    assert_eq!(post_nos(&covered.posts), vec![1]);
+
+    // Every reply was deleted, but the tail can't tell us that
+    let prev = metadata(&thread(2..=150));
+    let prev = prev.split_tail(&metadata(&[1])).err().unwrap();
+    assert_eq!(post_nos(&prev.posts), thread(2..=150));
+}
+
+#[test]
+fn tail_after_last_post() {
+    // Posts 151 to 159 might exist, so the whole thread must be fetched
+    let prev = metadata(&thread(2..=150));
+    let tail = metadata(&thread(160..=210));
+
+    let prev = prev.split_tail(&tail).err().unwrap();
+    assert_eq!(post_nos(&prev.posts), thread(2..=150));
+}
+
+#[test]
+fn op_data_changes() {
+    let op_data = |json: &str| serde_json::from_str::<OpData>(json).unwrap();
+    let prev = op_data(r#"{"unique_ips": 3}"#);
+    assert!(!op_data_changed(&prev, &op_data(r#"{"unique_ips": 3}"#)));
+    assert!(!op_data_changed(&prev, &op_data(r#"{"unique_ips": 4}"#)));
+    assert!(op_data_changed(
+        &prev,
+        &op_data(r#"{"unique_ips": 4, "bumplimit": 1}"#)
+    ));
+    assert!(op_data_changed(
+        &prev,
+        &op_data(r#"{"archived": 1, "archived_on": 1}"#)
+    ));
+}
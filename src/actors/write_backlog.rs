@@ -0,0 +1,102 @@
+//! Backpressure for database writes. When the database falls behind, writes wait in a queue for
+//! each board instead of all being sent at once, and `BoardPoller` skips polls of boards whose
+//! queue is too long, so that no new work is created until the database catches up.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use actix::prelude::*;
+use futures::prelude::*;
+
+use crate::{config::AdvancedConfig, four_chan::Board};
+
+type PendingWrite = Box<dyn Future<Item = (), Error = ()>>;
+
+/// The database writes of each board which haven't finished yet. It can be shared between actors.
+#[derive(Clone)]
+pub struct WriteBacklog(Arc<BacklogInner>);
+
+struct BacklogInner {
+    boards: Mutex<HashMap<Board, BoardBacklog>>,
+    max_in_flight: usize,
+    poll_threshold: usize,
+}
+
+#[derive(Default)]
+struct BoardBacklog {
+    in_flight: usize,
+    /// Writes waiting for one of the writes in flight to finish
+    queued: VecDeque<PendingWrite>,
+}
+
+impl WriteBacklog {
+    pub fn new(config: &AdvancedConfig) -> Self {
+        WriteBacklog(Arc::new(BacklogInner {
+            boards: Mutex::default(),
+            max_in_flight: config.writes_in_flight,
+            poll_threshold: config.write_backlog_threshold,
+        }))
+    }
+
+    /// Run a write once fewer than `advanced.writes_in_flight` writes of its board are running.
+    /// Writes of a board are started in the order they were given, so the write should be lazy
+    /// (e.g. wrapped in `future::lazy`) to reach the database in that order.
+    pub fn spawn<F>(&self, board: Board, write: F)
+    where
+        F: Future<Item = (), Error = ()> + 'static,
+    {
+        let write = Box::new(write);
+        {
+            let mut boards = self.0.boards.lock().unwrap();
+            let backlog = boards.entry(board).or_default();
+            if backlog.in_flight >= self.0.max_in_flight {
+                backlog.queued.push_back(write);
+                return;
+            }
+            backlog.in_flight += 1;
+        }
+        self.run(board, write);
+    }
+
+    /// The number of writes of a board which are running or queued.
+    pub fn len(&self, board: Board) -> usize {
+        self.0
+            .boards
+            .lock()
+            .unwrap()
+            .get(&board)
+            .map_or(0, |backlog| backlog.in_flight + backlog.queued.len())
+    }
+
+    /// Whether a board has more writes than `advanced.write_backlog_threshold`, in which case it
+    /// shouldn't be polled.
+    pub fn is_saturated(&self, board: Board) -> bool {
+        self.len(board) > self.0.poll_threshold
+    }
+
+    fn run(&self, board: Board, write: PendingWrite) {
+        let backlog = self.clone();
+        Arbiter::spawn(write.then(move |_| {
+            backlog.finish(board);
+            Ok(())
+        }));
+    }
+
+    fn finish(&self, board: Board) {
+        let next = {
+            let mut boards = self.0.boards.lock().unwrap();
+            let backlog = boards.get_mut(&board).unwrap();
+            let next = backlog.queued.pop_front();
+            // The next write takes the finished write's place
+            if next.is_none() {
+                backlog.in_flight -= 1;
+            }
+            next
+        };
+        if let Some(next) = next {
+            self.run(board, next);
+        }
+    }
+}
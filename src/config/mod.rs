@@ -0,0 +1,1401 @@
+//! Configuration file parsing.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{prelude::*, BufReader},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use failure::{Fail, ResultExt};
+use rand::Rng;
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
+use toml::Value;
+
+use crate::four_chan::{Board, API_URI_PREFIX, IMG_URI_PREFIX, STATIC_URI_PREFIX};
+
+mod tests;
+
+/// The commented default configuration file, printed by `ena print-default-config`.
+pub const DEFAULT_CONFIG: &str = include_str!("../../ena.example.toml");
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(skip_deserializing)]
+    pub boards: Arc<HashMap<Board, ScrapingConfig>>,
+    pub network: NetworkConfig,
+    pub database_media: DatabaseMediaConfig,
+    pub asagi_compat: AsagiCompatibilityConfig,
+    #[serde(default)]
+    pub state: StateConfig,
+    #[serde(default)]
+    pub clickhouse: Option<ClickHouseConfig>,
+    #[serde(default)]
+    pub lag_alert: Option<LagAlertConfig>,
+    #[serde(default)]
+    pub activity_log: Option<ActivityLogConfig>,
+    #[serde(default)]
+    pub coordination: Option<CoordinationConfig>,
+    #[serde(default)]
+    pub writer: Option<WriterConfig>,
+    #[serde(default)]
+    pub archive_export: Option<ArchiveExportConfig>,
+    #[serde(default)]
+    pub media_server: Option<MediaServerConfig>,
+    #[serde(default)]
+    pub html: HtmlConfig,
+    #[serde(default)]
+    pub post_processors: Vec<PostProcessorConfig>,
+    #[serde(default)]
+    pub advanced: AdvancedConfig,
+}
+
+#[derive(Deserialize)]
+pub struct ScrapingConfig {
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub poll_interval: Duration,
+    pub fetch_archive: bool,
+    pub download_media: bool,
+    pub download_thumbs: bool,
+    #[serde(default)]
+    pub use_tail_json: bool,
+    #[serde(default)]
+    pub store_raw_json: bool,
+    #[serde(default)]
+    pub store_comment_html: bool,
+    #[serde(default)]
+    pub store_quotes: bool,
+    #[serde(default)]
+    pub store_links: bool,
+    #[serde(default)]
+    pub store_media_hashes: bool,
+    #[serde(default, deserialize_with = "option_thread_filter")]
+    pub thread_filter: Option<ThreadFilter>,
+    #[serde(default, deserialize_with = "validate_max_comment_bytes")]
+    pub max_comment_bytes: Option<usize>,
+    /// Overrides `database_media.charset`. Only set in `[boards]`.
+    #[serde(skip_deserializing)]
+    pub charset: Option<String>,
+    /// Only set in `[boards]`.
+    #[serde(skip_deserializing)]
+    pub collation: Option<String>,
+    /// Overrides `asagi_compat.adjust_timestamps`. Only set in `[boards]`.
+    #[serde(skip_deserializing)]
+    pub adjust_timestamps: bool,
+    /// Overrides `asagi_compat.refetch_archived_threads`. Only set in `[boards]`.
+    #[serde(skip_deserializing)]
+    pub refetch_archived_threads: bool,
+    /// Overrides `asagi_compat.always_add_archive_times`. Only set in `[boards]`.
+    #[serde(skip_deserializing)]
+    pub always_add_archive_times: bool,
+}
+
+impl ScrapingConfig {
+    fn merge(&self, board: &OptionScrapingConfig, asagi_compat: &AsagiCompatibilityConfig) -> Self {
+        Self {
+            poll_interval: board.poll_interval.unwrap_or(self.poll_interval),
+            fetch_archive: board.fetch_archive.unwrap_or(self.fetch_archive),
+            download_media: board.download_media.unwrap_or(self.download_media),
+            download_thumbs: board.download_thumbs.unwrap_or(self.download_thumbs),
+            use_tail_json: board.use_tail_json.unwrap_or(self.use_tail_json),
+            store_raw_json: board.store_raw_json.unwrap_or(self.store_raw_json),
+            store_comment_html: board.store_comment_html.unwrap_or(self.store_comment_html),
+            store_quotes: board.store_quotes.unwrap_or(self.store_quotes),
+            store_links: board.store_links.unwrap_or(self.store_links),
+            store_media_hashes: board.store_media_hashes.unwrap_or(self.store_media_hashes),
+            thread_filter: board
+                .thread_filter
+                .clone()
+                .or_else(|| self.thread_filter.clone()),
+            max_comment_bytes: board.max_comment_bytes.or(self.max_comment_bytes),
+            charset: board.charset.clone(),
+            collation: board.collation.clone(),
+            adjust_timestamps: board
+                .adjust_timestamps
+                .unwrap_or(asagi_compat.adjust_timestamps),
+            refetch_archived_threads: board
+                .refetch_archived_threads
+                .unwrap_or(asagi_compat.refetch_archived_threads),
+            always_add_archive_times: board
+                .always_add_archive_times
+                .unwrap_or(asagi_compat.always_add_archive_times),
+        }
+    }
+}
+
+/// Regexes matched against the OP of each new thread. Only threads whose OP matches at least one of
+/// them are scraped. Comments are matched as HTML, and filenames include the extension.
+#[derive(Clone, Deserialize)]
+pub struct ThreadFilter {
+    #[serde(default, deserialize_with = "option_regex_from_string")]
+    pub subject: Option<Regex>,
+    #[serde(default, deserialize_with = "option_regex_from_string")]
+    pub comment: Option<Regex>,
+    #[serde(default, deserialize_with = "option_regex_from_string")]
+    pub filename: Option<Regex>,
+}
+
+/// Used to extract the global and board scraping configs for merging and insertion into Config.
+#[derive(Deserialize)]
+struct BoardsConfig {
+    scraping: ScrapingConfig,
+    #[serde(default)]
+    groups: HashMap<String, OptionScrapingConfig>,
+    boards: HashMap<String, OptionScrapingConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct OptionScrapingConfig {
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonzero_duration_from_secs")]
+    pub poll_interval: Option<Duration>,
+    pub fetch_archive: Option<bool>,
+    pub download_media: Option<bool>,
+    pub download_thumbs: Option<bool>,
+    pub use_tail_json: Option<bool>,
+    pub store_raw_json: Option<bool>,
+    pub store_comment_html: Option<bool>,
+    pub store_quotes: Option<bool>,
+    pub store_links: Option<bool>,
+    pub store_media_hashes: Option<bool>,
+    #[serde(default, deserialize_with = "option_thread_filter")]
+    pub thread_filter: Option<ThreadFilter>,
+    #[serde(default, deserialize_with = "validate_max_comment_bytes")]
+    pub max_comment_bytes: Option<usize>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub charset: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub collation: Option<String>,
+    pub adjust_timestamps: Option<bool>,
+    pub refetch_archived_threads: Option<bool>,
+    pub always_add_archive_times: Option<bool>,
+    /// The group whose settings this board uses. Only set in `[boards]`.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl OptionScrapingConfig {
+    /// Fill in the settings which aren't set with those of `group`.
+    fn or(self, group: &OptionScrapingConfig) -> Self {
+        Self {
+            poll_interval: self.poll_interval.or(group.poll_interval),
+            fetch_archive: self.fetch_archive.or(group.fetch_archive),
+            download_media: self.download_media.or(group.download_media),
+            download_thumbs: self.download_thumbs.or(group.download_thumbs),
+            use_tail_json: self.use_tail_json.or(group.use_tail_json),
+            store_raw_json: self.store_raw_json.or(group.store_raw_json),
+            store_comment_html: self.store_comment_html.or(group.store_comment_html),
+            store_quotes: self.store_quotes.or(group.store_quotes),
+            store_links: self.store_links.or(group.store_links),
+            store_media_hashes: self.store_media_hashes.or(group.store_media_hashes),
+            thread_filter: self.thread_filter.or_else(|| group.thread_filter.clone()),
+            max_comment_bytes: self.max_comment_bytes.or(group.max_comment_bytes),
+            charset: self.charset.or_else(|| group.charset.clone()),
+            collation: self.collation.or_else(|| group.collation.clone()),
+            adjust_timestamps: self.adjust_timestamps.or(group.adjust_timestamps),
+            refetch_archived_threads: self
+                .refetch_archived_threads
+                .or(group.refetch_archived_threads),
+            always_add_archive_times: self
+                .always_add_archive_times
+                .or(group.always_add_archive_times),
+            group: self.group,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NetworkConfig {
+    pub rate_limiting: RateLimitingConfig,
+    /// Used by request categories without their own settings in `retry`
+    pub retry_backoff: RetryBackoffConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub client: Option<HttpClientConfig>,
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonzero_duration_from_secs")]
+    pub challenge_cooldown: Option<Duration>,
+    /// A directory to save every API response in
+    #[serde(default)]
+    pub record_path: Option<PathBuf>,
+    /// A directory of responses saved with `record_path` to answer API requests with
+    #[serde(default)]
+    pub replay_path: Option<PathBuf>,
+    #[serde(default)]
+    pub request_log: Option<RequestLogConfig>,
+    #[serde(default)]
+    pub hosts: HostsConfig,
+}
+
+/// The hosts that requests are sent to. They default to 4chan's, and can be changed to scrape a
+/// mirror or a mock of the API.
+#[derive(Deserialize)]
+pub struct HostsConfig {
+    #[serde(default = "default_api_host")]
+    #[serde(deserialize_with = "host_uri")]
+    pub api: String,
+    #[serde(default = "default_image_host")]
+    #[serde(deserialize_with = "host_uri")]
+    pub image: String,
+    /// Serves the custom spoiler images of boards
+    #[serde(rename = "static")]
+    #[serde(default = "default_static_host")]
+    #[serde(deserialize_with = "host_uri")]
+    pub static_files: String,
+}
+
+impl Default for HostsConfig {
+    fn default() -> Self {
+        Self {
+            api: default_api_host(),
+            image: default_image_host(),
+            static_files: default_static_host(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RequestLogConfig {
+    /// The file to append the log to. Without it, requests are logged with the `log` crate
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct CircuitBreakerConfig {
+    #[serde(deserialize_with = "validate_breaker_failures")]
+    pub failures: usize,
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub cooldown: Duration,
+}
+
+/// Proxy URLs. `api` and `media` override `url` for API and media requests, and `media_pool`
+/// overrides both for media requests.
+#[derive(Deserialize)]
+pub struct ProxyConfig {
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub url: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub api: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub media: Option<String>,
+    #[serde(default)]
+    pub media_pool: Option<ProxyPoolConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct ProxyPoolConfig {
+    #[serde(deserialize_with = "validate_proxy_urls")]
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub rotation: ProxyRotation,
+    #[serde(deserialize_with = "validate_max_failures")]
+    pub max_failures: usize,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyRotation {
+    RoundRobin,
+    LeastRecentlyUsed,
+}
+
+impl Default for ProxyRotation {
+    fn default() -> Self {
+        ProxyRotation::RoundRobin
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct HttpClientConfig {
+    #[serde(default)]
+    pub http2_only: bool,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonzero_duration_from_secs")]
+    pub keep_alive_timeout: Option<Duration>,
+    #[serde(default)]
+    pub max_idle_per_host: Option<usize>,
+    #[serde(default)]
+    pub local_address: Option<IpAddr>,
+}
+
+#[derive(Deserialize)]
+pub struct RateLimitingConfig {
+    pub media: RateLimitingSettings,
+    /// If set, thumbnails are fetched separately from other media, with these limits
+    #[serde(default)]
+    pub thumbs: Option<RateLimitingSettings>,
+    pub thread: RateLimitingSettings,
+    pub thread_list: RateLimitingSettings,
+    /// A limit on the combined request rate of every category, in requests per second
+    #[serde(default)]
+    #[serde(deserialize_with = "validate_total_per_second")]
+    pub total_per_second: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct RateLimitingSettings {
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub interval: Duration,
+    #[serde(deserialize_with = "validate_max_interval")]
+    pub max_interval: usize,
+    #[serde(deserialize_with = "validate_max_concurrent")]
+    pub max_concurrent: usize,
+    /// If set, use a token bucket of this capacity (refilled at `max_interval` tokens per
+    /// `interval`) instead of fixed intervals.
+    #[serde(default)]
+    #[serde(deserialize_with = "validate_burst")]
+    pub burst: Option<usize>,
+}
+
+impl NetworkConfig {
+    pub fn thread_retry_backoff(&self) -> RetryBackoffConfig {
+        self.retry.thread.unwrap_or(self.retry_backoff)
+    }
+
+    pub fn media_retry_backoff(&self) -> RetryBackoffConfig {
+        self.retry.media.unwrap_or(self.retry_backoff)
+    }
+}
+
+/// The retry backoff of each request category, overriding `network.retry_backoff`.
+#[derive(Default, Deserialize)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub thread: Option<RetryBackoffConfig>,
+    #[serde(default)]
+    pub media: Option<RetryBackoffConfig>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct RetryBackoffConfig {
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub base: Duration,
+    pub factor: u32,
+    #[serde(deserialize_with = "duration_from_secs")]
+    pub max: Duration,
+    #[serde(default)]
+    pub jitter: Jitter,
+}
+
+/// Randomization of retry delays, so that requests which failed together aren't all retried
+/// together.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+    None,
+    /// A random delay between 0 and the backoff delay
+    Full,
+    /// Half of the backoff delay, plus a random delay of up to the other half
+    Equal,
+}
+
+impl Default for Jitter {
+    fn default() -> Self {
+        Jitter::None
+    }
+}
+
+impl Jitter {
+    pub fn apply(self, delay: Duration) -> Duration {
+        let nanos = delay.as_secs() * 1_000_000_000 + u64::from(delay.subsec_nanos());
+        let fixed = match self {
+            Jitter::None => return delay,
+            Jitter::Full => 0,
+            Jitter::Equal => nanos / 2,
+        };
+        Duration::from_nanos(fixed + rand::thread_rng().gen_range(0, nanos - fixed + 1))
+    }
+}
+
+/// What is synced to disk before a downloaded media file is moved into place.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaFsync {
+    None,
+    /// The file's contents, so that a power loss can't leave an empty or partial file in place
+    File,
+    /// The file's contents and then the directory, so that the rename itself is durable
+    FileAndDir,
+}
+
+impl Default for MediaFsync {
+    fn default() -> Self {
+        MediaFsync::None
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DatabaseMediaConfig {
+    /// Set from `database_url_file` if that is set instead
+    #[serde(default)]
+    #[serde(deserialize_with = "nonempty_string")]
+    pub database_url: String,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    database_url_file: Option<PathBuf>,
+    #[serde(default)]
+    pub board_database_urls: HashMap<Board, String>,
+    #[serde(deserialize_with = "nonempty_string")]
+    pub charset: String,
+    #[serde(deserialize_with = "pathbuf_from_string")]
+    pub media_path: PathBuf,
+    #[serde(default)]
+    pub media_fsync: MediaFsync,
+    #[serde(default)]
+    #[serde(deserialize_with = "validate_table_template")]
+    pub table_template: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub native_triggers: bool,
+    /// Store media in one `ena_media` table shared by all boards instead of `%%BOARD%%_images`
+    #[serde(default)]
+    pub global_media: bool,
+    #[serde(default)]
+    pub pool: Option<PoolConfig>,
+    #[serde(default)]
+    pub retry_backoff: Option<RetryBackoffConfig>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    pub journal_path: Option<PathBuf>,
+    #[serde(default)]
+    pub write_buffer: Option<WriteBufferConfig>,
+    #[serde(default)]
+    pub temp_cleanup: Option<TempCleanupConfig>,
+    /// Log queries which take longer than this
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonzero_duration_from_millis")]
+    pub slow_query_threshold: Option<Duration>,
+    #[serde(default)]
+    pub disk_space: Option<DiskSpaceConfig>,
+    #[serde(default)]
+    pub board_stats: Option<BoardStatsConfig>,
+    /// Send writes to the `ena writer` instance at this address instead of the database
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub remote_writer: Option<String>,
+    /// The secret to authenticate with the remote writer (see `writer.secret`). Set from
+    /// `remote_writer_secret_file` if that is set instead.
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub remote_writer_secret: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    remote_writer_secret_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct PoolConfig {
+    pub min: usize,
+    #[serde(deserialize_with = "validate_pool_max")]
+    pub max: usize,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonzero_duration_from_secs")]
+    pub conn_ttl: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct WriteBufferConfig {
+    #[serde(deserialize_with = "nonzero_duration_from_millis")]
+    pub max_delay: Duration,
+    #[serde(deserialize_with = "validate_max_rows")]
+    pub max_rows: usize,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct TempCleanupConfig {
+    /// Temporary files older than this are from downloads which never finished
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub max_age: Duration,
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub interval: Duration,
+    /// Fetch the media of removed files again
+    #[serde(default)]
+    pub requeue: bool,
+}
+
+#[derive(Deserialize)]
+pub struct DiskSpaceConfig {
+    /// Free space (in MiB) below which downloads are paused
+    #[serde(deserialize_with = "validate_min_free")]
+    pub min_free: u64,
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub interval: Duration,
+    /// Also check the volume of this directory (e.g. MySQL's datadir, if it is local)
+    #[serde(default)]
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    pub mysql_datadir: Option<PathBuf>,
+    /// Pause fetching from the API too, and not only media
+    #[serde(default)]
+    pub pause_scraping: bool,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct BoardStatsConfig {
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub interval: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct AsagiCompatibilityConfig {
+    pub adjust_timestamps: bool,
+    pub refetch_archived_threads: bool,
+    pub always_add_archive_times: bool,
+    pub create_index_counters: bool,
+    #[serde(default)]
+    pub update_users_table: bool,
+    #[serde(default)]
+    pub preserve_links: bool,
+    #[serde(default)]
+    pub decode_numeric_references: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct StateConfig {
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    pub path: Option<PathBuf>,
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub save_interval: Duration,
+    /// Seed the fetch cache from the posts in the database on start
+    pub seed_from_database: bool,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            save_interval: Duration::from_secs(300),
+            seed_from_database: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ClickHouseConfig {
+    /// Set from `url_file` if that is set instead
+    #[serde(default)]
+    #[serde(deserialize_with = "nonempty_string")]
+    pub url: String,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    url_file: Option<PathBuf>,
+    #[serde(deserialize_with = "nonempty_string")]
+    pub table: String,
+}
+
+#[derive(Deserialize)]
+pub struct CoordinationConfig {
+    /// A name for this instance, unique among the instances sharing the database
+    #[serde(deserialize_with = "nonempty_string")]
+    pub instance_id: String,
+    /// Leases not renewed for this long expire, and are taken over by other instances
+    #[serde(deserialize_with = "validate_lease_duration")]
+    pub lease_duration: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveExportConfig {
+    #[serde(deserialize_with = "pathbuf_from_string")]
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct MediaServerConfig {
+    /// The address which files in `media_path` are served on
+    pub listen: SocketAddr,
+}
+
+#[derive(Deserialize)]
+pub struct WriterConfig {
+    /// The address which `ena writer` accepts connections from scraping instances on
+    #[serde(default = "default_writer_listen")]
+    pub listen: SocketAddr,
+    /// The secret that scraping instances must send before making requests. Set from
+    /// `secret_file` if that is set instead.
+    #[serde(default)]
+    #[serde(deserialize_with = "option_nonempty_string")]
+    pub secret: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "option_pathbuf_from_string")]
+    secret_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+pub struct ActivityLogConfig {
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub interval: Duration,
+}
+
+#[derive(Deserialize)]
+pub struct LagAlertConfig {
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub threshold: Duration,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct HtmlConfig {
+    /// BBCode tags for `<span class="...">` elements, by class, in addition to the built-in ones
+    #[serde(default)]
+    #[serde(deserialize_with = "validate_span_tags")]
+    pub span_tags: HashMap<String, String>,
+}
+
+/// A built-in post processor (see `actors::PostProcessor`).
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PostProcessorConfig {
+    /// Replace the matches of `pattern` in comments and subjects
+    Replace {
+        #[serde(deserialize_with = "regex_from_string")]
+        pattern: Regex,
+        replacement: String,
+    },
+    /// Run a Lua script on each post (requires the `lua` feature)
+    #[cfg(feature = "lua")]
+    Lua { path: PathBuf },
+}
+
+/// Settings which most deployments don't need to change.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct AdvancedConfig {
+    #[serde(deserialize_with = "validate_mailbox_capacity")]
+    pub thread_updater_mailbox_capacity: usize,
+    #[serde(deserialize_with = "validate_mailbox_capacity")]
+    pub fetcher_mailbox_capacity: usize,
+    #[serde(deserialize_with = "validate_mailbox_capacity")]
+    pub database_mailbox_capacity: usize,
+    /// How many times the database and fetcher actors can be restarted after panicking within
+    /// `restart_window`
+    pub max_restarts: u32,
+    #[serde(deserialize_with = "nonzero_duration_from_secs")]
+    pub restart_window: Duration,
+    /// The most threads whose metadata the thread updater keeps in memory
+    #[serde(deserialize_with = "validate_max_tracked_threads")]
+    pub max_tracked_threads: Option<usize>,
+    /// The most database writes of a board which are sent to the database actor at once
+    #[serde(deserialize_with = "validate_writes_in_flight")]
+    pub writes_in_flight: usize,
+    /// Boards with more unfinished writes than this aren't polled
+    pub write_backlog_threshold: usize,
+    /// Poll intervals are stretched while the database actor has more unfinished writes than this
+    pub max_database_writes: Option<usize>,
+    /// Poll intervals are stretched while the average insert takes longer than this
+    #[serde(deserialize_with = "option_nonzero_duration_from_millis")]
+    pub max_insert_latency: Option<Duration>,
+    /// The most that poll intervals are stretched by
+    #[serde(deserialize_with = "validate_max_poll_stretch")]
+    pub max_poll_stretch: u32,
+}
+
+impl Default for AdvancedConfig {
+    fn default() -> Self {
+        Self {
+            thread_updater_mailbox_capacity: 500,
+            fetcher_mailbox_capacity: 500,
+            database_mailbox_capacity: 1000,
+            max_restarts: 5,
+            restart_window: Duration::from_secs(3600),
+            max_tracked_threads: None,
+            writes_in_flight: 20,
+            write_backlog_threshold: 500,
+            max_database_writes: None,
+            max_insert_latency: None,
+            max_poll_stretch: 8,
+        }
+    }
+}
+
+/// Configuration parsing errors.
+///
+/// Note: most of the configuration checking is done through (a kludge of) Serde's
+/// `deserialize_with` attributes and the `deserialize_validate!` macro. This enum is used for
+/// errors which don't work well with Serde's custom error message format.
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "Invalid config: `boards` must contain at least one board")]
+    NoBoards,
+
+    #[fail(display = "Invalid config: `{}.factor` must be at least 2", _0)]
+    SmallRetryFactor(&'static str),
+    #[fail(display = "Invalid config: `database_media.retry_backoff.factor` must be at least 2")]
+    SmallDatabaseRetryFactor,
+    #[fail(display = "Invalid config: `database_media.pool.min` must not be greater than `max`")]
+    PoolMinAboveMax,
+    #[fail(
+        display = "Invalid config: only one of `network.record_path` and `network.replay_path` \
+                   can be set"
+    )]
+    RecordAndReplay,
+    #[fail(display = "Invalid config: `database_media.global_media` requires `native_triggers`")]
+    GlobalMediaWithoutNativeTriggers,
+    #[fail(
+        display = "Invalid config: with `database_media.global_media`, every board must be on the \
+                   server of `database_url`"
+    )]
+    GlobalMediaOnOtherServer,
+    #[fail(
+        display = "Invalid config: included file {} must not include other files",
+        _0
+    )]
+    NestedInclude(String),
+    #[fail(
+        display = "Invalid config: board {} is in group {}, which doesn't exist",
+        _0, _1
+    )]
+    UnknownGroup(String, String),
+    #[fail(display = "Invalid config: group {} must not set `group`", _0)]
+    GroupInGroup(String),
+    #[fail(
+        display = "Invalid config: only one of `{0}` and `{0}_file` can be set",
+        _0
+    )]
+    SecretConflict(&'static str),
+    #[fail(
+        display = "Invalid config: `{0}` or `{0}_file` must be set (and not empty)",
+        _0
+    )]
+    MissingSecret(&'static str),
+    #[fail(
+        display = "Invalid config: polling {} board(s) at their `poll_interval`s needs {:.2} \
+                   threads.json requests every {} seconds (the sum of {} / poll_interval for each \
+                   board), but `network.rate_limiting.thread_list` only allows {}",
+        boards, needed, interval, interval, allowed
+    )]
+    ThreadListRateTooLow {
+        boards: usize,
+        needed: f64,
+        interval: f64,
+        allowed: usize,
+    },
+}
+
+/// Read a TOML file.
+fn read_toml(path: &Path) -> Result<Value, failure::Error> {
+    let file = File::open(path).with_context(|_| format!("Could not open {}", path.display()))?;
+    let mut buf_reader = BufReader::new(file);
+    let mut contents = String::new();
+    buf_reader
+        .read_to_string(&mut contents)
+        .with_context(|_| format!("Could not read {}", path.display()))?;
+    Ok(
+        toml::from_str(&contents)
+            .with_context(|_| format!("Could not parse {}", path.display()))?,
+    )
+}
+
+/// Merge `other` into `base`. Tables are merged recursively, and other values in `other` replace
+/// those in `base`.
+fn merge_toml(base: &mut Value, other: Value) {
+    match (base, other) {
+        (Value::Table(base), Value::Table(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge_toml(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, other) => *base = other,
+    }
+}
+
+/// Set a secret `value` (e.g. a URL with a password) from the contents of `file` (relative to
+/// `config_dir`), if given. Exactly one of the two must be set. `name` is the secret's config key,
+/// for error messages.
+fn read_secret(
+    value: &mut String,
+    file: Option<PathBuf>,
+    config_dir: &Path,
+    name: &'static str,
+) -> Result<(), failure::Error> {
+    match file {
+        Some(_) if !value.is_empty() => Err(ConfigError::SecretConflict(name).into()),
+        Some(file) => {
+            let file = config_dir.join(file);
+            let secret = fs::read_to_string(&file)
+                .with_context(|_| format!("Could not read {}", file.display()))?;
+            // Files often end with a newline, which isn't part of the secret
+            let secret = secret.trim();
+            if secret.is_empty() {
+                return Err(ConfigError::MissingSecret(name).into());
+            }
+            *value = secret.to_owned();
+            Ok(())
+        }
+        None if value.is_empty() => Err(ConfigError::MissingSecret(name).into()),
+        None => Ok(()),
+    }
+}
+
+/// Like `read_secret`, but for a secret which doesn't need to be set.
+fn read_optional_secret(
+    value: &mut Option<String>,
+    file: Option<PathBuf>,
+    config_dir: &Path,
+    name: &'static str,
+) -> Result<(), failure::Error> {
+    if file.is_none() {
+        return Ok(());
+    }
+    let mut secret = value.take().unwrap_or_default();
+    read_secret(&mut secret, file, config_dir, name)?;
+    *value = Some(secret);
+    Ok(())
+}
+
+/// Read the TOML file at `path` and merge in the files it includes (relative to `config_dir`).
+fn read_with_includes(path: &Path, config_dir: &Path) -> Result<Value, failure::Error> {
+    let mut value = read_toml(path)?;
+
+    let includes = match value
+        .as_table_mut()
+        .and_then(|table| table.remove("include"))
+    {
+        Some(includes) => Value::try_into::<Vec<PathBuf>>(includes)
+            .context("Could not parse `include` (it must be an array of paths)")?,
+        None => vec![],
+    };
+    // Merge the included files in order, and then this file, so that this file overrides them
+    let mut merged = Value::Table(Default::default());
+    for include in includes {
+        let include = config_dir.join(include);
+        let included = read_toml(&include)?;
+        if included.get("include").is_some() {
+            return Err(ConfigError::NestedInclude(include.display().to_string()).into());
+        }
+        merge_toml(&mut merged, included);
+    }
+    merge_toml(&mut merged, value);
+    Ok(merged)
+}
+
+/// Read the configuration file at `path` (and the files it includes) and parse it. Relative paths
+/// in the configuration (including those of included files) are resolved relative to the directory
+/// of the configuration file.
+pub fn parse_config(path: &Path) -> Result<Config, failure::Error> {
+    let config_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let value = read_with_includes(path, config_dir)?;
+
+    let boards_config: BoardsConfig = Value::try_into(value.clone())
+        .with_context(|_| format!("Could not parse {}", path.display()))?;
+    let mut config: Config =
+        Value::try_into(value).with_context(|_| format!("Could not parse {}", path.display()))?;
+
+    read_secret(
+        &mut config.database_media.database_url,
+        config.database_media.database_url_file.take(),
+        config_dir,
+        "database_media.database_url",
+    )?;
+    if let Some(clickhouse) = &mut config.clickhouse {
+        read_secret(
+            &mut clickhouse.url,
+            clickhouse.url_file.take(),
+            config_dir,
+            "clickhouse.url",
+        )?;
+    }
+    read_optional_secret(
+        &mut config.database_media.remote_writer_secret,
+        config.database_media.remote_writer_secret_file.take(),
+        config_dir,
+        "database_media.remote_writer_secret",
+    )?;
+    if let Some(writer) = &mut config.writer {
+        read_optional_secret(
+            &mut writer.secret,
+            writer.secret_file.take(),
+            config_dir,
+            "writer.secret",
+        )?;
+    }
+
+    let media_path = config_dir.join(&config.database_media.media_path);
+    config.database_media.media_path = media_path;
+    if let Some(journal_path) = &mut config.database_media.journal_path {
+        *journal_path = config_dir.join(&journal_path);
+    }
+    if let Some(datadir) = config
+        .database_media
+        .disk_space
+        .as_mut()
+        .and_then(|disk_space| disk_space.mysql_datadir.as_mut())
+    {
+        *datadir = config_dir.join(&datadir);
+    }
+    if let Some(state_path) = &mut config.state.path {
+        *state_path = config_dir.join(&state_path);
+    }
+    if let Some(archive_export) = &mut config.archive_export {
+        archive_export.path = config_dir.join(&archive_export.path);
+    }
+    if let Some(record_path) = &mut config.network.record_path {
+        *record_path = config_dir.join(&record_path);
+    }
+    if let Some(replay_path) = &mut config.network.replay_path {
+        *replay_path = config_dir.join(&replay_path);
+    }
+    if let Some(path) = config
+        .network
+        .request_log
+        .as_mut()
+        .and_then(|log| log.path.as_mut())
+    {
+        *path = config_dir.join(&path);
+    }
+    #[cfg(feature = "lua")]
+    for processor in &mut config.post_processors {
+        if let PostProcessorConfig::Lua { path } = processor {
+            *path = config_dir.join(&path);
+        }
+    }
+
+    if boards_config.boards.is_empty() {
+        return Err(ConfigError::NoBoards.into());
+    } else if let Some(name) = [
+        ("network.retry_backoff", Some(config.network.retry_backoff)),
+        ("network.retry.thread", config.network.retry.thread),
+        ("network.retry.media", config.network.retry.media),
+    ]
+    .iter()
+    .find(|(_, backoff)| backoff.map_or(false, |backoff| backoff.factor < 2))
+    .map(|&(name, _)| name)
+    {
+        return Err(ConfigError::SmallRetryFactor(name).into());
+    } else if config
+        .database_media
+        .retry_backoff
+        .map_or(false, |backoff| backoff.factor < 2)
+    {
+        return Err(ConfigError::SmallDatabaseRetryFactor.into());
+    } else if config
+        .database_media
+        .pool
+        .map_or(false, |pool| pool.min > pool.max)
+    {
+        return Err(ConfigError::PoolMinAboveMax.into());
+    } else if config.network.record_path.is_some() && config.network.replay_path.is_some() {
+        return Err(ConfigError::RecordAndReplay.into());
+    } else if config.database_media.global_media && !config.database_media.native_triggers {
+        return Err(ConfigError::GlobalMediaWithoutNativeTriggers.into());
+    } else if config.database_media.global_media
+        && config
+            .database_media
+            .board_database_urls
+            .values()
+            .any(|url| *url != config.database_media.database_url)
+    {
+        return Err(ConfigError::GlobalMediaOnOtherServer.into());
+    }
+
+    fs::create_dir_all(&config.database_media.media_path)
+        .context("Could not create media directory")?;
+    let mut test_file = config.database_media.media_path.clone();
+    test_file.push("ena_permission_test");
+    File::create(&test_file).context("Could not create test file in media directory")?;
+    fs::remove_file(&test_file).context("Could not remove media directory permission test file")?;
+
+    if let Some(state_path) = &config.state.path {
+        fs::create_dir_all(state_path).context("Could not create state directory")?;
+    }
+
+    if let Some(name) = boards_config
+        .groups
+        .iter()
+        .find(|(_, group)| group.group.is_some())
+        .map(|(name, _)| name)
+    {
+        return Err(ConfigError::GroupInGroup(name.clone()).into());
+    }
+
+    let asagi_compat = &config.asagi_compat;
+    let boards = Arc::get_mut(&mut config.boards).unwrap();
+    for (board, config) in boards_config.boards.into_iter() {
+        let mut config = match &config.group {
+            Some(group) => match boards_config.groups.get(group) {
+                Some(group) => config.or(group),
+                None => return Err(ConfigError::UnknownGroup(board, group.clone()).into()),
+            },
+            None => config,
+        };
+        let board: Board =
+            Value::try_into(Value::String(board)).context("Could not parse `boards`")?;
+        if !board.is_archived() && config.fetch_archive.unwrap_or(false) {
+            warn!(
+                "/{}/ is not an archived board, ignoring `fetch_archive = true`",
+                board
+            );
+            config.fetch_archive = Some(false);
+        }
+        boards.insert(board, boards_config.scraping.merge(&config, asagi_compat));
+    }
+    boards.shrink_to_fit();
+
+    if config
+        .boards
+        .values()
+        .any(|config| config.poll_interval.as_secs() < 10)
+    {
+        warn!("4chan API rules recommend a minimum `poll_interval` of 10 seconds");
+        warn!("A very short `poll_interval` may cause the API to return old data");
+    }
+
+    check_thread_list_rate(&config)?;
+
+    Ok(config)
+}
+
+/// Check that the `thread_list` rate limit allows polling every board at its `poll_interval`, so
+/// that polling doesn't silently fall behind. Since archive.json is only fetched on start, it only
+/// delays the first polls, which is worth a warning at most.
+///
+/// With `burst`, the sustained rate is still `max_interval` per `interval`, so only the requests
+/// which can be made on start (`burst` instead of `max_interval`) differ.
+fn check_thread_list_rate(config: &Config) -> Result<(), ConfigError> {
+    let settings = &config.network.rate_limiting.thread_list;
+    // Not `as_secs`, which rounds an interval shorter than a second down to 0
+    let interval = settings.interval.as_secs_f64();
+    let needed: f64 = config
+        .boards
+        .values()
+        .map(|board| interval / board.poll_interval.as_secs_f64())
+        .sum();
+    if needed > settings.max_interval as f64 {
+        return Err(ConfigError::ThreadListRateTooLow {
+            boards: config.boards.len(),
+            needed,
+            interval,
+            allowed: settings.max_interval,
+        });
+    }
+
+    if let Some(total) = config.network.rate_limiting.total_per_second {
+        let needed_per_second = needed / interval;
+        if needed_per_second > total {
+            warn!(
+                "Polling every board needs {:.2} threads.json requests per second, but \
+                 `network.rate_limiting.total_per_second` is {}. Polling will fall behind",
+                needed_per_second, total,
+            );
+        }
+    }
+
+    let archives = config
+        .boards
+        .iter()
+        .filter(|(board, config)| config.fetch_archive && board.is_archived())
+        .count();
+    let allowed_on_start = settings.burst.unwrap_or(settings.max_interval);
+    if archives + config.boards.len() > allowed_on_start {
+        warn!(
+            "On start, {} threads.json and {} archive.json requests will be made, but \
+             `network.rate_limiting.thread_list` only allows {} at once. The first polls will be \
+             delayed",
+            config.boards.len(),
+            archives,
+            allowed_on_start,
+        );
+    }
+
+    Ok(())
+}
+
+fn default_api_host() -> String {
+    API_URI_PREFIX.to_owned()
+}
+
+fn default_image_host() -> String {
+    IMG_URI_PREFIX.to_owned()
+}
+
+fn default_static_host() -> String {
+    STATIC_URI_PREFIX.to_owned()
+}
+
+fn default_writer_listen() -> SocketAddr {
+    ([127, 0, 0, 1], 7070).into()
+}
+
+/// Create a function for use with Serde's `deserialize_with` attribute which deserializes and/or
+/// validates a field.
+// This is a kludge, but it allow us to print error messages with context and doesn't require
+// procedural macros, another dependency, or copy-pasted code.
+macro_rules! deserialize_validate {
+    ($name:ident, $type:ty, $pred:expr, $err:expr $(,)*) => {
+        deserialize_validate!(
+            $name,
+            $type => $type,
+            $pred,
+            |a| a,
+            $err,
+        );
+    };
+
+    ($name:ident, $from:ty => $to:ty, $pred:expr, $ok:expr, $err:expr $(,)*) => {
+        fn $name<'de, D>(deserializer: D) -> Result<$to, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let data: $from = Deserialize::deserialize(deserializer)?;
+            if $pred(&data) {
+                Ok($ok(data))
+            } else {
+                Err(D::Error::custom($err))
+            }
+        }
+    };
+}
+
+deserialize_validate!(
+    nonempty_string,
+    String,
+    |s: &str| !s.is_empty(),
+    "string must not be empty",
+);
+
+deserialize_validate!(
+    option_nonempty_string,
+    Option<String>,
+    |s: &Option<String>| s.as_ref().map_or(true, |s| !s.is_empty()),
+    "string must not be empty",
+);
+
+fn regex_from_string<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(D::Error::custom)
+}
+
+fn option_regex_from_string<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(D::Error::custom))
+        .transpose()
+}
+
+deserialize_validate!(
+    option_thread_filter,
+    Option<ThreadFilter>,
+    |filter: &Option<ThreadFilter>| filter.as_ref().map_or(true, |filter| {
+        filter.subject.is_some() || filter.comment.is_some() || filter.filename.is_some()
+    }),
+    "`thread_filter` must set at least one of `subject`, `comment`, and `filename`",
+);
+
+deserialize_validate!(
+    pathbuf_from_string,
+    String => PathBuf,
+    |s: &str| !s.is_empty(),
+    |s| PathBuf::from(s),
+    "path must not be empty (use \".\" for current dir)",
+);
+
+deserialize_validate!(
+    option_pathbuf_from_string,
+    Option<String> => Option<PathBuf>,
+    |s: &Option<String>| s.as_ref().map_or(true, |s| !s.is_empty()),
+    |s: Option<String>| s.map(PathBuf::from),
+    "path must not be empty (use \".\" for current dir)",
+);
+
+deserialize_validate!(
+    validate_table_template,
+    Option<String>,
+    |s: &Option<String>| s.as_ref().map_or(true, |s| s.contains("%%BOARD%%")),
+    "`table_template` must contain `%%BOARD%%`",
+);
+
+deserialize_validate!(
+    duration_from_secs,
+    u64 => Duration,
+    |_| true,
+    |secs| Duration::from_secs(secs),
+    "",
+);
+
+deserialize_validate!(
+    nonzero_duration_from_secs,
+    u64 => Duration,
+    |&secs| secs != 0,
+    |secs| Duration::from_secs(secs),
+    "interval must be at least 1 second",
+);
+
+deserialize_validate!(
+    option_nonzero_duration_from_secs,
+    Option<u64> => Option<Duration>,
+    |secs: &Option<u64>| secs.map_or(true, |s| s != 0),
+    |secs: Option<u64>| secs.map(Duration::from_secs),
+    "interval must be at least 1 second",
+);
+
+deserialize_validate!(
+    option_nonzero_duration_from_millis,
+    Option<u64> => Option<Duration>,
+    |millis: &Option<u64>| millis.map_or(true, |m| m != 0),
+    |millis: Option<u64>| millis.map(Duration::from_millis),
+    "duration must be at least 1 millisecond",
+);
+
+deserialize_validate!(
+    nonzero_duration_from_millis,
+    u64 => Duration,
+    |&millis| millis != 0,
+    |millis| Duration::from_millis(millis),
+    "delay must be at least 1 millisecond",
+);
+
+deserialize_validate!(
+    validate_min_free,
+    u64,
+    |&min_free| min_free != 0,
+    "`min_free` must be at least 1 MiB",
+);
+
+deserialize_validate!(
+    validate_max_interval,
+    usize,
+    |&max| max != 0,
+    "`max_interval` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_total_per_second,
+    Option<f64>,
+    |rate: &Option<f64>| rate.map_or(true, |r| r.is_finite() && r > 0.0),
+    "`total_per_second` must be a positive number",
+);
+
+deserialize_validate!(
+    validate_span_tags,
+    HashMap<String, String>,
+    |tags: &HashMap<String, String>| tags
+        .iter()
+        .all(|(class, tag)| !class.is_empty() && !tag.is_empty() && !tag.contains(']')),
+    "`span_tags` classes and tags must not be empty, and tags must not contain `]`",
+);
+
+deserialize_validate!(
+    validate_burst,
+    Option<usize>,
+    |burst: &Option<usize>| burst.map_or(true, |b| b != 0),
+    "`burst` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_max_rows,
+    usize,
+    |&max| max != 0,
+    "`max_rows` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_pool_max,
+    usize,
+    |&max| max != 0,
+    "`max` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_breaker_failures,
+    usize,
+    |&failures| failures != 0,
+    "`failures` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_max_comment_bytes,
+    Option<usize>,
+    |max: &Option<usize>| max.map_or(true, |max| max > 0),
+    "`max_comment_bytes` must be greater than 0",
+);
+
+deserialize_validate!(
+    validate_lease_duration,
+    u64 => Duration,
+    |&secs| secs >= 3,
+    |secs| Duration::from_secs(secs),
+    "`lease_duration` must be at least 3 seconds",
+);
+
+deserialize_validate!(
+    validate_max_tracked_threads,
+    Option<usize>,
+    |max: &Option<usize>| max.map_or(true, |max| max != 0),
+    "`max_tracked_threads` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_max_poll_stretch,
+    u32,
+    |&stretch| stretch != 0,
+    "`max_poll_stretch` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_writes_in_flight,
+    usize,
+    |&writes| writes != 0,
+    "`writes_in_flight` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_mailbox_capacity,
+    usize,
+    |&capacity| capacity != 0,
+    "mailbox capacity must be at least 1",
+);
+
+deserialize_validate!(
+    validate_proxy_urls,
+    Vec<String>,
+    |urls: &Vec<String>| !urls.is_empty() && urls.iter().all(|url| !url.is_empty()),
+    "`urls` must contain at least one URL, and URLs must not be empty",
+);
+
+deserialize_validate!(
+    host_uri,
+    String => String,
+    |s: &str| s.starts_with("http://") || s.starts_with("https://"),
+    |s: String| s.trim_end_matches('/').to_owned(),
+    "host must be a URL starting with `http://` or `https://`",
+);
+
+deserialize_validate!(
+    validate_max_failures,
+    usize,
+    |&max| max != 0,
+    "`max_failures` must be at least 1",
+);
+
+deserialize_validate!(
+    validate_max_concurrent,
+    usize,
+    |&max| max != 0,
+    "`max_concurrent` must be at least 1",
+);
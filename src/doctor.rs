@@ -0,0 +1,226 @@
+//! `ena doctor`: end-to-end checks of everything that Ena needs, printed as a pass/fail report.
+
+use std::{
+    fs::{self, File},
+    net::ToSocketAddrs,
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use failure::{err_msg, Error, ResultExt};
+use futures::prelude::*;
+use hyper::{client::HttpConnector, header, Body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use tokio::{runtime::Runtime, timer::Timeout};
+
+use crate::{
+    actors::{check_database_servers, latest_schema_version},
+    config::Config,
+    four_chan::Board,
+};
+
+const RFC_1123_FORMAT: &str = "%a, %d %b %Y %T GMT";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far our clock can be from the API's before `If-Modified-Since` requests go wrong.
+const MAX_CLOCK_SKEW: i64 = 30;
+
+/// Media directories with less free space than this fail the check.
+const MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+
+/// Run every check and print the results. Returns `true` if every check passed.
+pub fn run(config: &Config) -> bool {
+    let mut report = Report::default();
+    let mut runtime = Runtime::new().unwrap();
+    let client = HttpsConnector::new(1)
+        .map(|https| Client::builder().build::<_, Body>(https))
+        .ok();
+
+    let hosts = &config.network.hosts;
+    for &(prefix, is_api) in &[(&hosts.api, true), (&hosts.image, false)] {
+        let uri: Uri = match prefix.parse() {
+            Ok(uri) => uri,
+            Err(err) => {
+                report.check(&format!("HTTPS {}", prefix), Err(Error::from(err)));
+                continue;
+            }
+        };
+        let host = uri.host().unwrap_or_default();
+        let default_port = if uri.scheme_str() == Some("http") {
+            80
+        } else {
+            443
+        };
+        let port = uri.port_part().map_or(default_port, |port| port.as_u16());
+        report.check(&format!("DNS {}", host), resolve(host, port));
+        match &client {
+            Some(client) => {
+                let result = runtime.block_on(request(client, uri.clone()));
+                let date = result.as_ref().ok().and_then(|&(_, date)| date);
+                report.check(&format!("HTTPS {}", host), result.map(|(status, _)| status));
+                // The API's clock is what `If-Modified-Since` is compared against
+                if let (true, Some(date)) = (is_api, date) {
+                    report.check("Clock", clock_skew(date));
+                }
+            }
+            None => report.check(
+                &format!("HTTPS {}", host),
+                Err(err_msg("Could not create HttpsConnector")),
+            ),
+        }
+    }
+
+    for (server, result) in check_database_servers(config) {
+        report.check(
+            &format!("Database {}", server),
+            result.map_err(Error::from).and_then(schema_status),
+        );
+    }
+
+    report.check("Media path", media_path(config));
+
+    drop(client);
+    runtime.shutdown_on_idle().wait().unwrap();
+    report.summary()
+}
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn check(&mut self, name: &str, result: Result<String, Error>) {
+        match result {
+            Ok(detail) => {
+                self.passed += 1;
+                println!("[PASS] {}: {}", name, detail);
+            }
+            Err(err) => {
+                self.failed += 1;
+                let mut pretty = err.to_string();
+                for cause in err.iter_causes() {
+                    pretty.push_str(": ");
+                    pretty.push_str(&cause.to_string());
+                }
+                println!("[FAIL] {}: {}", name, pretty);
+            }
+        }
+    }
+
+    fn summary(&self) -> bool {
+        println!("{} passed, {} failed", self.passed, self.failed);
+        self.failed == 0
+    }
+}
+
+fn resolve(host: &str, port: u16) -> Result<String, Error> {
+    let addrs: Vec<_> = (host, port)
+        .to_socket_addrs()
+        .context("Could not resolve host")?
+        .collect();
+    Ok(match addrs.first() {
+        Some(addr) if addrs.len() == 1 => addr.ip().to_string(),
+        Some(addr) => format!("{} (and {} more)", addr.ip(), addrs.len() - 1),
+        None => return Err(err_msg("No addresses")),
+    })
+}
+
+/// Make a request, returning its status and latency, and the `Date` of the response. Any response
+/// means that the host is reachable, so the status isn't checked.
+fn request(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    uri: Uri,
+) -> impl Future<Item = (String, Option<DateTime<Utc>>), Error = Error> {
+    let start = Instant::now();
+    Timeout::new(client.get(uri), REQUEST_TIMEOUT)
+        .map_err(|err| {
+            if err.is_elapsed() {
+                err_msg("Timed out")
+            } else if err.is_inner() {
+                Error::from(err.into_inner().unwrap())
+            } else {
+                Error::from(err.into_timer().unwrap())
+            }
+        })
+        .map(move |res| {
+            let date = res
+                .headers()
+                .get(header::DATE)
+                .and_then(|date| date.to_str().ok())
+                .and_then(|date| Utc.datetime_from_str(date, RFC_1123_FORMAT).ok());
+            let elapsed = start.elapsed();
+            let status = format!(
+                "{} in {} ms",
+                res.status(),
+                elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+            );
+            (status, date)
+        })
+}
+
+fn clock_skew(api_date: DateTime<Utc>) -> Result<String, Error> {
+    let skew = (Utc::now() - api_date).num_seconds();
+    if skew.abs() > MAX_CLOCK_SKEW {
+        Err(err_msg(format!(
+            "Local clock is {} seconds {} the API's. Sync it (e.g. with NTP)",
+            skew.abs(),
+            if skew > 0 { "ahead of" } else { "behind" },
+        )))
+    } else {
+        Ok(format!("Within {} seconds of the API", skew.abs()))
+    }
+}
+
+/// Older schemas are migrated when Ena starts, but newer ones were made by a newer version of Ena.
+fn schema_status(versions: Vec<(Board, usize)>) -> Result<String, Error> {
+    let latest = latest_schema_version();
+    let boards_at = |newer: bool| -> Vec<_> {
+        versions
+            .iter()
+            .filter(|&&(_, version)| {
+                if newer {
+                    version > latest
+                } else {
+                    version < latest
+                }
+            })
+            .map(|(board, version)| format!("/{}/ is at {}", board, version))
+            .collect()
+    };
+    let newer = boards_at(true);
+    if !newer.is_empty() {
+        return Err(err_msg(format!(
+            "Schema is newer than this version of Ena (latest is {}): {}",
+            latest,
+            newer.join(", ")
+        )));
+    }
+    let older = boards_at(false);
+    if older.is_empty() {
+        Ok(format!("Connected, schema version {}", latest))
+    } else {
+        Ok(format!(
+            "Connected, will migrate to schema version {} on startup: {}",
+            latest,
+            older.join(", ")
+        ))
+    }
+}
+
+fn media_path(config: &Config) -> Result<String, Error> {
+    let path = &config.database_media.media_path;
+    let test_file = path.join("ena_doctor_test");
+    File::create(&test_file).context("Could not create test file")?;
+    fs::remove_file(&test_file).context("Could not remove test file")?;
+
+    let free = fs2::available_space(path).context("Could not get free space")?;
+    let free_gib = free as f64 / (1024.0 * 1024.0 * 1024.0);
+    if free < MIN_FREE_SPACE {
+        Err(err_msg(format!("Only {:.2} GiB free", free_gib)))
+    } else {
+        Ok(format!("Writable, {:.1} GiB free", free_gib))
+    }
+}
@@ -1,13 +1,102 @@
 //! 4chan API definitions.
 
-use std::fmt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 mod tests;
 
 pub const API_URI_PREFIX: &str = "https://a.4cdn.org";
 pub const IMG_URI_PREFIX: &str = "https://i.4cdn.org";
+pub const STATIC_URI_PREFIX: &str = "https://s.4cdn.org";
+
+/// A wrapper struct used to deserialize the outer JSON object of `boards.json`.
+#[derive(Deserialize)]
+pub struct BoardsWrapper {
+    pub boards: Vec<BoardInfo>,
+}
+
+/// A board's settings from `boards.json`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BoardInfo {
+    // Required fields
+    pub board: Board,
+    pub title: String,
+    /// Set when the board is worksafe
+    #[serde(deserialize_with = "num_to_bool")]
+    pub ws_board: bool,
+    /// The number of threads on a page
+    pub per_page: u32,
+    /// The number of pages (so the board holds at most `per_page * pages` threads)
+    pub pages: u32,
+    /// The maximum file size in bytes
+    pub max_filesize: u32,
+    /// The maximum WebM file size in bytes
+    pub max_webm_filesize: u32,
+    pub max_comment_chars: u32,
+    /// The maximum WebM duration in seconds
+    pub max_webm_duration: u32,
+    /// The number of replies after which a thread stops bumping
+    pub bump_limit: u32,
+    /// The number of images after which no more images can be posted
+    pub image_limit: u32,
+    pub cooldowns: Cooldowns,
+
+    // Optional fields
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub is_archived: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub spoilers: bool,
+    /// The number of custom spoilers the board has
+    pub custom_spoilers: Option<u8>,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub user_ids: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub country_flags: bool,
+    /// Board flag codes and their names, on boards with board flags (e.g. /pol/)
+    pub board_flags: Option<HashMap<String, String>>,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub code_tags: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub math_tags: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub sjis_tags: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub oekaki: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub text_only: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub forced_anon: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub webm_audio: bool,
+    #[serde(deserialize_with = "num_to_bool")]
+    #[serde(default)]
+    pub require_subject: bool,
+    pub min_image_width: Option<u16>,
+    pub min_image_height: Option<u16>,
+}
+
+/// A board's posting cooldowns in seconds.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Cooldowns {
+    pub threads: u32,
+    pub replies: u32,
+    pub images: u32,
+}
 
 /// A wrapper struct used to deserialize the page objects of `threads.json`.
 #[derive(Deserialize)]
@@ -18,66 +107,223 @@ pub struct ThreadPage {
 /// A single thread from `threads.json`.
 #[derive(Deserialize)]
 pub struct Thread {
-    pub no: u64,
+    pub no: ThreadNo,
     pub last_modified: u64,
     #[serde(skip_deserializing)]
     pub bump_index: usize,
 }
 
+/// A wrapper struct used to deserialize the page objects of `catalog.json`.
+#[derive(Deserialize)]
+pub struct CatalogPage {
+    pub page: u32,
+    pub threads: Vec<CatalogThread>,
+}
+
+/// A single thread from `catalog.json`: its OP and a preview of its last replies.
+#[derive(Deserialize)]
+pub struct CatalogThread {
+    #[serde(flatten)]
+    pub op: Post,
+    /// The number of replies and image replies which aren't in `last_replies`
+    #[serde(default)]
+    pub omitted_posts: u32,
+    #[serde(default)]
+    pub omitted_images: u32,
+    /// Up to the last five replies of the thread
+    #[serde(default)]
+    pub last_replies: Vec<Post>,
+    pub last_modified: u64,
+}
+
 /// A wrapper struct used to deserialize the outer JSON object of a thread.
 #[derive(Deserialize)]
 pub struct PostsWrapper {
     pub posts: Vec<Post>,
 }
 
-/// A struct representing a post.
-///
-/// Unused fields are omitted.
+/// A wrapper struct used to deserialize the posts of a thread without processing them.
 #[derive(Deserialize)]
+pub struct RawPostsWrapper {
+    pub posts: Vec<serde_json::Value>,
+}
+
+/// A struct representing a post. Serializing a post gives the API's JSON, except that unset flags
+/// (e.g. an OP's `bumplimit: 0`) are omitted.
+#[derive(Deserialize, Serialize)]
 pub struct Post {
     // Required fields
-    pub no: u64,
-    #[serde(rename = "resto")]
-    pub reply_to: u64,
+    pub no: PostNo,
+    /// The thread that this post replies to, or `None` if this post is an OP (`resto` is 0)
+    #[serde(
+        rename = "resto",
+        deserialize_with = "resto_to_thread",
+        serialize_with = "thread_to_resto"
+    )]
+    pub reply_to: Option<ThreadNo>,
     pub time: u64,
 
     // Optional fields
+    /// The post time as shown on the site (MM/DD/YY(Day)HH:MM, with seconds on some boards)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub now: Option<String>,
     /// Only blank when name is blank and trip is provided
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub trip: Option<String>,
     /// Displays if board has DISPLAY_ID set
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<String>,
-    pub capcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capcode: Option<Capcode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub country: Option<String>,
-    #[serde(rename = "sub")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_name: Option<String>,
+    /// The board flag code, on boards with board flags (e.g. /pol/)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub board_flag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flag_name: Option<String>,
+    #[serde(rename = "sub", skip_serializing_if = "Option::is_none")]
     pub subject: Option<String>,
-    #[serde(rename = "com")]
+    #[serde(rename = "com", skip_serializing_if = "Option::is_none")]
     pub comment: Option<String>,
+    /// The year the poster bought their 4chan Pass, if they're showing the Pass badge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since4pass: Option<u16>,
+    /// The category of a /f/ thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+
+    // OP-only fields which change too often to be part of `OpData`
+    /// The slug of the thread's URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub semantic_url: Option<String>,
+    /// The ID of the board's custom spoiler image to use, if the board has custom spoilers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_spoiler: Option<u8>,
+    /// The number of replies and image replies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replies: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images: Option<u32>,
+    /// The number of posts in the thread's tail JSON (only in `-tail.json`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tail_size: Option<u32>,
 
     #[serde(flatten)]
     pub op_data: OpData,
 
     #[serde(flatten)]
     pub image: Option<PostImage>,
+
+    /// The unmodified JSON of this post. Only set if `store_raw_json` is enabled.
+    #[serde(skip)]
+    pub raw_json: Option<String>,
+    /// Extra entries for the `exif` column, added by post processors
+    #[serde(skip)]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// The capcode of a post.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Capcode {
+    Mod,
+    Admin,
+    AdminHighlight,
+    Manager,
+    Developer,
+    Founder,
+    Verified,
+    /// A capcode that isn't in the API documentation
+    Unknown(String),
+}
+
+impl Capcode {
+    /// The single-letter code that Asagi uses for this capcode. Like Asagi, unknown capcodes use
+    /// their uppercased first letter.
+    pub fn to_asagi(&self) -> char {
+        match self {
+            Capcode::Mod => 'M',
+            Capcode::Admin | Capcode::AdminHighlight => 'A',
+            Capcode::Manager => 'G',
+            Capcode::Developer => 'D',
+            Capcode::Founder => 'F',
+            Capcode::Verified => 'V',
+            Capcode::Unknown(capcode) => capcode
+                .chars()
+                .next()
+                .map_or('N', |c| c.to_ascii_uppercase()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Capcode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let capcode: String = Deserialize::deserialize(deserializer)?;
+        Ok(match capcode.as_str() {
+            "mod" => Capcode::Mod,
+            "admin" => Capcode::Admin,
+            "admin_highlight" => Capcode::AdminHighlight,
+            "manager" => Capcode::Manager,
+            "developer" => Capcode::Developer,
+            "founder" => Capcode::Founder,
+            "verified" => Capcode::Verified,
+            _ => Capcode::Unknown(capcode),
+        })
+    }
+}
+
+impl Serialize for Capcode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(match self {
+            Capcode::Mod => "mod",
+            Capcode::Admin => "admin",
+            Capcode::AdminHighlight => "admin_highlight",
+            Capcode::Manager => "manager",
+            Capcode::Developer => "developer",
+            Capcode::Founder => "founder",
+            Capcode::Verified => "verified",
+            Capcode::Unknown(capcode) => capcode,
+        })
+    }
 }
 
 /// A struct representing the OP data of a post.
-#[derive(Clone, Deserialize, PartialEq)]
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
 pub struct OpData {
-    #[serde(deserialize_with = "num_to_bool")]
-    #[serde(default)]
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub sticky: bool,
-    #[serde(deserialize_with = "num_to_bool")]
-    #[serde(default)]
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub closed: bool,
-    #[serde(deserialize_with = "num_to_bool")]
-    #[serde(default)]
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub archived: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub archived_on: Option<u64>,
+    /// Only present if the thread hasn't been archived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique_ips: Option<u32>,
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bumplimit: bool,
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub imagelimit: bool,
 }
 
 /// A struct representing the image data of a post.
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct PostImage {
     pub filename: String,
     pub ext: String,
@@ -94,9 +340,74 @@ pub struct PostImage {
     pub thumbnail_width: u8,
     #[serde(rename = "tn_h")]
     pub thumbnail_height: u8,
-    #[serde(deserialize_with = "num_to_bool")]
-    #[serde(default)]
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub spoiler: bool,
+    /// Set when the file has been deleted (but the post has not)
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub filedeleted: bool,
+    /// Set when a mobile optimized version of the image exists
+    #[serde(deserialize_with = "num_to_bool", serialize_with = "bool_to_num")]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub m_img: bool,
+}
+
+/// The number of a post.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct PostNo(pub u64);
+
+/// The number of a thread, which is the number of its OP.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ThreadNo(pub u64);
+
+impl fmt::Display for PostNo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for ThreadNo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl From<ThreadNo> for PostNo {
+    /// The OP of a thread.
+    fn from(no: ThreadNo) -> Self {
+        PostNo(no.0)
+    }
+}
+
+impl Post {
+    pub fn is_op(&self) -> bool {
+        self.reply_to.is_none()
+    }
+
+    /// The thread that this post is in.
+    pub fn thread_no(&self) -> ThreadNo {
+        self.reply_to.unwrap_or(ThreadNo(self.no.0))
+    }
+}
+
+fn resto_to_thread<'de, D>(deserializer: D) -> Result<Option<ThreadNo>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let resto: u64 = Deserialize::deserialize(deserializer)?;
+    Ok(if resto == 0 {
+        None
+    } else {
+        Some(ThreadNo(resto))
+    })
+}
+
+fn thread_to_resto<S>(reply_to: &Option<ThreadNo>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u64(reply_to.map_or(0, |no| no.0))
 }
 
 fn num_to_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
@@ -114,6 +425,17 @@ where
     }
 }
 
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn bool_to_num<S>(b: &bool, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u8(*b as u8)
+}
+
 impl fmt::Display for Board {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if let Board::_3 = self {
@@ -135,7 +457,7 @@ impl Board {
 
 /// An enum of every 4chan board.
 #[allow(non_camel_case_types)]
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub enum Board {
     #[serde(rename = "3")]
     _3,
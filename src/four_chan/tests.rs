@@ -4,23 +4,11 @@ use failure::Error;
 use futures::prelude::*;
 use hyper::{Body, Client};
 use hyper_tls::HttpsConnector;
-use serde::Deserialize;
 use tokio::runtime::Runtime;
 
-use super::{num_to_bool, API_URI_PREFIX};
-
-#[derive(Deserialize)]
-struct BoardsWrapper {
-    boards: Vec<Board>,
-}
-
-#[derive(Deserialize)]
-struct Board {
-    board: super::Board,
-    #[serde(deserialize_with = "num_to_bool")]
-    #[serde(default)]
-    is_archived: bool,
-}
+use super::{
+    BoardInfo, BoardsWrapper, Capcode, CatalogPage, PostNo, PostsWrapper, ThreadNo, API_URI_PREFIX,
+};
 
 #[test]
 fn boards_json() -> Result<(), Error> {
@@ -30,7 +18,7 @@ fn boards_json() -> Result<(), Error> {
 
     let uri = format!("{}/boards.json", API_URI_PREFIX).parse()?;
 
-    let boards: Result<Vec<Board>, Error> = runtime.block_on(
+    let boards: Result<Vec<BoardInfo>, Error> = runtime.block_on(
         client
             .get(uri)
             .from_err()
@@ -42,7 +30,10 @@ fn boards_json() -> Result<(), Error> {
     );
     runtime.shutdown_now().wait().unwrap();
 
-    for Board { board, is_archived } in boards? {
+    for BoardInfo {
+        board, is_archived, ..
+    } in boards?
+    {
         assert_eq!(
             board.is_archived(),
             is_archived,
@@ -53,3 +44,139 @@ fn boards_json() -> Result<(), Error> {
     }
     Ok(())
 }
+
+#[test]
+fn capcodes() {
+    let capcodes: Vec<Capcode> = serde_json::from_str(
+        r#"["mod", "admin", "admin_highlight", "manager", "developer", "founder", "verified", "janitor", ""]"#,
+    )
+    .unwrap();
+    let asagi: String = capcodes.iter().map(Capcode::to_asagi).collect();
+    assert_eq!(asagi, "MAAGDFVJN");
+    assert_eq!(capcodes[7], Capcode::Unknown(String::from("janitor")));
+}
+
+#[test]
+fn post_fields() {
+    let PostsWrapper { posts } = serde_json::from_str(
+        r#"{"posts": [
+            {"no": 1234, "now": "01/01/20(Wed)12:00:00", "name": "Anonymous", "sub": "Subject",
+             "com": "Comment", "filename": "image", "ext": ".png", "w": 800, "h": 600, "tn_w": 250,
+             "tn_h": 187, "tim": 1577898000123, "time": 1577898000, "md5": "c2tpcCBtZQ==",
+             "fsize": 12345, "m_img": 1, "resto": 0, "bumplimit": 0, "imagelimit": 0,
+             "semantic_url": "subject", "custom_spoiler": 2, "replies": 5, "images": 1,
+             "unique_ips": 3, "tag": "Other", "board_flag": "AC", "flag_name": "Anarcho-Capitalist",
+             "since4pass": 2016},
+            {"no": 1235, "now": "01/01/20(Wed)12:01:00", "name": "Anonymous", "com": "Reply",
+             "time": 1577898060, "resto": 1234, "country": "US", "country_name": "United States",
+             "filedeleted": 1}
+        ]}"#,
+    )
+    .unwrap();
+
+    let op = &posts[0];
+    assert!(op.is_op());
+    assert_eq!(op.thread_no(), ThreadNo(1234));
+    assert_eq!(op.now.as_ref().unwrap(), "01/01/20(Wed)12:00:00");
+    assert_eq!(op.semantic_url.as_ref().unwrap(), "subject");
+    assert_eq!(op.custom_spoiler, Some(2));
+    assert_eq!((op.replies, op.images), (Some(5), Some(1)));
+    assert_eq!(op.op_data.unique_ips, Some(3));
+    assert_eq!(op.tag.as_ref().unwrap(), "Other");
+    assert_eq!(op.board_flag.as_ref().unwrap(), "AC");
+    assert_eq!(op.flag_name.as_ref().unwrap(), "Anarcho-Capitalist");
+    assert_eq!(op.since4pass, Some(2016));
+    assert!(op.image.as_ref().unwrap().m_img);
+
+    let reply = &posts[1];
+    assert_eq!(reply.no, PostNo(1235));
+    assert_eq!(reply.reply_to, Some(ThreadNo(1234)));
+    assert_eq!(reply.thread_no(), ThreadNo(1234));
+    assert_eq!(reply.country_name.as_ref().unwrap(), "United States");
+    assert_eq!(reply.semantic_url, None);
+}
+
+#[test]
+fn board_info() {
+    let BoardsWrapper { boards } = serde_json::from_str(
+        r#"{"boards": [
+            {"board": "pol", "title": "Politically Incorrect", "ws_board": 0, "per_page": 15,
+             "pages": 10, "max_filesize": 4194304, "max_webm_filesize": 3145728,
+             "max_comment_chars": 2000, "max_webm_duration": 120, "bump_limit": 300,
+             "image_limit": 150, "cooldowns": {"threads": 600, "replies": 60, "images": 60},
+             "meta_description": "&quot;/pol/ - Politically Incorrect&quot;", "is_archived": 1,
+             "country_flags": 1, "board_flags": {"AC": "Anarcho-Capitalist", "NZ": "Nazi"}},
+            {"board": "b", "title": "Random", "ws_board": 0, "per_page": 15, "pages": 10,
+             "max_filesize": 2097152, "max_webm_filesize": 2097152, "max_comment_chars": 2000,
+             "max_webm_duration": 120, "bump_limit": 300, "image_limit": 150,
+             "cooldowns": {"threads": 600, "replies": 60, "images": 60},
+             "meta_description": "&quot;/b/ - Random&quot;", "user_ids": 1, "spoilers": 1,
+             "custom_spoilers": 1, "forced_anon": 1}
+        ]}"#,
+    )
+    .unwrap();
+
+    let pol = &boards[0];
+    assert_eq!(pol.board, super::Board::pol);
+    assert_eq!(pol.per_page * pol.pages, 150);
+    assert_eq!((pol.bump_limit, pol.image_limit), (300, 150));
+    assert_eq!(pol.cooldowns.threads, 600);
+    assert!(pol.is_archived && pol.country_flags && !pol.spoilers);
+    assert_eq!(
+        pol.board_flags.as_ref().unwrap()["AC"],
+        "Anarcho-Capitalist"
+    );
+
+    let b = &boards[1];
+    assert!(!b.is_archived && b.spoilers && b.user_ids && b.forced_anon);
+    assert_eq!(b.custom_spoilers, Some(1));
+    assert!(b.board_flags.is_none());
+}
+
+#[test]
+fn post_serialize() {
+    let json = serde_json::json!({"posts": [
+        {"no": 1234, "now": "01/01/20(Wed)12:00:00", "name": "Anonymous", "sub": "Subject",
+         "com": "Comment", "filename": "image", "ext": ".png", "w": 800, "h": 600, "tn_w": 250,
+         "tn_h": 187, "tim": 1_577_898_000_123u64, "time": 1_577_898_000, "md5": "c2tpcCBtZQ==",
+         "fsize": 12345, "spoiler": 1, "resto": 0, "sticky": 1, "closed": 1, "bumplimit": 1,
+         "capcode": "admin_highlight", "semantic_url": "subject", "replies": 1, "images": 0,
+         "unique_ips": 2},
+        {"no": 1235, "now": "01/01/20(Wed)12:01:00", "name": "Anonymous", "com": "Reply",
+         "time": 1_577_898_060, "resto": 1234, "capcode": "janitor", "since4pass": 2016}
+    ]});
+    let PostsWrapper { posts } = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&posts).unwrap(), json["posts"]);
+}
+
+#[test]
+fn catalog_json() {
+    let pages: Vec<CatalogPage> = serde_json::from_str(
+        r#"[{"page": 1, "threads": [
+            {"no": 1234, "now": "01/01/20(Wed)12:00:00", "name": "Anonymous", "sub": "Subject",
+             "com": "Comment", "time": 1577898000, "resto": 0, "sticky": 1, "closed": 1,
+             "semantic_url": "subject", "replies": 7, "images": 2, "omitted_posts": 2,
+             "omitted_images": 1, "last_modified": 1577898500, "last_replies": [
+                {"no": 1240, "now": "01/01/20(Wed)12:05:00", "name": "Anonymous", "com": "Reply",
+                 "time": 1577898300, "resto": 1234}
+             ]},
+            {"no": 1300, "now": "01/01/20(Wed)12:10:00", "name": "Anonymous", "time": 1577898600,
+             "resto": 0, "replies": 0, "images": 0, "last_modified": 1577898600}
+        ]}]"#,
+    )
+    .unwrap();
+
+    let threads = &pages[0].threads;
+    assert_eq!(pages[0].page, 1);
+    assert_eq!(threads[0].op.no, PostNo(1234));
+    assert_eq!(threads[0].op.subject.as_ref().unwrap(), "Subject");
+    assert!(threads[0].op.op_data.sticky && threads[0].op.op_data.closed);
+    assert_eq!(threads[0].op.replies, Some(7));
+    assert_eq!(
+        (threads[0].omitted_posts, threads[0].omitted_images),
+        (2, 1)
+    );
+    assert_eq!(threads[0].last_modified, 1_577_898_500);
+    assert_eq!(threads[0].last_replies[0].reply_to, Some(ThreadNo(1234)));
+    assert!(threads[1].last_replies.is_empty());
+}
@@ -0,0 +1,352 @@
+//! A parsed comment, for uses which need its structure (like quotes and links) instead of only its
+//! text.
+
+use std::slice;
+
+use failure::Fail;
+
+use super::*;
+
+lazy_static! {
+    static ref EXIF: Regex = Regex::new(
+        r#"<br><br><span class="abbr">.*?</span><br><table class="exif".*?</table>"#
+    ).unwrap();
+    // The tags which the grammar leaves in text
+    static ref INLINE: Regex = Regex::new(
+        r#"<a href="([^"]*)"(?P<quotelink> class="quotelink")?[^>]*>(.*?)</a>|<br>|<wbr>|</?[sbiu]>"#
+    ).unwrap();
+    static ref WBR: Regex = Regex::new("<wbr>").unwrap();
+}
+
+/// A parsed comment. Entities in text and attributes are unescaped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ast {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    Text(String),
+    LineBreak,
+    /// The start of a `<s>`, `<b>`, `<i>`, or `<u>` tag. These are kept as separate start and end
+    /// nodes because they aren't always nested properly.
+    Start(Style),
+    End(Style),
+    /// A link to a post, like `>>123456` or `>>>/g/123456`
+    Quotelink {
+        href: String,
+        text: String,
+    },
+    /// A link which isn't a quotelink
+    Link {
+        href: String,
+        text: String,
+    },
+    Greentext(Vec<Node>),
+    /// A quotelink to a post which no longer exists
+    DeadLink(Vec<Node>),
+    Fortune {
+        color: String,
+        text: String,
+    },
+    QstColor(QstColor, Vec<Node>),
+    Banned(Vec<Node>),
+    Code(Vec<Node>),
+    /// A `<span class="...">` without special handling (e.g. `sjis` or `math`)
+    Span {
+        class: String,
+        children: Vec<Node>,
+    },
+    /// An unknown `<span>`, `<pre>`, or `<strong>` element
+    Other {
+        start: String,
+        end: String,
+        children: Vec<Node>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Spoiler,
+    Bold,
+    Italic,
+    Underline,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QstColor {
+    Red,
+    Green,
+    Blue,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Failed to parse HTML: {}", _0)]
+pub struct ParseError(String);
+
+impl Ast {
+    /// Parse a comment. EXIF tables are removed.
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let input = EXIF.replace_all(input, "");
+        let pairs =
+            HtmlParser::parse(Rule::html, &input).map_err(|err| ParseError(err.to_string()))?;
+        let mut nodes = vec![];
+        build(&mut nodes, pairs);
+        Ok(Self { nodes })
+    }
+
+    /// Serialize to BBCode, like `clean`.
+    pub fn to_bbcode(&self) -> String {
+        let mut output = String::new();
+        to_bbcode(&mut output, &self.nodes, &DEFAULT_OPTIONS.span_tags);
+        output
+    }
+
+    /// Iterate over every node, depth-first.
+    pub fn iter(&self) -> Nodes<'_> {
+        Nodes {
+            stack: vec![self.nodes.iter()],
+        }
+    }
+
+    /// The `href`s of the quotelinks, in order.
+    pub fn quotes(&self) -> impl Iterator<Item = &str> {
+        self.iter().filter_map(|node| match node {
+            Node::Quotelink { href, .. } => Some(href.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The `href`s of the links which aren't quotelinks, in order.
+    pub fn links(&self) -> impl Iterator<Item = &str> {
+        self.iter().filter_map(|node| match node {
+            Node::Link { href, .. } => Some(href.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The text of the comment, in order. This includes the text of links and fortunes.
+    pub fn text(&self) -> impl Iterator<Item = &str> {
+        self.iter().filter_map(|node| match node {
+            Node::Text(text)
+            | Node::Quotelink { text, .. }
+            | Node::Link { text, .. }
+            | Node::Fortune { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// A depth-first iterator over the nodes of an `Ast`.
+pub struct Nodes<'a> {
+    stack: Vec<slice::Iter<'a, Node>>,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<&'a Node> {
+        loop {
+            let node = match self.stack.last_mut()?.next() {
+                Some(node) => node,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            match node {
+                Node::Greentext(children)
+                | Node::DeadLink(children)
+                | Node::QstColor(_, children)
+                | Node::Banned(children)
+                | Node::Code(children)
+                | Node::Span { children, .. }
+                | Node::Other { children, .. } => self.stack.push(children.iter()),
+                _ => {}
+            }
+            return Some(node);
+        }
+    }
+}
+
+fn build(nodes: &mut Vec<Node>, pairs: Pairs<Rule>) {
+    for pair in pairs {
+        let node = match pair.as_rule() {
+            Rule::text => {
+                build_text(nodes, pair.as_str());
+                continue;
+            }
+            Rule::quote => Node::Greentext(children(pair.into_inner())),
+            Rule::deadlink => Node::DeadLink(children(pair.into_inner())),
+            Rule::fortune => {
+                let mut inner = pair.into_inner();
+                Node::Fortune {
+                    color: inner.next().unwrap().as_str().to_owned(),
+                    text: unescape(inner.next().unwrap().as_str(), None).into_owned(),
+                }
+            }
+            Rule::qst_color => {
+                let mut inner = pair.into_inner();
+                let color = match inner.next().unwrap().as_rule() {
+                    Rule::red => QstColor::Red,
+                    Rule::green => QstColor::Green,
+                    Rule::blue => QstColor::Blue,
+                    _ => unreachable!(),
+                };
+                Node::QstColor(color, children(inner))
+            }
+            Rule::banned => Node::Banned(children(pair.into_inner())),
+            Rule::code => Node::Code(children(pair.into_inner())),
+            Rule::other => {
+                let mut inner = pair.into_inner();
+                let start = inner.next().unwrap().as_str();
+                let children = children(inner.next().unwrap().into_inner());
+                let end = inner.next().unwrap().as_str();
+                match SPAN_START.captures(start) {
+                    Some(caps) => Node::Span {
+                        class: unescape(&caps[1], None).into_owned(),
+                        children,
+                    },
+                    None => Node::Other {
+                        start: unescape(start, None).into_owned(),
+                        end: unescape(end, None).into_owned(),
+                        children,
+                    },
+                }
+            }
+            Rule::EOI => continue,
+            _ => unreachable!(),
+        };
+        nodes.push(node);
+    }
+}
+
+fn children(pairs: Pairs<Rule>) -> Vec<Node> {
+    let mut nodes = vec![];
+    build(&mut nodes, pairs);
+    nodes
+}
+
+/// Split text into text, link, and simple tag nodes.
+fn build_text(nodes: &mut Vec<Node>, text: &str) {
+    let mut pos = 0;
+    for caps in INLINE.captures_iter(text) {
+        let m = caps.get(0).unwrap();
+        push_text(nodes, &text[pos..m.start()]);
+        pos = m.end();
+        let node = match m.as_str() {
+            "<br>" => Node::LineBreak,
+            "<wbr>" => continue,
+            "<s>" => Node::Start(Style::Spoiler),
+            "</s>" => Node::End(Style::Spoiler),
+            "<b>" => Node::Start(Style::Bold),
+            "</b>" => Node::End(Style::Bold),
+            "<i>" => Node::Start(Style::Italic),
+            "</i>" => Node::End(Style::Italic),
+            "<u>" => Node::Start(Style::Underline),
+            "</u>" => Node::End(Style::Underline),
+            _ => {
+                let href = unescape(&caps[1], None).into_owned();
+                let text = unescape(WBR.replace_all(&caps[3], ""), None).into_owned();
+                if caps.name("quotelink").is_some() {
+                    Node::Quotelink { href, text }
+                } else {
+                    Node::Link { href, text }
+                }
+            }
+        };
+        nodes.push(node);
+    }
+    push_text(nodes, &text[pos..]);
+}
+
+/// Push unescaped text, merging it with the previous node if that is also text.
+fn push_text(nodes: &mut Vec<Node>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    let text = unescape(text, None);
+    if let Some(Node::Text(prev)) = nodes.last_mut() {
+        prev.push_str(&text);
+    } else {
+        nodes.push(Node::Text(text.into_owned()));
+    }
+}
+
+fn to_bbcode(output: &mut String, nodes: &[Node], span_tags: &HashMap<String, String>) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => output.push_str(text),
+            Node::LineBreak => output.push('\n'),
+            Node::Start(style) => {
+                output.push('[');
+                output.push_str(style.tag());
+                output.push(']');
+            }
+            Node::End(style) => {
+                output.push_str("[/");
+                output.push_str(style.tag());
+                output.push(']');
+            }
+            Node::Quotelink { text, .. } | Node::Link { text, .. } => output.push_str(text),
+            Node::Greentext(children) | Node::DeadLink(children) => {
+                to_bbcode(output, children, span_tags)
+            }
+            Node::Fortune { color, text } => {
+                output.push_str(&format!("[fortune color=\"{}\"]{}[/fortune]", color, text));
+            }
+            Node::QstColor(color, children) => {
+                output.push_str(match color {
+                    QstColor::Red => "[qstcolor=red]",
+                    QstColor::Green => "[qstcolor=green]",
+                    QstColor::Blue => "[qstcolor=blue]",
+                });
+                to_bbcode(output, children, span_tags);
+                output.push_str("[/qstcolor]");
+            }
+            Node::Banned(children) => {
+                output.push_str("[banned]");
+                to_bbcode(output, children, span_tags);
+                output.push_str("[/banned]");
+            }
+            Node::Code(children) => {
+                output.push_str("[code]");
+                to_bbcode(output, children, span_tags);
+                output.push_str("[/code]");
+            }
+            Node::Span { class, children } => match span_tags.get(class) {
+                Some(tag) => {
+                    output.push_str(&format!("[{}]", tag));
+                    to_bbcode(output, children, span_tags);
+                    output.push_str(&format!("[/{}]", tag));
+                }
+                None => {
+                    output.push_str(&format!("<span class=\"{}\">", class));
+                    to_bbcode(output, children, span_tags);
+                    output.push_str("</span>");
+                }
+            },
+            Node::Other {
+                start,
+                end,
+                children,
+            } => {
+                output.push_str(start);
+                to_bbcode(output, children, span_tags);
+                output.push_str(end);
+            }
+        }
+    }
+}
+
+impl Style {
+    /// The BBCode tag name.
+    fn tag(self) -> &'static str {
+        match self {
+            Style::Spoiler => "spoiler",
+            Style::Bold => "b",
+            Style::Italic => "i",
+            Style::Underline => "u",
+        }
+    }
+}
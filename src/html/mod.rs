@@ -1,21 +1,28 @@
-//! 4chan HTML unescaping and cleaning (HTML to BBCode conversion).
+//! 4chan HTML unescaping and cleaning (HTML to BBCode or plain text conversion).
 
 // We use trivial regexes because of useful methods like is_match and replace_all, which are much
 // faster than their std equivalents.
 #![allow(clippy::trivial_regex)]
 
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap};
 
 use lazy_static::lazy_static;
 use log::Level;
 use pest::{iterators::Pairs, Parser};
 use pest_derive::Parser;
-use regex::Regex;
+use regex::{Captures, Regex};
 
-use crate::four_chan::Board;
+use crate::four_chan::{Board, PostNo};
 
+mod ast;
+mod report;
 mod tests;
 
+use report::TokenKind;
+
+pub use ast::{Ast, Node, Nodes, ParseError, QstColor, Style};
+pub use report::{log_unknown_report, unknown_token_count};
+
 #[derive(Parser)]
 #[grammar = "html/html.pest"]
 struct HtmlParser;
@@ -29,13 +36,53 @@ lazy_static! {
         r#"<wbr>|<a[^>]*>|</a>|<br><br><span class="abbr">.*?</span><br><table class="exif".*?</table>"#,
     ).unwrap();
     static ref SIMPLE_TAGS: Regex = Regex::new("<br>|<s>|</s>|<b>|</b>|<i>|</i>|<u>|</u>").unwrap();
+    // Links which aren't quotelinks have the `quotelink` group unset
+    static ref LINK: Regex =
+        Regex::new(r#"<a href="([^"]*)"(?P<quotelink> class="quotelink")?[^>]*>(.*?)</a>"#).unwrap();
+    // The `href` of a quotelink: `#p<post>` for the same thread, or `/<board>/thread/<thread>#p<post>`
+    // (without `#p<post>` for OPs)
+    static ref QUOTE_HREF: Regex =
+        Regex::new(r"^(?:/([[:alnum:]]+)/thread/(\d+))?(?:#p(\d+))?$").unwrap();
+    // Quotelinks to posts which no longer exist
+    static ref DEADLINK: Regex =
+        Regex::new(r#"<span class="deadlink">&gt;&gt;(?:&gt;/([[:alnum:]]+)/)?(\d+)</span>"#).unwrap();
+    // 4chan doesn't link most URLs, and breaks up long ones with `<wbr>`. A URL ends at a tag, the
+    // end of an attribute, whitespace, or an escaped character which can't be in a URL (`&quot;`,
+    // `&lt;`, `&gt;`, and `&#039;`)
+    static ref URL: Regex = Regex::new(r#"(?i)\bhttps?://(?:[^\s<>&"]|&amp;)+"#).unwrap();
+    static ref WBR: Regex = Regex::new("<wbr>").unwrap();
+    static ref SPAN_START: Regex = Regex::new(r#"^<span class="([^"]+)">$"#).unwrap();
+    static ref DEFAULT_OPTIONS: CleanOptions = CleanOptions::default();
+    static ref TEXT_OPTIONS: CleanOptions = CleanOptions {
+        mode: CleanMode::Text,
+        ..CleanOptions::default()
+    };
     // It's tricky to match unknown elements, so we only match the tags and skip the contents
     static ref UNKNOWN_TAG: Regex = Regex::new("<[^>]+>").unwrap();
+    // An opening (`[b]`, `[qstcolor=red]`, `[fortune color="..."]`) or closing (`[/b]`) tag
+    static ref BBCODE_TAG: Regex = Regex::new(r"\[(/?)([[:alnum:]_-]+)(?:[= ][^\[\]]*)?\]").unwrap();
+}
+
+/// Unescape (some) HTML entities. Unknown entities are counted for `log_unknown_report`. If debug
+/// messages are enabled, the board and post number from `context` is printed to trace unknown
+/// entities back to their origins.
+///
+/// If nothing needs to be unescaped, `input` is returned as is (without copying it).
+pub fn unescape<'a>(
+    input: impl Into<Cow<'a, str>>,
+    context: Option<(Board, PostNo)>,
+) -> Cow<'a, str> {
+    unescape_with(input, context, false)
 }
 
-/// Unescape (some) HTML entities. If warnings are enabled, the board and post number from `context`
-/// is printed to trace unknown entities back to their origins.
-pub fn unescape(input: String, context: Option<(Board, u64)>) -> String {
+/// Unescape HTML entities like `unescape`. If `decode_numeric` is set, all decimal (`&#12354;`)
+/// and hexadecimal (`&#x3042;`) numeric character references are decoded too.
+pub fn unescape_with<'a>(
+    input: impl Into<Cow<'a, str>>,
+    context: Option<(Board, PostNo)>,
+    decode_numeric: bool,
+) -> Cow<'a, str> {
+    let input = input.into();
     if !ENTITY_CHECK.is_match(&input) {
         return input;
     }
@@ -52,41 +99,151 @@ pub fn unescape(input: String, context: Option<(Board, u64)>) -> String {
             "&quot;" => output.push('"'),
             "&lt;" => output.push('<'),
             "&amp;" => output.push('&'),
-            unknown => {
-                if log_enabled!(Level::Warn) {
-                    warn!(
-                        "{}Unknown entity: {}",
-                        context.map_or(String::new(), |context| format!(
-                            "/{}/ No. {}: ",
-                            context.0, context.1
-                        )),
-                        unknown
-                    );
+            other => match Some(other)
+                .filter(|_| decode_numeric)
+                .and_then(decode_numeric_reference)
+            {
+                Some(c) => output.push(c),
+                None => {
+                    report::record(TokenKind::Entity, other);
+                    if log_enabled!(Level::Debug) {
+                        debug!(
+                            "{}Unknown entity: {}",
+                            context.map_or(String::new(), |context| format!(
+                                "/{}/ No. {}: ",
+                                context.0, context.1
+                            )),
+                            other
+                        );
+                    }
+                    output.push_str(other);
                 }
-                output.push_str(unknown);
-            }
+            },
         }
         pos = m.end();
     }
     output.push_str(&input[pos..]);
 
-    output
+    Cow::Owned(output)
+}
+
+/// Decode a numeric character reference like `&#12354;` or `&#x3042;`. Returns `None` if `entity`
+/// isn't one, or if it isn't a valid character.
+fn decode_numeric_reference(entity: &str) -> Option<char> {
+    let number = entity
+        .get(2..entity.len() - 1)
+        .filter(|_| entity.starts_with("&#"))?;
+    let code = if number.starts_with('x') || number.starts_with('X') {
+        u32::from_str_radix(&number[1..], 16).ok()?
+    } else {
+        number.parse().ok()?
+    };
+    std::char::from_u32(code).filter(|&c| c != '\0')
+}
+
+/// The BBCode tags of `<span class="...">` elements which don't need special handling, by class.
+/// `math` and `eqn` are the TeX tags of /sci/, which FoolFuuka renders.
+const SPAN_TAGS: &[(&str, &str)] = &[
+    ("sjis", "shiftjis"),
+    ("mu-i", "i"),
+    ("mu-s", "b"),
+    ("math", "math"),
+    ("eqn", "eqn"),
+];
+
+/// How `clean_with` converts tags.
+#[derive(Clone, Debug)]
+pub struct CleanOptions {
+    pub mode: CleanMode,
+    /// In BBCode mode, convert links (other than quotelinks, which become plain text) to
+    /// `[url=...]` BBCode instead of removing them
+    pub preserve_links: bool,
+    /// The BBCode tags of `<span class="...">` elements, by class. Spans with other classes are
+    /// left unchanged
+    pub span_tags: HashMap<String, String>,
+    /// Decode all numeric character references (see `unescape_with`)
+    pub decode_numeric: bool,
+}
+
+impl Default for CleanOptions {
+    /// The options used by `clean`, with the built-in span tags.
+    fn default() -> Self {
+        Self {
+            mode: CleanMode::BBCode,
+            preserve_links: false,
+            decode_numeric: false,
+            span_tags: SPAN_TAGS
+                .iter()
+                .map(|&(class, tag)| (class.to_owned(), tag.to_owned()))
+                .collect(),
+        }
+    }
+}
+
+/// How tags are converted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CleanMode {
+    /// Convert tags to BBCode and leave other tags unchanged, as Asagi does.
+    BBCode,
+    /// Remove all markup (keeping line breaks), for search indexing and text processing.
+    Text,
+}
+
+impl CleanMode {
+    /// Push markup which is only output in BBCode mode.
+    fn push_markup(self, output: &mut String, markup: &str) {
+        if self == CleanMode::BBCode {
+            output.push_str(markup);
+        }
+    }
 }
 
 /// Clean comments by unescaping entities, converting tags to BBCode, and leaving other tags
-/// unchanged. The board and post number from `context` is printed at the start of messages about
-/// failed parses or unknown tags to trace errors back to their origins.
-pub fn clean(input: String, context: Option<(Board, u64)>) -> String {
+/// unchanged. Unknown tags are counted for `log_unknown_report`. The board and post number from
+/// `context` is printed at the start of messages about failed parses or unknown tags to trace
+/// errors back to their origins.
+pub fn clean<'a>(input: impl Into<Cow<'a, str>>, context: Option<(Board, PostNo)>) -> Cow<'a, str> {
+    clean_with(input, context, &DEFAULT_OPTIONS)
+}
+
+/// Convert comments to plain text by unescaping entities and removing all tags. See `clean`.
+pub fn to_text<'a>(
+    input: impl Into<Cow<'a, str>>,
+    context: Option<(Board, PostNo)>,
+) -> Cow<'a, str> {
+    clean_with(input, context, &TEXT_OPTIONS)
+}
+
+/// Clean comments with the given `options`. See `clean`. Like `unescape`, `input` is returned as is
+/// if it doesn't need to be changed.
+pub fn clean_with<'a>(
+    input: impl Into<Cow<'a, str>>,
+    context: Option<(Board, PostNo)>,
+    options: &CleanOptions,
+) -> Cow<'a, str> {
+    let input = input.into();
+    let mode = options.mode;
     if !TAG_CHECK.is_match(&input) {
-        return unescape(input, context);
+        return unescape_with(input, context, options.decode_numeric);
     }
 
+    let input = if options.preserve_links && mode == CleanMode::BBCode {
+        LINK.replace_all(&input, |caps: &Captures| {
+            if caps.name("quotelink").is_some() {
+                caps[3].to_owned()
+            } else {
+                format!("[url={}]{}[/url]", &caps[1], &caps[3])
+            }
+        })
+    } else {
+        Cow::Borrowed(&*input)
+    };
     let removed = REMOVED_TAGS.replace_all(&input, "");
 
     let serialized = HtmlParser::parse(Rule::html, &removed)
         .map(|parse| {
             let mut serialized = String::new();
-            serialize(&mut serialized, parse);
+            serialize(&mut serialized, parse, options);
             Cow::Owned(serialized)
         })
         .unwrap_or_else(|err| {
@@ -108,14 +265,14 @@ pub fn clean(input: String, context: Option<(Board, u64)>) -> String {
             output.push_str(&serialized[pos..m.start()]);
             match m.as_str() {
                 "<br>" => output.push('\n'),
-                "<s>" => output.push_str("[spoiler]"),
-                "</s>" => output.push_str("[/spoiler]"),
-                "<b>" => output.push_str("[b]"),
-                "</b>" => output.push_str("[/b]"),
-                "<i>" => output.push_str("[i]"),
-                "</i>" => output.push_str("[/i]"),
-                "<u>" => output.push_str("[u]"),
-                "</u>" => output.push_str("[/u]"),
+                "<s>" => mode.push_markup(&mut output, "[spoiler]"),
+                "</s>" => mode.push_markup(&mut output, "[/spoiler]"),
+                "<b>" => mode.push_markup(&mut output, "[b]"),
+                "</b>" => mode.push_markup(&mut output, "[/b]"),
+                "<i>" => mode.push_markup(&mut output, "[i]"),
+                "</i>" => mode.push_markup(&mut output, "[/i]"),
+                "<u>" => mode.push_markup(&mut output, "[u]"),
+                "</u>" => mode.push_markup(&mut output, "[/u]"),
                 _ => unreachable!(),
             }
             pos = m.end();
@@ -126,8 +283,14 @@ pub fn clean(input: String, context: Option<(Board, u64)>) -> String {
         serialized.into_owned()
     };
 
-    if log_enabled!(Level::Warn) && UNKNOWN_TAG.is_match(&replaced) {
-        warn!(
+    for tag in UNKNOWN_TAG.find_iter(&replaced) {
+        // Count elements by name, since attributes make most tags unique
+        let tag = tag.as_str();
+        let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len() - 1);
+        report::record(TokenKind::Tag, &format!("{}>", &tag[..name_end]));
+    }
+    if log_enabled!(Level::Debug) && UNKNOWN_TAG.is_match(&replaced) {
+        debug!(
             "{}Unknown tags: {:?}",
             context.map_or(String::new(), |context| format!(
                 "/{}/ No. {}: ",
@@ -140,66 +303,173 @@ pub fn clean(input: String, context: Option<(Board, u64)>) -> String {
         );
     }
 
-    unescape(replaced, context)
+    let replaced = match mode {
+        CleanMode::BBCode => replaced,
+        CleanMode::Text => UNKNOWN_TAG.replace_all(&replaced, "").into_owned(),
+    };
+
+    unescape_with(replaced, context, options.decode_numeric)
+}
+
+/// Truncate a cleaned comment to at most `max_bytes`. The comment is cut at a character boundary,
+/// and before any BBCode tag which would be split or left unclosed (so that tag and its contents
+/// are removed). Only tags which are closed later in the comment count, since stray brackets are
+/// left as is by `clean`. Returns whether the comment was truncated.
+pub fn truncate(comment: &mut String, max_bytes: usize) -> bool {
+    if comment.len() <= max_bytes {
+        return false;
+    }
+    let mut end = max_bytes;
+    while !comment.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut cut = end;
+    let mut open: Vec<(&str, usize)> = vec![];
+    for caps in BBCODE_TAG.captures_iter(comment) {
+        let tag = caps.get(0).unwrap();
+        if tag.start() < end && tag.end() > end {
+            cut = cut.min(tag.start());
+        }
+        let name = caps.get(2).unwrap().as_str();
+        if caps[1].is_empty() {
+            open.push((name, tag.start()));
+        } else if let Some(i) = open.iter().rposition(|&(open_name, _)| open_name == name) {
+            // Unclosed tags inside this one are dropped
+            let start = open[i].1;
+            open.truncate(i);
+            if start < end && tag.end() > end {
+                cut = cut.min(start);
+            }
+        }
+    }
+    comment.truncate(cut);
+    true
+}
+
+/// Find the posts quoted by a comment (including quotes of deleted posts), as `(board, post)`
+/// pairs without duplicates. `board` is the board of the comment, which quotes without a board are
+/// on. Quotelinks to boards or catalog searches are skipped.
+pub fn quoted_posts(input: &str, board: Board) -> Vec<(String, u64)> {
+    let board = board.to_string();
+    let mut quotes: Vec<_> = LINK
+        .captures_iter(input)
+        .filter(|caps| caps.name("quotelink").is_some())
+        .filter_map(|caps| {
+            let href = QUOTE_HREF.captures(caps.get(1).unwrap().as_str())?;
+            let post = href.get(3).or_else(|| href.get(2))?;
+            Some((
+                href.get(1)
+                    .map_or(&*board, |board| board.as_str())
+                    .to_owned(),
+                post.as_str().parse().ok()?,
+            ))
+        })
+        .chain(DEADLINK.captures_iter(input).filter_map(|caps| {
+            Some((
+                caps.get(1)
+                    .map_or(&*board, |board| board.as_str())
+                    .to_owned(),
+                caps[2].parse().ok()?,
+            ))
+        }))
+        .collect();
+    quotes.sort();
+    quotes.dedup();
+    quotes
+}
+
+/// Find the HTTP(S) URLs in a comment, as `(url, domain)` pairs without duplicates. The domain is
+/// lowercased. Punctuation at the end of a URL (e.g. a full stop) is assumed to not be a part of it.
+pub fn urls(input: &str) -> Vec<(String, String)> {
+    let input = WBR.replace_all(input, "");
+    let mut urls: Vec<_> = URL
+        .find_iter(&input)
+        .filter_map(|m| {
+            let url = m
+                .as_str()
+                .replace("&amp;", "&")
+                .trim_end_matches(|c| ".,;:!?'\")]}".contains(c))
+                .to_owned();
+            let domain = url_domain(&url)?;
+            Some((url, domain))
+        })
+        .collect();
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+/// Get the lowercased host of an HTTP(S) URL, without its port or user info.
+fn url_domain(url: &str) -> Option<String> {
+    let authority = url.splitn(2, "://").nth(1)?;
+    let authority = authority
+        .split(|c| c == '/' || c == '?' || c == '#')
+        .next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
 }
 
-/// Serialize an AST generated by the Pest parser.
-fn serialize(output: &mut String, pairs: Pairs<Rule>) {
+/// Serialize an AST generated by the Pest parser. In `CleanMode::Text`, only the text is output.
+fn serialize(output: &mut String, pairs: Pairs<Rule>, options: &CleanOptions) {
+    let mode = options.mode;
     for pair in pairs {
         match pair.as_rule() {
             Rule::text => output.push_str(pair.as_str()),
-            Rule::quote | Rule::deadlink => serialize(output, pair.into_inner()),
+            Rule::quote | Rule::deadlink => serialize(output, pair.into_inner(), options),
             Rule::fortune => {
-                output.push_str("[fortune color=\"");
                 let mut inner = pair.into_inner();
+                let color = inner.next().unwrap().as_str();
+                mode.push_markup(output, &format!("[fortune color=\"{}\"]", color));
                 output.push_str(inner.next().unwrap().as_str());
-                output.push_str("\"]");
-                output.push_str(inner.next().unwrap().as_str());
-                output.push_str("[/fortune]");
-            }
-            Rule::shiftjis => {
-                output.push_str("[shiftjis]");
-                serialize(output, pair.into_inner());
-                output.push_str("[/shiftjis]");
-            }
-            Rule::qst_italic => {
-                output.push_str("[i]");
-                serialize(output, pair.into_inner());
-                output.push_str("[/i]");
-            }
-            Rule::qst_bold => {
-                output.push_str("[b]");
-                serialize(output, pair.into_inner());
-                output.push_str("[/b]");
+                mode.push_markup(output, "[/fortune]");
             }
             Rule::qst_color => {
-                output.push_str("[qstcolor=");
                 let mut inner = pair.into_inner();
-                output.push_str(match inner.next().unwrap().as_rule() {
-                    Rule::red => "red",
-                    Rule::green => "green",
-                    Rule::blue => "blue",
-                    _ => unreachable!(),
-                });
-                output.push(']');
-                serialize(output, inner);
-                output.push_str("[/qstcolor]");
+                mode.push_markup(output, "[qstcolor=");
+                mode.push_markup(
+                    output,
+                    match inner.next().unwrap().as_rule() {
+                        Rule::red => "red",
+                        Rule::green => "green",
+                        Rule::blue => "blue",
+                        _ => unreachable!(),
+                    },
+                );
+                mode.push_markup(output, "]");
+                serialize(output, inner, options);
+                mode.push_markup(output, "[/qstcolor]");
             }
             Rule::banned => {
-                output.push_str("[banned]");
-                serialize(output, pair.into_inner());
-                output.push_str("[/banned]");
+                mode.push_markup(output, "[banned]");
+                serialize(output, pair.into_inner(), options);
+                mode.push_markup(output, "[/banned]");
             }
             Rule::code => {
-                output.push_str("[code]");
-                serialize(output, pair.into_inner());
-                output.push_str("[/code]");
+                mode.push_markup(output, "[code]");
+                serialize(output, pair.into_inner(), options);
+                mode.push_markup(output, "[/code]");
             }
             Rule::other => {
                 let mut inner = pair.into_inner();
-                output.push_str(inner.next().unwrap().as_str());
-                serialize(output, inner.next().unwrap().into_inner());
-                output.push_str(inner.next().unwrap().as_str());
+                let start = inner.next().unwrap().as_str();
+                let tag = SPAN_START
+                    .captures(start)
+                    .and_then(|caps| options.span_tags.get(&caps[1]));
+                match tag {
+                    Some(tag) => mode.push_markup(output, &format!("[{}]", tag)),
+                    None => mode.push_markup(output, start),
+                }
+                serialize(output, inner.next().unwrap().into_inner(), options);
+                let end = inner.next().unwrap().as_str();
+                match tag {
+                    Some(tag) => mode.push_markup(output, &format!("[/{}]", tag)),
+                    None => mode.push_markup(output, end),
+                }
             }
             Rule::EOI => {}
             _ => unreachable!(),
@@ -0,0 +1,102 @@
+//! Aggregated reports of unknown entities and tags. A busy board can have the same unknown token in
+//! thousands of posts, so instead of warning about each post, tokens are counted and summarized
+//! periodically.
+
+use std::{collections::HashMap, mem, sync::Mutex};
+
+use lazy_static::lazy_static;
+
+/// The maximum number of distinct tokens of each kind kept between reports. Other tokens are only
+/// counted.
+const MAX_DISTINCT: usize = 1000;
+
+/// The number of tokens of each kind listed in a report.
+const REPORTED_TOKENS: usize = 20;
+
+lazy_static! {
+    static ref REPORT: Mutex<Report> = Mutex::new(Report::default());
+}
+
+#[derive(Default)]
+struct Report {
+    entities: HashMap<String, u64>,
+    tags: HashMap<String, u64>,
+    /// Tokens which were seen since the last report, but not kept because of `MAX_DISTINCT`
+    dropped: u64,
+    /// Every unknown token seen since Ena started
+    total: u64,
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum TokenKind {
+    Entity,
+    Tag,
+}
+
+/// Count an unknown token in the next report.
+pub(super) fn record(kind: TokenKind, token: &str) {
+    let mut report = REPORT.lock().unwrap();
+    let report = &mut *report;
+    report.total += 1;
+    let counts = match kind {
+        TokenKind::Entity => &mut report.entities,
+        TokenKind::Tag => &mut report.tags,
+    };
+    if let Some(count) = counts.get_mut(token) {
+        *count += 1;
+    } else if counts.len() < MAX_DISTINCT {
+        counts.insert(token.to_owned(), 1);
+    } else {
+        report.dropped += 1;
+    }
+}
+
+/// The number of unknown entities and tags seen since Ena started.
+pub fn unknown_token_count() -> u64 {
+    REPORT.lock().unwrap().total
+}
+
+/// Log the unknown entities and tags seen since the last report (most common first), and start a
+/// new report. Nothing is logged if there weren't any.
+pub fn log_unknown_report() {
+    let (entities, tags, dropped) = {
+        let mut report = REPORT.lock().unwrap();
+        (
+            mem::replace(&mut report.entities, HashMap::new()),
+            mem::replace(&mut report.tags, HashMap::new()),
+            mem::replace(&mut report.dropped, 0),
+        )
+    };
+
+    for (kind, counts) in vec![("entities", entities), ("tags", tags)] {
+        if counts.is_empty() {
+            continue;
+        }
+        let distinct = counts.len();
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let listed = counts
+            .iter()
+            .take(REPORTED_TOKENS)
+            .map(|(token, count)| format!("{} ({})", token, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        warn!(
+            "{} distinct unknown {} since the last report{}: {}",
+            distinct,
+            kind,
+            if distinct > REPORTED_TOKENS {
+                " (showing the most common)"
+            } else {
+                ""
+            },
+            listed,
+        );
+    }
+    if dropped > 0 {
+        warn!(
+            "{} more unknown entities and tags were not counted individually",
+            dropped
+        );
+    }
+}
@@ -1,6 +1,10 @@
 #![cfg(test)]
 
-use super::{clean, unescape};
+use super::{
+    clean, clean_with, quoted_posts, to_text, truncate, unescape, unescape_with, urls, Ast,
+    CleanOptions, Node, Style,
+};
+use crate::four_chan::Board;
 
 macro_rules! test_c {
     ($name:ident, $input:expr, $output:expr) => {
@@ -11,6 +15,43 @@ macro_rules! test_c {
     };
 }
 
+macro_rules! test_n {
+    ($name:ident, $input:expr, $output:expr) => {
+        #[test]
+        fn $name() {
+            assert_eq!(
+                unescape_with($input.to_string(), None, true),
+                $output.to_string()
+            );
+        }
+    };
+}
+
+macro_rules! test_l {
+    ($name:ident, $input:expr, $output:expr) => {
+        #[test]
+        fn $name() {
+            let options = CleanOptions {
+                preserve_links: true,
+                ..CleanOptions::default()
+            };
+            assert_eq!(
+                clean_with($input.to_string(), None, &options),
+                $output.to_string()
+            );
+        }
+    };
+}
+
+macro_rules! test_t {
+    ($name:ident, $input:expr, $output:expr) => {
+        #[test]
+        fn $name() {
+            assert_eq!(to_text($input.to_string(), None), $output.to_string());
+        }
+    };
+}
+
 macro_rules! test_u {
     ($name:ident, $input:expr, $output:expr) => {
         #[test]
@@ -63,6 +104,11 @@ test_c!(
     r#"<span class="mu-r">red</span> <span class="mu-g">green</span> <span class="mu-b">blue</span>"#,
     "[qstcolor=red]red[/qstcolor] [qstcolor=green]green[/qstcolor] [qstcolor=blue]blue[/qstcolor]"
 );
+test_c!(
+    math,
+    r#"<span class="math">x^2 &lt; y</span> and <span class="eqn">\frac{a}{b} &amp;&gt; 1</span>"#,
+    r#"[math]x^2 < y[/math] and [eqn]\frac{a}{b} &> 1[/eqn]"#
+);
 test_c!(
     quote,
     r#"<span class="quote">&gt;implying</span>"#,
@@ -101,6 +147,11 @@ test_c!(
     r#"<b><span class="quote">&gt; <span class="deadlink">&gt;&gt;12345</span><br><span class="mu-r"><a href="example.com">this</a></span> <pre class="prettyprint">code</pre> <i>is</i> <s><span class="mu-g">green</span></s>?</span></b>"#,
     "[b]> >>12345\n[qstcolor=red]this[/qstcolor] [code]code[/code] [i]is[/i] [spoiler][qstcolor=green]green[/qstcolor][/spoiler]?[/b]"
 );
+test_c!(
+    math_nested_entities,
+    r#"<span class="math">&amp;lt; <i>x</i> &#039;&quot;</span><br><span class="eqn">a&amp;amp;b</span>"#,
+    "[math]&lt; [i]x[/i] '\"[/math]\n[eqn]a&amp;b[/eqn]"
+);
 test_c!(no_tags_or_entities, "plaintext", "plaintext");
 test_c!(
     mismatched_other_tags,
@@ -124,6 +175,58 @@ test_c!(
     r#"<span class="quote">failure</span></span>"#
 );
 
+// html::clean_with: Preserved links
+test_l!(
+    url_link,
+    r#"<a href="https://example.com/?a=1&amp;b=2">example<wbr>.com</a>"#,
+    "[url=https://example.com/?a=1&b=2]example.com[/url]"
+);
+test_l!(
+    url_quotelink,
+    r##"<a href="#p123456" class="quotelink">&gt;&gt;123456</a> <a href="/g/thread/1#p2" class="quotelink">&gt;&gt;&gt;/g/2</a>"##,
+    ">>123456 >>>/g/2"
+);
+
+// html::clean_with: Configured span tags
+#[test]
+fn configured_span_tag() {
+    let mut options = CleanOptions::default();
+    options
+        .span_tags
+        .insert("mu-new".to_owned(), "new".to_owned());
+    assert_eq!(
+        clean_with(
+            r#"<span class="mu-new">a <span class="sjis">b</span></span> <span class="x">c</span>"#
+                .to_string(),
+            None,
+            &options
+        ),
+        r#"[new]a [shiftjis]b[/shiftjis][/new] <span class="x">c</span>"#
+    );
+}
+
+// html::to_text
+test_t!(
+    text_fortune,
+    r#"<span class="fortune" style="color:#eef2ff"><br><br><b>Your fortune: You&#039;re gonna make it.</b></span>"#,
+    "Your fortune: You're gonna make it."
+);
+test_t!(
+    text_nested_tags,
+    r#"<b><span class="quote">&gt; <span class="deadlink">&gt;&gt;12345</span><br><span class="mu-r"><a href="example.com">this</a></span> <pre class="prettyprint">code</pre> <i>is</i> <s><span class="mu-g">green</span></s>?</span></b>"#,
+    "> >>12345\nthis code is green?"
+);
+test_t!(
+    text_unknown_known_mixed_nested,
+    r#"<span class="u"><p><b>txt</b></p><br><a href="a.com">a<wbr>.com</a><span style="color: blue"><span class="quote"><s><img src="pic.jpg"></s></span></span></span>"#,
+    "txt\na.com"
+);
+test_t!(
+    text_invalid_input,
+    r#"<span class="quote">x &lt; y</span></span>"#,
+    "x < y"
+);
+
 // html::unescape
 test_u!(entities, "&lt;&#039;&amp;&quot;&gt;", r#"<'&">"#);
 test_u!(
@@ -141,3 +244,124 @@ test_u!(
     "&epsilon;&#957;&#x3b1;",
     "&epsilon;&#957;&#x3b1;"
 );
+
+// html::unescape_with: Numeric character references
+test_n!(
+    numeric_references,
+    "&#12354;&#x3042;&#X3042; &epsilon;&#957;&#x3b1;&#039;",
+    "あああ &epsilon;να'"
+);
+test_n!(
+    invalid_numeric_references,
+    "&#xD800;&#0;&#99999999999;&#xzz;&#;",
+    "&#xD800;&#0;&#99999999999;&#xzz;&#;"
+);
+
+// html::Ast
+#[test]
+fn ast_to_bbcode_matches_clean() {
+    for input in &[
+        r#"<b><span class="quote">&gt; <span class="deadlink">&gt;&gt;12345</span><br><span class="mu-r"><a href="example.com">this</a></span> <pre class="prettyprint">code</pre> <i>is</i> <s><span class="mu-g">green</span></s>?</span></b>"#,
+        r#"<span class="u"><p><b>txt</b></p><br><a href="a.com">a<wbr>.com</a><span style="color: blue"><span class="quote"><s><img src="pic.jpg"></s></span></span></span>"#,
+        r#"<span class="fortune" style="color:#eef2ff"><br><br><b>Your fortune: You&#039;re gonna make it.</b></span>"#,
+        r#"<span class="sjis">(╯°□°）╯︵ ┻━┻</span> <span class="math">x &lt; y</span>"#,
+    ] {
+        assert_eq!(Ast::parse(input).unwrap().to_bbcode(), clean(*input, None));
+    }
+}
+
+#[test]
+fn ast_nodes() {
+    let ast = Ast::parse(
+        r##"<a href="#p123" class="quotelink">&gt;&gt;123</a><br><span class="quote">&gt;see <a href="https://example.com/?a&amp;b">exam<wbr>ple</a></span> <s>x</s>"##,
+    )
+    .unwrap();
+    assert_eq!(ast.quotes().collect::<Vec<_>>(), vec!["#p123"]);
+    assert_eq!(
+        ast.links().collect::<Vec<_>>(),
+        vec!["https://example.com/?a&b"]
+    );
+    assert_eq!(ast.text().collect::<String>(), ">>123>see example x");
+    assert_eq!(ast.nodes[1], Node::LineBreak);
+    assert_eq!(ast.nodes[4], Node::Start(Style::Spoiler));
+}
+
+#[test]
+fn quoted_posts_in_comment() {
+    let comment = r##"<a href="#p123" class="quotelink">&gt;&gt;123</a><br><a href="/g/thread/1#p2" class="quotelink">&gt;&gt;&gt;/g/2</a> <a href="/a/thread/456" class="quotelink">&gt;&gt;456</a><br><span class="deadlink">&gt;&gt;789</span> <span class="deadlink">&gt;&gt;&gt;/v/10</span> <a href="//boards.4chan.org/g/" class="quotelink">&gt;&gt;&gt;/g/</a> <a href="#p123" class="quotelink">&gt;&gt;123</a> <a href="#p5">not a quote</a>"##;
+    assert_eq!(
+        quoted_posts(comment, Board::a),
+        vec![
+            ("a".to_owned(), 123),
+            ("a".to_owned(), 456),
+            ("a".to_owned(), 789),
+            ("g".to_owned(), 2),
+            ("v".to_owned(), 10),
+        ]
+    );
+}
+
+#[test]
+fn urls_in_comment() {
+    let comment = r#"see https://Example.com/a?b=1&amp;c=2. and (http://user@ex<wbr>ample.org:8080/x)<br>HTTPS://EXAMPLE.COM/a?b=1&amp;c=2.<br>&quot;https://q.example/&quot; <a href="https://link.example/">https://link.example/</a> ftp://no.example https://"#;
+    assert_eq!(
+        urls(comment),
+        vec![
+            (
+                "HTTPS://EXAMPLE.COM/a?b=1&c=2".to_owned(),
+                "example.com".to_owned()
+            ),
+            (
+                "http://user@example.org:8080/x".to_owned(),
+                "example.org".to_owned()
+            ),
+            (
+                "https://Example.com/a?b=1&c=2".to_owned(),
+                "example.com".to_owned()
+            ),
+            (
+                "https://link.example/".to_owned(),
+                "link.example".to_owned()
+            ),
+            ("https://q.example/".to_owned(), "q.example".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn truncate_comment() {
+    let truncated = |comment: &str, max_bytes| {
+        let mut comment = comment.to_owned();
+        let truncated = truncate(&mut comment, max_bytes);
+        (comment, truncated)
+    };
+
+    assert_eq!(truncated("short", 5), ("short".to_owned(), false));
+    assert_eq!(truncated("plain text", 5), ("plain".to_owned(), true));
+    // At a character boundary
+    assert_eq!(truncated("aあ", 2), ("a".to_owned(), true));
+    // Not inside a tag
+    assert_eq!(
+        truncated("ab[spoiler]c[/spoiler]", 5),
+        ("ab".to_owned(), true)
+    );
+    assert_eq!(
+        truncated("[qstcolor=red]red[/qstcolor]", 10),
+        ("".to_owned(), true)
+    );
+    // Not inside a closing tag, or between a tag and its closing tag
+    assert_eq!(truncated("a [b]bold[/b] c", 11), ("a ".to_owned(), true));
+    assert_eq!(truncated("a [b]bold[/b] c", 7), ("a ".to_owned(), true));
+    // The outermost tag which would be left unclosed is removed
+    assert_eq!(
+        truncated("a [spoiler]b [i]c[/i][/spoiler] d", 18),
+        ("a ".to_owned(), true)
+    );
+    // Closed tags before the cut are kept
+    assert_eq!(
+        truncated("[b]a[/b] text", 10),
+        ("[b]a[/b] t".to_owned(), true)
+    );
+    // Stray brackets aren't tags
+    assert_eq!(truncated("[b] and more", 6), ("[b] an".to_owned(), true));
+}
@@ -47,5 +47,12 @@ macro_rules! log_error {
 
 pub mod actors;
 pub mod config;
+pub mod doctor;
 pub mod four_chan;
 pub mod html;
+mod media_server;
+#[cfg(feature = "mock-api")]
+pub mod mock_api;
+mod scraper;
+
+pub use scraper::{Scraper, ScraperBuilder};
@@ -1,14 +1,26 @@
+use std::env;
+use std::ffi::OsString;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process;
 
 use actix::prelude::*;
 use log::{error, info};
 
-use ena::{actors::*, config::parse_config, log_error};
-
-const THREAD_UPDATER_MAILBOX_CAPACITY: usize = 500;
+use ena::{
+    config::{parse_config, DEFAULT_CONFIG},
+    log_error, Scraper,
+};
 
 fn main() {
+    if env::args_os()
+        .nth(1)
+        .map_or(false, |arg| arg == "print-default-config")
+    {
+        print!("{}", DEFAULT_CONFIG);
+        return;
+    }
+
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("ena=info"))
         .format(|fmt, record| {
             let timestamp = fmt.timestamp();
@@ -26,45 +38,89 @@ fn main() {
         })
         .init();
 
-    info!("Ena is starting");
+    let mut args: Vec<_> = env::args_os().skip(1).collect();
+    let doctor = args.first().map_or(false, |arg| arg == "doctor");
+    let migrate_timestamps = args
+        .first()
+        .map_or(false, |arg| arg == "migrate-timestamps");
+    let writer = args.first().map_or(false, |arg| arg == "writer");
+    if doctor || migrate_timestamps {
+        args.remove(0);
+    } else if writer {
+        args.remove(0);
+        info!("Ena is starting as a remote writer");
+    } else {
+        info!("Ena is starting");
+    }
 
-    let config = parse_config().unwrap_or_else(|err| {
+    let config_path = config_path(args).unwrap_or_else(|| {
+        error!(
+            "Usage: ena [doctor | migrate-timestamps | writer] [--config <path>] | \
+             ena print-default-config"
+        );
+        process::exit(1);
+    });
+
+    let config = parse_config(&config_path).unwrap_or_else(|err| {
         log_error!(err.as_fail());
         process::exit(1);
     });
 
+    if doctor {
+        process::exit(if ena::doctor::run(&config) { 0 } else { 1 });
+    }
+    if migrate_timestamps {
+        if let Err(err) = ena::actors::migrate_timestamps(&config) {
+            log_error!(err.as_fail());
+            process::exit(1);
+        }
+        return;
+    }
+
     let sys = System::new("ena");
 
-    let database = {
-        let database = Database::try_new(&config).unwrap_or_else(|err| {
-            error!("Database initialization error: {}", err);
+    if writer {
+        let writer = match &config.writer {
+            Some(writer) => writer,
+            None => {
+                error!("`ena writer` needs a `[writer]` section in the config");
+                process::exit(1);
+            }
+        };
+        if let Err(err) = ena::actors::start_writer(&config, writer) {
+            log_error!(err.as_fail());
             process::exit(1);
-        });
-        Arbiter::builder()
-            .stop_system_on_panic(true)
-            .start(|_| database)
-    };
+        }
+        info!("Ena is running");
+        sys.run();
+        return;
+    }
 
-    // To create ThreadUpdater, we need Addr<Fetcher>. But to create Fetcher, we need
-    // Addr<ThreadUpdater>! To solve this circular dependency, we first create ThreadUpdater's
-    // Context. This gives us Addr<ThreadUpdater> without having to create ThreadUpdater. We then
-    // use this Addr to create Fetcher, which gives us Addr<Fetcher>. Finally, we pass this Addr to
-    // ThreadUpdater::new, and run ThreadUpdater in its previously created Context.
-    let thread_updater_ctx = {
-        let (_, receiver) = actix::dev::channel::channel(THREAD_UPDATER_MAILBOX_CAPACITY);
-        Context::with_receiver(receiver)
-    };
-
-    let fetcher = Fetcher::create(&config, thread_updater_ctx.address()).unwrap_or_else(|err| {
+    if let Err(err) = Scraper::builder(config).start() {
         log_error!(err.as_fail());
         process::exit(1);
-    });
-
-    let thread_updater =
-        thread_updater_ctx.run(ThreadUpdater::new(&config, database, fetcher.clone()));
-
-    BoardPoller::new(&config, thread_updater, fetcher).start();
+    }
 
     info!("Ena is running");
     sys.run();
 }
+
+/// The path of the configuration file: the `--config` argument if given, then the `ENA_CONFIG`
+/// environment variable, then `ena.toml` in the current directory. Returns `None` if the arguments
+/// are invalid.
+fn config_path(args: Vec<OsString>) -> Option<PathBuf> {
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            path = Some(PathBuf::from(args.next()?));
+        } else {
+            return None;
+        }
+    }
+
+    Some(
+        path.or_else(|| env::var_os("ENA_CONFIG").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from("ena.toml")),
+    )
+}
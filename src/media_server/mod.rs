@@ -0,0 +1,216 @@
+//! A static file server for `media_path`, so that downloaded media can be viewed without setting up
+//! a web server. Only `GET` and `HEAD` requests are answered, and only single byte ranges are
+//! supported (requests for several ranges get the whole file).
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix::prelude::*;
+use failure::{Error, ResultExt};
+use futures::{future, prelude::*};
+use futures_cpupool::CpuPool;
+use hyper::{
+    header::{self, HeaderValue},
+    service::service_fn,
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+use crate::config::MediaServerConfig;
+
+mod tests;
+
+/// Serve the files in `media_path` on the address in `config`. This must be called from within a
+/// running `System`.
+pub fn start(config: &MediaServerConfig, media_path: PathBuf) -> Result<(), Error> {
+    // Reading files blocks, so it's done on a separate pool
+    let pool = futures_cpupool::Builder::new()
+        .name_prefix("ena-media-server-")
+        .create();
+    let media_path = Arc::new(media_path);
+    let server = Server::try_bind(&config.listen)
+        .with_context(|_| format!("Could not listen on {}", config.listen))?
+        .serve(move || {
+            let media_path = media_path.clone();
+            let pool = pool.clone();
+            service_fn(move |req| respond(&req, &media_path, &pool))
+        });
+    info!("Serving media on {}", server.local_addr());
+    Arbiter::spawn(server.map_err(|err| error!("Media server error: {}", err)));
+    Ok(())
+}
+
+fn respond(
+    req: &Request<Body>,
+    media_path: &Path,
+    pool: &CpuPool,
+) -> Box<dyn Future<Item = Response<Body>, Error = io::Error> + Send> {
+    let head = match *req.method() {
+        Method::GET => false,
+        Method::HEAD => true,
+        _ => {
+            let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Box::new(future::ok(response));
+        }
+    };
+    let path = match file_path(media_path, req.uri().path()) {
+        Some(path) => path,
+        None => return Box::new(future::ok(status_response(StatusCode::NOT_FOUND))),
+    };
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(str::to_owned);
+
+    Box::new(
+        pool.spawn_fn(move || read_file(&path, range.as_ref().map(String::as_str), head))
+            .or_else(|err| {
+                error!("Media server could not read file: {}", err);
+                Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR))
+            }),
+    )
+}
+
+fn read_file(path: &Path, range: Option<&str>, head: bool) -> io::Result<Response<Body>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(status_response(StatusCode::NOT_FOUND));
+        }
+        Err(err) => return Err(err),
+    };
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    let len = metadata.len();
+
+    let mut builder = Response::builder();
+    builder
+        .header(header::CONTENT_TYPE, content_type(path))
+        .header(header::ACCEPT_RANGES, "bytes");
+    let (start, end) = match range.map_or(ByteRange::Whole, |range| parse_range(range, len)) {
+        ByteRange::Whole => (0, len),
+        ByteRange::Partial(start, end) => {
+            builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            );
+            (start, end)
+        }
+        ByteRange::Unsatisfiable => {
+            return Ok(builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap());
+        }
+    };
+    builder.header(header::CONTENT_LENGTH, (end - start).to_string());
+
+    let body = if head {
+        Body::empty()
+    } else {
+        let mut body = Vec::with_capacity((end - start) as usize);
+        file.seek(SeekFrom::Start(start))?;
+        file.take(end - start).read_to_end(&mut body)?;
+        Body::from(body)
+    };
+    Ok(builder.body(body).unwrap())
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The file of a request path. `None` is returned for paths which could leave `media_path`, and
+/// for the partial downloads in each board's `tmp` directory.
+fn file_path(media_path: &Path, uri_path: &str) -> Option<PathBuf> {
+    let mut path = media_path.to_owned();
+    for (i, component) in uri_path.trim_start_matches('/').split('/').enumerate() {
+        if component.is_empty()
+            || component.starts_with('.')
+            || component.contains('\\')
+            || (i == 1 && component == "tmp")
+        {
+            return None;
+        }
+        path.push(component);
+    }
+    Some(path)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webm") => "video/webm",
+        Some("mp4") => "video/mp4",
+        Some("pdf") => "application/pdf",
+        Some("swf") => "application/x-shockwave-flash",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The part of a file requested by a `Range` header.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Whole,
+    /// A half-open range of bytes
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parse the `Range` header of a request for a file of `len` bytes. Headers which aren't a single
+/// byte range are ignored, as allowed by RFC 7233.
+fn parse_range(range: &str, len: u64) -> ByteRange {
+    let range = range.trim();
+    if !range.starts_with("bytes=") || range.contains(',') {
+        return ByteRange::Whole;
+    }
+    let mut parts = range["bytes=".len()..].splitn(2, '-');
+    let (first, last) = match (parts.next(), parts.next()) {
+        (Some(first), Some(last)) => (first.trim(), last.trim()),
+        _ => return ByteRange::Whole,
+    };
+
+    let (start, end) = if first.is_empty() {
+        // A suffix range, for the last `last` bytes
+        match last.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (len.saturating_sub(suffix), len),
+            Err(_) => return ByteRange::Whole,
+        }
+    } else {
+        let start = match first.parse::<u64>() {
+            Ok(start) => start,
+            Err(_) => return ByteRange::Whole,
+        };
+        let end = if last.is_empty() {
+            len
+        } else {
+            match last.parse::<u64>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(len),
+                _ => return ByteRange::Whole,
+            }
+        };
+        (start, end)
+    };
+
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
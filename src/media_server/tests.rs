@@ -0,0 +1,76 @@
+#![cfg(test)]
+
+use std::path::{Path, PathBuf};
+
+use super::{content_type, file_path, parse_range, ByteRange};
+
+#[test]
+fn ranges() {
+    assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 100));
+    assert_eq!(
+        parse_range("bytes=500-", 1000),
+        ByteRange::Partial(500, 1000)
+    );
+    assert_eq!(
+        parse_range("bytes=-100", 1000),
+        ByteRange::Partial(900, 1000)
+    );
+    assert_eq!(
+        parse_range("bytes=-5000", 1000),
+        ByteRange::Partial(0, 1000)
+    );
+    assert_eq!(
+        parse_range("bytes=900-5000", 1000),
+        ByteRange::Partial(900, 1000)
+    );
+    assert_eq!(parse_range(" bytes=1-1 ", 1000), ByteRange::Partial(1, 2));
+}
+
+#[test]
+fn unsatisfiable_ranges() {
+    assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(
+        parse_range("bytes=1000-2000", 1000),
+        ByteRange::Unsatisfiable
+    );
+    assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+}
+
+#[test]
+fn ignored_ranges() {
+    assert_eq!(parse_range("bytes=0-99,200-299", 1000), ByteRange::Whole);
+    assert_eq!(parse_range("bytes=99-0", 1000), ByteRange::Whole);
+    assert_eq!(parse_range("bytes=a-b", 1000), ByteRange::Whole);
+    assert_eq!(parse_range("bytes=100", 1000), ByteRange::Whole);
+    assert_eq!(parse_range("items=0-99", 1000), ByteRange::Whole);
+}
+
+#[test]
+fn file_paths() {
+    let media = Path::new("/media");
+    assert_eq!(
+        file_path(media, "/a/image/1546/29/1546293600000.jpg"),
+        Some(PathBuf::from("/media/a/image/1546/29/1546293600000.jpg"))
+    );
+    assert_eq!(
+        file_path(media, "/a/spoiler/spoiler-a1.png"),
+        Some(PathBuf::from("/media/a/spoiler/spoiler-a1.png"))
+    );
+    assert_eq!(file_path(media, "/"), None);
+    assert_eq!(file_path(media, "/a/image/"), None);
+    assert_eq!(file_path(media, "/a//image"), None);
+    assert_eq!(file_path(media, "/a/../../etc/passwd"), None);
+    assert_eq!(file_path(media, "/a/.hidden"), None);
+    assert_eq!(file_path(media, "/a/image\\..\\..\\secret"), None);
+    assert_eq!(file_path(media, "/a/tmp/1546293600000.jpg"), None);
+}
+
+#[test]
+fn content_types() {
+    assert_eq!(content_type(Path::new("1.jpg")), "image/jpeg");
+    assert_eq!(content_type(Path::new("1s.jpg")), "image/jpeg");
+    assert_eq!(content_type(Path::new("1.webm")), "video/webm");
+    assert_eq!(content_type(Path::new("1.pdf")), "application/pdf");
+    assert_eq!(content_type(Path::new("1")), "application/octet-stream");
+}
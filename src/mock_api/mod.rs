@@ -0,0 +1,182 @@
+//! A mock 4chan API server for testing. Only built with the `mock-api` feature.
+//!
+//! The server answers requests from a table of canned responses, which can be changed while it is
+//! running to simulate threads being modified, deleted, or failing.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::prelude::*;
+use futures::prelude::*;
+use hyper::{
+    header::{self, HeaderValue},
+    service::service_fn_ok,
+    Body, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::four_chan::{Board, Post, ThreadNo};
+
+mod tests;
+
+const RFC_1123_FORMAT: &str = "%a, %d %b %Y %T GMT";
+
+/// A canned response.
+#[derive(Clone, Debug)]
+pub enum MockResponse {
+    /// `200 OK` with a body. Requests with an `If-Modified-Since` at or after `last_modified` get
+    /// `304 Not Modified` instead.
+    Ok {
+        body: Vec<u8>,
+        last_modified: DateTime<Utc>,
+    },
+    /// Any other status, with an empty body
+    Status(StatusCode),
+}
+
+/// A mock API server. Paths without a canned response get `404 Not Found`, just like deleted
+/// threads do.
+#[derive(Clone, Default)]
+pub struct MockApi {
+    responses: Arc<Mutex<HashMap<String, MockResponse>>>,
+    /// The path and status of every response served, in order
+    served: Arc<Mutex<Vec<(String, StatusCode)>>>,
+}
+
+impl MockApi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, path: &str, response: MockResponse) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), response);
+    }
+
+    pub fn set_json<T: Serialize>(&self, path: &str, value: &T, last_modified: DateTime<Utc>) {
+        let body = serde_json::to_vec(value).unwrap();
+        self.set(
+            path,
+            MockResponse::Ok {
+                body,
+                last_modified,
+            },
+        );
+    }
+
+    pub fn set_status(&self, path: &str, status: StatusCode) {
+        self.set(path, MockResponse::Status(status));
+    }
+
+    /// Remove the response of a path, so that it 404's.
+    pub fn remove(&self, path: &str) {
+        self.responses.lock().unwrap().remove(path);
+    }
+
+    /// Set `threads.json` of a board, given the `(no, last_modified)` of each thread in bump order.
+    pub fn set_thread_list(
+        &self,
+        board: Board,
+        threads: &[(ThreadNo, u64)],
+        last_modified: DateTime<Utc>,
+    ) {
+        let threads: Vec<_> = threads
+            .iter()
+            .map(|(no, last_modified)| json!({ "no": no, "last_modified": last_modified }))
+            .collect();
+        self.set_json(
+            &format!("/{}/threads.json", board),
+            &json!([{ "page": 1, "threads": threads }]),
+            last_modified,
+        );
+    }
+
+    pub fn set_thread(&self, board: Board, posts: &[Post], last_modified: DateTime<Utc>) {
+        self.set_json(
+            &format!("/{}/thread/{}.json", board, posts[0].no),
+            &json!({ "posts": posts }),
+            last_modified,
+        );
+    }
+
+    pub fn set_archive(&self, board: Board, threads: &[ThreadNo], last_modified: DateTime<Utc>) {
+        self.set_json(&format!("/{}/archive.json", board), &threads, last_modified);
+    }
+
+    pub fn set_media(&self, board: Board, filename: &str, body: Vec<u8>) {
+        self.set(
+            &format!("/{}/{}", board, filename),
+            MockResponse::Ok {
+                body,
+                last_modified: Utc::now(),
+            },
+        );
+    }
+
+    /// The path and status of every response served so far, in order.
+    pub fn served(&self) -> Vec<(String, StatusCode)> {
+        self.served.lock().unwrap().clone()
+    }
+
+    /// Bind the server to `addr` (use port 0 to pick any free port). The bound address and the
+    /// server future, which must be spawned on a Tokio runtime, are returned.
+    pub fn bind(
+        &self,
+        addr: &SocketAddr,
+    ) -> Result<(SocketAddr, impl Future<Item = (), Error = hyper::Error>), hyper::Error> {
+        let (responses, served) = (self.responses.clone(), self.served.clone());
+        let server = Server::try_bind(addr)?.serve(move || {
+            let (responses, served) = (responses.clone(), served.clone());
+            service_fn_ok(move |req| {
+                let response = respond(&responses, &req);
+                served
+                    .lock()
+                    .unwrap()
+                    .push((req.uri().path().to_owned(), response.status()));
+                response
+            })
+        });
+        Ok((server.local_addr(), server))
+    }
+}
+
+fn respond(
+    responses: &Mutex<HashMap<String, MockResponse>>,
+    req: &Request<Body>,
+) -> Response<Body> {
+    let response = responses.lock().unwrap().get(req.uri().path()).cloned();
+    let mut builder = Response::builder();
+    let response = match response {
+        Some(MockResponse::Ok {
+            body,
+            last_modified,
+        }) => {
+            let not_modified = req
+                .headers()
+                .get(header::IF_MODIFIED_SINCE)
+                .and_then(|since| since.to_str().ok())
+                .and_then(|since| Utc.datetime_from_str(since, RFC_1123_FORMAT).ok())
+                .map_or(false, |since| {
+                    last_modified.timestamp() <= since.timestamp()
+                });
+            builder.header(
+                header::LAST_MODIFIED,
+                HeaderValue::from_str(&last_modified.format(RFC_1123_FORMAT).to_string()).unwrap(),
+            );
+            if not_modified {
+                builder.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+            } else {
+                builder.body(Body::from(body))
+            }
+        }
+        Some(MockResponse::Status(status)) => builder.status(status).body(Body::empty()),
+        None => builder.status(StatusCode::NOT_FOUND).body(Body::empty()),
+    };
+    response.unwrap()
+}
@@ -0,0 +1,279 @@
+#![cfg(test)]
+
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use chrono::prelude::*;
+use failure::Error;
+use futures::{prelude::*, sync::mpsc};
+use hyper::{header, Body, Client, Request, StatusCode};
+use serde_json::json;
+use tokio::{
+    runtime::Runtime,
+    timer::{Delay, Interval},
+};
+
+use super::{MockApi, RFC_1123_FORMAT};
+use crate::{
+    actors::{GetTrackedThreads, PostsInserted},
+    config::parse_config,
+    four_chan::{Board, Post, ThreadNo, ThreadPage},
+    Scraper,
+};
+
+#[test]
+fn mock_api() -> Result<(), Error> {
+    let mut runtime = Runtime::new()?;
+    let api = MockApi::new();
+    let (addr, server) = api.bind(&([127, 0, 0, 1], 0).into())?;
+    runtime.spawn(server.map_err(|err| panic!("Mock API server failed: {}", err)));
+    let client = Client::new();
+
+    let last_modified = Utc.timestamp(1_577_898_000, 0);
+    api.set_thread_list(
+        Board::a,
+        &[(ThreadNo(2), 1_577_898_000), (ThreadNo(1), 1_577_897_000)],
+        last_modified,
+    );
+    api.set_status("/a/archive.json", StatusCode::INTERNAL_SERVER_ERROR);
+
+    let mut get = |path: &str, since: Option<DateTime<Utc>>| {
+        let mut req = Request::get(format!("http://{}{}", addr, path).as_str());
+        if let Some(since) = since {
+            req.header(
+                header::IF_MODIFIED_SINCE,
+                since.format(RFC_1123_FORMAT).to_string().as_str(),
+            );
+        }
+        runtime.block_on(
+            client
+                .request(req.body(Body::empty()).unwrap())
+                .and_then(|res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| (status, body))
+                }),
+        )
+    };
+
+    let (status, body) = get("/a/threads.json", None)?;
+    assert_eq!(status, StatusCode::OK);
+    let pages: Vec<ThreadPage> = serde_json::from_slice(&body)?;
+    let nums: Vec<_> = pages[0].threads.iter().map(|thread| thread.no).collect();
+    assert_eq!(nums, vec![ThreadNo(2), ThreadNo(1)]);
+
+    let (status, _) = get("/a/threads.json", Some(last_modified))?;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    let (status, _) = get(
+        "/a/threads.json",
+        Some(last_modified - chrono::Duration::seconds(1)),
+    )?;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = get("/a/archive.json", None)?;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    let (status, _) = get("/a/thread/1.json", None)?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    api.remove("/a/threads.json");
+    let (status, _) = get("/a/threads.json", None)?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    runtime.shutdown_now().wait().unwrap();
+    Ok(())
+}
+
+/// A directory which is removed when the test ends.
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Sends the numbers of the posts it receives to a channel.
+struct PostSink(mpsc::UnboundedSender<Vec<u64>>);
+
+impl Actor for PostSink {
+    type Context = Context<Self>;
+}
+
+impl Handler<PostsInserted> for PostSink {
+    type Result = ();
+
+    fn handle(&mut self, msg: PostsInserted, _: &mut Self::Context) {
+        let nums = msg.1.iter().map(|post| post.num.0).collect();
+        self.0.unbounded_send(nums).unwrap();
+    }
+}
+
+/// A thread with the OP No. 1 and replies up to No. `last`.
+fn thread(last: u64) -> Vec<Post> {
+    (1..=last)
+        .map(|no| {
+            let resto = if no == 1 { 0 } else { 1 };
+            serde_json::from_value(json!({ "no": no, "resto": resto, "time": 1_577_897_000 + no }))
+                .unwrap()
+        })
+        .collect()
+}
+
+/// Update the thread list of /a/ so that thread No. 1 was last modified at `time`.
+fn set_modified(api: &MockApi, time: u64) {
+    api.set_thread_list(
+        Board::a,
+        &[(ThreadNo(1), time)],
+        Utc.timestamp(time as i64, 0),
+    );
+}
+
+/// Check `condition` every 50 ms until it's true.
+fn wait_until<F, R>(mut condition: F) -> impl Future<Item = (), Error = ()>
+where
+    F: FnMut() -> R + 'static,
+    R: IntoFuture<Item = bool, Error = ()> + 'static,
+{
+    Interval::new_interval(Duration::from_millis(50))
+        .map_err(|err| panic!("{}", err))
+        .and_then(move |_| condition())
+        .skip_while(|&done| Ok(!done))
+        .into_future()
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Run the whole scraper (board poller, fetcher, and thread updater) against the mock API, with
+/// database writes turned off by `dry_run`.
+#[test]
+fn scrape() -> Result<(), Error> {
+    let sys = System::new("test");
+    let api = MockApi::new();
+    let (addr, server) = api.bind(&([127, 0, 0, 1], 0).into())?;
+    Arbiter::spawn(server.map_err(|err| panic!("Mock API server failed: {}", err)));
+
+    let dir = TempDir(env::temp_dir().join(format!("ena-test-scrape-{}", process::id())));
+    let _ = fs::remove_dir_all(&dir.0);
+    fs::create_dir_all(&dir.0)?;
+    let hosts = format!("[network]\nhosts = {{ api = \"http://{}/\" }}", addr);
+    let config = [
+        ("poll_interval = 300", "poll_interval = 1"),
+        ("fetch_archive = true", "fetch_archive = false"),
+        ("download_media = true", "download_media = false"),
+        ("download_thumbs = true", "download_thumbs = false"),
+        ("dry_run = false", "dry_run = true"),
+        ("[boards]", "[boards]\na = {}"),
+        ("[network]", hosts.as_str()),
+    ]
+    .iter()
+    .fold(
+        include_str!("../../ena.example.toml").to_owned(),
+        |config, (from, to)| {
+            let from = format!("\n{}\n", from);
+            assert!(
+                config.contains(&from),
+                "{:?} isn't in the example config",
+                from
+            );
+            config.replacen(&from, &format!("\n{}\n", to), 1)
+        },
+    );
+    let config_path = dir.0.join("ena.toml");
+    fs::write(&config_path, config)?;
+    let config = parse_config(&config_path)?;
+
+    api.set_thread(Board::a, &thread(2), Utc.timestamp(1_577_898_000, 0));
+    set_modified(&api, 1_577_898_000);
+
+    let (sender, receiver) = mpsc::unbounded();
+    let scraper = Scraper::builder(config)
+        .post_sink(PostSink(sender).start().recipient())
+        .start()?;
+    let thread_updater = scraper.thread_updater().clone();
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let flag = finished.clone();
+    let served = {
+        let api = api.clone();
+        move |status| {
+            let api = api.clone();
+            move || -> Result<bool, ()> {
+                Ok(api
+                    .served()
+                    .iter()
+                    .any(|(path, s)| path == "/a/thread/1.json" && *s == status))
+            }
+        }
+    };
+    Arbiter::spawn(
+        receiver
+            .into_future()
+            .map_err(|_| ())
+            .and_then({
+                let api = api.clone();
+                move |(posts, receiver)| {
+                    // The new thread is fetched in full
+                    assert_eq!(posts, Some(vec![1, 2]));
+                    api.set_thread(Board::a, &thread(3), Utc.timestamp(1_577_898_100, 0));
+                    set_modified(&api, 1_577_898_100);
+                    receiver.into_future().map_err(|_| ())
+                }
+            })
+            .and_then({
+                let api = api.clone();
+                let served = served.clone();
+                move |(posts, receiver)| {
+                    // Only the new reply is inserted
+                    assert_eq!(posts, Some(vec![3]));
+                    // The thread list says the thread changed, but the thread JSON hasn't, so the
+                    // API answers `304 Not Modified`
+                    set_modified(&api, 1_577_898_200);
+                    wait_until(served(StatusCode::NOT_MODIFIED)).map(move |()| receiver)
+                }
+            })
+            .and_then(move |receiver| {
+                // The thread is deleted before its next fetch
+                api.remove("/a/thread/1.json");
+                set_modified(&api, 1_577_898_300);
+                wait_until(served(StatusCode::NOT_FOUND)).map(move |()| receiver)
+            })
+            .and_then(move |receiver| {
+                wait_until(move || {
+                    thread_updater
+                        .send(GetTrackedThreads)
+                        .map(|tracked| !tracked.unwrap().contains_key(&Board::a))
+                        .map_err(|err| panic!("{}", err))
+                })
+                .map(move |()| receiver)
+            })
+            .map(move |mut receiver| {
+                // Nothing was inserted after the `304 Not Modified` or the `404 Not Found`
+                assert_eq!(receiver.poll(), Ok(Async::NotReady));
+                flag.store(true, Ordering::SeqCst);
+                System::current().stop();
+            }),
+    );
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let flag = timed_out.clone();
+    Arbiter::spawn(
+        Delay::new(Instant::now() + Duration::from_secs(60))
+            .map(move |()| {
+                flag.store(true, Ordering::SeqCst);
+                System::current().stop();
+            })
+            .map_err(|err| panic!("{}", err)),
+    );
+    sys.run();
+    assert!(finished.load(Ordering::SeqCst));
+    assert!(!timed_out.load(Ordering::SeqCst));
+    Ok(())
+}
@@ -0,0 +1,366 @@
+//! Starting Ena from another program.
+
+use std::collections::HashMap;
+
+use actix::{
+    actors::signal::{Signal, SignalType},
+    prelude::*,
+};
+use chrono::prelude::*;
+use failure::{Error, ResultExt};
+use futures::{future, prelude::*};
+
+use crate::{actors::*, config::Config, four_chan::Board, media_server};
+
+/// A running scraper. It holds the addresses of its actors, which can be sent messages such as
+/// `GetFetcherStats`, `GetScrapeLag`, or `GetThread`.
+#[derive(Clone)]
+pub struct Scraper {
+    database: Addr<Database>,
+    fetcher: Addr<Fetcher>,
+    thread_updater: Addr<ThreadUpdater>,
+    board_poller: Addr<BoardPoller>,
+    coordinator: Option<Addr<Coordinator>>,
+}
+
+/// Configures and starts a `Scraper`.
+pub struct ScraperBuilder {
+    config: Config,
+    post_sinks: Vec<Recipient<PostsInserted>>,
+    archive_sinks: Vec<Recipient<ThreadArchived>>,
+    post_processors: Vec<Box<dyn PostProcessor>>,
+}
+
+impl Scraper {
+    pub fn builder(config: Config) -> ScraperBuilder {
+        ScraperBuilder {
+            config,
+            post_sinks: vec![],
+            archive_sinks: vec![],
+            post_processors: vec![],
+        }
+    }
+
+    pub fn database(&self) -> &Addr<Database> {
+        &self.database
+    }
+
+    pub fn fetcher(&self) -> &Addr<Fetcher> {
+        &self.fetcher
+    }
+
+    pub fn thread_updater(&self) -> &Addr<ThreadUpdater> {
+        &self.thread_updater
+    }
+
+    pub fn board_poller(&self) -> &Addr<BoardPoller> {
+        &self.board_poller
+    }
+
+    /// The coordinator, if `coordination` is configured.
+    pub fn coordinator(&self) -> Option<&Addr<Coordinator>> {
+        self.coordinator.as_ref()
+    }
+
+    /// Save the state of the fetcher and thread updater, and then stop the `System`. This is what
+    /// happens when Ena receives `SIGINT` or `SIGTERM`.
+    pub fn shutdown(self) -> impl Future<Item = (), Error = MailboxError> {
+        let thread_updater = self.thread_updater;
+        self.fetcher
+            .send(Signal(SignalType::Term))
+            .and_then(move |()| thread_updater.send(Signal(SignalType::Term)))
+    }
+
+    /// Log a snapshot of the scraper's state: the polling and tracked threads of each board, the
+    /// request queues, and the database writes. This is what happens when Ena receives `SIGUSR1`.
+    pub fn dump_state(&self) -> impl Future<Item = (), Error = MailboxError> {
+        self.board_poller
+            .send(GetBoardPollStatus)
+            .join5(
+                self.thread_updater.send(GetTrackedThreads),
+                self.thread_updater.send(GetThreadUpdaterStats),
+                self.thread_updater.send(GetScrapeLag),
+                self.fetcher.send(GetFetcherStats),
+            )
+            .join(self.database.send(GetDatabaseStats))
+            .map(|((boards, tracked, updater, lag, fetcher), database)| {
+                log_state(
+                    boards.unwrap_or_default(),
+                    tracked.unwrap_or_default(),
+                    updater.unwrap_or_default(),
+                    lag.unwrap_or_default(),
+                    fetcher.unwrap_or_default(),
+                    database,
+                );
+            })
+    }
+}
+
+fn log_state(
+    boards: HashMap<Board, BoardPollStatus>,
+    tracked: HashMap<Board, TrackedThreads>,
+    updater: ThreadUpdaterStats,
+    lag: HashMap<Board, LagStats>,
+    fetcher: FetcherStats,
+    database: Result<DatabaseStats, mysql_async::error::Error>,
+) {
+    info!("State dump:");
+    let mut names: Vec<Board> = boards.keys().cloned().collect();
+    names.sort();
+    let now = Utc::now();
+    for board in names {
+        let status = boards[&board];
+        let tracked = tracked.get(&board).cloned().unwrap_or_default();
+        let last_poll = match status.last_poll {
+            Some(time) => format!("{} s ago", (now - time).num_seconds()),
+            None => String::from("never"),
+        };
+        let lag = match lag.get(&board) {
+            Some(lag) if lag.samples > 0 => format!("{} s", lag.latest.as_secs()),
+            _ => String::from("unknown"),
+        };
+        info!(
+            "  /{}/: {}, {} threads listed, {} tracked ({} KiB of metadata), last poll {}, \
+             {} writes waiting, lag {}",
+            board,
+            if status.polling {
+                "polling"
+            } else {
+                "not polling"
+            },
+            status.threads,
+            tracked.threads,
+            tracked.metadata_bytes / 1024,
+            last_poll,
+            status.waiting_writes,
+            lag,
+        );
+    }
+
+    info!(
+        "  Thread updater: {} threads tracked, {} evicted, {} ignored",
+        updater.tracked_threads, updater.evicted_threads, updater.ignored_threads,
+    );
+    let channels = [
+        ("media", Some(fetcher.media)),
+        ("thumbs", fetcher.thumbs),
+        ("thread", Some(fetcher.thread)),
+        ("thread list", Some(fetcher.thread_list)),
+    ];
+    for (name, channel) in channels.iter() {
+        if let Some(channel) = channel {
+            info!(
+                "  Fetcher {} channel: {} queued, {} retrying, {} started",
+                name, channel.queued, channel.retrying, channel.started,
+            );
+        }
+    }
+    info!(
+        "  Fetcher: {} circuit breaker trips, {} API blocks, {} media blocks, disk space {}",
+        fetcher.circuit_breaker_trips,
+        fetcher.api_blocked,
+        fetcher.media_blocked,
+        if fetcher.disk_space_low { "low" } else { "ok" },
+    );
+    match database {
+        Ok(database) => info!(
+            "  Database: {} writes in progress, {} failed, {} ms per insert",
+            database.active_writes,
+            database.errors,
+            database.insert_latency.as_secs() * 1000
+                + u64::from(database.insert_latency.subsec_millis()),
+        ),
+        Err(err) => warn!("  Database: could not get statistics: {}", err),
+    }
+}
+
+/// Dump the scraper's state (see `Scraper::dump_state`) whenever Ena receives `SIGUSR1`.
+#[cfg(unix)]
+fn dump_state_on_signal(scraper: Scraper) {
+    Arbiter::spawn(
+        tokio_signal::unix::Signal::new(tokio_signal::unix::SIGUSR1)
+            .flatten_stream()
+            .map_err(|err| error!("Could not listen for SIGUSR1: {}", err))
+            .for_each(move |_| {
+                scraper
+                    .dump_state()
+                    .map_err(|err| error!("Could not dump state: {}", err))
+                    .then(|_| Ok(()))
+            }),
+    );
+}
+
+/// Seed the fetch cache of each board from the database (see `state.seed_from_database`). Boards
+/// which can't be read are logged and skipped.
+fn seed_fetch_cache(
+    config: &Config,
+    database: &Addr<Database>,
+    fetcher: &Addr<Fetcher>,
+) -> impl Future<Item = (), Error = ()> {
+    // Older cache entries would be dropped by the fetcher's daily cleanup anyway
+    let since = Utc::now() - chrono::Duration::days(1);
+    let seeds = config.boards.keys().map(|&board| {
+        let fetcher = fetcher.clone();
+        database
+            .send(GetThreadModifiedTimes(board, since))
+            .then(move |res| -> Result<(), ()> {
+                match res {
+                    Ok(Ok(times)) => fetcher.do_send(SeedFetchCache(board, times)),
+                    Ok(Err(err)) => error!("/{}/: Failed to seed the fetch cache: {}", board, err),
+                    Err(err) => error!("/{}/: Failed to seed the fetch cache: {}", board, err),
+                }
+                Ok(())
+            })
+    });
+    future::join_all(seeds.collect::<Vec<_>>()).map(|_| ())
+}
+
+impl ScraperBuilder {
+    /// Add an actor which receives a `PostsInserted` event whenever posts are sent to the
+    /// database. If ClickHouse is configured, it is always added as a post sink.
+    pub fn post_sink(mut self, sink: Recipient<PostsInserted>) -> Self {
+        self.post_sinks.push(sink);
+        self
+    }
+
+    /// Add an actor which receives a `ThreadArchived` event with the final state of each thread
+    /// that is archived. If `archive_export` is configured, an `ArchiveExporter` is always added as
+    /// an archive sink.
+    pub fn archive_sink(mut self, sink: Recipient<ThreadArchived>) -> Self {
+        self.archive_sinks.push(sink);
+        self
+    }
+
+    /// Add a step which can change new and modified posts before they're inserted. It runs after
+    /// the processors in `post_processors` in the config.
+    pub fn post_processor(mut self, processor: Box<dyn PostProcessor>) -> Self {
+        self.post_processors.push(processor);
+        self
+    }
+
+    /// Start the actors. This must be called from within a running `System`. The database and
+    /// fetcher actors are restarted if they panic (see `PanicSupervisor`), and the system is stopped
+    /// if they panic too often.
+    pub fn start(self) -> Result<Scraper, Error> {
+        let Self {
+            config,
+            mut post_sinks,
+            mut archive_sinks,
+            post_processors: extra_processors,
+        } = self;
+
+        // With a remote writer, the writer checks its own database. A dry run never reads or
+        // writes timestamps, so there's nothing to check
+        if config.database_media.remote_writer.is_none() && !config.database_media.dry_run {
+            check_timestamp_modes(&config).context("Database initialization error")?;
+        }
+        let (database, load) = {
+            let database = Database::try_new(&config).context("Database initialization error")?;
+            let load = database.load(&config.advanced);
+            // Panics which the supervisor can't catch still stop the system
+            let arbiter = Arbiter::builder()
+                .name("database")
+                .stop_system_on_panic(true)
+                .build();
+            let database = PanicSupervisor::start_in_arbiter(
+                "database",
+                RestartPolicy::new(&config.advanced),
+                &arbiter,
+                config.advanced.database_mailbox_capacity,
+                database,
+            );
+            (database, load)
+        };
+
+        // To create ThreadUpdater, we need Addr<Fetcher>. But to create Fetcher, we need
+        // Addr<ThreadUpdater>! To solve this circular dependency, we first create ThreadUpdater's
+        // Context. This gives us Addr<ThreadUpdater> without having to create ThreadUpdater. We
+        // then use this Addr to create Fetcher, which gives us Addr<Fetcher>. Finally, we pass this
+        // Addr to ThreadUpdater::new, and run ThreadUpdater in its previously created Context.
+        let thread_updater_ctx = {
+            let (_, receiver) =
+                actix::dev::channel::channel(config.advanced.thread_updater_mailbox_capacity);
+            Context::with_receiver(receiver)
+        };
+
+        let fetcher = Fetcher::create(
+            &config,
+            thread_updater_ctx.address(),
+            database.clone().recipient(),
+        )?;
+
+        if let Some(clickhouse_config) = &config.clickhouse {
+            let clickhouse = ClickHouse::try_new(clickhouse_config)?;
+            post_sinks.push(clickhouse.start().recipient());
+        }
+        if let Some(archive_export_config) = &config.archive_export {
+            let exporter = ArchiveExporter::try_new(archive_export_config)?;
+            archive_sinks.push(exporter.start_in_own_arbiter().recipient());
+        }
+        if let Some(media_server_config) = &config.media_server {
+            media_server::start(
+                media_server_config,
+                config.database_media.media_path.clone(),
+            )?;
+        }
+
+        let lag = LagTracker::new(config.lag_alert.as_ref())?;
+        let backlog = WriteBacklog::new(&config.advanced);
+        let mut post_processors = processors_from_config(&config.post_processors)?;
+        post_processors.extend(extra_processors);
+        let thread_updater = thread_updater_ctx.run(ThreadUpdater::new(
+            &config,
+            database.clone(),
+            fetcher.clone(),
+            post_sinks,
+            archive_sinks,
+            post_processors,
+            lag,
+            backlog.clone(),
+        ));
+
+        let board_poller = BoardPoller::new(
+            &config,
+            thread_updater.clone(),
+            fetcher.clone(),
+            backlog,
+            load,
+        );
+        let board_poller = if config.state.seed_from_database {
+            // Boards aren't polled until the fetch cache is seeded, or their threads would be
+            // fetched without it
+            let ctx = Context::new();
+            let addr = ctx.address();
+            Arbiter::spawn(
+                seed_fetch_cache(&config, &database, &fetcher).then(move |_| {
+                    ctx.run(board_poller);
+                    Ok(())
+                }),
+            );
+            addr
+        } else {
+            board_poller.start()
+        };
+
+        // Boards aren't polled until the coordinator claims them
+        let coordinator = match &config.coordination {
+            Some(coordination) => Some(
+                Coordinator::try_new(&config, coordination, board_poller.clone())
+                    .context("Coordination initialization error")?
+                    .start(),
+            ),
+            None => None,
+        };
+
+        let scraper = Scraper {
+            database,
+            fetcher,
+            thread_updater,
+            board_poller,
+            coordinator,
+        };
+        #[cfg(unix)]
+        dump_state_on_signal(scraper.clone());
+        Ok(scraper)
+    }
+}
@@ -0,0 +1,22 @@
+-- Rollups of board activity, maintained by Ena when `database_media.board_stats` is set. `time` is
+-- the start of each hour or day
+
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_stats_hourly` (
+  `time` int unsigned NOT NULL,
+  `posts` int unsigned NOT NULL,
+  `images` int unsigned NOT NULL,
+  `threads` int unsigned NOT NULL,
+  `names` int unsigned NOT NULL,
+
+  PRIMARY KEY (`time`)
+) ENGINE=InnoDB;
+
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_stats_daily` (
+  `time` int unsigned NOT NULL,
+  `posts` int unsigned NOT NULL,
+  `images` int unsigned NOT NULL,
+  `threads` int unsigned NOT NULL,
+  `names` int unsigned NOT NULL,
+
+  PRIMARY KEY (`time`)
+) ENGINE=InnoDB;
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS %%TABLE%% (
+  board LowCardinality(String),
+  num UInt64,
+  thread_num UInt64,
+  op UInt8,
+  timestamp DateTime('UTC'),
+  capcode FixedString(1),
+  country LowCardinality(Nullable(String)),
+  poster_hash Nullable(String),
+  has_media UInt8
+) ENGINE = ReplacingMergeTree
+PARTITION BY toYYYYMM(timestamp)
+ORDER BY (board, num)
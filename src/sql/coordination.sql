@@ -0,0 +1,15 @@
+-- Used to assign boards to the instances of Ena sharing a database (see `coordination` in the
+-- config). Each instance renews its row in `ena_instances` and its leases in `ena_board_leases`
+
+CREATE TABLE IF NOT EXISTS `ena_instances` (
+  `instance` varchar(100) NOT NULL,
+  `expires` int unsigned NOT NULL,
+  PRIMARY KEY (`instance`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
+
+CREATE TABLE IF NOT EXISTS `ena_board_leases` (
+  `board` varchar(20) NOT NULL,
+  `instance` varchar(100) NOT NULL DEFAULT '',
+  `expires` int unsigned NOT NULL DEFAULT '0',
+  PRIMARY KEY (`board`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
@@ -0,0 +1,25 @@
+-- Used instead of `%%BOARD%%_images` when `database_media.global_media` is enabled. Each file has
+-- one row, no matter how many boards it was posted on, and the `media_id` of posts refers to this
+-- table. Files are only downloaded to the directory of the board they were first posted on, which
+-- is stored next to each filename (e.g. `media` is at `{media_path}/{media_board}/image/...`).
+
+CREATE TABLE IF NOT EXISTS `ena_media` (
+  `media_id` int unsigned NOT NULL auto_increment,
+  `media_hash` varchar(25) NOT NULL,
+  `media_size` int unsigned NOT NULL DEFAULT '0',
+  `media_w` smallint unsigned NOT NULL DEFAULT '0',
+  `media_h` smallint unsigned NOT NULL DEFAULT '0',
+  `media` varchar(20),
+  `media_board` varchar(20),
+  `preview_op` varchar(20),
+  `preview_op_board` varchar(20),
+  `preview_reply` varchar(20),
+  `preview_reply_board` varchar(20),
+  `total` int unsigned NOT NULL DEFAULT '0',
+  `banned` smallint unsigned NOT NULL DEFAULT '0',
+
+  PRIMARY KEY (`media_id`),
+  UNIQUE media_hash_index (`media_hash`),
+  INDEX total_index (`total`),
+  INDEX banned_index (`banned`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_html` (
+  `num` int unsigned NOT NULL,
+  `comment` mediumtext,
+
+  PRIMARY KEY (`num`)
+) ENGINE=InnoDB;
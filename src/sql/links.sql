@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_links` (
+  `num` int unsigned NOT NULL,
+  `url_hash` binary(16) NOT NULL,
+  `url` text NOT NULL,
+  `domain` varchar(255) NOT NULL,
+
+  PRIMARY KEY (`num`, `url_hash`),
+  INDEX domain_index (`domain`)
+) ENGINE=InnoDB;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_media_hashes` (
+  `media_hash` varchar(25) NOT NULL,
+  `sha256` binary(32) NOT NULL,
+
+  PRIMARY KEY (`media_hash`),
+  INDEX sha256_index (`sha256`)
+) ENGINE=InnoDB;
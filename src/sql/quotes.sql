@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_quotes` (
+  `num` int unsigned NOT NULL,
+  `quoted_board` varchar(16) NOT NULL,
+  `quoted_num` int unsigned NOT NULL,
+
+  PRIMARY KEY (`num`, `quoted_board`, `quoted_num`),
+  INDEX quoted_index (`quoted_board`, `quoted_num`)
+) ENGINE=InnoDB;
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS `%%BOARD%%_raw` (
+  `num` int unsigned NOT NULL,
+  `timestamp_fetched` int unsigned NOT NULL,
+  `json` mediumblob NOT NULL,
+
+  PRIMARY KEY (`num`, `timestamp_fetched`)
+) ENGINE=InnoDB;
@@ -0,0 +1,7 @@
+-- The schema version of each board's tables (by base table name), used to apply migrations
+
+CREATE TABLE IF NOT EXISTS `ena_schema_version` (
+  `table_name` varchar(64) NOT NULL,
+  `version` int unsigned NOT NULL,
+  PRIMARY KEY (`table_name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;
@@ -0,0 +1,10 @@
+-- The progress of `ena migrate-timestamps` on each board (by base table name). `step` is the index
+-- of the table being converted, and `last_key` is the last primary key converted in it
+
+CREATE TABLE IF NOT EXISTS `ena_utc_timestamps` (
+  `table_name` varchar(64) NOT NULL,
+  `step` int unsigned NOT NULL DEFAULT '0',
+  `last_key` int unsigned NOT NULL DEFAULT '0',
+  `done` bool NOT NULL DEFAULT '0',
+  PRIMARY KEY (`table_name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8;